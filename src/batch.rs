@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+
+use crate::types::{Command, Message, Source};
+
+/// Messages the server grouped with `BATCH`, e.g. a netsplit or a
+/// chathistory reply, once the batch has been closed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Batch {
+    /// Without the leading `+`.
+    pub reference: String,
+    pub kind: String,
+    pub params: Vec<String>,
+    /// The `BATCH +reference` message itself, for its tags and source.
+    pub start: Message,
+    /// Messages tagged with this batch, without their `batch` tag.
+    pub messages: Vec<Message>,
+    pub nested: Vec<Batch>,
+}
+
+impl Batch {
+    /// Whether the messages replay history rather than happen now.
+    pub fn is_history(&self) -> bool {
+        return self.kind == "chathistory"
+    }
+
+    /// Messages of this batch and all nested ones.
+    pub fn all_messages(&self) -> Vec<&Message> {
+        return self.messages.iter().chain(self.nested.iter().flat_map(Batch::all_messages)).collect()
+    }
+}
+
+/// What [`BatchAssembler::feed`] lets through.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Assembled {
+    Message(Message),
+    Batch(Batch),
+}
+
+/// Collects messages carrying a `batch` tag until their batch ends; nested
+/// batches end up in their parent.
+#[derive(Debug, Default)]
+pub struct BatchAssembler {
+    open: HashMap<String, (Option<String>, Batch)>,
+}
+
+impl BatchAssembler {
+    pub fn new() -> Self {
+        return BatchAssembler::default()
+    }
+
+    /// Number of batches started but not ended yet.
+    pub fn pending(&self) -> usize {
+        return self.open.len()
+    }
+
+    /// Returns `message` unless it belongs to an open batch, and a top-level
+    /// batch once its end arrives.
+    pub fn feed(&mut self, mut message: Message) -> Option<Assembled> {
+        let parent = message.batch().filter(|reference| self.open.contains_key(*reference)).map(str::to_string);
+
+        if let Command::BATCH { reference, kind, params } = &message.command {
+            if let Some(reference) = reference.strip_prefix('+') {
+                let batch = Batch {
+                    reference: reference.to_string(),
+                    kind: kind.clone().unwrap_or_default(),
+                    params: params.clone(),
+                    start: message.clone(),
+                    messages: Vec::new(),
+                    nested: Vec::new(),
+                };
+                self.open.insert(reference.to_string(), (parent, batch));
+                return None;
+            }
+            if let Some((parent, batch)) = reference.strip_prefix('-').and_then(|reference| self.open.remove(reference)) {
+                return match parent.and_then(|parent| self.open.get_mut(&parent)) {
+                    Some((_, parent)) => {
+                        parent.nested.push(batch);
+                        None
+                    }
+                    None => Some(Assembled::Batch(batch)),
+                }
+            }
+        }
+
+        let Some(parent) = parent else {
+            return Some(Assembled::Message(message));
+        };
+        message.remove_tag("batch");
+        self.open.get_mut(&parent).unwrap().1.messages.push(message);
+        return None
+    }
+}
+
+enum Item {
+    Message(Message),
+    Batch(BatchBuilder),
+}
+
+/// Wraps messages in `BATCH +ref` / `BATCH -ref`, tagging each with the
+/// generated reference.
+pub struct BatchBuilder {
+    kind: String,
+    params: Vec<String>,
+    source: Option<Source>,
+    tags: Vec<(String, Option<String>)>,
+    items: Vec<Item>,
+}
+
+impl BatchBuilder {
+    pub fn new(kind: &str, params: Vec<String>) -> Self {
+        return BatchBuilder { kind: kind.to_string(), params, source: None, tags: Vec::new(), items: Vec::new() }
+    }
+
+    /// Source of the `BATCH` lines, usually the server; nested batches
+    /// without one use their parent's.
+    pub fn source(mut self, source: Source) -> Self {
+        self.source = Some(source);
+        return self
+    }
+
+    /// Tags the `BATCH +ref` line, e.g. with the `label` of a labeled response.
+    pub fn tag(mut self, key: &str, value: Option<&str>) -> Self {
+        self.tags.push((key.to_string(), value.map(str::to_string)));
+        return self
+    }
+
+    pub fn message(mut self, message: Message) -> Self {
+        self.items.push(Item::Message(message));
+        return self
+    }
+
+    pub fn messages<I: IntoIterator<Item = Message>>(mut self, messages: I) -> Self {
+        self.items.extend(messages.into_iter().map(Item::Message));
+        return self
+    }
+
+    pub fn nested(mut self, batch: BatchBuilder) -> Self {
+        self.items.push(Item::Batch(batch));
+        return self
+    }
+
+    /// The lines to send; `next_reference` is called once per batch.
+    pub fn build<F: FnMut() -> String>(self, next_reference: &mut F) -> Vec<Message> {
+        return self.build_in(None, None, next_reference)
+    }
+
+    fn build_in<F: FnMut() -> String>(self, parent: Option<&str>, source: Option<&Source>, next_reference: &mut F) -> Vec<Message> {
+        let reference = next_reference();
+        let source = self.source.or_else(|| source.cloned());
+        let wrap = |message: Message| match parent {
+            Some(parent) => message.with_tag("batch", Some(parent)),
+            None => message,
+        };
+
+        let mut start = Message::new(None, source.clone(), Command::BATCH {
+            reference: format!("+{}", reference),
+            kind: Some(self.kind),
+            params: self.params,
+        });
+        for (key, value) in &self.tags {
+            start.set_tag(key, value.as_deref());
+        }
+        let mut messages = vec![wrap(start)];
+        for item in self.items {
+            match item {
+                // Lines of batches built elsewhere keep their own reference.
+                Item::Message(message) if message.has_tag("batch") => messages.push(message),
+                Item::Message(message) => messages.push(message.with_tag("batch", Some(&reference))),
+                Item::Batch(batch) => messages.extend(batch.build_in(Some(&reference), source.as_ref(), next_reference)),
+            }
+        }
+        messages.push(wrap(Message::new(None, source, Command::BATCH {
+            reference: format!("-{}", reference),
+            kind: None,
+            params: Vec::new(),
+        })));
+        return messages
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::types::{Command, Message, Source};
+    use crate::test_util::parse;
+
+    use super::{Assembled, BatchAssembler, BatchBuilder};
+
+    #[test]
+    fn test_assembler() {
+        let mut assembler = BatchAssembler::new();
+        let lines = [
+            ":irc.example.com BATCH +outer labeled-response",
+            "@batch=outer :irc.example.com BATCH +inner chathistory #chan",
+            "@batch=inner :dan!d@host PRIVMSG #chan :old",
+            "@batch=outer :irc.example.com NOTICE me :done",
+            "@batch=unknown :eve!e@host PRIVMSG #chan :live",
+            "@batch=outer :irc.example.com BATCH -inner",
+        ];
+        for line in lines {
+            let passed = assembler.feed(parse(line));
+            assert_eq!(line.starts_with("@batch=unknown"), passed.is_some());
+        }
+        assert_eq!(1, assembler.pending());
+
+        let Some(Assembled::Batch(batch)) = assembler.feed(parse(":irc.example.com BATCH -outer")) else { panic!() };
+        assert_eq!(0, assembler.pending());
+        assert_eq!(("outer", "labeled-response"), (batch.reference.as_str(), batch.kind.as_str()));
+        assert_eq!("NOTICE me done\r\n", Message { source: None, ..batch.messages[0].clone() }.to_bytes());
+        let inner = &batch.nested[0];
+        assert!(inner.is_history());
+        assert_eq!(vec!["#chan".to_string()], inner.params);
+        assert_eq!(2, batch.all_messages().len());
+    }
+
+    #[test]
+    fn test_builder() {
+        let server = Source { name: "irc.example.com".to_string(), user: None, host: None };
+        let quit = |nick: &str| Message::from_bytes(format!(":{}!u@host QUIT :a.net b.net", nick).as_bytes()).unwrap();
+        let mut next = 0;
+        let lines: Vec<String> = BatchBuilder::new("netsplit", vec!["a.net".to_string(), "b.net".to_string()])
+            .source(server)
+            .message(quit("dan"))
+            .nested(BatchBuilder::new("example.com/inner", Vec::new()).message(quit("eve")))
+            .build(&mut || { next += 1; format!("b{}", next) })
+            .into_iter()
+            .map(Message::to_bytes)
+            .collect();
+        assert_eq!(vec![
+            ":irc.example.com BATCH +b1 netsplit a.net b.net\r\n",
+            "@batch=b1 :dan!u@host QUIT :a.net b.net\r\n",
+            "@batch=b1 :irc.example.com BATCH +b2 example.com/inner\r\n",
+            "@batch=b2 :eve!u@host QUIT :a.net b.net\r\n",
+            "@batch=b1 :irc.example.com BATCH -b2\r\n",
+            ":irc.example.com BATCH -b1\r\n",
+        ], lines);
+
+        // What the builder produces, the assembler takes apart again.
+        let mut assembler = BatchAssembler::new();
+        let assembled: Vec<Assembled> = lines.iter().filter_map(|line| assembler.feed(parse(line.trim_end()))).collect();
+        let [Assembled::Batch(batch)] = assembled.as_slice() else { panic!() };
+        assert_eq!(Command::QUIT { reason: Some("a.net b.net".to_string()) }, batch.nested[0].messages[0].command);
+    }
+}
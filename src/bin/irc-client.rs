@@ -0,0 +1,294 @@
+use std::io;
+
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::TcpStream;
+
+use irc_proto::connection::{Connection, IRCError};
+use irc_proto::ctcp::{Ctcp, CtcpResponder};
+use irc_proto::encoding::{Decoding, LegacyEncoding};
+use irc_proto::types::{Command, Message, Source};
+use irc_proto::unix_time;
+
+/// How many times an `_` is appended to a nickname that is in use.
+const MAX_NICK_RETRIES: usize = 3;
+
+const USAGE: &str = "commands: /join <#channel> [key], /part [#channel] [reason], /msg <target> <text>, /me <action>, /nick <nick>, /quote <raw line>, /quit [reason]";
+
+struct Session {
+    nick: String,
+    registered: bool,
+    nick_retries: usize,
+    target: Option<String>,
+    ctcp: CtcpResponder,
+}
+
+/// `HH:MM:SS` of the current UTC time.
+fn timestamp() -> String {
+    let secs = unix_time() % 86400;
+    return format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+fn print_line(line: &str) {
+    println!("[{}] {}", timestamp(), line);
+}
+
+fn render(message: &Message) -> String {
+    let nick = message.source.as_ref().map(|source| source.name.as_str()).unwrap_or("*");
+    return match &message.command {
+        Command::PRIVMSG { targets, text } => match Ctcp::parse(text) {
+            Some(ctcp) if ctcp.is_action() => format!("{} * {} {}", targets, nick, ctcp.params.unwrap_or_default()),
+            Some(ctcp) => format!("-- CTCP {} request from {}", ctcp.command, nick),
+            None => format!("{} <{}> {}", targets, nick, text),
+        },
+        Command::NOTICE { targets, text } => match Ctcp::parse(text) {
+            Some(ctcp) => format!("-- CTCP {} reply from {}: {}", ctcp.command, nick, ctcp.params.unwrap_or_default()),
+            None => format!("{} -{}- {}", targets, nick, text),
+        },
+        Command::JOIN { channels, .. } => format!("--> {} joined {}", nick, channels),
+        Command::PART { channels, reason } => format!("<-- {} left {} ({})", nick, channels, reason.as_deref().unwrap_or("")),
+        Command::KICK { channel, user, comment } => format!("<-- {} was kicked from {} by {} ({})", user, channel, nick, comment.as_deref().unwrap_or("")),
+        Command::QUIT { reason } => format!("<-- {} quit ({})", nick, reason.as_deref().unwrap_or("")),
+        Command::NICK { nickname } => format!("-- {} is now known as {}", nick, nickname),
+        Command::TOPIC { channel, topic } => format!("-- {} set the topic of {} to: {}", nick, channel, topic.as_deref().unwrap_or("")),
+        Command::MODE { target, modestring, arguments } => {
+            format!("-- {} sets mode {} {} {}", nick, target, modestring.as_deref().unwrap_or(""), arguments.join(" ")).trim_end().to_string()
+        }
+        Command::ERROR { reason } => format!("!! {}", reason),
+        command if command.numeric() != 0 => {
+            // Skip the client parameter, it is always our own nick.
+            let params = command.params();
+            format!("{} {}", command.command(), params.get(1..).unwrap_or_default().join(" "))
+        }
+        _ => message.clone().to_bytes().trim_end().to_string(),
+    }
+}
+
+/// Turns one line of user input into the message to send, or `None` when
+/// the line was handled locally.
+fn parse_input(session: &mut Session, line: &str) -> Option<Message> {
+    let line = line.trim_end();
+    if line.is_empty() {
+        return None;
+    }
+    let Some(input) = line.strip_prefix('/') else {
+        return match &session.target {
+            Some(target) => Some(privmsg(session, target.clone(), line.to_string())),
+            None => {
+                print_line("no target, /join a channel or /msg someone first");
+                None
+            }
+        };
+    };
+
+    let (name, rest) = input.split_once(' ').unwrap_or((input, ""));
+    let mut args = rest.splitn(2, ' ');
+    let first = args.next().filter(|arg| !arg.is_empty()).map(str::to_string);
+    let second = args.next().map(str::to_string);
+
+    let command = match (name.to_ascii_lowercase().as_str(), first) {
+        ("join", Some(channels)) => {
+            session.target = channels.split(',').next().map(str::to_string);
+            Command::JOIN { channels, keys: second, account: None, realname: None }
+        }
+        ("part", Some(channels)) if channels.starts_with(['#', '&']) => Command::PART { channels, reason: second },
+        ("part", _) => match &session.target {
+            Some(target) => Command::PART { channels: target.clone(), reason: Some(rest.to_string()).filter(|reason| !reason.is_empty()) },
+            None => {
+                print_line("not on a channel");
+                return None;
+            }
+        },
+        ("msg", Some(target)) if second.is_some() => {
+            session.target = Some(target.clone());
+            return Some(privmsg(session, target, second.unwrap()));
+        }
+        ("me", _) if !rest.is_empty() && session.target.is_some() => {
+            let target = session.target.clone().unwrap();
+            return Some(privmsg(session, target, Ctcp::action(rest).to_string()));
+        }
+        ("nick", Some(nickname)) => Command::NICK { nickname },
+        ("quote", Some(_)) => match Message::from_bytes(rest.as_bytes()) {
+            Some(message) => return Some(message),
+            None => {
+                print_line("could not parse message");
+                return None;
+            }
+        },
+        ("quit", _) => Command::QUIT { reason: Some(rest.to_string()).filter(|reason| !reason.is_empty()) },
+        _ => {
+            print_line(USAGE);
+            return None;
+        }
+    };
+    return Some(Message::new(None, None, command))
+}
+
+/// Builds a PRIVMSG and echoes it locally, since servers do not send our own messages back.
+fn privmsg(session: &Session, target: String, text: String) -> Message {
+    let message = Message::new(None, None, Command::PRIVMSG { targets: target, text });
+    let mut echo = message.clone();
+    echo.source = Some(Source { name: session.nick.clone(), user: None, host: None });
+    print_line(&render(&echo));
+    return message
+}
+
+/// Handles protocol housekeeping for an incoming message, returning a reply if one is due.
+fn handle_incoming(session: &mut Session, message: &Message) -> Option<Message> {
+    match &message.command {
+        Command::PING { token } => return Some(Message::new(None, None, Command::PONG { server: None, token: token.clone() })),
+        Command::RPL_WELCOME { client, .. } => {
+            session.nick = client.clone();
+            session.registered = true;
+        }
+        Command::ERR_NICKNAMEINUSE { .. } if !session.registered => {
+            if session.nick_retries == MAX_NICK_RETRIES {
+                print_line("nickname in use, pick another one with /nick");
+                return None;
+            }
+            session.nick_retries += 1;
+            session.nick.push('_');
+            return Some(Message::new(None, None, Command::NICK { nickname: session.nick.clone() }));
+        }
+        Command::NICK { nickname } if message.source.as_ref().is_some_and(|source| source.name == session.nick) => {
+            session.nick = nickname.clone();
+        }
+        Command::PRIVMSG { .. } => return session.ctcp.respond(message, unix_time()),
+        _ => {}
+    }
+    return None
+}
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    env_logger::init();
+
+    let mut args = std::env::args().skip(1);
+    let (Some(address), Some(nick)) = (args.next(), args.next()) else {
+        eprintln!("usage: irc-client <host:port> <nick>");
+        std::process::exit(2);
+    };
+
+    let stream = TcpStream::connect(&address).await?;
+    let peer = stream.peer_addr()?;
+    let mut connection = Connection::new(stream, peer);
+    // Older clients on the other end of a channel may still send CP1252.
+    connection.set_decoding(Decoding::Fallback(LegacyEncoding::Cp1252));
+    let ctcp = CtcpResponder::new(&format!("irc-client {}", env!("CARGO_PKG_VERSION")));
+    let mut session = Session { nick: nick.clone(), registered: false, nick_retries: 0, target: None, ctcp };
+    print_line(&format!("connected to {}", address));
+    print_line(USAGE);
+
+    let registration = [
+        Command::NICK { nickname: nick.clone() },
+        Command::USER { user: nick.clone(), mode: "0".to_string(), unused: "*".to_string(), realname: nick },
+    ];
+    for command in registration {
+        if connection.write(Message::new(None, None, command)).await.is_err() {
+            return Ok(());
+        }
+    }
+
+    let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        tokio::select! {
+            incoming = connection.read() => {
+                let message = match incoming {
+                    Ok(message) => message,
+                    Err(IRCError::LengthExceeded) => {
+                        print_line("skipped an overlong line");
+                        continue;
+                    }
+                    Err(_) => {
+                        print_line("connection closed");
+                        return Ok(());
+                    }
+                };
+                if !matches!(message.command, Command::PING { .. }) {
+                    print_line(&render(&message));
+                }
+                if let Some(reply) = handle_incoming(&mut session, &message) {
+                    if connection.write(reply).await.is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+            line = stdin.next_line() => {
+                let Some(line) = line? else {
+                    connection.write(Message::new(None, None, Command::QUIT { reason: None })).await.ok();
+                    connection.shutdown().await;
+                    return Ok(());
+                };
+                if let Some(message) = parse_input(&mut session, &line) {
+                    if connection.write(message).await.is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use irc_proto::ctcp::{Ctcp, CtcpResponder};
+    use irc_proto::types::Message;
+
+    use super::{handle_incoming, parse_input, render, Session, MAX_NICK_RETRIES};
+
+    fn parse(line: &str) -> Message {
+        return Message::from_bytes(line.as_bytes()).unwrap()
+    }
+
+    fn session() -> Session {
+        return Session { nick: "dan".to_string(), registered: false, nick_retries: 0, target: None, ctcp: CtcpResponder::new("test") }
+    }
+
+    fn input(session: &mut Session, line: &str) -> Option<String> {
+        return parse_input(session, line).map(|message| message.to_bytes())
+    }
+
+    #[test]
+    fn test_parse_input() {
+        let mut session = session();
+        assert_eq!(None, input(&mut session, "hello"));
+        assert_eq!(None, input(&mut session, "/part"));
+        assert_eq!(None, input(&mut session, "/bogus"));
+
+        assert_eq!(Some("JOIN #a,#b key\r\n".to_string()), input(&mut session, "/join #a,#b key"));
+        assert_eq!(Some("#a".to_string()), session.target);
+        assert_eq!(Some("PRIVMSG #a :hello there\r\n".to_string()), input(&mut session, "hello there"));
+        assert_eq!(Some(format!("PRIVMSG #a :{}\r\n", Ctcp::action("waves"))), input(&mut session, "/me waves"));
+        assert_eq!(Some("PART #a :bye now\r\n".to_string()), input(&mut session, "/part bye now"));
+
+        assert_eq!(Some("PRIVMSG eve hi\r\n".to_string()), input(&mut session, "/msg eve hi"));
+        assert_eq!(Some("eve".to_string()), session.target);
+        assert_eq!(Some("NICK dan2\r\n".to_string()), input(&mut session, "/NICK dan2"));
+        assert_eq!(Some("WHOIS eve\r\n".to_string()), input(&mut session, "/quote WHOIS eve"));
+        assert_eq!(Some("QUIT\r\n".to_string()), input(&mut session, "/quit"));
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!("#a <eve> hi", render(&parse(":eve!e@host PRIVMSG #a :hi")));
+        assert_eq!("#a * eve waves", render(&parse(":eve!e@host PRIVMSG #a :\x01ACTION waves\x01")));
+        assert_eq!("-- CTCP VERSION reply from eve: v1", render(&parse(":eve!e@host NOTICE dan :\x01VERSION v1\x01")));
+        assert_eq!("--> eve joined #a", render(&parse(":eve!e@host JOIN #a")));
+        assert_eq!("-- eve sets mode #a +o dan", render(&parse(":eve!e@host MODE #a +o dan")));
+        assert_eq!("-- eve sets mode #a +m", render(&parse(":eve!e@host MODE #a +m")));
+        // Numerics leave out our own nick.
+        assert_eq!("433 dan", render(&parse(":irc.example.com 433 * dan :Nickname is already in use")));
+    }
+
+    #[test]
+    fn test_nick_in_use() {
+        let mut session = session();
+        let in_use = parse(":irc.example.com 433 * dan :Nickname is already in use");
+        for retry in 1..=MAX_NICK_RETRIES {
+            let reply = handle_incoming(&mut session, &in_use).unwrap();
+            assert_eq!(format!("NICK dan{}\r\n", "_".repeat(retry)), reply.to_bytes());
+        }
+        assert_eq!(None, handle_incoming(&mut session, &in_use));
+        assert_eq!(format!("dan{}", "_".repeat(MAX_NICK_RETRIES)), session.nick);
+    }
+}
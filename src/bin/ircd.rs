@@ -0,0 +1,581 @@
+use std::io;
+use std::sync::Arc;
+
+use log::info;
+use serde::Deserialize;
+use tokio::net::TcpListener;
+
+use irc_proto::capability::Capability;
+use irc_proto::channel::{is_channel_name, ModeChange, PREFIX_MODES};
+use irc_proto::channel_registry::Broadcast;
+use irc_proto::names::names_reply;
+use irc_proto::server::{Context, IrcServer, ServerConfig};
+use irc_proto::tags::ClientTagDeny;
+use irc_proto::types::{Command, Message};
+use irc_proto::unix_time;
+use irc_proto::who::{who_reply, WhoQuery};
+use irc_proto::whois::{whois_reply, whowas_reply};
+
+const CHANNEL_MODES: &str = "beIiklmnpst";
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct Config {
+    listen: String,
+    name: String,
+    network: String,
+    description: String,
+    password: Option<String>,
+    motd: Vec<String>,
+    whowas_capacity: usize,
+    monitor_limit: usize,
+    /// CLIENTTAGDENY value, e.g. `*,-draft/react`.
+    client_tag_deny: String,
+    /// SQLite database for CHATHISTORY, history is kept in memory without one.
+    history_path: Option<String>,
+    #[serde(rename = "oper")]
+    opers: Vec<OperConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OperConfig {
+    name: String,
+    password: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let defaults = ServerConfig::default();
+        return Config {
+            listen: "127.0.0.1:6667".to_string(),
+            name: defaults.name,
+            network: defaults.network,
+            description: "irc_proto reference server".to_string(),
+            password: None,
+            motd: Vec::new(),
+            whowas_capacity: defaults.whowas_capacity,
+            monitor_limit: defaults.monitor_limit,
+            client_tag_deny: defaults.client_tag_deny.to_string(),
+            history_path: None,
+            opers: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    fn load(path: &str) -> io::Result<Config> {
+        let contents = std::fs::read_to_string(path)?;
+        return toml::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+    }
+}
+
+fn motd(config: &Config, ctx: &mut Context<'_>) {
+    let client = ctx.nick();
+    if config.motd.is_empty() {
+        ctx.reply(Command::ERR_NOMOTD { client });
+        return;
+    }
+    ctx.reply(Command::RPL_MOTDSTART { client: client.clone(), line: format!("- {} Message of the day - ", config.name) });
+    for line in &config.motd {
+        ctx.reply(Command::RPL_MOTD { client: client.clone(), line: format!("- {}", line) });
+    }
+    ctx.reply(Command::RPL_ENDOFMOTD { client });
+}
+
+fn send_topic(ctx: &Context<'_>, name: &str, reply_if_unset: bool) {
+    let client = ctx.nick();
+    let Some(channel) = ctx.state.channels.get(name) else { return };
+    match &channel.topic {
+        Some(topic) => {
+            ctx.reply(Command::RPL_TOPIC { client: client.clone(), channel: channel.name.clone(), topic: topic.text.clone() });
+            ctx.reply(Command::RPL_TOPICWHOTIME { client, channel: channel.name.clone(), nick: topic.setter.clone(), setat: topic.set_at.to_string() });
+        }
+        None if reply_if_unset => ctx.reply(Command::RPL_NOTOPIC { client, channel: channel.name.clone() }),
+        None => {}
+    }
+}
+
+fn send_names(ctx: &Context<'_>, name: &str) {
+    let client = ctx.nick();
+    let replies = match ctx.state.channels.get(name) {
+        Some(channel) if !channel.modes.secret || channel.is_member(&client) => {
+            let users = &ctx.state.users;
+            names_reply(&ctx.state.server_source(), &client, channel, &ctx.client().caps, |nick| users.get(nick).map(|user| user.source()))
+        }
+        _ => vec![Message::new(None, Some(ctx.state.server_source()), Command::RPL_ENDOFNAMES { client, channel: name.to_string() })],
+    };
+    for reply in replies {
+        ctx.send(reply);
+    }
+}
+
+fn join(ctx: &mut Context<'_>, message: &Message) {
+    let Command::JOIN { channels, keys, .. } = &message.command else { return };
+    let source = ctx.source();
+
+    if channels == "0" {
+        let joined: Vec<String> = ctx.state.channels.channels_of(&source.name).map(|channel| channel.name.clone()).collect();
+        for name in joined {
+            if let Ok(broadcasts) = ctx.state.channels.part(&source, &name, None) {
+                ctx.deliver(broadcasts);
+            }
+        }
+        return;
+    }
+
+    let mut keys = keys.as_deref().unwrap_or("").split(',');
+    for name in channels.split(',') {
+        let key = keys.next().filter(|key| !key.is_empty());
+        match ctx.state.channels.join(&source, name, key) {
+            Ok(broadcasts) if broadcasts.is_empty() => {}
+            Ok(broadcasts) => {
+                ctx.deliver(broadcasts);
+                send_topic(ctx, name, false);
+                send_names(ctx, name);
+            }
+            Err(error) => ctx.reply(error.to_command(&ctx.nick())),
+        }
+    }
+}
+
+fn part(ctx: &mut Context<'_>, message: &Message) {
+    let Command::PART { channels, reason } = &message.command else { return };
+    let source = ctx.source();
+    for name in channels.split(',') {
+        match ctx.state.channels.part(&source, name, reason.clone()) {
+            Ok(broadcasts) => ctx.deliver(broadcasts),
+            Err(error) => ctx.reply(error.to_command(&ctx.nick())),
+        }
+    }
+}
+
+fn kick(ctx: &mut Context<'_>, message: &Message) {
+    let Command::KICK { channel, user, comment } = &message.command else { return };
+    let source = ctx.source();
+    for target in user.split(',') {
+        match ctx.state.channels.kick(&source, channel, target, comment.clone()) {
+            Ok(broadcasts) => ctx.deliver(broadcasts),
+            Err(error) => ctx.reply(error.to_command(&ctx.nick())),
+        }
+    }
+}
+
+fn topic(ctx: &mut Context<'_>, message: &Message) {
+    let Command::TOPIC { channel: name, topic } = &message.command else { return };
+    let source = ctx.source();
+    let client = ctx.nick();
+
+    let Some(channel) = ctx.state.channels.get_mut(name) else {
+        ctx.reply(Command::ERR_NOSUCHCHANNEL { client, channel: name.clone() });
+        return;
+    };
+    let Some(member) = channel.member(&client) else {
+        let channel = channel.name.clone();
+        ctx.reply(Command::ERR_NOTONCHANNEL { client, channel });
+        return;
+    };
+
+    match topic {
+        None => send_topic(ctx, name, true),
+        Some(_) if channel.modes.topic_lock && !member.is_halfop(&channel.mode_table.prefix_symbols) => {
+            let channel = channel.name.clone();
+            ctx.reply(Command::ERR_CHANOPRIVSNEEDED { client, channel });
+        }
+        Some(text) => {
+            channel.set_topic(text.clone(), client, unix_time());
+            let broadcast = Broadcast {
+                recipients: channel.members().map(|member| member.nick.clone()).collect(),
+                message: Message::new(None, Some(source), Command::TOPIC { channel: channel.name.clone(), topic: Some(text.clone()) }),
+            };
+            ctx.deliver(vec![broadcast]);
+        }
+    }
+}
+
+fn names(ctx: &mut Context<'_>, message: &Message) {
+    let Command::NAMES { channels } = &message.command else { return };
+    match channels {
+        Some(channels) => channels.split(',').for_each(|name| send_names(ctx, name)),
+        None => ctx.reply(Command::RPL_ENDOFNAMES { client: ctx.nick(), channel: "*".to_string() }),
+    }
+}
+
+/// Delivers PRIVMSG, NOTICE or TAGMSG to every target with the client-only
+/// tags the server relays, echoing it back to senders with `echo-message`;
+/// NOTICE never triggers replies and TAGMSG is not kept in history.
+fn route(ctx: &mut Context<'_>, message: &Message) {
+    let (targets, text, is_notice) = match &message.command {
+        Command::PRIVMSG { targets, text } => (targets, Some(text), false),
+        Command::NOTICE { targets, text } => (targets, Some(text), true),
+        Command::TAGMSG { targets } => (targets, None, false),
+        _ => return,
+    };
+    let relayed = ctx.state.relayed_tags(message);
+    let source = ctx.source();
+    let client = ctx.nick();
+    let reply = |ctx: &Context<'_>, command: Command| {
+        if !is_notice {
+            ctx.reply(command);
+        }
+    };
+    let echo = |ctx: &Context<'_>, message: Message| {
+        if ctx.client().caps.contains(Capability::EchoMessage) {
+            ctx.send(message);
+        }
+    };
+
+    if text.is_some_and(|text| text.is_empty()) {
+        reply(ctx, Command::ERR_NOTEXTTOSEND { client });
+        return;
+    }
+
+    for target in targets.split(',') {
+        let command = match (text, is_notice) {
+            (Some(text), true) => Command::NOTICE { targets: target.to_string(), text: text.to_string() },
+            (Some(text), false) => Command::PRIVMSG { targets: target.to_string(), text: text.to_string() },
+            (None, _) => Command::TAGMSG { targets: target.to_string() },
+        };
+        let tags = Some(relayed.clone()).filter(|tags| !tags.is_empty());
+        let outgoing = ctx.state.stamp(Message::new(tags, Some(source.clone()), command));
+
+        if is_channel_name(target) {
+            let Some(channel) = ctx.state.channels.get(target) else {
+                reply(ctx, Command::ERR_NOSUCHCHANNEL { client: client.clone(), channel: target.to_string() });
+                continue;
+            };
+            let member = channel.member(&client);
+            let banned = channel.bans.matches(&source) && !channel.excepts.matches(&source);
+            let allowed = match member {
+                Some(member) => member.is_voiced() || (!channel.modes.moderated && !banned),
+                None => !channel.modes.no_external && !channel.modes.moderated && !banned,
+            };
+            if !allowed {
+                reply(ctx, Command::ERR_CANNOTSENDTOCHAN { client: client.clone(), channel: channel.name.clone() });
+                continue;
+            }
+            let recipients = channel.members()
+                .filter(|member| !ctx.state.users.casemapping().equals(&member.nick, &client))
+                .map(|member| member.nick.clone())
+                .collect();
+            ctx.deliver(vec![Broadcast { recipients, message: outgoing.clone() }]);
+            if text.is_some() {
+                ctx.state.record_history(&client, target, &outgoing);
+            }
+            echo(ctx, outgoing);
+        } else {
+            match ctx.state.users.get(target) {
+                Some(user) => {
+                    if let (Some(away), Some(_)) = (&user.away, text) {
+                        reply(ctx, Command::RPL_AWAY { client: client.clone(), nick: user.nick.clone(), message: away.clone() });
+                    }
+                    ctx.state.send_to_nick(target, outgoing.clone());
+                    if text.is_some() {
+                        ctx.state.record_history(&client, target, &outgoing);
+                    }
+                    echo(ctx, outgoing);
+                }
+                None => reply(ctx, Command::ERR_NOSUCHNICK { client: client.clone(), nick: target.to_string() }),
+            }
+        }
+    }
+}
+
+fn mode(ctx: &mut Context<'_>, message: &Message) {
+    let Command::MODE { target, modestring, arguments } = &message.command else { return };
+    if is_channel_name(target) {
+        channel_mode(ctx, target, modestring.as_deref(), arguments);
+    } else {
+        user_mode(ctx, target, modestring.as_deref());
+    }
+}
+
+fn channel_mode(ctx: &mut Context<'_>, name: &str, modestring: Option<&str>, arguments: &[String]) {
+    let source = ctx.source();
+    let client = ctx.nick();
+    let Some(channel) = ctx.state.channels.get(name) else {
+        ctx.reply(Command::ERR_NOSUCHCHANNEL { client, channel: name.to_string() });
+        return;
+    };
+    let channel_name = channel.name.clone();
+
+    let Some(modestring) = modestring else {
+        let modes = channel.mode_params(channel.is_member(&client));
+        let created_at = channel.created_at.to_string();
+        ctx.reply(Command::RPL_CHANNELMODEIS { client: client.clone(), channel: channel_name.clone(), modes });
+        ctx.reply(Command::RPL_CREATIONTIME { client, channel: channel_name, creationtime: created_at });
+        return;
+    };
+
+    let changes = ModeChange::parse(modestring, arguments);
+    let mut to_apply = Vec::new();
+    for change in changes {
+        if !CHANNEL_MODES.contains(change.mode) && !PREFIX_MODES.contains(change.mode) {
+            ctx.reply(Command::ERR_UNKNOWNMODE { client: client.clone(), modechar: change.mode.to_string() });
+        } else if change.argument.is_none() && "beI".contains(change.mode) {
+            list_query(ctx, &channel_name, change.mode);
+        } else {
+            to_apply.push(change);
+        }
+    }
+    if to_apply.is_empty() {
+        return;
+    }
+
+    let channel = ctx.state.channels.get_mut(name).unwrap();
+    if !channel.member(&client).is_some_and(|member| member.is_op(&channel.mode_table.prefix_symbols)) {
+        ctx.reply(Command::ERR_CHANOPRIVSNEEDED { client, channel: channel_name });
+        return;
+    }
+    let applied = channel.apply_modes(&to_apply, &source.to_string(), unix_time());
+    if applied.is_empty() {
+        return;
+    }
+
+    let mut params = ModeChange::format(&applied).into_iter();
+    let command = Command::MODE { target: channel_name, modestring: params.next(), arguments: params.collect() };
+    let broadcast = Broadcast {
+        recipients: channel.members().map(|member| member.nick.clone()).collect(),
+        message: Message::new(None, Some(source), command),
+    };
+    ctx.deliver(vec![broadcast]);
+}
+
+fn list_query(ctx: &Context<'_>, name: &str, mode: char) {
+    let client = ctx.nick();
+    let channel = ctx.state.channels.get(name).unwrap();
+    let list = match mode {
+        'b' => &channel.bans,
+        'e' => &channel.excepts,
+        _ => &channel.invite_exceptions,
+    };
+
+    for entry in list.iter() {
+        let (client, channel, mask, who, set_ts) = (client.clone(), channel.name.clone(), entry.mask.as_str().to_string(), entry.setter.clone(), entry.set_at.to_string());
+        ctx.reply(match mode {
+            'b' => Command::RPL_BANLIST { client, channel, mask, who, set_ts },
+            'e' => Command::RPL_EXCEPTLIST { client, channel, mask, who, set_ts },
+            _ => Command::RPL_INVITELIST { client, channel, mask, who, set_ts },
+        });
+    }
+    let channel = channel.name.clone();
+    ctx.reply(match mode {
+        'b' => Command::RPL_ENDOFBANLIST { client, channel },
+        'e' => Command::RPL_ENDOFEXCEPTLIST { client, channel },
+        _ => Command::RPL_ENDOFINVITELIST { client, channel },
+    });
+}
+
+fn user_mode(ctx: &mut Context<'_>, target: &str, modestring: Option<&str>) {
+    let client = ctx.nick();
+    if !ctx.state.users.casemapping().equals(target, &client) {
+        match ctx.state.users.contains(target) {
+            true => ctx.reply(Command::ERR_USERSDONTMATCH { client }),
+            false => ctx.reply(Command::ERR_NOSUCHNICK { client, nick: target.to_string() }),
+        }
+        return;
+    }
+
+    let source = ctx.source();
+    let user = ctx.state.users.get_mut(&client).unwrap();
+    let Some(modestring) = modestring else {
+        let modes = format!("+{}{}", if user.invisible { "i" } else { "" }, if user.oper { "o" } else { "" });
+        ctx.reply(Command::RPL_UMODEIS { client, modes });
+        return;
+    };
+
+    // User modes never take arguments, so no ModeChange::parse here.
+    let mut applied = Vec::new();
+    let mut unknown = false;
+    let mut adding = true;
+    for mode in modestring.chars() {
+        if mode == '+' || mode == '-' {
+            adding = mode == '+';
+            continue;
+        }
+        let changed = match (mode, adding) {
+            ('i', adding) => std::mem::replace(&mut user.invisible, adding) != adding,
+            ('o', false) => std::mem::replace(&mut user.oper, false),
+            ('o', true) => false,
+            _ => {
+                unknown = true;
+                false
+            }
+        };
+        if changed {
+            applied.push(ModeChange { adding, mode, argument: None });
+        }
+    }
+
+    if unknown {
+        ctx.reply(Command::ERR_UMODEUNKNOWNFLAG { client: client.clone() });
+    }
+    if !applied.is_empty() {
+        let mut params = ModeChange::format(&applied).into_iter();
+        ctx.send(Message::new(None, Some(source), Command::MODE { target: client, modestring: params.next(), arguments: Vec::new() }));
+    }
+}
+
+fn oper(config: &Config, ctx: &mut Context<'_>, message: &Message) {
+    let Command::OPER { name, password } = &message.command else { return };
+    let client = ctx.nick();
+
+    match config.opers.iter().find(|oper| &oper.name == name) {
+        None => ctx.reply(Command::ERR_NOOPERHOST { client }),
+        Some(oper) if &oper.password != password => ctx.reply(Command::ERR_PASSWDMISMATCH { client }),
+        Some(_) => {
+            info!("{} is now an operator", client);
+            ctx.state.users.get_mut(&client).unwrap().oper = true;
+            ctx.reply(Command::RPL_YOUREOPER { client: client.clone() });
+            let source = ctx.source();
+            ctx.send(Message::new(None, Some(source), Command::MODE { target: client, modestring: Some("+o".to_string()), arguments: Vec::new() }));
+        }
+    }
+}
+
+fn who(ctx: &mut Context<'_>, message: &Message) {
+    let Some(query) = WhoQuery::from_command(&message.command).filter(|query| !query.mask.is_empty()) else {
+        ctx.reply(Command::ERR_NEEDMOREPARAMS { client: ctx.nick(), command: "WHO".to_string() });
+        return
+    };
+    let state = &ctx.state;
+    for reply in who_reply(&state.server_source(), &ctx.nick(), &query, &state.users, &state.channels, &ctx.client().caps) {
+        ctx.send(reply);
+    }
+}
+
+fn whois(config: &Config, ctx: &mut Context<'_>, message: &Message) {
+    let Command::WHOIS { nick, .. } = &message.command else { return };
+    let state = &ctx.state;
+    for nick in nick.split(',') {
+        for reply in whois_reply(&state.server_source(), &config.description, &ctx.nick(), nick, &state.users, &state.channels) {
+            ctx.send(reply);
+        }
+    }
+}
+
+fn whowas(ctx: &mut Context<'_>, message: &Message) {
+    let Command::WHOWAS { nick, count } = &message.command else { return };
+    let count = count.as_deref().and_then(|count| count.parse().ok());
+    for reply in whowas_reply(&ctx.state.server_source(), &ctx.nick(), nick, count, &ctx.state.whowas) {
+        ctx.send(reply);
+    }
+}
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    irc_proto::enable_logging();
+
+    let config = match std::env::args().nth(1) {
+        Some(path) => Config::load(&path)?,
+        None => Config::default(),
+    };
+    let config = Arc::new(config);
+
+    let server_config = ServerConfig {
+        name: config.name.clone(),
+        network: config.network.clone(),
+        password: config.password.clone(),
+        whowas_capacity: config.whowas_capacity,
+        monitor_limit: config.monitor_limit,
+        client_tag_deny: ClientTagDeny::parse(&config.client_tag_deny),
+        ..Default::default()
+    };
+
+    let (motd_config, oper_config, whois_config) = (config.clone(), config.clone(), config.clone());
+    let motd_handler = move |ctx: &mut Context<'_>, _: &Message| motd(&motd_config, ctx);
+    let server = IrcServer::new(server_config)
+        .on_registered(motd_handler.clone())
+        .handler("MOTD", motd_handler)
+        .handler("JOIN", join)
+        .handler("PART", part)
+        .handler("KICK", kick)
+        .handler("TOPIC", topic)
+        .handler("NAMES", names)
+        .handler("PRIVMSG", route)
+        .handler("NOTICE", route)
+        .handler("TAGMSG", route)
+        .handler("MODE", mode)
+        .handler("OPER", move |ctx: &mut Context<'_>, message: &Message| oper(&oper_config, ctx, message))
+        .handler("WHO", who)
+        .handler("WHOIS", move |ctx: &mut Context<'_>, message: &Message| whois(&whois_config, ctx, message))
+        .handler("WHOWAS", whowas);
+    #[cfg(feature = "sqlite")]
+    let server = match &config.history_path {
+        Some(path) => server.history(irc_proto::chathistory::SqliteHistory::open(path).map_err(|e| io::Error::other(e.to_string()))?),
+        None => server,
+    };
+    #[cfg(not(feature = "sqlite"))]
+    if config.history_path.is_some() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "history_path needs ircd built with the sqlite feature"));
+    }
+
+    let shutdown = server.shutdown_handle();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            shutdown.shutdown("Server shutting down");
+        }
+    });
+
+    let listener = TcpListener::bind(&config.listen).await?;
+    return server.run(listener).await
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::net::{TcpListener, TcpStream};
+
+    use irc_proto::connection::Connection;
+    use irc_proto::server::{Context, IrcServer, ServerConfig};
+    use irc_proto::types::{Command, Message};
+
+    use super::{mode, oper, who, Config, OperConfig};
+
+    async fn send(client: &mut Connection, line: &str) -> String {
+        client.write(Message::from_bytes(line.as_bytes()).unwrap()).await.unwrap();
+        return client.read().await.unwrap().to_bytes()
+    }
+
+    #[tokio::test]
+    async fn test_user_mode() {
+        let config = Arc::new(Config { opers: vec![OperConfig { name: "admin".to_string(), password: "pw".to_string() }], ..Default::default() });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = IrcServer::new(ServerConfig::default())
+            .handler("MODE", mode)
+            .handler("OPER", move |ctx: &mut Context<'_>, message: &Message| oper(&config, ctx, message));
+        tokio::spawn(server.run(listener));
+
+        let mut dan = Connection::new(TcpStream::connect(addr).await.unwrap(), addr);
+        dan.write(Message::from_bytes(b"NICK dan").unwrap()).await.unwrap();
+        send(&mut dan, "USER dan 0 * :Dan").await;
+        while !matches!(dan.read().await.unwrap().command, Command::RPL_ISUPPORT { .. }) {}
+
+        assert_eq!(":irc.localhost 381 dan\r\n", send(&mut dan, "OPER admin pw").await);
+        assert_eq!(":dan!dan@127.0.0.1 MODE dan +o\r\n", dan.read().await.unwrap().to_bytes());
+        assert_eq!(":irc.localhost 221 dan +o\r\n", send(&mut dan, "MODE dan").await);
+
+        assert_eq!(":dan!dan@127.0.0.1 MODE dan +i-o\r\n", send(&mut dan, "MODE dan +io-o").await);
+        assert_eq!(":irc.localhost 221 dan +i\r\n", send(&mut dan, "MODE dan").await);
+        assert_eq!(":irc.localhost 501 dan\r\n", send(&mut dan, "MODE dan -iz").await);
+        assert_eq!(":dan!dan@127.0.0.1 MODE dan -i\r\n", dan.read().await.unwrap().to_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_who_without_mask() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(IrcServer::new(ServerConfig::default()).handler("WHO", who).run(listener));
+
+        let mut dan = Connection::new(TcpStream::connect(addr).await.unwrap(), addr);
+        dan.write(Message::from_bytes(b"NICK dan").unwrap()).await.unwrap();
+        send(&mut dan, "USER dan 0 * :Dan").await;
+        while !matches!(dan.read().await.unwrap().command, Command::RPL_ISUPPORT { .. }) {}
+
+        assert_eq!(":irc.localhost 461 dan WHO\r\n", send(&mut dan, "WHO").await);
+        assert_eq!(":irc.localhost 315 dan nobody\r\n", send(&mut dan, "WHO nobody").await);
+    }
+}
@@ -0,0 +1,622 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use log::{debug, error, warn};
+use regex::Regex;
+
+use crate::capability::{Capability, CapabilitySet};
+use crate::channel::is_channel_name;
+use crate::connection::{IRCError, Transport};
+use crate::ctcp::Ctcp;
+use crate::mask::Mask;
+use crate::types::{Casemapping, Command, Message, Source};
+use crate::unix_time;
+
+/// How many times an `_` is appended to a nickname that is in use.
+pub const MAX_NICK_RETRIES: usize = 3;
+
+#[derive(Debug, Clone)]
+pub struct BotConfig {
+    pub nick: String,
+    pub user: String,
+    pub realname: String,
+    pub password: Option<String>,
+    pub channels: Vec<String>,
+    pub prefix: String,
+    pub casemapping: Casemapping,
+    pub tick_interval: Duration,
+}
+
+impl Default for BotConfig {
+    fn default() -> Self {
+        return BotConfig {
+            nick: "bot".to_string(),
+            user: "bot".to_string(),
+            realname: "irc_proto bot".to_string(),
+            password: None,
+            channels: Vec::new(),
+            prefix: "!".to_string(),
+            casemapping: Casemapping::default(),
+            tick_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Messages queued by handlers and plugins, flushed after each event.
+#[derive(Debug, Default)]
+pub struct Outbox {
+    nick: String,
+    messages: Vec<Message>,
+}
+
+impl Outbox {
+    /// The bot's current nick.
+    pub fn nick(&self) -> &str {
+        return &self.nick
+    }
+
+    pub fn send(&mut self, command: Command) {
+        self.messages.push(Message::new(None, None, command));
+    }
+
+    pub fn say(&mut self, target: &str, text: &str) {
+        self.send(Command::PRIVMSG { targets: target.to_string(), text: text.to_string() });
+    }
+
+    pub fn notice(&mut self, target: &str, text: &str) {
+        self.send(Command::NOTICE { targets: target.to_string(), text: text.to_string() });
+    }
+
+    pub fn action(&mut self, target: &str, text: &str) {
+        self.send(Ctcp::action(text).request(target));
+    }
+
+    pub fn join(&mut self, channel: &str) {
+        self.send(Command::JOIN { channels: channel.to_string(), keys: None, account: None, realname: None });
+    }
+
+    pub fn part(&mut self, channel: &str, reason: Option<&str>) {
+        self.send(Command::PART { channels: channel.to_string(), reason: reason.map(str::to_string) });
+    }
+
+    pub fn drain(&mut self) -> Vec<Message> {
+        return std::mem::take(&mut self.messages)
+    }
+}
+
+/// A PRIVMSG that fired a trigger.
+#[derive(Debug, Clone)]
+pub struct Invocation {
+    pub source: Source,
+    pub account: Option<String>,
+    /// Where the message was sent: a channel or the bot's nick.
+    pub target: String,
+    /// Where answers go: the channel, or the sender for private messages.
+    pub reply_to: String,
+    /// Command name or hook name.
+    pub name: String,
+    /// Text after the command name; the whole message for hooks.
+    pub args: String,
+    /// Regex capture groups for hooks, unmatched groups are empty.
+    pub captures: Vec<String>,
+}
+
+impl Invocation {
+    pub fn is_private(&self) -> bool {
+        return !is_channel_name(&self.target)
+    }
+
+    pub fn reply(&self, outbox: &mut Outbox, text: &str) {
+        if self.is_private() {
+            outbox.say(&self.reply_to, text);
+        } else {
+            outbox.say(&self.reply_to, &format!("{}: {}", self.source.name, text));
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Permission {
+    Anyone,
+    Hostmask(Mask),
+    Account(String),
+    AnyOf(Vec<Permission>),
+}
+
+impl Permission {
+    pub fn hostmask(mask: &str) -> Self {
+        return Permission::Hostmask(Mask::new(mask, Casemapping::default()))
+    }
+
+    pub fn allows(&self, source: &Source, account: Option<&str>) -> bool {
+        return match self {
+            Permission::Anyone => true,
+            Permission::Hostmask(mask) => mask.matches(source),
+            Permission::Account(name) => account.is_some_and(|account| account.eq_ignore_ascii_case(name)),
+            Permission::AnyOf(permissions) => permissions.iter().any(|permission| permission.allows(source, account)),
+        }
+    }
+}
+
+pub type TriggerHandler = Box<dyn FnMut(&mut Outbox, &Invocation) + Send>;
+
+enum TriggerKind {
+    Command(String),
+    Pattern(Regex),
+}
+
+pub struct Trigger {
+    name: String,
+    kind: TriggerKind,
+    permission: Permission,
+    cooldown: u64,
+    handler: TriggerHandler,
+}
+
+impl Trigger {
+    /// Fires on `<prefix><name> args`, the name is matched case-insensitively.
+    pub fn command<F>(name: &str, handler: F) -> Self
+        where F: FnMut(&mut Outbox, &Invocation) + Send + 'static {
+        let name = name.to_ascii_lowercase();
+        return Trigger { kind: TriggerKind::Command(name.clone()), name, permission: Permission::Anyone, cooldown: 0, handler: Box::new(handler) }
+    }
+
+    /// Fires on any message matching `pattern`.
+    pub fn pattern<F>(name: &str, pattern: Regex, handler: F) -> Self
+        where F: FnMut(&mut Outbox, &Invocation) + Send + 'static {
+        return Trigger { name: name.to_string(), kind: TriggerKind::Pattern(pattern), permission: Permission::Anyone, cooldown: 0, handler: Box::new(handler) }
+    }
+
+    pub fn permission(mut self, permission: Permission) -> Self {
+        self.permission = permission;
+        return self
+    }
+
+    /// Minimum seconds between two firings in the same channel or query.
+    pub fn cooldown(mut self, seconds: u64) -> Self {
+        self.cooldown = seconds;
+        return self
+    }
+
+    pub fn name(&self) -> &str {
+        return &self.name
+    }
+}
+
+pub trait Plugin: Send {
+    fn name(&self) -> &str;
+
+    /// Called once the bot is registered, right after it asks to join its
+    /// channels; the joins have not completed yet.
+    fn on_connect(&mut self, _outbox: &mut Outbox) {}
+
+    /// Called for every incoming message, unless the plugin is disabled for
+    /// the channel the message targets.
+    fn on_message(&mut self, _outbox: &mut Outbox, _message: &Message) {}
+
+    /// Called every [`BotConfig::tick_interval`] while connected.
+    fn on_tick(&mut self, _outbox: &mut Outbox, _now: u64) {}
+}
+
+pub struct Bot {
+    config: BotConfig,
+    nick: String,
+    nick_retries: usize,
+    registered: bool,
+    caps: CapabilitySet,
+    triggers: Vec<Trigger>,
+    plugins: Vec<Box<dyn Plugin>>,
+    /// Folded channel to the trigger and plugin names disabled in it.
+    disabled: HashMap<String, HashSet<String>>,
+    /// Trigger name and folded reply target to the time it last fired.
+    last_fired: HashMap<(String, String), u64>,
+}
+
+impl Bot {
+    pub fn new(config: BotConfig) -> Self {
+        let nick = config.nick.clone();
+        return Bot {
+            config,
+            nick,
+            nick_retries: 0,
+            registered: false,
+            caps: CapabilitySet::new(),
+            triggers: Vec::new(),
+            plugins: Vec::new(),
+            disabled: HashMap::new(),
+            last_fired: HashMap::new(),
+        }
+    }
+
+    pub fn trigger(mut self, trigger: Trigger) -> Self {
+        self.triggers.push(trigger);
+        return self
+    }
+
+    pub fn command<F>(self, name: &str, handler: F) -> Self
+        where F: FnMut(&mut Outbox, &Invocation) + Send + 'static {
+        return self.trigger(Trigger::command(name, handler))
+    }
+
+    pub fn hook<F>(self, name: &str, pattern: Regex, handler: F) -> Self
+        where F: FnMut(&mut Outbox, &Invocation) + Send + 'static {
+        return self.trigger(Trigger::pattern(name, pattern, handler))
+    }
+
+    pub fn plugin<P: Plugin + 'static>(mut self, plugin: P) -> Self {
+        self.plugins.push(Box::new(plugin));
+        return self
+    }
+
+    pub fn nick(&self) -> &str {
+        return &self.nick
+    }
+
+    pub fn is_registered(&self) -> bool {
+        return self.registered
+    }
+
+    /// The capabilities the server acknowledged.
+    pub fn caps(&self) -> &CapabilitySet {
+        return &self.caps
+    }
+
+    /// Turns a trigger or plugin on or off in one channel.
+    pub fn set_enabled(&mut self, channel: &str, name: &str, enabled: bool) {
+        let key = self.config.casemapping.fold(channel);
+        let disabled = self.disabled.entry(key.clone()).or_default();
+        if enabled {
+            disabled.remove(name);
+            if disabled.is_empty() {
+                self.disabled.remove(&key);
+            }
+        } else {
+            disabled.insert(name.to_string());
+        }
+    }
+
+    pub fn is_enabled(&self, channel: &str, name: &str) -> bool {
+        return self.disabled.get(&self.config.casemapping.fold(channel))
+            .is_none_or(|disabled| !disabled.contains(name))
+    }
+
+    /// The registration burst: CAP LS and REQ for `account-tag`, which
+    /// [`Permission::Account`] relies on, then PASS, NICK and USER.
+    /// Registration completes once the server answers the REQ.
+    pub fn register(&self) -> Vec<Message> {
+        let cap = |subcommand: &str, capabilities: &str| Command::CAP {
+            target: None,
+            subcommand: subcommand.to_string(),
+            capabilities: Some(capabilities.to_string()),
+        };
+        let mut commands = vec![cap("LS", "302"), cap("REQ", Capability::AccountTag.name())];
+        if let Some(password) = &self.config.password {
+            commands.push(Command::PASS { password: password.clone() });
+        }
+        commands.push(Command::NICK { nickname: self.nick.clone() });
+        commands.push(Command::USER {
+            user: self.config.user.clone(),
+            mode: "0".to_string(),
+            unused: "*".to_string(),
+            realname: self.config.realname.clone(),
+        });
+        return commands.into_iter().map(|command| Message::new(None, None, command)).collect()
+    }
+
+    /// Processes one incoming message and returns everything to send back.
+    pub fn handle(&mut self, message: &Message) -> Vec<Message> {
+        return self.handle_at(message, unix_time())
+    }
+
+    pub fn handle_at(&mut self, message: &Message, now: u64) -> Vec<Message> {
+        let mut outbox = Outbox { nick: self.nick.clone(), messages: Vec::new() };
+
+        match &message.command {
+            Command::PING { token } => outbox.send(Command::PONG { server: None, token: token.clone() }),
+            Command::RPL_WELCOME { client, .. } => {
+                self.nick = client.clone();
+                outbox.nick = client.clone();
+                self.registered = true;
+                if !self.config.channels.is_empty() {
+                    outbox.join(&self.config.channels.join(","));
+                }
+                for plugin in &mut self.plugins {
+                    plugin.on_connect(&mut outbox);
+                }
+            }
+            Command::CAP { subcommand, capabilities, .. } if subcommand == "ACK" || subcommand == "NAK" => {
+                if subcommand == "ACK" {
+                    for cap in CapabilitySet::parse(capabilities.as_deref().unwrap_or("")).iter() {
+                        self.caps.insert(cap);
+                    }
+                }
+                if !self.caps.contains(Capability::AccountTag) {
+                    warn!("no account-tag, account permissions will never match");
+                }
+                if !self.registered {
+                    outbox.send(Command::CAP { target: None, subcommand: "END".to_string(), capabilities: None });
+                }
+            }
+            Command::ERR_NICKNAMEINUSE { .. } if !self.registered => {
+                if self.nick_retries == MAX_NICK_RETRIES {
+                    error!("nick {} is in use, giving up after {} retries", self.config.nick, MAX_NICK_RETRIES);
+                } else {
+                    self.nick_retries += 1;
+                    self.nick.push('_');
+                    outbox.send(Command::NICK { nickname: self.nick.clone() });
+                }
+            }
+            Command::NICK { nickname } if self.is_self(message.source.as_ref()) => {
+                self.nick = nickname.clone();
+                outbox.nick = nickname.clone();
+            }
+            Command::PRIVMSG { targets, text } if !self.is_self(message.source.as_ref()) => {
+                if let Some(source) = &message.source {
+                    self.fire_triggers(&mut outbox, message, source, targets, text, now);
+                }
+            }
+            _ => {}
+        }
+
+        let channel = match &message.command {
+            Command::PRIVMSG { targets, .. } | Command::NOTICE { targets, .. } if is_channel_name(targets) => Some(targets.as_str()),
+            _ => None,
+        };
+        for index in 0..self.plugins.len() {
+            if channel.is_none_or(|channel| self.is_enabled(channel, self.plugins[index].name())) {
+                self.plugins[index].on_message(&mut outbox, message);
+            }
+        }
+
+        return outbox.drain()
+    }
+
+    pub fn tick(&mut self, now: u64) -> Vec<Message> {
+        let mut outbox = Outbox { nick: self.nick.clone(), messages: Vec::new() };
+        for plugin in &mut self.plugins {
+            plugin.on_tick(&mut outbox, now);
+        }
+        return outbox.drain()
+    }
+
+    /// Registers over `transport` and serves until the connection closes or
+    /// the bot sends QUIT.
+    pub async fn run<T: Transport>(mut self, mut transport: T) -> Result<(), IRCError> {
+        for message in self.register() {
+            transport.write(message).await?;
+        }
+
+        let mut ticker = tokio::time::interval(self.config.tick_interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            let outgoing = tokio::select! {
+                incoming = transport.read() => match incoming {
+                    Ok(message) => self.handle(&message),
+                    Err(IRCError::LengthExceeded | IRCError::InvalidUtf8) => continue,
+                    Err(IRCError::ClientExited) => return Ok(()),
+                    Err(error) => return Err(error),
+                },
+                _ = ticker.tick(), if self.registered => self.tick(unix_time()),
+            };
+            for message in outgoing {
+                let quitting = matches!(message.command, Command::QUIT { .. });
+                transport.write(message).await?;
+                if quitting {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn is_self(&self, source: Option<&Source>) -> bool {
+        return source.is_some_and(|source| self.config.casemapping.equals(&source.name, &self.nick))
+    }
+
+    fn fire_triggers(&mut self, outbox: &mut Outbox, message: &Message, source: &Source, target: &str, text: &str, now: u64) {
+        // CTCP requests are not commands.
+        if Ctcp::parse(text).is_some() {
+            return;
+        }
+        let account = message.account().map(str::to_string);
+        let is_channel = is_channel_name(target);
+        let reply_to = if is_channel { target.to_string() } else { source.name.clone() };
+        let command = text.strip_prefix(self.config.prefix.as_str()).map(|rest| {
+            let (name, args) = rest.split_once(' ').unwrap_or((rest, ""));
+            (name.to_ascii_lowercase(), args.trim_start().to_string())
+        });
+
+        for index in 0..self.triggers.len() {
+            let trigger = &self.triggers[index];
+            let (args, captures) = match (&trigger.kind, &command) {
+                (TriggerKind::Command(name), Some((invoked, args))) if name == invoked => (args.clone(), Vec::new()),
+                (TriggerKind::Pattern(pattern), _) => match pattern.captures(text) {
+                    Some(captures) => {
+                        let groups = captures.iter().skip(1)
+                            .map(|group| group.map(|group| group.as_str().to_string()).unwrap_or_default())
+                            .collect();
+                        (text.to_string(), groups)
+                    }
+                    None => continue,
+                },
+                _ => continue,
+            };
+            if is_channel && !self.is_enabled(target, &trigger.name) {
+                continue;
+            }
+            if !trigger.permission.allows(source, account.as_deref()) {
+                debug!("{} is not allowed to use {}", source, trigger.name);
+                continue;
+            }
+            let key = (trigger.name.clone(), self.config.casemapping.fold(&reply_to));
+            if trigger.cooldown > 0 && self.last_fired.get(&key).is_some_and(|last| now < last + trigger.cooldown) {
+                continue;
+            }
+            self.last_fired.insert(key, now);
+
+            let invocation = Invocation {
+                source: source.clone(),
+                account: account.clone(),
+                target: target.to_string(),
+                reply_to: reply_to.clone(),
+                name: trigger.name.clone(),
+                args,
+                captures,
+            };
+            (self.triggers[index].handler)(outbox, &invocation);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use regex::Regex;
+    use tokio::net::TcpStream;
+
+    use crate::types::{Command, Message};
+
+    use crate::connection::{Connection, MemoryTransport};
+    use crate::server::{Context, IrcServer, ServerConfig};
+    use crate::test_util::{connect, parse, spawn_server};
+
+    use super::{Bot, BotConfig, Outbox, Permission, Plugin, Trigger, MAX_NICK_RETRIES};
+
+    fn texts(messages: Vec<Message>) -> Vec<String> {
+        return messages.into_iter().map(|message| message.to_bytes().trim_end().to_string()).collect()
+    }
+
+    #[derive(Default)]
+    struct Counter {
+        seen: usize,
+    }
+
+    impl Plugin for Counter {
+        fn name(&self) -> &str {
+            return "counter"
+        }
+
+        fn on_message(&mut self, outbox: &mut Outbox, message: &Message) {
+            if let Command::PRIVMSG { targets, .. } = &message.command {
+                self.seen += 1;
+                outbox.notice(targets, &self.seen.to_string());
+            }
+        }
+    }
+
+    #[test]
+    fn test_commands() {
+        let config = BotConfig { nick: "bot".to_string(), ..Default::default() };
+        let mut bot = Bot::new(config)
+            .command("weather", |outbox, invocation| invocation.reply(outbox, &format!("sunny in {}", invocation.args)))
+            .trigger(Trigger::command("op", |outbox, invocation| outbox.say(&invocation.reply_to, "ok"))
+                .permission(Permission::AnyOf(vec![Permission::hostmask("*!*@trusted.example"), Permission::Account("admin".to_string())])))
+            .trigger(Trigger::command("ping", |outbox, invocation| outbox.say(&invocation.reply_to, "pong")).cooldown(30))
+            .hook("issue", Regex::new(r"#(\d+)").unwrap(), |outbox, invocation| {
+                outbox.say(&invocation.reply_to, &format!("issue {}", invocation.captures[0]));
+            });
+
+        assert_eq!(vec!["PRIVMSG #chan :dan: sunny in Paris"], texts(bot.handle_at(&parse(":dan!d@host PRIVMSG #chan :!Weather Paris"), 0)));
+        assert_eq!(vec!["PRIVMSG dan :sunny in Oslo"], texts(bot.handle_at(&parse(":dan!d@host PRIVMSG bot :!weather Oslo"), 0)));
+        assert!(bot.handle_at(&parse(":dan!d@host PRIVMSG #chan :weather"), 0).is_empty());
+
+        assert!(bot.handle_at(&parse(":dan!d@host PRIVMSG #chan :!op"), 0).is_empty());
+        assert_eq!(1, bot.handle_at(&parse(":dan!d@trusted.example PRIVMSG #chan :!op"), 0).len());
+        assert_eq!(1, bot.handle_at(&parse("@account=admin :eve!e@elsewhere PRIVMSG #chan :!op"), 0).len());
+
+        assert_eq!(1, bot.handle_at(&parse(":dan!d@host PRIVMSG #chan :!ping"), 100).len());
+        assert!(bot.handle_at(&parse(":dan!d@host PRIVMSG #chan :!ping"), 110).is_empty());
+        assert_eq!(1, bot.handle_at(&parse(":dan!d@host PRIVMSG #other :!ping"), 110).len());
+        assert_eq!(1, bot.handle_at(&parse(":dan!d@host PRIVMSG #chan :!ping"), 130).len());
+
+        assert_eq!(vec!["PRIVMSG #chan :issue 42"], texts(bot.handle_at(&parse(":dan!d@host PRIVMSG #chan :see #42"), 0)));
+        bot.set_enabled("#CHAN", "issue", false);
+        assert!(bot.handle_at(&parse(":dan!d@host PRIVMSG #chan :see #42"), 0).is_empty());
+        assert_eq!(1, bot.handle_at(&parse(":dan!d@host PRIVMSG #other :see #42"), 0).len());
+        bot.set_enabled("#chan", "issue", true);
+        assert_eq!(1, bot.handle_at(&parse(":dan!d@host PRIVMSG #chan :see #42"), 0).len());
+    }
+
+    #[test]
+    fn test_plugins() {
+        let config = BotConfig { channels: vec!["#a".to_string(), "#b".to_string()], ..Default::default() };
+        let mut bot = Bot::new(config).plugin(Counter::default());
+
+        let in_use = parse(":irc.example.com 433 * bot :Nickname is already in use");
+        assert_eq!(vec!["NICK bot_"], texts(bot.handle(&in_use)));
+        assert_eq!(vec!["JOIN #a,#b"], texts(bot.handle(&parse(":irc.example.com 001 bot_ :Welcome"))));
+        assert_eq!("bot_", bot.nick());
+
+        assert_eq!(vec!["NOTICE #a 1"], texts(bot.handle(&parse(":dan!d@host PRIVMSG #a :hi"))));
+        bot.set_enabled("#a", "counter", false);
+        assert!(bot.handle(&parse(":dan!d@host PRIVMSG #a :hi")).is_empty());
+        assert_eq!(vec!["NOTICE #b 2"], texts(bot.handle(&parse(":dan!d@host PRIVMSG #b :hi"))));
+
+        // A taken nick is retried a few times only.
+        let mut bot = Bot::new(BotConfig::default());
+        for retry in 1..=MAX_NICK_RETRIES {
+            assert_eq!(vec![format!("NICK bot{}", "_".repeat(retry))], texts(bot.handle(&in_use)));
+        }
+        assert!(bot.handle(&in_use).is_empty());
+        assert_eq!("bot___", bot.nick());
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let (transport, mut peer) = MemoryTransport::pair();
+        let bot = Bot::new(BotConfig::default())
+            .command("quit", |outbox, _| outbox.send(Command::QUIT { reason: Some("bye".to_string()) }));
+        let task = tokio::spawn(bot.run(transport));
+
+        assert_eq!("CAP LS 302\r\n", peer.recv().await.unwrap().to_bytes());
+        assert_eq!("CAP REQ account-tag\r\n", peer.recv().await.unwrap().to_bytes());
+        assert_eq!("NICK bot\r\n", peer.recv().await.unwrap().to_bytes());
+        assert!(matches!(peer.recv().await.unwrap().command, Command::USER { .. }));
+        peer.send(parse(":irc.example.com CAP * NAK account-tag"));
+        assert_eq!("CAP END\r\n", peer.recv().await.unwrap().to_bytes());
+        peer.send(parse("PING :abc"));
+        assert_eq!("PONG abc\r\n", peer.recv().await.unwrap().to_bytes());
+        peer.send(parse(":dan!d@host PRIVMSG bot :!quit"));
+        assert_eq!("QUIT bye\r\n", peer.recv().await.unwrap().to_bytes());
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_account_tag() {
+        let server = IrcServer::new(ServerConfig::default())
+            .handler("PRIVMSG", |ctx: &mut Context<'_>, message: &Message| {
+                let Command::PRIVMSG { targets, text } = &message.command else { return };
+                let relayed = ctx.state.stamp(Message::new(None, Some(ctx.source()), Command::PRIVMSG { targets: targets.clone(), text: text.clone() }));
+                ctx.state.send_to_nick(targets, relayed);
+            });
+        let state = server.state();
+        let (addr, _) = spawn_server(server).await;
+
+        // The bot talks to the server through a memory transport.
+        let (transport, mut peer) = MemoryTransport::pair();
+        let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap(), addr);
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    Some(message) = peer.recv() => if connection.write(message).await.is_err() { break },
+                    Ok(message) = connection.read() => if !peer.send(message) { break },
+                    else => break,
+                }
+            }
+        });
+        let bot = Bot::new(BotConfig::default())
+            .trigger(Trigger::command("op", |outbox, invocation| invocation.reply(outbox, "ok")).permission(Permission::Account("admin".to_string())));
+        tokio::spawn(bot.run(transport));
+        while state.lock().unwrap().users.get("bot").is_none() {
+            tokio::task::yield_now().await;
+        }
+
+        let mut eve = connect(addr, "eve").await;
+        let mut dan = connect(addr, "dan").await;
+        state.lock().unwrap().set_account("dan", Some("admin".to_string()));
+        eve.write(parse("PRIVMSG bot :!op")).await.unwrap();
+        dan.write(parse("PRIVMSG bot :!op")).await.unwrap();
+        assert_eq!(":bot!bot@127.0.0.1 PRIVMSG dan ok\r\n", dan.read().await.unwrap().to_bytes());
+        eve.write(parse("PING x")).await.unwrap();
+        assert_eq!(":irc.localhost PONG x irc.localhost\r\n", eve.read().await.unwrap().to_bytes());
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::HashSet;
+
+use crate::types::{Command, TagKey};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Capability {
+    MultiPrefix,
+    UserhostInNames,
+    ServerTime,
+    MessageTags,
+    Batch,
+    LabeledResponse,
+    EchoMessage,
+    ChatHistory,
+    AwayNotify,
+    AccountNotify,
+    ChgHost,
+    SetName,
+    ExtendedJoin,
+    AccountTag,
+}
+
+impl Capability {
+    pub const ALL: &'static [Capability] = &[
+        Capability::MultiPrefix,
+        Capability::UserhostInNames,
+        Capability::ServerTime,
+        Capability::MessageTags,
+        Capability::Batch,
+        Capability::LabeledResponse,
+        Capability::EchoMessage,
+        Capability::ChatHistory,
+        Capability::AwayNotify,
+        Capability::AccountNotify,
+        Capability::ChgHost,
+        Capability::SetName,
+        Capability::ExtendedJoin,
+        Capability::AccountTag,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Capability::MultiPrefix => "multi-prefix",
+            Capability::UserhostInNames => "userhost-in-names",
+            Capability::ServerTime => "server-time",
+            Capability::MessageTags => "message-tags",
+            Capability::Batch => "batch",
+            Capability::LabeledResponse => "labeled-response",
+            Capability::EchoMessage => "echo-message",
+            Capability::ChatHistory => "draft/chathistory",
+            Capability::AwayNotify => "away-notify",
+            Capability::AccountNotify => "account-notify",
+            Capability::ChgHost => "chghost",
+            Capability::SetName => "setname",
+            Capability::ExtendedJoin => "extended-join",
+            Capability::AccountTag => "account-tag",
+        }
+    }
+
+    /// The capability a client needs to be sent tag `key`.
+    pub fn for_tag(key: &TagKey) -> Capability {
+        match (key.is_client_only(), key.vendor.as_deref(), key.value.as_str()) {
+            (false, None, "time") => Capability::ServerTime,
+            (false, None, "batch") => Capability::Batch,
+            (false, None, "label") => Capability::LabeledResponse,
+            (false, None, "account") => Capability::AccountTag,
+            _ => Capability::MessageTags,
+        }
+    }
+
+    /// The capability a client needs to be sent `command` at all.
+    pub fn for_command(command: &Command) -> Option<Capability> {
+        match command {
+            Command::BATCH { .. } => Some(Capability::Batch),
+            Command::AWAY { .. } => Some(Capability::AwayNotify),
+            Command::ACCOUNT { .. } => Some(Capability::AccountNotify),
+            Command::CHGHOST { .. } => Some(Capability::ChgHost),
+            Command::SETNAME { .. } => Some(Capability::SetName),
+            Command::TAGMSG { .. } => Some(Capability::MessageTags),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Capability> {
+        return Capability::ALL.iter().find(|cap| cap.name() == name).copied()
+    }
+}
+
+/// Capabilities negotiated on a connection.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CapabilitySet {
+    caps: HashSet<Capability>,
+}
+
+impl CapabilitySet {
+    pub fn new() -> Self {
+        return CapabilitySet::default()
+    }
+
+    /// Parses a space separated list such as the argument of `CAP ACK`,
+    /// ignoring unknown names and `-` removals.
+    pub fn parse(list: &str) -> Self {
+        let caps = list.split(' ')
+            .filter_map(|name| Capability::from_name(name.split('=').next().unwrap_or(name)))
+            .collect();
+        return CapabilitySet { caps }
+    }
+
+    pub fn contains(&self, cap: Capability) -> bool {
+        return self.caps.contains(&cap)
+    }
+
+    pub fn insert(&mut self, cap: Capability) -> bool {
+        return self.caps.insert(cap)
+    }
+
+    pub fn remove(&mut self, cap: Capability) -> bool {
+        return self.caps.remove(&cap)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Capability> + '_ {
+        return self.caps.iter().copied()
+    }
+}
+
+impl FromIterator<Capability> for CapabilitySet {
+    fn from_iter<T: IntoIterator<Item = Capability>>(iter: T) -> Self {
+        return CapabilitySet { caps: iter.into_iter().collect() }
+    }
+}
@@ -1,16 +1,566 @@
-#[derive(Debug)]
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use crate::mask::BanList;
+use crate::types::Casemapping;
+use crate::unix_time;
+
+/// Membership prefixes from highest to lowest rank, as advertised in
+/// `PREFIX=(qaohv)~&@%+`.
+pub const PREFIX_SYMBOLS: &str = "~&@%+";
+pub const PREFIX_MODES: &str = "qaohv";
+
+// CHANMODES=beI,k,l,imnpst
+const LIST_MODES: &str = "beI";
+const KEY_MODES: &str = "k";
+const SET_ONLY_MODES: &str = "l";
+const FLAG_MODES: &str = "imnpst";
+
+pub fn prefix_for_mode(mode: char) -> Option<char> {
+    return PREFIX_MODES.find(mode).and_then(|idx| PREFIX_SYMBOLS.chars().nth(idx))
+}
+
+pub fn mode_for_prefix(prefix: char) -> Option<char> {
+    return PREFIX_SYMBOLS.find(prefix).and_then(|idx| PREFIX_MODES.chars().nth(idx))
+}
+
+/// The channel modes a server supports, from ISUPPORT `PREFIX` and
+/// `CHANMODES`. The default is what this crate's server advertises.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModeTable {
+    /// Both from highest to lowest rank.
+    pub prefix_modes: String,
+    pub prefix_symbols: String,
+    /// Type A: lists, with an argument when set or removed.
+    pub list_modes: String,
+    /// Type B: always with an argument.
+    pub key_modes: String,
+    /// Type C: with an argument only when set.
+    pub set_only_modes: String,
+    /// Type D: never with an argument.
+    pub flag_modes: String,
+}
+
+impl Default for ModeTable {
+    fn default() -> Self {
+        return ModeTable {
+            prefix_modes: PREFIX_MODES.to_string(),
+            prefix_symbols: PREFIX_SYMBOLS.to_string(),
+            list_modes: LIST_MODES.to_string(),
+            key_modes: KEY_MODES.to_string(),
+            set_only_modes: SET_ONLY_MODES.to_string(),
+            flag_modes: FLAG_MODES.to_string(),
+        }
+    }
+}
+
+impl ModeTable {
+    /// Takes a `PREFIX` value such as `(qaohv)~&@%+`, ignoring a malformed one.
+    pub fn set_prefix(&mut self, value: &str) {
+        let Some((modes, symbols)) = value.strip_prefix('(').and_then(|value| value.split_once(')')) else { return };
+        if modes.chars().count() == symbols.chars().count() {
+            (self.prefix_modes, self.prefix_symbols) = (modes.to_string(), symbols.to_string());
+        }
+    }
+
+    /// Takes a `CHANMODES` value such as `beI,k,l,imnpst`; types beyond the
+    /// fourth are ignored.
+    pub fn set_chanmodes(&mut self, value: &str) {
+        let mut types = value.split(',').map(str::to_string);
+        if let (Some(list), Some(key), Some(set_only), Some(flag)) = (types.next(), types.next(), types.next(), types.next()) {
+            (self.list_modes, self.key_modes, self.set_only_modes, self.flag_modes) = (list, key, set_only, flag);
+        }
+    }
+
+    pub fn prefix_for_mode(&self, mode: char) -> Option<char> {
+        return self.prefix_modes.chars().position(|m| m == mode).and_then(|idx| self.prefix_symbols.chars().nth(idx))
+    }
+
+    pub fn mode_for_prefix(&self, prefix: char) -> Option<char> {
+        return self.prefix_symbols.chars().position(|p| p == prefix).and_then(|idx| self.prefix_modes.chars().nth(idx))
+    }
+
+    fn takes_argument(&self, mode: char, adding: bool) -> bool {
+        return self.prefix_modes.contains(mode)
+            || self.list_modes.contains(mode)
+            || self.key_modes.contains(mode)
+            || (adding && self.set_only_modes.contains(mode))
+    }
+}
+
+pub const CHANNEL_TYPES: &str = "#&";
+
+pub fn is_channel_name(name: &str) -> bool {
+    return name.starts_with(|c| CHANNEL_TYPES.contains(c))
+        && name.len() > 1
+        && name.len() <= 50
+        && !name.contains([' ', ',', '\x07'])
+}
+
+/// Rank of `prefix` among `symbols`, 0 being the highest.
+fn prefix_rank(prefix: char, symbols: &str) -> Option<usize> {
+    return symbols.chars().position(|symbol| symbol == prefix)
+}
+
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub nick: String,
+    /// Status prefixes held by the member, highest rank first.
+    pub prefixes: String,
+}
+
+impl Member {
+    pub fn new(nick: String) -> Self {
+        return Member { nick, prefixes: String::new() }
+    }
+
+    pub fn highest_prefix(&self) -> Option<char> {
+        return self.prefixes.chars().next()
+    }
+
+    pub fn has_prefix(&self, prefix: char) -> bool {
+        return self.prefixes.contains(prefix)
+    }
+
+    /// True for halfops and above, ranked by `symbols`, the prefix symbols
+    /// of a [`ModeTable`]; without halfops that means ops.
+    pub fn is_halfop(&self, symbols: &str) -> bool {
+        return self.ranks_at_least(if symbols.contains('%') { '%' } else { '@' }, symbols)
+    }
+
+    /// True for ops and above, ranked by `symbols`.
+    pub fn is_op(&self, symbols: &str) -> bool {
+        return self.ranks_at_least('@', symbols)
+    }
+
+    fn ranks_at_least(&self, prefix: char, symbols: &str) -> bool {
+        let highest = self.highest_prefix().and_then(|highest| prefix_rank(highest, symbols));
+        return highest.zip(prefix_rank(prefix, symbols)).is_some_and(|(highest, required)| highest <= required)
+    }
+
+    pub fn is_voiced(&self) -> bool {
+        return !self.prefixes.is_empty()
+    }
+
+    /// Adds `prefix`, ranked by its position in `symbols`.
+    pub fn add_prefix(&mut self, prefix: char, symbols: &str) -> bool {
+        if self.has_prefix(prefix) || !symbols.contains(prefix) {
+            return false;
+        }
+        let mut prefixes: Vec<char> = self.prefixes.chars().chain(std::iter::once(prefix)).collect();
+        prefixes.sort_by_key(|p| symbols.chars().position(|symbol| symbol == *p));
+        self.prefixes = prefixes.into_iter().collect();
+        return true
+    }
+
+    pub fn remove_prefix(&mut self, prefix: char) -> bool {
+        if !self.has_prefix(prefix) {
+            return false;
+        }
+        self.prefixes.retain(|p| p != prefix);
+        return true
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Topic {
+    pub text: String,
+    pub setter: String,
+    pub set_at: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChannelModes {
+    pub invite_only: bool,
+    pub moderated: bool,
+    pub no_external: bool,
+    pub private: bool,
+    pub secret: bool,
+    pub topic_lock: bool,
+    /// Other type D modes that are set.
+    pub others: BTreeSet<char>,
+}
+
+impl ChannelModes {
+    pub fn flag(&self, mode: char) -> bool {
+        match mode {
+            'i' => self.invite_only,
+            'm' => self.moderated,
+            'n' => self.no_external,
+            'p' => self.private,
+            's' => self.secret,
+            't' => self.topic_lock,
+            _ => self.others.contains(&mode),
+        }
+    }
+
+    /// Sets or clears `mode`, returns whether that changed anything.
+    fn set_flag(&mut self, mode: char, on: bool) -> bool {
+        let flag = match mode {
+            'i' => &mut self.invite_only,
+            'm' => &mut self.moderated,
+            'n' => &mut self.no_external,
+            'p' => &mut self.private,
+            's' => &mut self.secret,
+            't' => &mut self.topic_lock,
+            _ if on => return self.others.insert(mode),
+            _ => return self.others.remove(&mode),
+        };
+        let changed = *flag != on;
+        *flag = on;
+        return changed
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModeChange {
+    pub adding: bool,
+    pub mode: char,
+    pub argument: Option<String>,
+}
+
+impl ModeChange {
+    /// Splits a modestring and its arguments into individual changes.
+    ///
+    /// List modes without an argument are kept with `argument: None` and
+    /// denote a list query; other modes missing their argument are dropped.
+    pub fn parse(modestring: &str, arguments: &[String]) -> Vec<ModeChange> {
+        return ModeChange::parse_with(modestring, arguments, &ModeTable::default())
+    }
+
+    /// Like [`ModeChange::parse`], with the modes a server advertised.
+    pub fn parse_with(modestring: &str, arguments: &[String], table: &ModeTable) -> Vec<ModeChange> {
+        let mut changes = Vec::new();
+        let mut arguments = arguments.iter();
+        let mut adding = true;
+
+        for mode in modestring.chars() {
+            match mode {
+                '+' => adding = true,
+                '-' => adding = false,
+                _ => {
+                    let takes_argument = table.takes_argument(mode, adding);
+                    let argument = if takes_argument { arguments.next().cloned() } else { None };
+
+                    if argument.is_none() && takes_argument && !table.list_modes.contains(mode) {
+                        continue;
+                    }
+                    changes.push(ModeChange { adding, mode, argument });
+                }
+            }
+        }
+        return changes
+    }
+
+    /// Joins changes back into a modestring followed by its arguments.
+    pub fn format(changes: &[ModeChange]) -> Vec<String> {
+        let mut modestring = String::new();
+        let mut arguments = Vec::new();
+        let mut current: Option<bool> = None;
+
+        for change in changes {
+            if current != Some(change.adding) {
+                modestring.push(if change.adding { '+' } else { '-' });
+                current = Some(change.adding);
+            }
+            modestring.push(change.mode);
+            if let Some(argument) = &change.argument {
+                arguments.push(argument.clone());
+            }
+        }
+        return std::iter::once(modestring).chain(arguments).collect()
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Channel {
     pub name: String,
-    pub members: Vec<String>,
-    pub flags: String
+    pub casemapping: Casemapping,
+    members: BTreeMap<String, Member>,
+    pub topic: Option<Topic>,
+    pub created_at: u64,
+    pub key: Option<String>,
+    pub limit: Option<usize>,
+    /// Arguments of the type B and C modes other than `k` and `l`.
+    pub params: BTreeMap<char, String>,
+    pub modes: ChannelModes,
+    pub bans: BanList,
+    pub excepts: BanList,
+    pub invite_exceptions: BanList,
+    /// How modestrings and member prefixes are read, see [`ModeTable`].
+    pub mode_table: ModeTable,
+    invited: HashSet<String>,
 }
 
 impl Channel {
     pub fn new(name: String, member: String) -> Channel {
+        let mut channel = Channel::with_casemapping(name, Casemapping::default());
+        channel.add_member(member);
+        return channel
+    }
+
+    pub fn with_casemapping(name: String, casemapping: Casemapping) -> Channel {
+        return Channel::with_mode_table(name, casemapping, ModeTable::default())
+    }
+
+    /// A channel on a server that advertised `mode_table`.
+    pub fn with_mode_table(name: String, casemapping: Casemapping, mode_table: ModeTable) -> Channel {
         Channel{
             name,
-            members: Vec::from([member]),
-            flags: String::new(),
+            casemapping,
+            members: BTreeMap::new(),
+            topic: None,
+            created_at: unix_time(),
+            key: None,
+            limit: None,
+            params: BTreeMap::new(),
+            modes: ChannelModes::default(),
+            bans: BanList::new(casemapping),
+            excepts: BanList::new(casemapping),
+            invite_exceptions: BanList::new(casemapping),
+            mode_table,
+            invited: HashSet::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        return self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.members.is_empty()
+    }
+
+    pub fn members(&self) -> impl Iterator<Item = &Member> {
+        return self.members.values()
+    }
+
+    pub fn member(&self, nick: &str) -> Option<&Member> {
+        return self.members.get(&self.casemapping.fold(nick))
+    }
+
+    pub fn member_mut(&mut self, nick: &str) -> Option<&mut Member> {
+        return self.members.get_mut(&self.casemapping.fold(nick))
+    }
+
+    pub fn is_member(&self, nick: &str) -> bool {
+        return self.member(nick).is_some()
+    }
+
+    pub fn add_member(&mut self, nick: String) -> bool {
+        let key = self.casemapping.fold(&nick);
+        if self.members.contains_key(&key) {
+            return false;
         }
+        self.invited.remove(&key);
+        self.members.insert(key, Member::new(nick));
+        return true
+    }
+
+    pub fn remove_member(&mut self, nick: &str) -> Option<Member> {
+        return self.members.remove(&self.casemapping.fold(nick))
+    }
+
+    pub fn rename_member(&mut self, old: &str, new: &str) -> bool {
+        match self.members.remove(&self.casemapping.fold(old)) {
+            Some(mut member) => {
+                member.nick = new.to_string();
+                self.members.insert(self.casemapping.fold(new), member);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn invite(&mut self, nick: &str) {
+        self.invited.insert(self.casemapping.fold(nick));
+    }
+
+    pub fn is_invited(&self, nick: &str) -> bool {
+        return self.invited.contains(&self.casemapping.fold(nick))
+    }
+
+    pub fn set_topic(&mut self, text: String, setter: String, set_at: u64) {
+        if text.is_empty() {
+            self.topic = None;
+        } else {
+            self.topic = Some(Topic { text, setter, set_at });
+        }
+    }
+
+    /// Current modes as MODE parameters, e.g. `["+kl", "secret", "10"]`.
+    pub fn mode_params(&self, reveal_key: bool) -> Vec<String> {
+        let mut modestring = String::from("+");
+        let mut arguments = Vec::new();
+
+        let table = &self.mode_table;
+        for mode in table.flag_modes.chars() {
+            if self.modes.flag(mode) {
+                modestring.push(mode);
+            }
+        }
+        for mode in table.key_modes.chars().chain(table.set_only_modes.chars()) {
+            let argument = match mode {
+                'k' => self.key.as_ref().map(|key| if reveal_key { key.clone() } else { "*".to_string() }),
+                'l' => self.limit.map(|limit| limit.to_string()),
+                _ => self.params.get(&mode).cloned(),
+            };
+            if let Some(argument) = argument {
+                modestring.push(mode);
+                arguments.push(argument);
+            }
+        }
+        return std::iter::once(modestring).chain(arguments).collect()
+    }
+
+    /// Applies parsed changes and returns the subset that altered the channel.
+    pub fn apply_modes(&mut self, changes: &[ModeChange], setter: &str, set_at: u64) -> Vec<ModeChange> {
+        let mut applied = Vec::new();
+
+        for change in changes {
+            let effective = match (change.mode, &change.argument) {
+                (mode, _) if self.mode_table.flag_modes.contains(mode) => {
+                    let changed = self.modes.set_flag(mode, change.adding);
+                    changed.then(|| ModeChange { argument: None, ..change.clone() })
+                }
+                ('k', Some(key)) if self.mode_table.key_modes.contains('k') => {
+                    if change.adding {
+                        let changed = self.key.as_deref() != Some(key.as_str()) && !key.is_empty() && !key.contains(' ');
+                        if changed {
+                            self.key = Some(key.clone());
+                        }
+                        changed.then(|| change.clone())
+                    } else {
+                        self.key.take().map(|key| ModeChange { argument: Some(key), ..change.clone() })
+                    }
+                }
+                ('l', argument) if self.mode_table.set_only_modes.contains('l') => {
+                    if change.adding {
+                        match argument.as_deref().and_then(|l| l.parse::<usize>().ok()).filter(|l| *l > 0) {
+                            Some(limit) if self.limit != Some(limit) => {
+                                self.limit = Some(limit);
+                                Some(ModeChange { argument: Some(limit.to_string()), ..change.clone() })
+                            }
+                            _ => None,
+                        }
+                    } else {
+                        self.limit.take().map(|_| ModeChange { argument: None, ..change.clone() })
+                    }
+                }
+                (mode, argument) if self.mode_table.key_modes.contains(mode) || self.mode_table.set_only_modes.contains(mode) => {
+                    match (change.adding, argument) {
+                        (true, Some(value)) => {
+                            let changed = self.params.get(&mode) != Some(value);
+                            self.params.insert(mode, value.clone());
+                            changed.then(|| change.clone())
+                        }
+                        (true, None) => None,
+                        // Only type B modes name their argument when removed.
+                        (false, _) => self.params.remove(&mode).map(|value| ModeChange {
+                            argument: self.mode_table.key_modes.contains(mode).then_some(value),
+                            ..change.clone()
+                        }),
+                    }
+                }
+                (mode, Some(mask)) if self.mode_table.list_modes.contains(mode) => {
+                    let list = match mode {
+                        'b' => &mut self.bans,
+                        'e' => &mut self.excepts,
+                        'I' => &mut self.invite_exceptions,
+                        // Lists of other servers, such as quiets, are not kept.
+                        _ => continue,
+                    };
+                    let normalized = crate::mask::normalize_mask(mask);
+                    let changed = if change.adding {
+                        list.add(&normalized, setter, set_at)
+                    } else {
+                        list.remove(&normalized).is_some()
+                    };
+                    changed.then(|| ModeChange { argument: Some(normalized), ..change.clone() })
+                }
+                (mode, Some(nick)) if self.mode_table.prefix_for_mode(mode).is_some() => {
+                    let prefix = self.mode_table.prefix_for_mode(mode).unwrap();
+                    let symbols = self.mode_table.prefix_symbols.clone();
+                    match self.member_mut(nick) {
+                        Some(member) => {
+                            let changed = if change.adding {
+                                member.add_prefix(prefix, &symbols)
+                            } else {
+                                member.remove_prefix(prefix)
+                            };
+                            let nick = member.nick.clone();
+                            changed.then(|| ModeChange { argument: Some(nick), ..change.clone() })
+                        }
+                        None => None,
+                    }
+                }
+                _ => None,
+            };
+
+            if let Some(effective) = effective {
+                applied.push(effective);
+            }
+        }
+        return applied
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Channel, ModeChange, ModeTable, PREFIX_SYMBOLS};
+
+    fn args(args: &[&str]) -> Vec<String> {
+        return args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_modes() {
+        let changes = ModeChange::parse("+ntk-l+ob", &args(&["secret", "dan"]));
+        assert_eq!(vec!["+ntk-l+ob", "secret", "dan"], ModeChange::format(&changes));
+        assert_eq!(None, changes[5].argument);
+    }
+
+    #[test]
+    fn test_mode_table() {
+        let mut table = ModeTable::default();
+        table.set_prefix("(Yov)!@+");
+        table.set_chanmodes("beIq,k,fl,imnpstC,X");
+        table.set_prefix("(ov)@");
+        assert_eq!(("Yov", "!@+"), (table.prefix_modes.as_str(), table.prefix_symbols.as_str()));
+        assert_eq!((Some('!'), Some('v')), (table.prefix_for_mode('Y'), table.mode_for_prefix('+')));
+
+        let changes = ModeChange::parse_with("+fqY-f", &args(&["5:10", "troll", "eve"]), &table);
+        assert_eq!(vec!["+fqY-f", "5:10", "troll", "eve"], ModeChange::format(&changes));
+        let mut channel = Channel::with_mode_table("#chan".to_string(), Default::default(), table.clone());
+        channel.add_member("eve".to_string());
+        channel.apply_modes(&ModeChange::parse_with("+vY", &args(&["eve", "eve"]), &table), "dan", 1);
+        assert_eq!("!+", channel.member("eve").unwrap().prefixes);
+        assert!(channel.member("eve").unwrap().is_op(&table.prefix_symbols));
+        assert!(!channel.member("eve").unwrap().is_op(PREFIX_SYMBOLS));
+
+        let applied = channel.apply_modes(&ModeChange::parse_with("+fCn", &args(&["5:10"]), &table), "dan", 2);
+        assert_eq!(vec!["+fCn", "5:10"], ModeChange::format(&applied));
+        assert_eq!(vec!["+nCf", "5:10"], channel.mode_params(true));
+        let applied = channel.apply_modes(&ModeChange::parse_with("-fC", &[], &table), "dan", 3);
+        assert_eq!(vec!["-fC"], ModeChange::format(&applied));
+        assert_eq!(vec!["+n"], channel.mode_params(true));
+    }
+
+    #[test]
+    fn test_apply_modes() {
+        let mut channel = Channel::new("#chan".to_string(), "Dan".to_string());
+        channel.add_member("eve".to_string());
+
+        let changes = ModeChange::parse("+nt+o+v+l+b", &args(&["dan", "eve", "10", "troll"]));
+        let applied = channel.apply_modes(&changes, "Dan", 1);
+        assert_eq!(vec!["+ntovlb", "Dan", "eve", "10", "troll!*@*"], ModeChange::format(&applied));
+        assert!(channel.member("DAN").unwrap().is_op(PREFIX_SYMBOLS));
+        assert_eq!("+", channel.member("eve").unwrap().prefixes);
+        assert_eq!(vec!["+ntl", "10"], channel.mode_params(true));
+
+        let changes = ModeChange::parse("+n-i+o-b+h", &args(&["dan", "troll", "dan"]));
+        let applied = channel.apply_modes(&changes, "Dan", 2);
+        assert_eq!(vec!["-b+h", "troll!*@*", "Dan"], ModeChange::format(&applied));
+        assert_eq!("@%", channel.member("dan").unwrap().prefixes);
     }
 }
@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+
+use crate::channel::{is_channel_name, Channel};
+use crate::types::{Casemapping, Command, Message, Source};
+
+/// A message together with the nicks it has to be delivered to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Broadcast {
+    pub recipients: Vec<String>,
+    pub message: Message,
+}
+
+impl Broadcast {
+    fn to_channel(channel: &Channel, source: &Source, command: Command) -> Self {
+        return Broadcast {
+            recipients: channel.members().map(|member| member.nick.clone()).collect(),
+            message: Message::new(None, Some(source.clone()), command),
+        }
+    }
+}
+
+/// Why a channel operation was refused, see [`ChannelError::to_command`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChannelError {
+    NoSuchChannel(String),
+    NotOnChannel(String),
+    UserNotInChannel { nick: String, channel: String },
+    ChanOpPrivsNeeded(String),
+    BannedFromChan(String),
+    InviteOnlyChan(String),
+    BadChannelKey(String),
+    ChannelIsFull(String),
+}
+
+impl ChannelError {
+    /// The error numeric to send back to `client`.
+    pub fn to_command(&self, client: &str) -> Command {
+        let client = client.to_string();
+        match self.clone() {
+            ChannelError::NoSuchChannel(channel) => Command::ERR_NOSUCHCHANNEL { client, channel },
+            ChannelError::NotOnChannel(channel) => Command::ERR_NOTONCHANNEL { client, channel },
+            ChannelError::UserNotInChannel { nick, channel } => Command::ERR_USERNOTINCHANNEL { client, nick, channel },
+            ChannelError::ChanOpPrivsNeeded(channel) => Command::ERR_CHANOPRIVSNEEDED { client, channel },
+            ChannelError::BannedFromChan(channel) => Command::ERR_BANNEDFROMCHAN { client, channel },
+            ChannelError::InviteOnlyChan(channel) => Command::ERR_INVITEONLYCHAN { client, channel },
+            ChannelError::BadChannelKey(channel) => Command::ERR_BADCHANNELKEY { client, channel },
+            ChannelError::ChannelIsFull(channel) => Command::ERR_CHANNELISFULL { client, channel },
+        }
+    }
+}
+
+/// All channels known to a server, keyed by their casefolded name.
+///
+/// Every operation returns the broadcasts the caller has to deliver, or the
+/// error to send back to the client that issued it.
+#[derive(Debug, Default)]
+pub struct ChannelRegistry {
+    casemapping: Casemapping,
+    channels: HashMap<String, Channel>,
+}
+
+impl ChannelRegistry {
+    pub fn new(casemapping: Casemapping) -> Self {
+        return ChannelRegistry { casemapping, channels: HashMap::new() }
+    }
+
+    pub fn casemapping(&self) -> Casemapping {
+        return self.casemapping
+    }
+
+    pub fn len(&self) -> usize {
+        return self.channels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.channels.is_empty()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Channel> {
+        return self.channels.get(&self.casemapping.fold(name))
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Channel> {
+        return self.channels.get_mut(&self.casemapping.fold(name))
+    }
+
+    pub fn channels(&self) -> impl Iterator<Item = &Channel> {
+        return self.channels.values()
+    }
+
+    pub fn channels_of<'a>(&'a self, nick: &'a str) -> impl Iterator<Item = &'a Channel> {
+        return self.channels.values().filter(move |channel| channel.is_member(nick))
+    }
+
+    /// Nicks sharing at least one channel with `nick`, excluding `nick` itself.
+    pub fn neighbours(&self, nick: &str) -> Vec<String> {
+        let mut seen = HashMap::new();
+        for channel in self.channels_of(nick) {
+            for member in channel.members() {
+                if !self.casemapping.equals(&member.nick, nick) {
+                    seen.entry(self.casemapping.fold(&member.nick)).or_insert_with(|| member.nick.clone());
+                }
+            }
+        }
+        let mut neighbours: Vec<String> = seen.into_values().collect();
+        neighbours.sort();
+        return neighbours
+    }
+
+    pub fn join(&mut self, source: &Source, name: &str, key: Option<&str>) -> Result<Vec<Broadcast>, ChannelError> {
+        if !is_channel_name(name) {
+            return Err(ChannelError::NoSuchChannel(name.to_string()));
+        }
+
+        let folded = self.casemapping.fold(name);
+        let channel = match self.channels.get_mut(&folded) {
+            Some(channel) => {
+                if channel.is_member(&source.name) {
+                    return Ok(Vec::new());
+                }
+                ChannelRegistry::check_join(channel, source, key)?;
+                channel.add_member(source.name.clone());
+                channel
+            }
+            None => {
+                let mut channel = Channel::with_casemapping(name.to_string(), self.casemapping);
+                channel.add_member(source.name.clone());
+                let symbols = channel.mode_table.prefix_symbols.clone();
+                channel.member_mut(&source.name).unwrap().add_prefix('@', &symbols);
+                self.channels.entry(folded).or_insert(channel)
+            }
+        };
+
+        let command = Command::JOIN { channels: channel.name.clone(), keys: None, account: None, realname: None };
+        return Ok(vec![Broadcast::to_channel(channel, source, command)])
+    }
+
+    fn check_join(channel: &Channel, source: &Source, key: Option<&str>) -> Result<(), ChannelError> {
+        let name = channel.name.clone();
+
+        if channel.bans.matches(source) && !channel.excepts.matches(source) {
+            return Err(ChannelError::BannedFromChan(name));
+        }
+        if channel.modes.invite_only && !channel.is_invited(&source.name) && !channel.invite_exceptions.matches(source) {
+            return Err(ChannelError::InviteOnlyChan(name));
+        }
+        if channel.key.is_some() && channel.key.as_deref() != key {
+            return Err(ChannelError::BadChannelKey(name));
+        }
+        if channel.limit.is_some_and(|limit| channel.len() >= limit) {
+            return Err(ChannelError::ChannelIsFull(name));
+        }
+        return Ok(())
+    }
+
+    pub fn part(&mut self, source: &Source, name: &str, reason: Option<String>) -> Result<Vec<Broadcast>, ChannelError> {
+        let channel = self.member_channel(source, name)?;
+
+        let command = Command::PART { channels: channel.name.clone(), reason };
+        let broadcast = Broadcast::to_channel(channel, source, command);
+        channel.remove_member(&source.name);
+
+        self.cleanup(name);
+        return Ok(vec![broadcast])
+    }
+
+    pub fn kick(&mut self, source: &Source, name: &str, target: &str, comment: Option<String>) -> Result<Vec<Broadcast>, ChannelError> {
+        let channel = self.member_channel(source, name)?;
+
+        let kicker = channel.member(&source.name).unwrap();
+        let victim = match channel.member(target) {
+            Some(victim) => victim,
+            None => return Err(ChannelError::UserNotInChannel { nick: target.to_string(), channel: channel.name.clone() }),
+        };
+        let symbols = &channel.mode_table.prefix_symbols;
+        if !kicker.is_halfop(symbols) || (!kicker.is_op(symbols) && victim.is_op(symbols)) {
+            return Err(ChannelError::ChanOpPrivsNeeded(channel.name.clone()));
+        }
+
+        let command = Command::KICK { channel: channel.name.clone(), user: victim.nick.clone(), comment };
+        let broadcast = Broadcast::to_channel(channel, source, command);
+        channel.remove_member(target);
+
+        self.cleanup(name);
+        return Ok(vec![broadcast])
+    }
+
+    /// Removes the client from every channel; the quitting client itself is
+    /// not among the recipients.
+    pub fn quit(&mut self, source: &Source, reason: Option<String>) -> Vec<Broadcast> {
+        let recipients = self.neighbours(&source.name);
+
+        for channel in self.channels.values_mut() {
+            channel.remove_member(&source.name);
+        }
+        self.channels.retain(|_, channel| !channel.is_empty());
+
+        if recipients.is_empty() {
+            return Vec::new();
+        }
+        let message = Message::new(None, Some(source.clone()), Command::QUIT { reason });
+        return vec![Broadcast { recipients, message }]
+    }
+
+    /// Renames the client in every channel it is in. The broadcast is also
+    /// addressed to the client, under its new nick.
+    pub fn nick_change(&mut self, source: &Source, nickname: &str) -> Vec<Broadcast> {
+        let mut recipients = self.neighbours(&source.name);
+        recipients.push(nickname.to_string());
+
+        for channel in self.channels.values_mut() {
+            channel.rename_member(&source.name, nickname);
+        }
+
+        let message = Message::new(None, Some(source.clone()), Command::NICK { nickname: nickname.to_string() });
+        return vec![Broadcast { recipients, message }]
+    }
+
+    fn member_channel(&mut self, source: &Source, name: &str) -> Result<&mut Channel, ChannelError> {
+        match self.channels.get_mut(&self.casemapping.fold(name)) {
+            Some(channel) if channel.is_member(&source.name) => Ok(channel),
+            Some(channel) => Err(ChannelError::NotOnChannel(channel.name.clone())),
+            None => Err(ChannelError::NoSuchChannel(name.to_string())),
+        }
+    }
+
+    fn cleanup(&mut self, name: &str) {
+        let folded = self.casemapping.fold(name);
+        if self.channels.get(&folded).is_some_and(|channel| channel.is_empty()) {
+            self.channels.remove(&folded);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::channel::PREFIX_SYMBOLS;
+    use crate::types::{Casemapping, Command};
+    use crate::test_util::source;
+
+    use super::{ChannelError, ChannelRegistry};
+
+    #[test]
+    fn test_join_part() {
+        let mut registry = ChannelRegistry::new(Casemapping::Rfc1459);
+
+        let broadcasts = registry.join(&source("dan"), "#Chan", None).unwrap();
+        assert_eq!(vec!["dan"], broadcasts[0].recipients);
+        assert!(registry.get("#chan").unwrap().member("dan").unwrap().is_op(PREFIX_SYMBOLS));
+
+        let broadcasts = registry.join(&source("eve"), "#chan", None).unwrap();
+        assert_eq!(vec!["dan", "eve"], broadcasts[0].recipients);
+        assert_eq!("JOIN #Chan\r\n", broadcasts[0].message.clone().to_bytes().split_once(' ').unwrap().1);
+        assert!(registry.join(&source("eve"), "#chan", None).unwrap().is_empty());
+
+        assert!(matches!(registry.join(&source("dan"), "chan", None), Err(ChannelError::NoSuchChannel(_))));
+
+        registry.part(&source("dan"), "#chan", None).unwrap();
+        registry.part(&source("eve"), "#chan", Some("bye".to_string())).unwrap();
+        assert!(registry.is_empty());
+        assert!(matches!(registry.part(&source("eve"), "#chan", None), Err(ChannelError::NoSuchChannel(_))));
+    }
+
+    #[test]
+    fn test_join_restrictions() {
+        let mut registry = ChannelRegistry::new(Casemapping::Rfc1459);
+        registry.join(&source("dan"), "#chan", None).unwrap();
+
+        let channel = registry.get_mut("#chan").unwrap();
+        channel.key = Some("secret".to_string());
+        assert!(matches!(registry.join(&source("eve"), "#chan", None), Err(ChannelError::BadChannelKey(_))));
+        assert!(registry.join(&source("eve"), "#chan", Some("secret")).is_ok());
+
+        let channel = registry.get_mut("#chan").unwrap();
+        channel.limit = Some(2);
+        assert!(matches!(registry.join(&source("bob"), "#chan", Some("secret")), Err(ChannelError::ChannelIsFull(_))));
+
+        let channel = registry.get_mut("#chan").unwrap();
+        channel.limit = None;
+        channel.bans.add("bob!*@*", "dan", 0);
+        assert!(matches!(registry.join(&source("bob"), "#chan", Some("secret")), Err(ChannelError::BannedFromChan(_))));
+
+        let channel = registry.get_mut("#chan").unwrap();
+        channel.bans.remove("bob");
+        channel.modes.invite_only = true;
+        assert!(matches!(registry.join(&source("bob"), "#chan", Some("secret")), Err(ChannelError::InviteOnlyChan(_))));
+        registry.get_mut("#chan").unwrap().invite("bob");
+        assert!(registry.join(&source("bob"), "#chan", Some("secret")).is_ok());
+    }
+
+    #[test]
+    fn test_kick_quit_nick() {
+        let mut registry = ChannelRegistry::new(Casemapping::Rfc1459);
+        registry.join(&source("dan"), "#a", None).unwrap();
+        registry.join(&source("eve"), "#a", None).unwrap();
+        registry.join(&source("eve"), "#b", None).unwrap();
+        registry.join(&source("bob"), "#b", None).unwrap();
+
+        assert!(matches!(registry.kick(&source("eve"), "#a", "dan", None), Err(ChannelError::ChanOpPrivsNeeded(_))));
+        let error = registry.kick(&source("dan"), "#a", "bob", None).unwrap_err();
+        assert_eq!(Command::ERR_USERNOTINCHANNEL { client: "dan".to_string(), nick: "bob".to_string(), channel: "#a".to_string() }, error.to_command("dan"));
+        assert!(matches!(registry.kick(&source("dan"), "#a", "bob", None), Err(ChannelError::UserNotInChannel { .. })));
+
+        let broadcasts = registry.nick_change(&source("eve"), "Eva");
+        assert_eq!(vec!["bob", "dan", "Eva"], broadcasts[0].recipients);
+        assert!(registry.get("#b").unwrap().member("eva").unwrap().is_op(PREFIX_SYMBOLS));
+
+        let broadcasts = registry.quit(&source("dan"), None);
+        assert_eq!(vec!["Eva"], broadcasts[0].recipients);
+
+        let broadcasts = registry.kick(&source("eva"), "#b", "BOB", Some("out".to_string())).unwrap();
+        assert_eq!(":eva!u@host KICK #b bob out\r\n", broadcasts[0].message.clone().to_bytes());
+        assert_eq!(2, registry.len());
+    }
+}
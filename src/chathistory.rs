@@ -0,0 +1,729 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::mpsc;
+use std::thread;
+
+use tokio::sync::oneshot;
+
+use crate::batch::BatchBuilder;
+use crate::channel::is_channel_name;
+use crate::channel_registry::ChannelRegistry;
+use crate::standard_reply::StandardReply;
+use crate::time::Timestamp;
+use crate::types::{Casemapping, Command, Message, Source};
+use crate::user::is_valid_nick;
+
+/// Points at a message in a CHATHISTORY request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Selector {
+    MsgId(String),
+    Timestamp(Timestamp),
+}
+
+impl Selector {
+    /// Parses `msgid=...` or `timestamp=...`.
+    pub fn parse(input: &str) -> Option<Selector> {
+        return match input.split_once('=')? {
+            ("msgid", msgid) if !msgid.is_empty() => Some(Selector::MsgId(msgid.to_string())),
+            ("timestamp", time) => Timestamp::parse(time).map(Selector::Timestamp),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Selector::MsgId(msgid) => write!(f, "msgid={}", msgid),
+            Selector::Timestamp(time) => write!(f, "timestamp={}", time),
+        }
+    }
+}
+
+/// A CHATHISTORY request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HistoryQuery {
+    /// The newest messages, only those after `after` if given.
+    Latest{target: String, after: Option<Selector>, limit: usize},
+    Before{target: String, selector: Selector, limit: usize},
+    After{target: String, selector: Selector, limit: usize},
+    Around{target: String, selector: Selector, limit: usize},
+    /// Walks from `start` towards `end`, in either direction.
+    Between{target: String, start: Selector, end: Selector, limit: usize},
+    /// Conversations with activity between two points in time.
+    Targets{start: Timestamp, end: Timestamp, limit: usize},
+}
+
+/// Why a CHATHISTORY request was refused, sent as `FAIL CHATHISTORY <code>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HistoryError {
+    NeedMoreParams,
+    UnknownCommand(String),
+    InvalidParams(String),
+    InvalidTarget(String),
+}
+
+impl HistoryError {
+    pub fn to_command(&self) -> Command {
+        let (code, context, description) = match self {
+            HistoryError::NeedMoreParams => ("NEED_MORE_PARAMS", None, "Missing parameters"),
+            HistoryError::UnknownCommand(subcommand) => ("UNKNOWN_COMMAND", Some(subcommand), "Unknown subcommand"),
+            HistoryError::InvalidParams(param) => ("INVALID_PARAMS", Some(param), "Invalid parameters"),
+            HistoryError::InvalidTarget(target) => ("INVALID_TARGET", Some(target), "Messages could not be retrieved"),
+        };
+        let reply = StandardReply::fail("CHATHISTORY", code).description(description);
+        return match context {
+            Some(param) => reply.context(param),
+            None => reply,
+        }.to_command()
+    }
+}
+
+impl HistoryQuery {
+    pub fn parse(subcommand: &str, params: &[String]) -> Result<HistoryQuery, HistoryError> {
+        let subcommand = subcommand.to_ascii_uppercase();
+        let count = match subcommand.as_str() {
+            "LATEST" | "BEFORE" | "AFTER" | "AROUND" | "TARGETS" => 3,
+            "BETWEEN" => 4,
+            _ => return Err(HistoryError::UnknownCommand(subcommand)),
+        };
+        if params.len() < count {
+            return Err(HistoryError::NeedMoreParams);
+        }
+        let selector = |param: &String| Selector::parse(param).ok_or_else(|| HistoryError::InvalidParams(param.clone()));
+        let timestamp = |param: &String| match selector(param)? {
+            Selector::Timestamp(time) => Ok(time),
+            Selector::MsgId(_) => Err(HistoryError::InvalidParams(param.clone())),
+        };
+        let limit = &params[count - 1];
+        let limit = limit.parse::<usize>().map_err(|_| HistoryError::InvalidParams(limit.clone()))?;
+        let target = params[0].clone();
+
+        return Ok(match subcommand.as_str() {
+            "LATEST" => {
+                let after = match params[1].as_str() {
+                    "*" => None,
+                    _ => Some(selector(&params[1])?),
+                };
+                HistoryQuery::Latest { target, after, limit }
+            }
+            "BEFORE" => HistoryQuery::Before { target, selector: selector(&params[1])?, limit },
+            "AFTER" => HistoryQuery::After { target, selector: selector(&params[1])?, limit },
+            "AROUND" => HistoryQuery::Around { target, selector: selector(&params[1])?, limit },
+            "BETWEEN" => HistoryQuery::Between { target, start: selector(&params[1])?, end: selector(&params[2])?, limit },
+            _ => HistoryQuery::Targets { start: timestamp(&params[0])?, end: timestamp(&params[1])?, limit },
+        })
+    }
+
+    /// The conversation asked for, `None` for TARGETS.
+    pub fn target(&self) -> Option<&str> {
+        return match self {
+            HistoryQuery::Latest { target, .. }
+            | HistoryQuery::Before { target, .. }
+            | HistoryQuery::After { target, .. }
+            | HistoryQuery::Around { target, .. }
+            | HistoryQuery::Between { target, .. } => Some(target),
+            HistoryQuery::Targets { .. } => None,
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        return match self {
+            HistoryQuery::Latest { limit, .. }
+            | HistoryQuery::Before { limit, .. }
+            | HistoryQuery::After { limit, .. }
+            | HistoryQuery::Around { limit, .. }
+            | HistoryQuery::Between { limit, .. }
+            | HistoryQuery::Targets { limit, .. } => *limit,
+        }
+    }
+
+    /// Lowers the limit to at most `max`.
+    pub fn clamp_limit(&mut self, max: usize) {
+        match self {
+            HistoryQuery::Latest { limit, .. }
+            | HistoryQuery::Before { limit, .. }
+            | HistoryQuery::After { limit, .. }
+            | HistoryQuery::Around { limit, .. }
+            | HistoryQuery::Between { limit, .. }
+            | HistoryQuery::Targets { limit, .. } => *limit = (*limit).min(max),
+        }
+    }
+
+    pub fn to_command(&self) -> Command {
+        let (subcommand, params) = match self {
+            HistoryQuery::Latest { target, after, limit } => {
+                let after = after.as_ref().map(Selector::to_string).unwrap_or_else(|| "*".to_string());
+                ("LATEST", vec![target.clone(), after, limit.to_string()])
+            }
+            HistoryQuery::Before { target, selector, limit } => ("BEFORE", vec![target.clone(), selector.to_string(), limit.to_string()]),
+            HistoryQuery::After { target, selector, limit } => ("AFTER", vec![target.clone(), selector.to_string(), limit.to_string()]),
+            HistoryQuery::Around { target, selector, limit } => ("AROUND", vec![target.clone(), selector.to_string(), limit.to_string()]),
+            HistoryQuery::Between { target, start, end, limit } => ("BETWEEN", vec![target.clone(), start.to_string(), end.to_string(), limit.to_string()]),
+            HistoryQuery::Targets { start, end, limit } => ("TARGETS", vec![
+                Selector::Timestamp(*start).to_string(),
+                Selector::Timestamp(*end).to_string(),
+                limit.to_string(),
+            ]),
+        };
+        return Command::CHATHISTORY { subcommand: subcommand.to_string(), params }
+    }
+}
+
+/// Where a message sits in a conversation: ordered by time, then by the
+/// order the store received it in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Position {
+    pub time: Timestamp,
+    /// Starts at 1.
+    pub seq: u64,
+}
+
+impl Position {
+    fn start_of(time: Timestamp) -> Self {
+        return Position { time, seq: 0 }
+    }
+
+    fn end_of(time: Timestamp) -> Self {
+        return Position { time, seq: u64::MAX }
+    }
+}
+
+/// Message history, keyed by conversation. The server decides the keys, e.g.
+/// casefolded channel names.
+pub trait HistoryStore: Send {
+    /// Records `message`, which should carry `time` and `msgid` tags.
+    fn append(&mut self, key: &str, message: &Message);
+
+    fn find(&self, key: &str, msgid: &str) -> Option<Position>;
+
+    /// At most `limit` messages strictly between `after` and `before`, oldest
+    /// first; with `newest` the ones closest to `before` are kept.
+    fn range(&self, key: &str, after: Option<Position>, before: Option<Position>, limit: usize, newest: bool) -> Vec<Message>;
+
+    /// Conversations whose latest message is strictly between `after` and
+    /// `before`, with its time, oldest first.
+    fn targets(&self, after: Timestamp, before: Timestamp, limit: usize) -> Vec<(String, Timestamp)>;
+
+    /// Answers any query but TARGETS, see [`HistoryStore::targets`].
+    fn query(&self, key: &str, query: &HistoryQuery) -> Vec<Message> {
+        let position = |selector: &Selector| match selector {
+            Selector::MsgId(msgid) => self.find(key, msgid),
+            Selector::Timestamp(time) => Some(Position::start_of(*time)),
+        };
+        // Messages at a timestamp belong to neither side of it.
+        let after = |selector: &Selector| match selector {
+            Selector::MsgId(msgid) => self.find(key, msgid),
+            Selector::Timestamp(time) => Some(Position::end_of(*time)),
+        };
+
+        return match query {
+            HistoryQuery::Latest { after: None, limit, .. } => self.range(key, None, None, *limit, true),
+            HistoryQuery::Latest { after: Some(selector), limit, .. } => match after(selector) {
+                Some(after) => self.range(key, Some(after), None, *limit, true),
+                None => Vec::new(),
+            },
+            HistoryQuery::Before { selector, limit, .. } => match position(selector) {
+                Some(before) => self.range(key, None, Some(before), *limit, true),
+                None => Vec::new(),
+            },
+            HistoryQuery::After { selector, limit, .. } => match after(selector) {
+                Some(after) => self.range(key, Some(after), None, *limit, false),
+                None => Vec::new(),
+            },
+            HistoryQuery::Around { selector, limit, .. } => {
+                let Some(center) = position(selector) else { return Vec::new() };
+                let mut messages = self.range(key, None, Some(center), limit / 2, true);
+                // The selected message itself is part of the later half.
+                let from = Position { seq: center.seq.saturating_sub(1), ..center };
+                messages.extend(self.range(key, Some(from), None, limit - messages.len(), false));
+                messages
+            }
+            HistoryQuery::Between { start, end, limit, .. } => {
+                let (Some(from), Some(to)) = (position(start), position(end)) else { return Vec::new() };
+                match from <= to {
+                    true => self.range(key, after(start), Some(to), *limit, false),
+                    false => self.range(key, after(end), Some(from), *limit, true),
+                }
+            }
+            HistoryQuery::Targets { .. } => Vec::new(),
+        }
+    }
+}
+
+/// Keeps the last `capacity` messages of every conversation in memory.
+#[derive(Debug)]
+pub struct MemoryHistory {
+    capacity: usize,
+    next_seq: u64,
+    conversations: HashMap<String, VecDeque<(Position, Message)>>,
+}
+
+impl MemoryHistory {
+    pub fn new(capacity: usize) -> Self {
+        return MemoryHistory { capacity, next_seq: 1, conversations: HashMap::new() }
+    }
+}
+
+impl HistoryStore for MemoryHistory {
+    fn append(&mut self, key: &str, message: &Message) {
+        if self.capacity == 0 {
+            return;
+        }
+        let position = Position { time: message.server_time().unwrap_or_else(Timestamp::now), seq: self.next_seq };
+        self.next_seq += 1;
+        let conversation = self.conversations.entry(key.to_string()).or_default();
+        if conversation.len() == self.capacity {
+            conversation.pop_front();
+        }
+        // Keep the buffer sorted even if a message arrives with an older time.
+        let idx = conversation.partition_point(|(existing, _)| *existing <= position);
+        conversation.insert(idx, (position, message.clone()));
+    }
+
+    fn find(&self, key: &str, msgid: &str) -> Option<Position> {
+        let conversation = self.conversations.get(key)?;
+        return conversation.iter().find(|(_, message)| message.msgid() == Some(msgid)).map(|(position, _)| *position)
+    }
+
+    fn range(&self, key: &str, after: Option<Position>, before: Option<Position>, limit: usize, newest: bool) -> Vec<Message> {
+        let Some(conversation) = self.conversations.get(key) else { return Vec::new() };
+        let start = after.map(|after| conversation.partition_point(|(position, _)| *position <= after)).unwrap_or(0);
+        let end = before.map(|before| conversation.partition_point(|(position, _)| *position < before)).unwrap_or(conversation.len());
+        if start >= end {
+            return Vec::new();
+        }
+        let (start, end) = match newest {
+            true => (end.saturating_sub(limit).max(start), end),
+            false => (start, (start + limit).min(end)),
+        };
+        return conversation.range(start..end).map(|(_, message)| message.clone()).collect()
+    }
+
+    fn targets(&self, after: Timestamp, before: Timestamp, limit: usize) -> Vec<(String, Timestamp)> {
+        let mut targets: Vec<(String, Timestamp)> = self.conversations.iter()
+            .filter_map(|(key, conversation)| conversation.back().map(|(position, _)| (key.clone(), position.time)))
+            .filter(|(_, time)| after < *time && *time < before)
+            .collect();
+        targets.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+        targets.truncate(limit);
+        return targets
+    }
+}
+
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteHistory;
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use std::path::Path;
+
+    use log::warn;
+    use rusqlite::{params, Connection, OptionalExtension};
+
+    use crate::time::Timestamp;
+    use crate::types::Message;
+
+    use super::{HistoryStore, Position};
+
+    /// Keeps all history in an SQLite database, one row per message.
+    pub struct SqliteHistory {
+        db: Connection,
+    }
+
+    impl SqliteHistory {
+        pub fn open<P: AsRef<Path>>(path: P) -> rusqlite::Result<Self> {
+            return SqliteHistory::init(Connection::open(path)?)
+        }
+
+        pub fn open_in_memory() -> rusqlite::Result<Self> {
+            return SqliteHistory::init(Connection::open_in_memory()?)
+        }
+
+        fn init(db: Connection) -> rusqlite::Result<Self> {
+            db.execute_batch(
+                "CREATE TABLE IF NOT EXISTS history (
+                    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                    target TEXT NOT NULL,
+                    time INTEGER NOT NULL,
+                    msgid TEXT,
+                    line TEXT NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS history_position ON history (target, time, seq);
+                CREATE INDEX IF NOT EXISTS history_msgid ON history (target, msgid);",
+            )?;
+            return Ok(SqliteHistory { db })
+        }
+
+        /// Bounds as SQL parameters; `seq` is stored as a signed integer.
+        fn bound(position: Option<Position>, default: i64) -> (i64, i64) {
+            return match position {
+                Some(position) => (position.time.millis() as i64, position.seq.min(i64::MAX as u64) as i64),
+                None => (default, default),
+            }
+        }
+    }
+
+    impl HistoryStore for SqliteHistory {
+        fn append(&mut self, key: &str, message: &Message) {
+            let time = message.server_time().unwrap_or_else(Timestamp::now);
+            let line = message.clone().to_bytes();
+            let result = self.db.execute(
+                "INSERT INTO history (target, time, msgid, line) VALUES (?1, ?2, ?3, ?4)",
+                params![key, time.millis() as i64, message.msgid(), line.trim_end()],
+            );
+            if let Err(e) = result {
+                warn!("could not store history for {}: {}", key, e);
+            }
+        }
+
+        fn find(&self, key: &str, msgid: &str) -> Option<Position> {
+            let row = self.db.query_row(
+                "SELECT time, seq FROM history WHERE target = ?1 AND msgid = ?2",
+                params![key, msgid],
+                |row| Ok(Position { time: Timestamp::from_millis(row.get::<_, i64>(0)? as u64), seq: row.get::<_, i64>(1)? as u64 }),
+            );
+            return row.optional().unwrap_or_else(|e| {
+                warn!("could not look up {} in {}: {}", msgid, key, e);
+                None
+            })
+        }
+
+        fn range(&self, key: &str, after: Option<Position>, before: Option<Position>, limit: usize, newest: bool) -> Vec<Message> {
+            let (after_time, after_seq) = SqliteHistory::bound(after, -1);
+            let (before_time, before_seq) = SqliteHistory::bound(before, i64::MAX);
+            let order = if newest { "DESC" } else { "ASC" };
+            let sql = format!(
+                "SELECT line FROM history WHERE target = ?1 AND (time, seq) > (?2, ?3) AND (time, seq) < (?4, ?5)
+                 ORDER BY time {0}, seq {0} LIMIT ?6",
+                order,
+            );
+            let lines: rusqlite::Result<Vec<String>> = self.db.prepare_cached(&sql).and_then(|mut statement| {
+                let rows = statement.query_map(
+                    params![key, after_time, after_seq, before_time, before_seq, limit.min(i64::MAX as usize) as i64],
+                    |row| row.get(0),
+                )?;
+                rows.collect()
+            });
+            let mut messages: Vec<Message> = match lines {
+                Ok(lines) => lines.iter().filter_map(|line| Message::parse(line)).collect(),
+                Err(e) => {
+                    warn!("could not read history of {}: {}", key, e);
+                    Vec::new()
+                }
+            };
+            if newest {
+                messages.reverse();
+            }
+            return messages
+        }
+
+        fn targets(&self, after: Timestamp, before: Timestamp, limit: usize) -> Vec<(String, Timestamp)> {
+            let targets: rusqlite::Result<Vec<(String, Timestamp)>> = self.db.prepare_cached(
+                "SELECT target, MAX(time) AS latest FROM history GROUP BY target
+                 HAVING latest > ?1 AND latest < ?2 ORDER BY latest, target LIMIT ?3",
+            ).and_then(|mut statement| {
+                let rows = statement.query_map(
+                    params![after.millis() as i64, before.millis() as i64, limit.min(i64::MAX as usize) as i64],
+                    |row| Ok((row.get(0)?, Timestamp::from_millis(row.get::<_, i64>(1)? as u64))),
+                )?;
+                rows.collect()
+            });
+            return targets.unwrap_or_else(|e| {
+                warn!("could not list history targets: {}", e);
+                Vec::new()
+            })
+        }
+    }
+}
+
+type Job = Box<dyn FnOnce(&mut dyn HistoryStore) + Send>;
+
+/// Runs a [`HistoryStore`] on a thread of its own, so that its disk I/O
+/// blocks neither the async runtime nor whoever holds the server state.
+/// Jobs run in the order they were submitted.
+#[derive(Clone)]
+pub struct HistoryWorker {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl HistoryWorker {
+    pub fn spawn<S: HistoryStore + 'static>(mut store: S) -> Self {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        thread::Builder::new()
+            .name("history".to_string())
+            .spawn(move || {
+                for job in receiver {
+                    job(&mut store);
+                }
+            })
+            .expect("could not start the history thread");
+        return HistoryWorker { jobs }
+    }
+
+    /// Queues `message` for `key` without waiting for it to be stored.
+    pub fn append(&self, key: String, message: Message) {
+        _ = self.jobs.send(Box::new(move |store: &mut dyn HistoryStore| store.append(&key, &message)));
+    }
+
+    /// Runs `job` against the store, `None` if the store has gone away.
+    pub async fn run<T, F>(&self, job: F) -> Option<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut dyn HistoryStore) -> T + Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        _ = self.jobs.send(Box::new(move |store: &mut dyn HistoryStore| {
+            _ = sender.send(job(store));
+        }));
+        return receiver.await.ok()
+    }
+}
+
+/// The history key of a conversation with `target`: the casefolded channel,
+/// or for private messages the casefolded `account` they are kept for and
+/// the nick on the other side. Nicks change hands, accounts do not.
+pub fn conversation_key(casemapping: Casemapping, account: &str, target: &str) -> String {
+    if is_channel_name(target) {
+        return casemapping.fold(target)
+    }
+    return format!("{} {}", casemapping.fold(account), casemapping.fold(target))
+}
+
+/// Whom `account` talked to in the conversation `key`, `None` if not theirs.
+fn conversation_target(casemapping: Casemapping, account: Option<&str>, key: &str) -> Option<String> {
+    let Some((owner, target)) = key.split_once(' ') else {
+        return Some(key.to_string());
+    };
+    return match account {
+        Some(account) if casemapping.fold(account) == owner => Some(target.to_string()),
+        _ => None,
+    }
+}
+
+/// A CHATHISTORY query checked against what its sender may see, so that it
+/// can be answered wherever the store lives.
+#[derive(Debug, Clone)]
+pub struct HistoryRequest {
+    account: Option<String>,
+    casemapping: Casemapping,
+    query: HistoryQuery,
+    /// The channels the sender is on by casefolded name, for TARGETS.
+    channels: HashMap<String, String>,
+}
+
+impl HistoryRequest {
+    /// Checks `query` from `nick`, who only sees history of channels they
+    /// are on and, when logged in to `account`, of its private conversations.
+    pub fn new(nick: &str, account: Option<&str>, query: HistoryQuery, channels: &ChannelRegistry) -> Result<Self, HistoryError> {
+        let casemapping = channels.casemapping();
+        let account = account.map(str::to_string);
+        let mut request = HistoryRequest { account, casemapping, query, channels: HashMap::new() };
+        let Some(target) = request.query.target() else {
+            request.channels = channels.channels_of(nick).map(|channel| (casemapping.fold(&channel.name), channel.name.clone())).collect();
+            return Ok(request)
+        };
+
+        let allowed = match is_channel_name(target) {
+            true => channels.get(target).is_some_and(|channel| channel.member(nick).is_some()),
+            false => request.account.is_some() && is_valid_nick(target),
+        };
+        if !allowed {
+            return Err(HistoryError::InvalidTarget(target.to_string()));
+        }
+        return Ok(request)
+    }
+
+    pub fn answer(&self, server: &Source, store: &dyn HistoryStore) -> BatchBuilder {
+        let Some(target) = self.query.target() else {
+            let HistoryQuery::Targets { start, end, limit } = &self.query else { unreachable!() };
+            let (after, before) = if start <= end { (*start, *end) } else { (*end, *start) };
+            let targets = store.targets(after, before, usize::MAX).into_iter()
+                .filter_map(|(key, time)| {
+                    let target = conversation_target(self.casemapping, self.account.as_deref(), &key)?;
+                    match is_channel_name(&target) {
+                        true => Some((self.channels.get(&target)?.clone(), time)),
+                        false => Some((target, time)),
+                    }
+                })
+                .take(*limit)
+                .collect();
+            return targets_batch(server, targets)
+        };
+        let account = self.account.as_deref().unwrap_or_default();
+        let messages = store.query(&conversation_key(self.casemapping, account, target), &self.query);
+        return history_batch(server, target, messages)
+    }
+}
+
+/// Answers `query` from `nick`, see [`HistoryRequest::new`].
+pub fn chathistory_reply(server: &Source, nick: &str, account: Option<&str>, query: &HistoryQuery, store: &dyn HistoryStore, channels: &ChannelRegistry) -> Result<BatchBuilder, HistoryError> {
+    return Ok(HistoryRequest::new(nick, account, query.clone(), channels)?.answer(server, store))
+}
+
+/// The `chathistory` batch answering a query for `target`.
+pub fn history_batch(server: &Source, target: &str, messages: Vec<Message>) -> BatchBuilder {
+    return BatchBuilder::new("chathistory", vec![target.to_string()])
+        .source(server.clone())
+        .messages(messages)
+}
+
+/// The `draft/chathistory-targets` batch answering a TARGETS query.
+pub fn targets_batch(server: &Source, targets: Vec<(String, Timestamp)>) -> BatchBuilder {
+    let lines = targets.into_iter().map(|(target, time)| Message::new(None, Some(server.clone()), Command::CHATHISTORY {
+        subcommand: "TARGETS".to_string(),
+        params: vec![target, time.to_string()],
+    }));
+    return BatchBuilder::new("draft/chathistory-targets", Vec::new())
+        .source(server.clone())
+        .messages(lines)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::channel_registry::ChannelRegistry;
+    use crate::time::Timestamp;
+    use crate::types::{Casemapping, Command, Message, Source};
+    use crate::test_util::{parse, server};
+
+    use super::{chathistory_reply, conversation_key, HistoryError, HistoryQuery, HistoryStore, HistoryWorker, MemoryHistory, Selector};
+
+    fn message(secs: u64, text: &str) -> Message {
+        let line = format!("@time={};msgid={} :dan!d@host PRIVMSG #chan {}", Timestamp::from_secs(secs), text, text);
+        return parse(&line)
+    }
+
+    fn texts(messages: Vec<Message>) -> Vec<String> {
+        return messages.into_iter().filter_map(|message| match message.command {
+            Command::PRIVMSG { text, .. } => Some(text),
+            _ => None,
+        }).collect()
+    }
+
+    fn query(line: &str) -> HistoryQuery {
+        let params: Vec<String> = line.split(' ').map(str::to_string).collect();
+        return HistoryQuery::parse(&params[0], &params[1..]).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let latest = query("LATEST #chan * 50");
+        assert_eq!(HistoryQuery::Latest { target: "#chan".to_string(), after: None, limit: 50 }, latest);
+        assert_eq!("CHATHISTORY LATEST #chan * 50\r\n", Message::new(None, None, latest.to_command()).to_bytes());
+
+        let between = query("between #chan msgid=abc timestamp=2023-11-14T22:13:20.000Z 10");
+        assert_eq!(HistoryQuery::Between {
+            target: "#chan".to_string(),
+            start: Selector::MsgId("abc".to_string()),
+            end: Selector::Timestamp(Timestamp::from_secs(1700000000)),
+            limit: 10,
+        }, between);
+        let command = Message::new(None, None, between.to_command()).to_bytes();
+        let parsed = parse(command.trim_end());
+        let Command::CHATHISTORY { subcommand, params } = parsed.command else { panic!() };
+        assert_eq!(Ok(between), HistoryQuery::parse(&subcommand, &params));
+
+        let params = |line: &str| line.split(' ').map(str::to_string).collect::<Vec<_>>();
+        assert_eq!(Err(HistoryError::NeedMoreParams), HistoryQuery::parse("BEFORE", &params("#chan 10")));
+        assert_eq!(Err(HistoryError::InvalidParams("id=1".to_string())), HistoryQuery::parse("BEFORE", &params("#chan id=1 10")));
+        assert_eq!(Err(HistoryError::InvalidParams("msgid=a".to_string())), HistoryQuery::parse("TARGETS", &params("msgid=a msgid=b 10")));
+        assert_eq!(Err(HistoryError::UnknownCommand("LAST".to_string())), HistoryQuery::parse("last", &params("#chan * 1")));
+    }
+
+    fn check_store<S: HistoryStore>(store: &mut S) {
+        for (secs, text) in [(10, "a"), (20, "b"), (20, "c"), (30, "d"), (40, "e")] {
+            store.append("#chan", &message(secs, text));
+        }
+        store.append("#other", &message(25, "x"));
+
+        assert_eq!(vec!["d", "e"], texts(store.query("#chan", &query("LATEST #chan * 2"))));
+        assert_eq!(vec!["d", "e"], texts(store.query("#chan", &query("LATEST #chan msgid=c 10"))));
+        assert_eq!(vec!["b", "c"], texts(store.query("#chan", &query("BEFORE #chan msgid=d 2"))));
+        assert_eq!(vec!["a"], texts(store.query("#chan", &query("BEFORE #chan timestamp=1970-01-01T00:00:20.000Z 5"))));
+        assert_eq!(vec!["d"], texts(store.query("#chan", &query("AFTER #chan timestamp=1970-01-01T00:00:20.000Z 1"))));
+        assert_eq!(vec!["c", "d"], texts(store.query("#chan", &query("AFTER #chan msgid=b 2"))));
+        assert_eq!(vec!["b", "c", "d"], texts(store.query("#chan", &query("AROUND #chan msgid=c 3"))));
+        assert_eq!(vec!["b", "c"], texts(store.query("#chan", &query("BETWEEN #chan msgid=a msgid=d 10"))));
+        assert_eq!(vec!["c", "d"], texts(store.query("#chan", &query("BETWEEN #chan msgid=e msgid=a 2"))));
+        assert!(store.query("#chan", &query("AFTER #chan msgid=unknown 10")).is_empty());
+        assert!(store.query("#none", &query("LATEST #none * 10")).is_empty());
+
+        let targets = store.targets(Timestamp::from_secs(0), Timestamp::from_secs(100), 10);
+        assert_eq!(vec![("#other".to_string(), Timestamp::from_secs(25)), ("#chan".to_string(), Timestamp::from_secs(40))], targets);
+        assert_eq!(1, store.targets(Timestamp::from_secs(0), Timestamp::from_secs(100), 1).len());
+        assert!(store.targets(Timestamp::from_secs(40), Timestamp::from_secs(100), 10).is_empty());
+    }
+
+    #[test]
+    fn test_memory_history() {
+        check_store(&mut MemoryHistory::new(100));
+
+        let mut store = MemoryHistory::new(2);
+        for (secs, text) in [(10, "a"), (30, "c"), (20, "b")] {
+            store.append("#chan", &message(secs, text));
+        }
+        assert_eq!(vec!["b", "c"], texts(store.query("#chan", &query("LATEST #chan * 10"))));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite_history() {
+        let mut store = super::SqliteHistory::open_in_memory().unwrap();
+        check_store(&mut store);
+        let message = &store.query("#chan", &query("LATEST #chan * 1"))[0];
+        assert_eq!(Some(Timestamp::from_secs(40)), message.server_time());
+        assert_eq!(Some("e"), message.msgid());
+    }
+
+    #[tokio::test]
+    async fn test_worker() {
+        let worker = HistoryWorker::spawn(MemoryHistory::new(10));
+        worker.append("#chan".to_string(), message(10, "a"));
+        worker.append("#chan".to_string(), message(20, "b"));
+        let messages = worker.run(|store| store.query("#chan", &query("LATEST #chan * 10"))).await.unwrap();
+        assert_eq!(vec![Some("a"), Some("b")], messages.iter().map(Message::msgid).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_reply() {
+        let casemapping = Casemapping::default();
+        let server = server();
+        let mut channels = ChannelRegistry::new(casemapping);
+        channels.join(&Source { name: "dan".to_string(), user: None, host: None }, "#Chan", None).unwrap();
+        channels.join(&Source { name: "eve".to_string(), user: None, host: None }, "#secret", None).unwrap();
+
+        let mut store = MemoryHistory::new(10);
+        store.append("#chan", &message(10, "a"));
+        store.append("#secret", &message(20, "b"));
+        store.append(&conversation_key(casemapping, "Dan", "eve"), &message(30, "c"));
+        store.append(&conversation_key(casemapping, "bob", "eve"), &message(40, "d"));
+        assert_eq!("dan eve", conversation_key(casemapping, "Dan", "EVE"));
+
+        let history = |account: Option<&str>, query: &str| -> Result<Vec<String>, HistoryError> {
+            let batch = chathistory_reply(&server, "dan", account, &super::tests::query(query), &store, &channels)?;
+            let mut next = 0;
+            return Ok(batch.build(&mut || { next += 1; format!("h{}", next) }).into_iter().map(Message::to_bytes).collect())
+        };
+        let lines = |query: &str| history(Some("dan"), query);
+        let reply = lines("LATEST #CHAN * 10").unwrap();
+        assert_eq!(":irc.example.com BATCH +h1 chathistory #CHAN\r\n", reply[0]);
+        assert!(reply[1].starts_with("@time=1970-01-01T00:00:10.000Z;msgid=a;batch=h1 :dan!d@host PRIVMSG #chan a"));
+        assert_eq!(3, reply.len());
+        assert_eq!(3, lines("LATEST eve * 10").unwrap().len());
+        assert_eq!(Err(HistoryError::InvalidTarget("#secret".to_string())), lines("LATEST #secret * 10"));
+
+        let reply = lines("TARGETS timestamp=1970-01-01T00:01:00.000Z timestamp=1970-01-01T00:00:00.000Z 10").unwrap();
+        assert_eq!(vec![
+            ":irc.example.com BATCH +h1 draft/chathistory-targets\r\n",
+            "@batch=h1 :irc.example.com CHATHISTORY TARGETS #Chan 1970-01-01T00:00:10.000Z\r\n",
+            "@batch=h1 :irc.example.com CHATHISTORY TARGETS eve 1970-01-01T00:00:30.000Z\r\n",
+            ":irc.example.com BATCH -h1\r\n",
+        ], reply);
+
+        // Private history belongs to the account, not to whoever has the nick.
+        assert_eq!(Err(HistoryError::InvalidTarget("eve".to_string())), history(None, "LATEST eve * 10"));
+        assert_eq!(2, history(Some("dan2"), "LATEST eve * 10").unwrap().len());
+        assert_eq!(3, history(None, "TARGETS timestamp=1970-01-01T00:01:00.000Z timestamp=1970-01-01T00:00:00.000Z 10").unwrap().len());
+    }
+}
@@ -0,0 +1,475 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::channel::{Channel, ModeChange, ModeTable};
+use crate::names::NamesEntry;
+use crate::tags::ClientTagDeny;
+use crate::types::{Casemapping, Command, Message, Source};
+use crate::unix_time;
+
+/// What the client knows about another user it shares a channel with.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KnownUser {
+    pub nick: String,
+    pub user: Option<String>,
+    pub host: Option<String>,
+    pub realname: Option<String>,
+    pub account: Option<String>,
+    /// Away message; empty when the user is known to be away without one.
+    pub away: Option<String>,
+}
+
+impl KnownUser {
+    pub fn source(&self) -> Source {
+        return Source { name: self.nick.clone(), user: self.user.clone(), host: self.host.clone() }
+    }
+
+    fn update_source(&mut self, source: &Source) {
+        if source.user.is_some() {
+            self.user = source.user.clone();
+        }
+        if source.host.is_some() {
+            self.host = source.host.clone();
+        }
+    }
+}
+
+/// Client-side view of the connection, kept up to date by feeding it every
+/// incoming message.
+#[derive(Debug)]
+pub struct ClientState {
+    me: KnownUser,
+    registered: bool,
+    user_modes: BTreeSet<char>,
+    casemapping: Casemapping,
+    mode_table: ModeTable,
+    isupport: HashMap<String, String>,
+    channels: HashMap<String, Channel>,
+    users: HashMap<String, KnownUser>,
+}
+
+impl ClientState {
+    pub fn new(nick: &str) -> Self {
+        return ClientState {
+            me: KnownUser { nick: nick.to_string(), ..Default::default() },
+            registered: false,
+            user_modes: BTreeSet::new(),
+            casemapping: Casemapping::default(),
+            mode_table: ModeTable::default(),
+            isupport: HashMap::new(),
+            channels: HashMap::new(),
+            users: HashMap::new(),
+        }
+    }
+
+    pub fn nick(&self) -> &str {
+        return &self.me.nick
+    }
+
+    /// Our own identity as last seen by the server.
+    pub fn me(&self) -> &KnownUser {
+        return &self.me
+    }
+
+    pub fn is_registered(&self) -> bool {
+        return self.registered
+    }
+
+    pub fn user_modes(&self) -> String {
+        return self.user_modes.iter().collect()
+    }
+
+    pub fn casemapping(&self) -> Casemapping {
+        return self.casemapping
+    }
+
+    /// Value of an ISUPPORT token; flags without a value map to "".
+    pub fn isupport(&self, key: &str) -> Option<&str> {
+        return self.isupport.get(key).map(String::as_str)
+    }
+
+    /// The channel modes from `PREFIX` and `CHANMODES`.
+    pub fn mode_table(&self) -> &ModeTable {
+        return &self.mode_table
+    }
+
+    /// Whether the server advertised `UTF8ONLY`, so text we send must be UTF-8.
+    pub fn utf8_only(&self) -> bool {
+        return self.isupport.contains_key("UTF8ONLY")
+    }
+
+    /// The client-only tags the server will not relay, from `CLIENTTAGDENY`.
+    pub fn client_tag_deny(&self) -> ClientTagDeny {
+        return ClientTagDeny::parse(self.isupport("CLIENTTAGDENY").unwrap_or(""))
+    }
+
+    pub fn is_me(&self, nick: &str) -> bool {
+        return self.casemapping.equals(nick, &self.me.nick)
+    }
+
+    pub fn channel(&self, name: &str) -> Option<&Channel> {
+        return self.channels.get(&self.casemapping.fold(name))
+    }
+
+    pub fn channels(&self) -> impl Iterator<Item = &Channel> {
+        return self.channels.values()
+    }
+
+    pub fn user(&self, nick: &str) -> Option<&KnownUser> {
+        if self.is_me(nick) {
+            return Some(&self.me);
+        }
+        return self.users.get(&self.casemapping.fold(nick))
+    }
+
+    pub fn users(&self) -> impl Iterator<Item = &KnownUser> {
+        return self.users.values()
+    }
+
+    /// Channels we share with `nick`.
+    pub fn common_channels(&self, nick: &str) -> Vec<&Channel> {
+        return self.channels.values().filter(|channel| channel.is_member(nick)).collect()
+    }
+
+    pub fn feed(&mut self, message: &Message) {
+        let cm = self.casemapping;
+
+        if let Some(source) = &message.source {
+            if let Some(user) = self.user_mut(&source.name) {
+                user.update_source(source);
+            }
+            let account = message.account().map(str::to_string);
+            if let (Some(account), Some(user)) = (account, self.user_mut(&source.name)) {
+                user.account = Some(account);
+            }
+        }
+        let source_nick = message.source.as_ref().map(|source| source.name.clone()).unwrap_or_default();
+
+        match &message.command {
+            Command::RPL_WELCOME { client, message } => {
+                self.registered = true;
+                self.me.nick = client.clone();
+                // "Welcome to the Network, nick!user@host"
+                if let Some((_, userhost)) = message.rsplit(' ').next().and_then(|mask| mask.split_once('!')) {
+                    if let Some((user, host)) = userhost.split_once('@') {
+                        self.me.user = Some(user.to_string());
+                        self.me.host = Some(host.to_string());
+                    }
+                }
+            }
+            Command::RPL_ISUPPORT { tokens, .. } => {
+                for token in tokens {
+                    let (key, value) = token.split_once('=').unwrap_or((token, ""));
+                    match key {
+                        "CASEMAPPING" => self.casemapping = Casemapping::from_isupport(value).unwrap_or(self.casemapping),
+                        "PREFIX" => self.mode_table.set_prefix(value),
+                        "CHANMODES" => self.mode_table.set_chanmodes(value),
+                        _ => {}
+                    }
+                    match key.strip_prefix('-') {
+                        Some(key) => self.isupport.remove(key),
+                        None => self.isupport.insert(key.to_string(), value.to_string()),
+                    };
+                }
+            }
+            Command::RPL_UMODEIS { modes, .. } => {
+                self.user_modes = modes.chars().filter(|mode| *mode != '+').collect();
+            }
+            Command::JOIN { channels, account, realname, .. } => {
+                let Some(source) = &message.source else { return };
+                let Some(name) = channels.split(',').next() else { return };
+                if self.is_me(&source.name) {
+                    self.me.update_source(source);
+                    self.channels.insert(cm.fold(name), Channel::with_mode_table(name.to_string(), cm, self.mode_table.clone()));
+                }
+                if let Some(channel) = self.channels.get_mut(&cm.fold(name)) {
+                    channel.add_member(source.name.clone());
+                    self.track(source);
+                }
+                // extended-join
+                if let (Some(realname), Some(user)) = (realname, self.user_mut(&source.name)) {
+                    user.account = account.clone();
+                    user.realname = Some(realname.clone());
+                }
+            }
+            Command::AWAY { message } => {
+                if let Some(user) = self.user_mut(&source_nick) {
+                    user.away = message.clone();
+                }
+            }
+            Command::ACCOUNT { account } => {
+                if let Some(user) = self.user_mut(&source_nick) {
+                    user.account = Some(account.clone()).filter(|account| account != "*");
+                }
+            }
+            Command::CHGHOST { user: username, host } => {
+                if let Some(user) = self.user_mut(&source_nick) {
+                    user.user = Some(username.clone());
+                    user.host = Some(host.clone());
+                }
+            }
+            Command::SETNAME { realname } => {
+                if let Some(user) = self.user_mut(&source_nick) {
+                    user.realname = Some(realname.clone());
+                }
+            }
+            Command::PART { channels, .. } => {
+                for name in channels.split(',') {
+                    self.leave(name, &source_nick);
+                }
+            }
+            Command::KICK { channel, user, .. } => {
+                for nick in user.split(',') {
+                    self.leave(channel, nick);
+                }
+            }
+            Command::QUIT { .. } => {
+                for channel in self.channels.values_mut() {
+                    channel.remove_member(&source_nick);
+                }
+                self.users.remove(&cm.fold(&source_nick));
+            }
+            Command::NICK { nickname } => {
+                for channel in self.channels.values_mut() {
+                    channel.rename_member(&source_nick, nickname);
+                }
+                if self.is_me(&source_nick) {
+                    self.me.nick = nickname.clone();
+                } else if let Some(mut user) = self.users.remove(&cm.fold(&source_nick)) {
+                    user.nick = nickname.clone();
+                    self.users.insert(cm.fold(nickname), user);
+                }
+            }
+            Command::RPL_NAMREPLY { channel, members, .. } => {
+                let Some(channel) = self.channels.get_mut(&cm.fold(channel)) else { return };
+                let mut seen = Vec::new();
+                let symbols = channel.mode_table.prefix_symbols.clone();
+                for member in members {
+                    let entry = NamesEntry::parse(member, &symbols);
+                    channel.add_member(entry.nick.clone());
+                    if let Some(member) = channel.member_mut(&entry.nick) {
+                        entry.prefixes.chars().for_each(|prefix| { member.add_prefix(prefix, &symbols); });
+                    }
+                    seen.push(Source { name: entry.nick, user: entry.user, host: entry.host });
+                }
+                seen.iter().for_each(|source| self.track(source));
+            }
+            Command::MODE { target, modestring: Some(modestring), arguments } => {
+                if let Some(channel) = self.channels.get_mut(&cm.fold(target)) {
+                    channel.apply_modes(&ModeChange::parse_with(modestring, arguments, &self.mode_table), &source_nick, unix_time());
+                } else if self.is_me(target) {
+                    // User modes never take arguments, so no ModeChange::parse here.
+                    let mut adding = true;
+                    for mode in modestring.chars() {
+                        match mode {
+                            '+' | '-' => adding = mode == '+',
+                            _ if adding => { self.user_modes.insert(mode); }
+                            _ => { self.user_modes.remove(&mode); }
+                        }
+                    }
+                }
+            }
+            Command::RPL_CHANNELMODEIS { channel, modes, .. } => {
+                if let (Some(channel), Some((modestring, arguments))) = (self.channels.get_mut(&cm.fold(channel)), modes.split_first()) {
+                    channel.apply_modes(&ModeChange::parse_with(modestring, arguments, &self.mode_table), "", 0);
+                }
+            }
+            Command::RPL_CREATIONTIME { channel, creationtime, .. } => {
+                if let (Some(channel), Ok(created_at)) = (self.channels.get_mut(&cm.fold(channel)), creationtime.parse()) {
+                    channel.created_at = created_at;
+                }
+            }
+            Command::TOPIC { channel, topic: Some(topic) } => {
+                if let Some(channel) = self.channels.get_mut(&cm.fold(channel)) {
+                    channel.set_topic(topic.clone(), source_nick, unix_time());
+                }
+            }
+            Command::RPL_TOPIC { channel, topic, .. } => {
+                if let Some(channel) = self.channels.get_mut(&cm.fold(channel)) {
+                    channel.set_topic(topic.clone(), String::new(), 0);
+                }
+            }
+            Command::RPL_TOPICWHOTIME { channel, nick, setat, .. } => {
+                if let Some(topic) = self.channels.get_mut(&cm.fold(channel)).and_then(|channel| channel.topic.as_mut()) {
+                    topic.setter = nick.clone();
+                    topic.set_at = setat.parse().unwrap_or(0);
+                }
+            }
+            Command::RPL_WHOREPLY { username, host, nick, flags, realname, .. } => {
+                if let Some(user) = self.user_mut(nick) {
+                    user.user = Some(username.clone());
+                    user.host = Some(host.clone());
+                    user.realname = Some(realname.clone());
+                    if flags.starts_with('G') {
+                        user.away.get_or_insert_with(String::new);
+                    } else {
+                        user.away = None;
+                    }
+                }
+            }
+            Command::RPL_WHOISUSER { nick, username, host, realname, .. } => {
+                if let Some(user) = self.user_mut(nick) {
+                    user.user = Some(username.clone());
+                    user.host = Some(host.clone());
+                    user.realname = Some(realname.clone());
+                }
+            }
+            Command::RPL_AWAY { nick, message, .. } => {
+                if let Some(user) = self.user_mut(nick) {
+                    user.away = Some(message.clone());
+                }
+            }
+            Command::RPL_WHOISACCOUNT { nick, account, .. } => {
+                if let Some(user) = self.user_mut(nick) {
+                    user.account = Some(account.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn user_mut(&mut self, nick: &str) -> Option<&mut KnownUser> {
+        if self.is_me(nick) {
+            return Some(&mut self.me);
+        }
+        return self.users.get_mut(&self.casemapping.fold(nick))
+    }
+
+    fn track(&mut self, source: &Source) {
+        if self.is_me(&source.name) {
+            return;
+        }
+        self.users.entry(self.casemapping.fold(&source.name))
+            .or_insert_with(|| KnownUser { nick: source.name.clone(), ..Default::default() })
+            .update_source(source);
+    }
+
+    fn leave(&mut self, name: &str, nick: &str) {
+        let key = self.casemapping.fold(name);
+        if self.is_me(nick) {
+            self.channels.remove(&key);
+            let channels = &self.channels;
+            self.users.retain(|_, user| channels.values().any(|channel| channel.is_member(&user.nick)));
+            return;
+        }
+        if let Some(channel) = self.channels.get_mut(&key) {
+            channel.remove_member(nick);
+        }
+        if self.common_channels(nick).is_empty() {
+            self.users.remove(&self.casemapping.fold(nick));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::parse;
+
+    use super::ClientState;
+
+    fn feed(state: &mut ClientState, lines: &[&str]) {
+        for line in lines {
+            state.feed(&parse(line));
+        }
+    }
+
+    #[test]
+    fn test_channels() {
+        let mut state = ClientState::new("me");
+        feed(&mut state, &[
+            ":irc.example.com 001 me :Welcome to the Example Network, me!u@host.example",
+            ":irc.example.com 005 me CASEMAPPING=ascii PREFIX=(ov)@+ UTF8ONLY CLIENTTAGDENY=*,-draft/react :are supported by this server",
+            ":me!u@cloak.example JOIN #Chan",
+            ":irc.example.com 332 me #chan :Hello world",
+            ":irc.example.com 333 me #chan dan 1700000000",
+            ":irc.example.com 353 me = #chan :@dan +eve!e@eve.example me",
+            ":irc.example.com 324 me #chan +ntk secret",
+            ":dan!d@dan.example MODE #chan +o eve",
+            "@account=dan :dan!d@dan.example PRIVMSG #chan :hi",
+        ]);
+        assert!(state.is_registered());
+        assert_eq!(Some("ascii"), state.isupport("CASEMAPPING"));
+        assert!(state.utf8_only());
+        assert!(state.client_tag_deny().allows("+draft/react") && !state.client_tag_deny().allows("+typing"));
+        assert_eq!(Some("cloak.example"), state.me().host.as_deref());
+
+        let channel = state.channel("#CHAN").unwrap();
+        assert_eq!("#Chan", channel.name);
+        assert_eq!(3, channel.len());
+        assert_eq!("@+", channel.member("eve").unwrap().prefixes);
+        assert_eq!(Some(("Hello world", "dan", 1700000000)), channel.topic.as_ref().map(|t| (t.text.as_str(), t.setter.as_str(), t.set_at)));
+        assert!(channel.modes.topic_lock && channel.modes.no_external);
+        assert_eq!(Some("secret".to_string()), channel.key);
+
+        let dan = state.user("dan").unwrap();
+        assert_eq!(Some("dan.example"), dan.host.as_deref());
+        assert_eq!(Some("dan"), dan.account.as_deref());
+        assert_eq!(Some("eve.example"), state.user("eve").unwrap().host.as_deref());
+
+        feed(&mut state, &[":irc.example.com 301 me dan :lunch", ":dan!d@dan.example NICK daniel", ":me!u@cloak.example NICK myself"]);
+        assert_eq!("myself", state.nick());
+        assert!(state.user("dan").is_none());
+        assert_eq!(Some("lunch"), state.user("daniel").unwrap().away.as_deref());
+        assert!(state.channel("#chan").unwrap().member("myself").is_some());
+
+        feed(&mut state, &[":daniel!d@dan.example KICK #chan eve", ":daniel!d@dan.example QUIT :bye"]);
+        assert_eq!(1, state.channel("#chan").unwrap().len());
+        assert_eq!(0, state.users().count());
+
+        feed(&mut state, &[":myself!u@cloak.example PART #chan"]);
+        assert!(state.channel("#chan").is_none());
+    }
+
+    #[test]
+    fn test_mode_table() {
+        let mut state = ClientState::new("me");
+        feed(&mut state, &[
+            ":irc.example.com 005 me PREFIX=(Yov)!@+ CHANMODES=beIq,k,fl,imnpst :are supported by this server",
+            ":me!u@host JOIN #chan",
+            ":irc.example.com 353 me = #chan :@!dan +eve me",
+            ":dan!d@dan.example MODE #chan +fqY 5:10 troll!*@* eve",
+        ]);
+        assert_eq!("Yov", state.mode_table().prefix_modes);
+        let channel = state.channel("#chan").unwrap();
+        let symbols = &channel.mode_table.prefix_symbols;
+        assert_eq!("!@", channel.member("dan").unwrap().prefixes);
+        assert!(channel.member("dan").unwrap().is_op(symbols));
+        assert_eq!("!+", channel.member("eve").unwrap().prefixes);
+        assert!(channel.member("eve").unwrap().is_op(symbols));
+        assert!(!channel.member("me").unwrap().is_halfop(symbols));
+        assert_eq!(0, channel.bans.iter().count());
+    }
+
+    #[test]
+    fn test_notifications() {
+        let mut state = ClientState::new("me");
+        feed(&mut state, &[
+            ":me!u@host JOIN #chan * :My Name",
+            ":dan!d@dan.example JOIN #chan dan :Dan Smith",
+            ":eve!e@eve.example JOIN #chan",
+            ":dan!d@dan.example AWAY :lunch",
+            ":eve!e@eve.example ACCOUNT eve",
+            ":dan!d@dan.example CHGHOST dan cloak.example",
+            ":eve!e@eve.example SETNAME :Eve Jones",
+            ":me!u@host SETNAME :Just Me",
+        ]);
+        let dan = state.user("dan").unwrap();
+        assert_eq!((Some("dan"), Some("Dan Smith")), (dan.account.as_deref(), dan.realname.as_deref()));
+        assert_eq!(Some("lunch"), dan.away.as_deref());
+        assert_eq!("dan!dan@cloak.example", dan.source().to_string());
+        let eve = state.user("eve").unwrap();
+        assert_eq!((Some("eve"), Some("Eve Jones")), (eve.account.as_deref(), eve.realname.as_deref()));
+        assert_eq!(Some("Just Me"), state.me().realname.as_deref());
+
+        feed(&mut state, &[":dan!dan@cloak.example AWAY", ":eve!e@eve.example ACCOUNT *"]);
+        assert_eq!(None, state.user("dan").unwrap().away);
+        assert_eq!(None, state.user("eve").unwrap().account);
+    }
+
+    #[test]
+    fn test_user_modes() {
+        let mut state = ClientState::new("me");
+        feed(&mut state, &[":irc.example.com 221 me +iw", ":me MODE me :-w+o"]);
+        assert_eq!("io", state.user_modes());
+    }
+}
@@ -1,5 +1,7 @@
 use crate::types::Command;
 
+const CAP_SUBCOMMANDS: &[&str] = &["LS", "LIST", "REQ", "ACK", "NAK", "END", "NEW", "DEL"];
+
 
 impl Command {
     pub fn new(command: &str, params: Vec<String>) -> Self {
@@ -23,7 +25,14 @@ impl Command {
         }
 
         match command {
-            "CAP" => CAP{subcommand: required!(), capabilities: optional!()},
+            "CAP" => {
+                let first = required!();
+                if CAP_SUBCOMMANDS.contains(&first.as_str()) {
+                    CAP{target: None, subcommand: first, capabilities: optional!()}
+                } else {
+                    CAP{target: Some(first), subcommand: required!(), capabilities: optional!()}
+                }
+            }
             "PASS" => PASS{password: required!()},
             "NICK" => NICK{nickname: required!()},
             "USER" => USER{user: required!(), mode: required!(), unused: required!(), realname: required!()},
@@ -32,26 +41,141 @@ impl Command {
             "OPER" => OPER{name: required!(), password: required!()},
             "QUIT" => QUIT{reason: optional!()},
             "ERROR" => ERROR{reason: required!()},
-            "JOIN" => JOIN{channels: required!(), keys: optional!()},
+            "JOIN" => {
+                let channels = required!();
+                let second = optional!();
+                match optional!() {
+                    Some(realname) => JOIN{channels, keys: None, account: second.filter(|account| account != "*"), realname: Some(realname)},
+                    None => JOIN{channels, keys: second, account: None, realname: None},
+                }
+            }
+            "PART" => PART{channels: required!(), reason: optional!()},
+            "KICK" => KICK{channel: required!(), user: required!(), comment: optional!()},
+            "INVITE" => INVITE{nickname: required!(), channel: required!()},
+            "TOPIC" => TOPIC{channel: required!(), topic: optional!()},
+            "NAMES" => NAMES{channels: optional!()},
+            "MOTD" => MOTD{target: optional!()},
+            "MODE" => MODE{target: required!(), modestring: optional!(), arguments: params_iter.collect()},
             "PRIVMSG" => PRIVMSG{targets: required!(), text: required!()},
-            "WHO" => WHO{mask: required!()},
+            "NOTICE" => NOTICE{targets: required!(), text: required!()},
+            // Kept without a mask so the server can answer ERR_NEEDMOREPARAMS.
+            "WHO" => WHO{mask: optional!().unwrap_or_default(), fields: optional!()},
+            "WHOIS" => {
+                let first = required!();
+                match optional!() {
+                    Some(nick) => WHOIS{target: Some(first), nick},
+                    None => WHOIS{target: None, nick: first},
+                }
+            }
+            "WHOWAS" => WHOWAS{nick: required!(), count: optional!()},
+            "AWAY" => AWAY{message: optional!()},
+            "BATCH" => BATCH{reference: required!(), kind: optional!(), params: params_iter.collect()},
+            "ACK" => ACK,
+            "CHATHISTORY" => CHATHISTORY{subcommand: required!(), params: params_iter.collect()},
+            "MONITOR" => MONITOR{subcommand: required!(), targets: optional!()},
+            "ACCOUNT" => ACCOUNT{account: required!()},
+            "CHGHOST" => CHGHOST{user: required!(), host: required!()},
+            "SETNAME" => SETNAME{realname: required!()},
+            "TAGMSG" => TAGMSG{targets: required!()},
+            "FAIL" | "WARN" | "NOTE" => {
+                let kind = command;
+                let command = required!();
+                let code = required!();
+                let mut context: Vec<String> = params_iter.collect();
+                let Some(description) = context.pop() else { return UNKNOWN };
+                match kind {
+                    "FAIL" => FAIL{command, code, context, description},
+                    "WARN" => WARN{command, code, context, description},
+                    _ => NOTE{command, code, context, description},
+                }
+            }
 
+            "001" => RPL_WELCOME{client: required!(), message: required!()},
+            "002" => RPL_YOURHOST{client: required!(), message: required!()},
+            "003" => RPL_CREATED{client: required!(), message: required!()},
+            "004" => RPL_MYINFO{client: required!(), servername: required!(), version: required!(), user_modes: required!(), channel_modes: required!()},
+            "005" => RPL_ISUPPORT{client: required!(), tokens: params_iter.filter(|token| !token.contains(' ')).collect()},
+            "221" => RPL_UMODEIS{client: required!(), modes: required!()},
+            "301" => RPL_AWAY{client: required!(), nick: required!(), message: required!()},
+            "305" => RPL_UNAWAY{client: required!(), message: required!()},
+            "306" => RPL_NOWAWAY{client: required!(), message: required!()},
+            "311" => {
+                let (client, nick, username, host) = (required!(), required!(), required!(), required!());
+                let _unused = required!();
+                RPL_WHOISUSER{client, nick, username, host, realname: required!()}
+            }
+            "312" => RPL_WHOISSERVER{client: required!(), nick: required!(), server: required!(), info: required!()},
+            "313" => RPL_WHOISOPERATOR{client: required!(), nick: required!()},
+            "314" => {
+                let (client, nick, username, host) = (required!(), required!(), required!(), required!());
+                let _unused = required!();
+                RPL_WHOWASUSER{client, nick, username, host, realname: required!()}
+            }
             "315" => RPL_ENDOFWHO{client: required!(), mask: required!()},
-            "352" => RPL_WHOREPLY{client: required!(), channel: required!(), username: required!(), host: required!(), server: required!(), nick: required!(), flags: required!(), hopcount: required!(), realname: required!()},
-            "353" => RPL_NAMREPLY{client: required!(), symbol: required!(), channel: required!(), members: params_iter.collect()},
+            "317" => RPL_WHOISIDLE{client: required!(), nick: required!(), secs: required!(), signon: required!()},
+            "318" => RPL_ENDOFWHOIS{client: required!(), nick: required!()},
+            "319" => RPL_WHOISCHANNELS{client: required!(), nick: required!(), channels: params_iter.flat_map(|p| p.split_whitespace().map(|s| s.to_string()).collect::<Vec<String>>()).collect()},
+            "324" => RPL_CHANNELMODEIS{client: required!(), channel: required!(), modes: params_iter.collect()},
+            "329" => RPL_CREATIONTIME{client: required!(), channel: required!(), creationtime: required!()},
+            "330" => RPL_WHOISACCOUNT{client: required!(), nick: required!(), account: required!()},
+            "331" => RPL_NOTOPIC{client: required!(), channel: required!()},
+            "332" => RPL_TOPIC{client: required!(), channel: required!(), topic: required!()},
+            "333" => RPL_TOPICWHOTIME{client: required!(), channel: required!(), nick: required!(), setat: required!()},
+            "346" => RPL_INVITELIST{client: required!(), channel: required!(), mask: required!(), who: required!(), set_ts: required!()},
+            "347" => RPL_ENDOFINVITELIST{client: required!(), channel: required!()},
+            "348" => RPL_EXCEPTLIST{client: required!(), channel: required!(), mask: required!(), who: required!(), set_ts: required!()},
+            "349" => RPL_ENDOFEXCEPTLIST{client: required!(), channel: required!()},
+            "352" => {
+                let (client, channel, username, host, server, nick, flags) = (required!(), required!(), required!(), required!(), required!(), required!(), required!());
+                let trailing = required!();
+                let (hopcount, realname) = trailing.split_once(' ').unwrap_or((&trailing, ""));
+                RPL_WHOREPLY{client, channel, username, host, server, nick, flags, hopcount: hopcount.to_string(), realname: realname.to_string()}
+            }
+            "354" => RPL_WHOSPCRPL{client: required!(), fields: params_iter.collect()},
+            "353" => RPL_NAMREPLY{client: required!(), symbol: required!(), channel: required!(), members: params_iter.flat_map(|p| p.split_whitespace().map(|s| s.to_string()).collect::<Vec<String>>()).collect()},
             "366" => RPL_ENDOFNAMES{client: required!(), channel: required!()},
+            "367" => RPL_BANLIST{client: required!(), channel: required!(), mask: required!(), who: required!(), set_ts: required!()},
+            "368" => RPL_ENDOFBANLIST{client: required!(), channel: required!()},
+            "369" => RPL_ENDOFWHOWAS{client: required!(), nick: required!()},
             "372" => RPL_MOTD{client: required!(), line: required!()},
             "375" => RPL_MOTDSTART{client: required!(), line: required!()},
             "376" => RPL_ENDOFMOTD{client: required!()},
+            "381" => RPL_YOUREOPER{client: required!()},
+            "671" => RPL_WHOISSECURE{client: required!(), nick: required!()},
+            "730" => RPL_MONONLINE{client: required!(), targets: required!()},
+            "731" => RPL_MONOFFLINE{client: required!(), targets: required!()},
+            "732" => RPL_MONLIST{client: required!(), targets: required!()},
+            "733" => RPL_ENDOFMONLIST{client: required!(), message: required!()},
 
+            "401" => ERR_NOSUCHNICK{client: required!(), nick: required!()},
+            "403" => ERR_NOSUCHCHANNEL{client: required!(), channel: required!()},
+            "404" => ERR_CANNOTSENDTOCHAN{client: required!(), channel: required!()},
+            "406" => ERR_WASNOSUCHNICK{client: required!(), nick: required!()},
+            "411" => ERR_NORECIPIENT{client: required!()},
             "412" => ERR_NOTEXTTOSEND{client: required!()},
+            "417" => ERR_INPUTTOOLONG{client: required!()},
+            "421" => ERR_UNKNOWNCOMMAND{client: required!(), command: required!()},
+            "422" => ERR_NOMOTD{client: required!()},
             "431" => ERR_NONICKNAMEGIVEN{client: required!()},
             "432" => ERR_ERRONEUSNICKNAME{client: required!(), nick: required!()},
             "433" => ERR_NICKNAMEINUSE{client: required!(), nick: required!()},
             "436" => ERR_NICKCOLLISION{client: required!(), nick: required!(), user: required!(), host: required!()},
+            "441" => ERR_USERNOTINCHANNEL{client: required!(), nick: required!(), channel: required!()},
+            "442" => ERR_NOTONCHANNEL{client: required!(), channel: required!()},
+            "451" => ERR_NOTREGISTERED{client: required!()},
             "461" => ERR_NEEDMOREPARAMS{client: required!(), command: required!()},
             "462" => ERR_ALREADYREGISTERED{client: required!()},
             "464" => ERR_PASSWDMISMATCH{client: required!()},
+            "471" => ERR_CHANNELISFULL{client: required!(), channel: required!()},
+            "472" => ERR_UNKNOWNMODE{client: required!(), modechar: required!()},
+            "473" => ERR_INVITEONLYCHAN{client: required!(), channel: required!()},
+            "474" => ERR_BANNEDFROMCHAN{client: required!(), channel: required!()},
+            "475" => ERR_BADCHANNELKEY{client: required!(), channel: required!()},
+            "482" => ERR_CHANOPRIVSNEEDED{client: required!(), channel: required!()},
+            "491" => ERR_NOOPERHOST{client: required!()},
+            "501" => ERR_UMODEUNKNOWNFLAG{client: required!()},
+            "502" => ERR_USERSDONTMATCH{client: required!()},
+            "734" => ERR_MONLISTFULL{client: required!(), limit: required!(), targets: required!(), message: required!()},
 
             _ => UNKNOWN,
         }
@@ -61,13 +185,11 @@ impl Command {
         use Command::*;
 
         match self {
-            // TODO: fix CAP subcommands
-            CAP {subcommand, capabilities} => {
-                if let Some(cap) = capabilities {
-                    vec![subcommand.to_string(), cap.to_string()]
-                } else {
-                    vec![subcommand.to_string()]
-                }
+            CAP {target, subcommand, capabilities} => {
+                target.iter().cloned()
+                    .chain(std::iter::once(subcommand.to_string()))
+                    .chain(capabilities.clone())
+                    .collect()
             }
             PING{token} => vec![token.to_string()],
             PONG{server, token} => {
@@ -77,29 +199,110 @@ impl Command {
                     vec![token.to_string()]
                 }
             }
-            JOIN{channels, keys} => std::iter::once(channels.to_string()).chain(keys.clone()).collect(),
+            OPER{name, password} => vec![name.to_string(), password.to_string()],
+            QUIT{reason} => reason.iter().cloned().collect(),
+            ERROR{reason} => vec![reason.to_string()],
+            JOIN{channels, realname: Some(realname), account, ..} => vec![channels.to_string(), account.clone().unwrap_or_else(|| "*".to_string()), realname.to_string()],
+            JOIN{channels, keys, ..} => std::iter::once(channels.to_string()).chain(keys.clone()).collect(),
+            PART{channels, reason} => std::iter::once(channels.to_string()).chain(reason.clone()).collect(),
+            KICK{channel, user, comment} => [channel.to_string(), user.to_string()].into_iter().chain(comment.clone()).collect(),
+            INVITE{nickname, channel} => vec![nickname.to_string(), channel.to_string()],
+            TOPIC{channel, topic} => std::iter::once(channel.to_string()).chain(topic.clone()).collect(),
+            NAMES{channels} => channels.iter().cloned().collect(),
+            MOTD{target} => target.iter().cloned().collect(),
+            MODE{target, modestring, arguments} => std::iter::once(target.to_string()).chain(modestring.clone()).chain(arguments.iter().cloned()).collect(),
             PRIVMSG{targets, text} => vec![targets.to_string(), text.to_string()],
+            NOTICE{targets, text} => vec![targets.to_string(), text.to_string()],
             PASS{password} => vec![password.to_string()],
             NICK{nickname} => vec![nickname.to_string()],
             USER{user, mode, unused, realname} => vec![user.to_string(), mode.to_string(), unused.to_string(), realname.to_string()],
-            WHO{mask} => vec![mask.to_string()],
+            WHO{mask, fields} => std::iter::once(mask.to_string()).chain(fields.clone()).collect(),
+            WHOIS{target, nick} => target.iter().cloned().chain(std::iter::once(nick.to_string())).collect(),
+            WHOWAS{nick, count} => std::iter::once(nick.to_string()).chain(count.clone()).collect(),
+            AWAY{message} => message.iter().cloned().collect(),
+            BATCH{reference, kind, params} => std::iter::once(reference.to_string()).chain(kind.clone()).chain(params.iter().cloned()).collect(),
+            ACK => vec![],
+            CHATHISTORY{subcommand, params} => std::iter::once(subcommand.to_string()).chain(params.iter().cloned()).collect(),
+            MONITOR{subcommand, targets} => std::iter::once(subcommand.to_string()).chain(targets.clone()).collect(),
+            ACCOUNT{account} => vec![account.to_string()],
+            CHGHOST{user, host} => vec![user.to_string(), host.to_string()],
+            SETNAME{realname} => vec![realname.to_string()],
+            TAGMSG{targets} => vec![targets.to_string()],
+            FAIL{command, code, context, description} | WARN{command, code, context, description} | NOTE{command, code, context, description} => [command.to_string(), code.to_string()].into_iter().chain(context.iter().cloned()).chain(std::iter::once(description.to_string())).collect(),
 
+            RPL_WELCOME{client, message} => vec![client.to_string(), message.to_string()],
+            RPL_YOURHOST{client, message} => vec![client.to_string(), message.to_string()],
+            RPL_CREATED{client, message} => vec![client.to_string(), message.to_string()],
+            RPL_MYINFO{client, servername, version, user_modes, channel_modes} => vec![client.to_string(), servername.to_string(), version.to_string(), user_modes.to_string(), channel_modes.to_string()],
+            RPL_ISUPPORT{client, tokens} => std::iter::once(client.to_string()).chain(tokens.iter().cloned()).collect(),
+            RPL_UMODEIS{client, modes} => vec![client.to_string(), modes.to_string()],
+            RPL_AWAY{client, nick, message} => vec![client.to_string(), nick.to_string(), message.to_string()],
+            RPL_UNAWAY{client, message} => vec![client.to_string(), message.to_string()],
+            RPL_NOWAWAY{client, message} => vec![client.to_string(), message.to_string()],
+            RPL_WHOISUSER{client, nick, username, host, realname} => vec![client.to_string(), nick.to_string(), username.to_string(), host.to_string(), "*".to_string(), realname.to_string()],
+            RPL_WHOISSERVER{client, nick, server, info} => vec![client.to_string(), nick.to_string(), server.to_string(), info.to_string()],
+            RPL_WHOISOPERATOR{client, nick} => vec![client.to_string(), nick.to_string()],
+            RPL_WHOWASUSER{client, nick, username, host, realname} => vec![client.to_string(), nick.to_string(), username.to_string(), host.to_string(), "*".to_string(), realname.to_string()],
             RPL_ENDOFWHO{client, mask} => vec![client.to_string(), mask.to_string()],
-            // RPL_WHOREPLY{client, channel, username, host, server, nick, flags, hopcount, realname} => vec![client, channel, username, host, server, nick, flags, hopcount, realname],
-            // RPL_NAMREPLY{client, symbol, channel, members} => vec![client, symbol, channel, members],
+            RPL_CHANNELMODEIS{client, channel, modes} => [client.to_string(), channel.to_string()].into_iter().chain(modes.iter().cloned()).collect(),
+            RPL_CREATIONTIME{client, channel, creationtime} => vec![client.to_string(), channel.to_string(), creationtime.to_string()],
+            RPL_NOTOPIC{client, channel} => vec![client.to_string(), channel.to_string()],
+            RPL_TOPIC{client, channel, topic} => vec![client.to_string(), channel.to_string(), topic.to_string()],
+            RPL_TOPICWHOTIME{client, channel, nick, setat} => vec![client.to_string(), channel.to_string(), nick.to_string(), setat.to_string()],
+            RPL_INVITELIST{client, channel, mask, who, set_ts} => vec![client.to_string(), channel.to_string(), mask.to_string(), who.to_string(), set_ts.to_string()],
+            RPL_ENDOFINVITELIST{client, channel} => vec![client.to_string(), channel.to_string()],
+            RPL_EXCEPTLIST{client, channel, mask, who, set_ts} => vec![client.to_string(), channel.to_string(), mask.to_string(), who.to_string(), set_ts.to_string()],
+            RPL_ENDOFEXCEPTLIST{client, channel} => vec![client.to_string(), channel.to_string()],
+            RPL_WHOREPLY{client, channel, username, host, server, nick, flags, hopcount, realname} => vec![client.to_string(), channel.to_string(), username.to_string(), host.to_string(), server.to_string(), nick.to_string(), flags.to_string(), format!("{} {}", hopcount, realname)],
+            RPL_WHOSPCRPL{client, fields} => std::iter::once(client.to_string()).chain(fields.iter().cloned()).collect(),
+            RPL_NAMREPLY{client, symbol, channel, members} => vec![client.to_string(), symbol.to_string(), channel.to_string(), members.join(" ")],
+            RPL_WHOISIDLE{client, nick, secs, signon} => vec![client.to_string(), nick.to_string(), secs.to_string(), signon.to_string()],
+            RPL_ENDOFWHOIS{client, nick} => vec![client.to_string(), nick.to_string()],
+            RPL_WHOISCHANNELS{client, nick, channels} => vec![client.to_string(), nick.to_string(), channels.join(" ")],
+            RPL_WHOISACCOUNT{client, nick, account} => vec![client.to_string(), nick.to_string(), account.to_string()],
             RPL_ENDOFNAMES{client, channel} => vec![client.to_string(), channel.to_string()],
+            RPL_BANLIST{client, channel, mask, who, set_ts} => vec![client.to_string(), channel.to_string(), mask.to_string(), who.to_string(), set_ts.to_string()],
+            RPL_ENDOFBANLIST{client, channel} => vec![client.to_string(), channel.to_string()],
+            RPL_ENDOFWHOWAS{client, nick} => vec![client.to_string(), nick.to_string()],
             RPL_MOTD{client, line} => vec![client.to_string(), line.to_string()],
             RPL_MOTDSTART{client, line} => vec![client.to_string(), line.to_string()],
             RPL_ENDOFMOTD{client} => vec![client.to_string()],
+            RPL_YOUREOPER{client} => vec![client.to_string()],
+            RPL_WHOISSECURE{client, nick} => vec![client.to_string(), nick.to_string()],
+            RPL_MONONLINE{client, targets} => vec![client.to_string(), targets.to_string()],
+            RPL_MONOFFLINE{client, targets} => vec![client.to_string(), targets.to_string()],
+            RPL_MONLIST{client, targets} => vec![client.to_string(), targets.to_string()],
+            RPL_ENDOFMONLIST{client, message} => vec![client.to_string(), message.to_string()],
 
+            ERR_NOSUCHNICK{client, nick} => vec![client.to_string(), nick.to_string()],
+            ERR_CANNOTSENDTOCHAN{client, channel} => vec![client.to_string(), channel.to_string()],
+            ERR_WASNOSUCHNICK{client, nick} => vec![client.to_string(), nick.to_string()],
+            ERR_NOSUCHCHANNEL{client, channel} => vec![client.to_string(), channel.to_string()],
+            ERR_NORECIPIENT{client} => vec![client.to_string()],
             ERR_NOTEXTTOSEND{client} => vec![client.to_string()],
+            ERR_INPUTTOOLONG{client} => vec![client.to_string()],
+            ERR_UNKNOWNCOMMAND{client, command} => vec![client.to_string(), command.to_string()],
+            ERR_NOMOTD{client} => vec![client.to_string()],
             ERR_NONICKNAMEGIVEN{client} => vec![client.to_string()],
             ERR_ERRONEUSNICKNAME{client, nick} => vec![client.to_string(), nick.to_string()],
             ERR_NICKNAMEINUSE{client, nick} => vec![client.to_string(), nick.to_string()],
             ERR_NICKCOLLISION{client, nick, user, host} => vec![client.to_string(), nick.to_string(), user.to_string(), host.to_string()],
+            ERR_USERNOTINCHANNEL{client, nick, channel} => vec![client.to_string(), nick.to_string(), channel.to_string()],
+            ERR_NOTONCHANNEL{client, channel} => vec![client.to_string(), channel.to_string()],
+            ERR_NOTREGISTERED{client} => vec![client.to_string()],
             ERR_NEEDMOREPARAMS{client, command} => vec![client.to_string(), command.to_string()],
             ERR_ALREADYREGISTERED{client} => vec![client.to_string()],
             ERR_PASSWDMISMATCH{client} => vec![client.to_string()],
+            ERR_CHANNELISFULL{client, channel} => vec![client.to_string(), channel.to_string()],
+            ERR_UNKNOWNMODE{client, modechar} => vec![client.to_string(), modechar.to_string()],
+            ERR_INVITEONLYCHAN{client, channel} => vec![client.to_string(), channel.to_string()],
+            ERR_BANNEDFROMCHAN{client, channel} => vec![client.to_string(), channel.to_string()],
+            ERR_BADCHANNELKEY{client, channel} => vec![client.to_string(), channel.to_string()],
+            ERR_CHANOPRIVSNEEDED{client, channel} => vec![client.to_string(), channel.to_string()],
+            ERR_NOOPERHOST{client} => vec![client.to_string()],
+            ERR_UMODEUNKNOWNFLAG{client} => vec![client.to_string()],
+            ERR_USERSDONTMATCH{client} => vec![client.to_string()],
+            ERR_MONLISTFULL{client, limit, targets, message} => vec![client.to_string(), limit.to_string(), targets.to_string(), message.to_string()],
 
 
             _ => vec![],
@@ -113,36 +316,119 @@ impl Command {
             CAP {..} => "CAP".to_string(),
             PING{..} => "PING".to_string(),
             PONG{..} => "PONG".to_string(),
+            OPER{..} => "OPER".to_string(),
+            QUIT{..} => "QUIT".to_string(),
+            ERROR{..} => "ERROR".to_string(),
             JOIN{..} => "JOIN".to_string(),
+            PART{..} => "PART".to_string(),
+            KICK{..} => "KICK".to_string(),
+            INVITE{..} => "INVITE".to_string(),
+            TOPIC{..} => "TOPIC".to_string(),
+            NAMES{..} => "NAMES".to_string(),
+            MOTD{..} => "MOTD".to_string(),
+            MODE{..} => "MODE".to_string(),
             PRIVMSG{..} => "PRIVMSG".to_string(),
+            NOTICE{..} => "NOTICE".to_string(),
             PASS{..} => "PASS".to_string(),
             NICK{..} => "NICK".to_string(),
             USER{..} => "USER".to_string(),
             WHO{..} => "WHO".to_string(),
+            WHOIS{..} => "WHOIS".to_string(),
+            WHOWAS{..} => "WHOWAS".to_string(),
+            AWAY{..} => "AWAY".to_string(),
+            BATCH{..} => "BATCH".to_string(),
+            ACK => "ACK".to_string(),
+            CHATHISTORY{..} => "CHATHISTORY".to_string(),
+            MONITOR{..} => "MONITOR".to_string(),
+            ACCOUNT{..} => "ACCOUNT".to_string(),
+            CHGHOST{..} => "CHGHOST".to_string(),
+            SETNAME{..} => "SETNAME".to_string(),
+            TAGMSG{..} => "TAGMSG".to_string(),
+            FAIL{..} => "FAIL".to_string(),
+            WARN{..} => "WARN".to_string(),
+            NOTE{..} => "NOTE".to_string(),
 
-            _ => "".to_string(),
+            UNKNOWN => "".to_string(),
+            _ => format!("{:03}", self.numeric()),
         }
     }
 
     pub fn numeric(&self) -> u16 {
         use Command::*;
         match self {
+            RPL_WELCOME{..} => 1,
+            RPL_YOURHOST{..} => 2,
+            RPL_CREATED{..} => 3,
+            RPL_MYINFO{..} => 4,
+            RPL_ISUPPORT{..} => 5,
+            RPL_UMODEIS{..} => 221,
+            RPL_AWAY{..} => 301,
+            RPL_UNAWAY{..} => 305,
+            RPL_NOWAWAY{..} => 306,
+            RPL_WHOISUSER{..} => 311,
+            RPL_WHOISSERVER{..} => 312,
+            RPL_WHOISOPERATOR{..} => 313,
+            RPL_WHOWASUSER{..} => 314,
             RPL_ENDOFWHO{..} => 315,
+            RPL_WHOISIDLE{..} => 317,
+            RPL_ENDOFWHOIS{..} => 318,
+            RPL_WHOISCHANNELS{..} => 319,
+            RPL_CHANNELMODEIS{..} => 324,
+            RPL_CREATIONTIME{..} => 329,
+            RPL_WHOISACCOUNT{..} => 330,
+            RPL_NOTOPIC{..} => 331,
+            RPL_TOPIC{..} => 332,
+            RPL_TOPICWHOTIME{..} => 333,
+            RPL_INVITELIST{..} => 346,
+            RPL_ENDOFINVITELIST{..} => 347,
+            RPL_EXCEPTLIST{..} => 348,
+            RPL_ENDOFEXCEPTLIST{..} => 349,
             RPL_WHOREPLY{..} => 352,
+            RPL_WHOSPCRPL{..} => 354,
             RPL_NAMREPLY{..} => 353,
             RPL_ENDOFNAMES{..} => 366,
+            RPL_BANLIST{..} => 367,
+            RPL_ENDOFBANLIST{..} => 368,
+            RPL_ENDOFWHOWAS{..} => 369,
             RPL_MOTD{..} => 372,
             RPL_MOTDSTART{..} => 375,
             RPL_ENDOFMOTD{..} => 376,
+            RPL_YOUREOPER{..} => 381,
+            RPL_WHOISSECURE{..} => 671,
+            RPL_MONONLINE{..} => 730,
+            RPL_MONOFFLINE{..} => 731,
+            RPL_MONLIST{..} => 732,
+            RPL_ENDOFMONLIST{..} => 733,
 
+            ERR_NOSUCHNICK{..} => 401,
+            ERR_NOSUCHCHANNEL{..} => 403,
+            ERR_CANNOTSENDTOCHAN{..} => 404,
+            ERR_WASNOSUCHNICK{..} => 406,
+            ERR_NORECIPIENT{..} => 411,
             ERR_NOTEXTTOSEND{..} => 412,
+            ERR_INPUTTOOLONG{..} => 417,
+            ERR_UNKNOWNCOMMAND{..} => 421,
+            ERR_NOMOTD{..} => 422,
             ERR_NONICKNAMEGIVEN{..} => 431,
             ERR_ERRONEUSNICKNAME{..} => 432,
             ERR_NICKNAMEINUSE{..} => 433,
             ERR_NICKCOLLISION{..} => 436,
+            ERR_USERNOTINCHANNEL{..} => 441,
+            ERR_NOTONCHANNEL{..} => 442,
+            ERR_NOTREGISTERED{..} => 451,
             ERR_NEEDMOREPARAMS{..} => 461,
             ERR_ALREADYREGISTERED{..} => 462,
             ERR_PASSWDMISMATCH{..} => 464,
+            ERR_CHANNELISFULL{..} => 471,
+            ERR_UNKNOWNMODE{..} => 472,
+            ERR_INVITEONLYCHAN{..} => 473,
+            ERR_BANNEDFROMCHAN{..} => 474,
+            ERR_BADCHANNELKEY{..} => 475,
+            ERR_CHANOPRIVSNEEDED{..} => 482,
+            ERR_NOOPERHOST{..} => 491,
+            ERR_UMODEUNKNOWNFLAG{..} => 501,
+            ERR_USERSDONTMATCH{..} => 502,
+            ERR_MONLISTFULL{..} => 734,
 
             _ => 0,
         }
@@ -1,23 +1,45 @@
 use std::net::{IpAddr, SocketAddr};
 use tokio::{self, io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream};
+use std::future::Future;
 use std::io::Cursor;
 
 use bytes::{Buf, BytesMut};
 use log::{debug, warn};
+use tokio::sync::mpsc;
 
+use crate::encoding::Decoding;
 use crate::types::Message;
 
+/// Longest tag section of an incoming line, leading '@' and trailing space included.
+pub const MAX_TAGS_LEN: usize = 8191;
+/// Longest line without its tag section, CRLF included.
+pub const MAX_LINE_LEN: usize = 512;
+const MAX_FRAME_LEN: usize = MAX_TAGS_LEN + MAX_LINE_LEN;
+
 pub struct Connection {
     tcp_stream: TcpStream,
     socket_addr: SocketAddr,
     in_buffer: BytesMut,
+    /// Set once an overlong line has been reported, until its end is found.
+    discarding: bool,
+    decoding: Decoding,
+    /// The last line rejected by [`Decoding::Strict`], decoded lossily.
+    rejected: Option<Message>,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum IRCError {
     ClientExited = -1,
     NoMessageLeftInBuffer = -2,
     LengthExceeded = -3,
+    InvalidUtf8 = -4,
+    TimedOut = -5,
+}
+
+#[derive(Debug, PartialEq)]
+enum Frame<'a> {
+    Line(&'a [u8]),
+    TooLong,
 }
 
 impl Connection {
@@ -26,22 +48,43 @@ impl Connection {
             tcp_stream,
             socket_addr,
             in_buffer: BytesMut::with_capacity(1024 * 2),
+            discarding: false,
+            decoding: Decoding::default(),
+            rejected: None,
         }
     }
 
+    pub fn decoding(&self) -> Decoding {
+        return self.decoding
+    }
+
+    /// Sets how lines that are not valid UTF-8 are decoded from now on.
+    pub fn set_decoding(&mut self, decoding: Decoding) {
+        self.decoding = decoding;
+    }
+
+    /// Takes the line behind the last `IRCError::InvalidUtf8`, with invalid
+    /// sequences replaced, e.g. to name its command in a FAIL reply.
+    pub fn take_rejected(&mut self) -> Option<Message> {
+        return self.rejected.take()
+    }
+
     pub fn address(&self) -> IpAddr {
         return self.socket_addr.ip()
     }
 
+    /// Reads the next message. An overlong line yields
+    /// `IRCError::LengthExceeded` once and is skipped, as is a line rejected
+    /// by the decoding policy with `IRCError::InvalidUtf8`; the connection stays usable.
     pub async fn read(&mut self) -> Result<Message, IRCError> {
         loop {
-            if let Some(msg) = self.parse_frame() {
+            if let Some(msg) = self.parse_frame()? {
                 return Ok(msg)
             }
 
             match self.tcp_stream.read_buf(&mut self.in_buffer).await {
                 Ok(0) => {
-                    debug!("Remote side closed the session or the buffer is full");
+                    debug!("Remote side closed the session");
                     self.shutdown().await;
                     return Err(IRCError::ClientExited);
                 },
@@ -75,7 +118,7 @@ impl Connection {
                 Ok(n) => {
                     debug!("write {:?} bytes to {:?}", n, self.socket_addr);
                     idx += n;
-                    if n == msg_bytes.len() {
+                    if idx == msg_bytes.len() {
                         return Ok(());
                     }
                 },
@@ -87,48 +130,132 @@ impl Connection {
         _ = self.tcp_stream.shutdown().await
     }
 
-    fn parse_frame(&mut self) -> Option<Message> {
+    fn parse_frame(&mut self) -> Result<Option<Message>, IRCError> {
         loop {
             let mut cursor = Cursor::new(self.in_buffer.chunk());
-            match Connection::get_frame(&mut cursor) {
-                Some(msg_bytes) => {
-                    let frame_len = cursor.position() as usize;
-                    let opt_msg = Message::from_bytes(msg_bytes);
-                    self.in_buffer.advance(frame_len);
-                    if let Some(msg) = opt_msg {
-                        return Some(msg);
+            let Some(frame) = Connection::get_frame(&mut cursor) else {
+                if self.in_buffer.len() > MAX_FRAME_LEN {
+                    // No delimiter in sight, drop what we have and skip to the next one.
+                    self.in_buffer.clear();
+                    if !std::mem::replace(&mut self.discarding, true) {
+                        return Err(IRCError::LengthExceeded);
                     }
                 }
-                None => return None,
+                return Ok(None);
             };
+            let frame_len = cursor.position() as usize;
+            let discarding = std::mem::replace(&mut self.discarding, false);
+            let result = match frame {
+                _ if discarding => Ok(None),
+                Frame::Line(msg_bytes) => match self.decoding.decode(msg_bytes) {
+                    Some(line) => Ok(Message::parse(&line)),
+                    None => {
+                        self.rejected = Message::parse(&String::from_utf8_lossy(msg_bytes));
+                        Err(IRCError::InvalidUtf8)
+                    }
+                },
+                Frame::TooLong => Err(IRCError::LengthExceeded),
+            };
+            self.in_buffer.advance(frame_len);
+            match result {
+                Ok(None) => continue,
+                result => return result,
+            }
         }
     }
 
-    fn get_frame<'a>(src: &mut Cursor<&'a [u8]>) -> Option<&'a [u8]> {
-        if src.has_remaining() {
-            let start = src.position() as usize;
-            let end = src.get_ref().len();
+    /// Finds the next line ended by CR, LF or CRLF.
+    fn get_frame<'a>(src: &mut Cursor<&'a [u8]>) -> Option<Frame<'a>> {
+        let start = src.position() as usize;
+        let buffer = *src.get_ref();
+        let end = start + buffer[start..].iter().position(|b| *b == b'\r' || *b == b'\n')?;
 
-            for i in start..end-1 {
-                // if src.get_ref()[i] == b'\r' && src.get_ref()[i+1] == b'\n' {
-                if src.get_ref()[i] == b'\r' || src.get_ref()[i] == b'\n' {
-                    let mut sep_len = 1;
-                    if src.get_ref()[i+1] == b'\r' || src.get_ref()[i+1] == b'\n' {
-                        sep_len += 1;
-                    }
-                    if i-start > 512 {
-                        // TODO: off by one error ???
-                        src.set_position((i+sep_len)as u64);
-                        return None;
-                    }
-                    src.set_position((i+sep_len)as u64);
-                    return Some(&src.get_ref()[start..i]);
-                }
-            }
-            // NOTE: should buffer be advanced when no frame delimiter was read ?
-            // src.set_position((end+1)as u64);
+        let mut sep_len = 1;
+        if buffer[end] == b'\r' && buffer.get(end + 1) == Some(&b'\n') {
+            sep_len += 1;
+        }
+        src.set_position((end + sep_len) as u64);
+
+        let line = &buffer[start..end];
+        if Connection::exceeds_limits(line) {
+            return Some(Frame::TooLong);
         }
-        return None;
+        return Some(Frame::Line(line))
+    }
+
+    fn exceeds_limits(line: &[u8]) -> bool {
+        let tags_len = match line.first() {
+            Some(b'@') => line.iter().position(|b| *b == b' ').map(|space| space + 1).unwrap_or(line.len()),
+            _ => 0,
+        };
+        // The CRLF counts towards the limit.
+        return tags_len > MAX_TAGS_LEN || line.len() - tags_len + 2 > MAX_LINE_LEN
+    }
+}
+
+
+/// Something a client can exchange messages over, a [`Connection`] in
+/// production and a [`MemoryTransport`] in tests.
+pub trait Transport: Send {
+    fn read(&mut self) -> impl Future<Output = Result<Message, IRCError>> + Send;
+    fn write(&mut self, message: Message) -> impl Future<Output = Result<(), IRCError>> + Send;
+}
+
+impl Transport for Connection {
+    fn read(&mut self) -> impl Future<Output = Result<Message, IRCError>> + Send {
+        return Connection::read(self)
+    }
+
+    fn write(&mut self, message: Message) -> impl Future<Output = Result<(), IRCError>> + Send {
+        return Connection::write(self, message)
+    }
+}
+
+/// In-memory transport for tests; the paired [`MemoryPeer`] plays the server.
+pub struct MemoryTransport {
+    incoming: mpsc::UnboundedReceiver<Message>,
+    outgoing: mpsc::UnboundedSender<Message>,
+}
+
+pub struct MemoryPeer {
+    incoming: mpsc::UnboundedSender<Message>,
+    outgoing: mpsc::UnboundedReceiver<Message>,
+}
+
+impl MemoryTransport {
+    pub fn pair() -> (MemoryTransport, MemoryPeer) {
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+        let (outgoing_tx, outgoing_rx) = mpsc::unbounded_channel();
+        let transport = MemoryTransport { incoming: incoming_rx, outgoing: outgoing_tx };
+        let peer = MemoryPeer { incoming: incoming_tx, outgoing: outgoing_rx };
+        return (transport, peer)
+    }
+}
+
+impl Transport for MemoryTransport {
+    async fn read(&mut self) -> Result<Message, IRCError> {
+        return self.incoming.recv().await.ok_or(IRCError::ClientExited)
+    }
+
+    async fn write(&mut self, message: Message) -> Result<(), IRCError> {
+        return self.outgoing.send(message).map_err(|_| IRCError::ClientExited)
+    }
+}
+
+impl MemoryPeer {
+    /// Delivers a message to the transport's reader. Returns false once the
+    /// transport is dropped.
+    pub fn send(&self, message: Message) -> bool {
+        return self.incoming.send(message).is_ok()
+    }
+
+    /// Next message written to the transport, or `None` once it is dropped.
+    pub async fn recv(&mut self) -> Option<Message> {
+        return self.outgoing.recv().await
+    }
+
+    pub fn try_recv(&mut self) -> Option<Message> {
+        return self.outgoing.try_recv().ok()
     }
 }
 
@@ -139,9 +266,10 @@ mod tests {
     use log::info;
     use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}};
 
+    use crate::encoding::{Decoding, LegacyEncoding};
     use crate::types::{Command, Message};
 
-    use super::Connection;
+    use super::{Connection, IRCError, MAX_LINE_LEN, MAX_TAGS_LEN};
 
     async fn start_listen() -> (TcpListener, SocketAddr) {
         let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
@@ -187,4 +315,50 @@ mod tests {
         server.shutdown().await.unwrap();
         drop(listener);
     }
+
+    #[tokio::test]
+    async fn test_read_limits() {
+        let (listener, server_addr) = start_listen().await;
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut client = Connection::new(stream, server_addr);
+
+        let body = format!("PRIVMSG #chan {}", "a".repeat(MAX_LINE_LEN - 16));
+        let tags = format!("@label={}", "b".repeat(MAX_TAGS_LEN - 8));
+        let input = format!("{}\r\n{}x\r\n{} {}\r\n{}{}\nPING :last\n", body, body, tags, body, "c".repeat(MAX_TAGS_LEN + MAX_LINE_LEN), body);
+        server.write_all(input.as_bytes()).await.unwrap();
+
+        // A line of exactly 512 bytes, one byte too many, 8191 bytes of tags
+        // followed by a full line, and a flood without a delimiter.
+        assert_eq!(MAX_LINE_LEN, client.read().await.unwrap().to_bytes().len());
+        assert_eq!(IRCError::LengthExceeded, client.read().await.unwrap_err());
+        assert!(client.read().await.unwrap().tags.is_some());
+        assert_eq!(IRCError::LengthExceeded, client.read().await.unwrap_err());
+        // The delimiter is the very last byte we received.
+        assert_eq!(Command::PING { token: "last".to_string() }, client.read().await.unwrap().command);
+
+        client.shutdown().await;
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_decoding() {
+        let (listener, server_addr) = start_listen().await;
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut client = Connection::new(stream, server_addr);
+
+        server.write_all(b"PRIVMSG #chan :caf\xe9\r\nPRIVMSG #chan :caf\xe9 \x80\r\n").await.unwrap();
+
+        assert_eq!(IRCError::InvalidUtf8, client.read().await.unwrap_err());
+        let rejected = client.take_rejected().unwrap();
+        assert_eq!(Command::PRIVMSG { targets: "#chan".to_string(), text: "caf\u{FFFD}".to_string() }, rejected.command);
+        assert!(client.take_rejected().is_none());
+
+        client.set_decoding(Decoding::Fallback(LegacyEncoding::Cp1252));
+        assert_eq!(Command::PRIVMSG { targets: "#chan".to_string(), text: "café €".to_string() }, client.read().await.unwrap().command);
+
+        client.shutdown().await;
+        server.shutdown().await.unwrap();
+    }
 }
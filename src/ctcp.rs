@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::time::civil_from_days;
+use crate::types::{Command, Message};
+
+const DELIMITER: char = '\x01';
+
+/// A CTCP message embedded in PRIVMSG (request) or NOTICE (reply) text,
+/// e.g. `\x01PING 1700000000\x01`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ctcp {
+    /// Uppercased command name.
+    pub command: String,
+    pub params: Option<String>,
+}
+
+impl Ctcp {
+    pub fn new(command: &str, params: Option<&str>) -> Self {
+        return Ctcp { command: command.to_ascii_uppercase(), params: params.map(str::to_string) }
+    }
+
+    pub fn action(text: &str) -> Self {
+        return Ctcp::new("ACTION", Some(text))
+    }
+
+    /// Parses message text; the trailing delimiter is optional as some
+    /// clients drop it.
+    pub fn parse(text: &str) -> Option<Ctcp> {
+        let body = text.strip_prefix(DELIMITER)?;
+        let body = body.strip_suffix(DELIMITER).unwrap_or(body);
+        if body.is_empty() || body.contains(DELIMITER) {
+            return None;
+        }
+        let (command, params) = match body.split_once(' ') {
+            Some((command, params)) => (command, Some(params)),
+            None => (body, None),
+        };
+        return Some(Ctcp::new(command, params))
+    }
+
+    /// The CTCP carried by a PRIVMSG or NOTICE, with `true` when it is a reply.
+    pub fn from_message(message: &Message) -> Option<(Ctcp, bool)> {
+        return match &message.command {
+            Command::PRIVMSG { text, .. } => Ctcp::parse(text).map(|ctcp| (ctcp, false)),
+            Command::NOTICE { text, .. } => Ctcp::parse(text).map(|ctcp| (ctcp, true)),
+            _ => None,
+        }
+    }
+
+    pub fn is_action(&self) -> bool {
+        return self.command == "ACTION"
+    }
+
+    pub fn request(&self, target: &str) -> Command {
+        return Command::PRIVMSG { targets: target.to_string(), text: self.to_string() }
+    }
+
+    pub fn reply(&self, target: &str) -> Command {
+        return Command::NOTICE { targets: target.to_string(), text: self.to_string() }
+    }
+}
+
+impl fmt::Display for Ctcp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.params {
+            Some(params) => write!(f, "{}{} {}{}", DELIMITER, self.command, params, DELIMITER),
+            None => write!(f, "{}{}{}", DELIMITER, self.command, DELIMITER),
+        }
+    }
+}
+
+/// `secs` since the epoch as `Sat Oct 18 20:47:52 2026 UTC`.
+fn format_time(secs: u64) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let days = secs / 86400;
+    let (year, month, day) = civil_from_days(days as i64);
+    let time = secs % 86400;
+    return format!(
+        "{} {} {:02} {:02}:{:02}:{:02} {} UTC",
+        DAYS[(days % 7) as usize], MONTHS[month as usize - 1], day, time / 3600, time / 60 % 60, time % 60, year,
+    )
+}
+
+/// Answers VERSION, PING, TIME and CLIENTINFO requests, allowing each source
+/// host at most `max_replies` answers per `window` seconds.
+#[derive(Debug)]
+pub struct CtcpResponder {
+    pub version: String,
+    pub max_replies: usize,
+    pub window: u64,
+    recent: HashMap<String, Vec<u64>>,
+}
+
+impl CtcpResponder {
+    pub const SUPPORTED: &'static str = "ACTION CLIENTINFO PING TIME VERSION";
+
+    pub fn new(version: &str) -> Self {
+        return CtcpResponder { version: version.to_string(), max_replies: 3, window: 10, recent: HashMap::new() }
+    }
+
+    pub fn rate_limit(mut self, max_replies: usize, window: u64) -> Self {
+        self.max_replies = max_replies;
+        self.window = window;
+        return self
+    }
+
+    /// The NOTICE to send back for `message`, if it is a CTCP request we
+    /// answer and the sender is within its rate limit.
+    pub fn respond(&mut self, message: &Message, now: u64) -> Option<Message> {
+        let source = message.source.as_ref()?;
+        let (ctcp, false) = Ctcp::from_message(message)? else {
+            return None;
+        };
+        let params = match ctcp.command.as_str() {
+            "VERSION" => self.version.clone(),
+            "PING" => ctcp.params.clone().unwrap_or_default(),
+            "TIME" => format_time(now),
+            "CLIENTINFO" => CtcpResponder::SUPPORTED.to_string(),
+            _ => return None,
+        };
+
+        let key = source.host.clone().unwrap_or_else(|| source.name.clone());
+        let window = self.window;
+        self.recent.retain(|_, times| {
+            times.retain(|time| time + window > now);
+            !times.is_empty()
+        });
+        let times = self.recent.entry(key).or_default();
+        if times.len() >= self.max_replies {
+            return None;
+        }
+        times.push(now);
+
+        let reply = Ctcp::new(&ctcp.command, Some(&params).filter(|params| !params.is_empty()).map(String::as_str));
+        return Some(Message::new(None, None, reply.reply(&source.name)))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::parse;
+
+    use super::{format_time, Ctcp, CtcpResponder};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Some(Ctcp::action("waves hello")), Ctcp::parse("\x01ACTION waves hello\x01"));
+        assert_eq!(Some(Ctcp::new("VERSION", None)), Ctcp::parse("\x01version"));
+        assert_eq!(None, Ctcp::parse("hello"));
+        assert_eq!(None, Ctcp::parse("\x01\x01"));
+        assert_eq!("\x01PING 123\x01", Ctcp::new("ping", Some("123")).to_string());
+
+        let (ctcp, reply) = Ctcp::from_message(&parse(":dan!d@host NOTICE me :\x01VERSION irssi\x01")).unwrap();
+        assert!(reply);
+        assert_eq!(Some("irssi".to_string()), ctcp.params);
+    }
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!("Thu Jan 01 00:00:00 1970 UTC", format_time(0));
+        assert_eq!("Tue Nov 14 22:13:20 2023 UTC", format_time(1700000000));
+        assert_eq!("Thu Feb 29 12:00:00 2024 UTC", format_time(1709208000));
+    }
+
+    #[test]
+    fn test_responder() {
+        let mut responder = CtcpResponder::new("irc_proto 0.1").rate_limit(2, 10);
+        let reply = responder.respond(&parse(":dan!d@host PRIVMSG me :\x01PING 42\x01"), 0).unwrap();
+        assert_eq!("NOTICE dan :\x01PING 42\x01\r\n", reply.to_bytes());
+        let reply = responder.respond(&parse(":dan!d@host PRIVMSG me :\x01CLIENTINFO\x01"), 1).unwrap();
+        assert_eq!("NOTICE dan :\x01CLIENTINFO ACTION CLIENTINFO PING TIME VERSION\x01\r\n", reply.to_bytes());
+
+        // Flooding from the same host under another nick is still limited.
+        assert!(responder.respond(&parse(":dan2!d@host PRIVMSG me :\x01VERSION\x01"), 2).is_none());
+        assert!(responder.respond(&parse(":eve!e@elsewhere PRIVMSG me :\x01VERSION\x01"), 2).is_some());
+        assert!(responder.respond(&parse(":dan!d@host PRIVMSG me :\x01VERSION\x01"), 11).is_some());
+
+        assert!(responder.respond(&parse(":eve!e@elsewhere PRIVMSG me :\x01ACTION waves\x01"), 20).is_none());
+        assert!(responder.respond(&parse(":eve!e@elsewhere NOTICE me :\x01VERSION x\x01"), 20).is_none());
+    }
+}
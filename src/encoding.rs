@@ -0,0 +1,72 @@
+use std::borrow::Cow;
+
+/// Single-byte encodings still used by older clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LegacyEncoding {
+    Latin1,
+    Cp1252,
+}
+
+/// Code points of CP1252 bytes 0x80-0x9F; the five unassigned bytes map to
+/// the C1 control of the same value, like Latin-1.
+const CP1252_HIGH: [char; 32] = [
+    '\u{20AC}', '\u{0081}', '\u{201A}', '\u{0192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{02C6}', '\u{2030}', '\u{0160}', '\u{2039}', '\u{0152}', '\u{008D}', '\u{017D}', '\u{008F}',
+    '\u{0090}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{02DC}', '\u{2122}', '\u{0161}', '\u{203A}', '\u{0153}', '\u{009D}', '\u{017E}', '\u{0178}',
+];
+
+impl LegacyEncoding {
+    pub fn decode(&self, bytes: &[u8]) -> String {
+        return bytes.iter().map(|byte| match (self, byte) {
+            (LegacyEncoding::Cp1252, 0x80..=0x9F) => CP1252_HIGH[(byte - 0x80) as usize],
+            _ => char::from(*byte),
+        }).collect()
+    }
+}
+
+/// What a [`Connection`](crate::connection::Connection) does with a line
+/// that is not valid UTF-8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Decoding {
+    /// Reject the line with `IRCError::InvalidUtf8`.
+    #[default]
+    Strict,
+    /// Replace invalid sequences with U+FFFD.
+    Lossy,
+    /// Decode the whole line with a legacy encoding instead.
+    Fallback(LegacyEncoding),
+}
+
+impl Decoding {
+    /// The text of `bytes`, or `None` if the policy rejects it.
+    pub fn decode<'a>(&self, bytes: &'a [u8]) -> Option<Cow<'a, str>> {
+        if let Ok(text) = std::str::from_utf8(bytes) {
+            return Some(Cow::Borrowed(text));
+        }
+        return match self {
+            Decoding::Strict => None,
+            Decoding::Lossy => Some(String::from_utf8_lossy(bytes)),
+            Decoding::Fallback(encoding) => Some(Cow::Owned(encoding.decode(bytes))),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Decoding, LegacyEncoding};
+
+    #[test]
+    fn test_decode() {
+        let latin1 = b"PRIVMSG #caf\xe9 :na\xefve \x80";
+        assert_eq!(None, Decoding::Strict.decode(latin1));
+        assert_eq!("PRIVMSG #caf\u{FFFD} :na\u{FFFD}ve \u{FFFD}", Decoding::Lossy.decode(latin1).unwrap());
+        assert_eq!("PRIVMSG #café :naïve \u{80}", Decoding::Fallback(LegacyEncoding::Latin1).decode(latin1).unwrap());
+        assert_eq!("PRIVMSG #café :naïve €", Decoding::Fallback(LegacyEncoding::Cp1252).decode(latin1).unwrap());
+
+        // Valid UTF-8 is never reinterpreted.
+        let utf8 = "PRIVMSG #café :€".as_bytes();
+        assert_eq!("PRIVMSG #café :€", Decoding::Fallback(LegacyEncoding::Cp1252).decode(utf8).unwrap());
+    }
+}
@@ -0,0 +1,530 @@
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::Stream;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::batch::{Assembled, Batch, BatchAssembler};
+use crate::channel::{ModeChange, ModeTable};
+use crate::chathistory::HistoryQuery;
+use crate::connection::{IRCError, Transport};
+use crate::ctcp::Ctcp;
+use crate::names::NamesEntry;
+use crate::standard_reply::StandardReply;
+use crate::types::{Casemapping, Command, Message, Source};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Registered{nick: String, server: String},
+    Message{from: Source, target: String, text: String, is_action: bool, is_notice: bool},
+    Join{channel: String, user: Source},
+    Part{channel: String, user: Source, reason: Option<String>},
+    Kick{channel: String, by: Source, nick: String, reason: Option<String>},
+    NickChange{user: Source, new_nick: String},
+    /// `channels` are the channels shared with the user that quit.
+    Quit{user: Source, reason: Option<String>, channels: Vec<String>},
+    TopicChange{channel: String, setter: Source, topic: String},
+    ModeChange{target: String, setter: Source, changes: Vec<ModeChange>},
+    Invite{from: Source, nick: String, channel: String},
+    /// A closed `BATCH`, e.g. a netsplit or chathistory reply.
+    Batch(Batch),
+    /// `FAIL`, `WARN` or `NOTE`; [`StandardReply::concerns`] tells which
+    /// command it answers.
+    StandardReply(StandardReply),
+    /// Anything without a dedicated event, numerics included.
+    Other(Message),
+}
+
+#[derive(Debug)]
+struct Membership {
+    name: String,
+    members: HashSet<String>,
+}
+
+/// Turns protocol messages into [`Event`]s, tracking just enough state
+/// (own nick, channel members and the server's modes) to fill them in.
+#[derive(Debug, Default)]
+pub struct EventDecoder {
+    nick: String,
+    casemapping: Casemapping,
+    mode_table: ModeTable,
+    channels: HashMap<String, Membership>,
+}
+
+impl EventDecoder {
+    pub fn new(nick: &str) -> Self {
+        return EventDecoder { nick: nick.to_string(), ..Default::default() }
+    }
+
+    pub fn nick(&self) -> &str {
+        return &self.nick
+    }
+
+    fn is_self(&self, nick: &str) -> bool {
+        return self.casemapping.equals(nick, &self.nick)
+    }
+
+    /// Updates membership from the live messages of `batch`, skipping
+    /// replayed history, and wraps it in an event.
+    pub fn feed_batch(&mut self, batch: Batch) -> Event {
+        self.track_batch(&batch);
+        return Event::Batch(batch)
+    }
+
+    fn track_batch(&mut self, batch: &Batch) {
+        if batch.is_history() {
+            return;
+        }
+        for message in &batch.messages {
+            self.feed(message);
+        }
+        for nested in &batch.nested {
+            self.track_batch(nested);
+        }
+    }
+
+    pub fn feed(&mut self, message: &Message) -> Event {
+        let cm = self.casemapping;
+        let Some(source) = message.source.clone() else {
+            return Event::Other(message.clone());
+        };
+
+        return match &message.command {
+            Command::RPL_WELCOME { client, .. } => {
+                self.nick = client.clone();
+                Event::Registered { nick: client.clone(), server: source.name }
+            }
+            Command::RPL_ISUPPORT { tokens, .. } => {
+                for token in tokens {
+                    match token.split_once('=') {
+                        Some(("CASEMAPPING", value)) => self.casemapping = Casemapping::from_isupport(value).unwrap_or(self.casemapping),
+                        Some(("PREFIX", value)) => self.mode_table.set_prefix(value),
+                        Some(("CHANMODES", value)) => self.mode_table.set_chanmodes(value),
+                        _ => {}
+                    }
+                }
+                Event::Other(message.clone())
+            }
+            Command::RPL_NAMREPLY { channel, members, .. } => {
+                if let Some(membership) = self.channels.get_mut(&cm.fold(channel)) {
+                    for member in members {
+                        membership.members.insert(cm.fold(&NamesEntry::parse(member, &self.mode_table.prefix_symbols).nick));
+                    }
+                }
+                Event::Other(message.clone())
+            }
+            Command::PRIVMSG { targets, text } | Command::NOTICE { targets, text } => {
+                let is_notice = matches!(message.command, Command::NOTICE { .. });
+                let action = Ctcp::parse(text).filter(Ctcp::is_action);
+                Event::Message {
+                    from: source,
+                    target: targets.clone(),
+                    is_action: action.is_some(),
+                    text: action.and_then(|action| action.params).unwrap_or_else(|| text.clone()),
+                    is_notice,
+                }
+            }
+            Command::JOIN { channels, .. } => {
+                let channel = channels.split(',').next().unwrap_or_default().to_string();
+                if self.is_self(&source.name) {
+                    self.channels.insert(cm.fold(&channel), Membership { name: channel.clone(), members: HashSet::new() });
+                }
+                if let Some(membership) = self.channels.get_mut(&cm.fold(&channel)) {
+                    membership.members.insert(cm.fold(&source.name));
+                }
+                Event::Join { channel, user: source }
+            }
+            Command::PART { channels, reason } => {
+                let channel = channels.split(',').next().unwrap_or_default().to_string();
+                self.remove_member(&channel, &source.name);
+                Event::Part { channel, user: source, reason: reason.clone() }
+            }
+            Command::KICK { channel, user, comment } => {
+                self.remove_member(channel, user);
+                Event::Kick { channel: channel.clone(), by: source, nick: user.clone(), reason: comment.clone() }
+            }
+            Command::NICK { nickname } => {
+                if self.is_self(&source.name) {
+                    self.nick = nickname.clone();
+                }
+                let (old, new) = (cm.fold(&source.name), cm.fold(nickname));
+                for membership in self.channels.values_mut() {
+                    if membership.members.remove(&old) {
+                        membership.members.insert(new.clone());
+                    }
+                }
+                Event::NickChange { user: source, new_nick: nickname.clone() }
+            }
+            Command::QUIT { reason } => {
+                let nick = cm.fold(&source.name);
+                let mut channels: Vec<String> = self.channels.values_mut()
+                    .filter_map(|membership| membership.members.remove(&nick).then(|| membership.name.clone()))
+                    .collect();
+                channels.sort();
+                Event::Quit { user: source, reason: reason.clone(), channels }
+            }
+            Command::TOPIC { channel, topic: Some(topic) } => {
+                Event::TopicChange { channel: channel.clone(), setter: source, topic: topic.clone() }
+            }
+            Command::MODE { target, modestring: Some(modestring), arguments } => {
+                Event::ModeChange { target: target.clone(), setter: source, changes: ModeChange::parse_with(modestring, arguments, &self.mode_table) }
+            }
+            Command::INVITE { nickname, channel } => {
+                Event::Invite { from: source, nick: nickname.clone(), channel: channel.clone() }
+            }
+            Command::FAIL { .. } | Command::WARN { .. } | Command::NOTE { .. } => {
+                Event::StandardReply(StandardReply::from_message(message).unwrap())
+            }
+            _ => Event::Other(message.clone()),
+        }
+    }
+
+    fn remove_member(&mut self, channel: &str, nick: &str) {
+        let key = self.casemapping.fold(channel);
+        if self.is_self(nick) {
+            self.channels.remove(&key);
+        } else if let Some(membership) = self.channels.get_mut(&key) {
+            membership.members.remove(&self.casemapping.fold(nick));
+        }
+    }
+}
+
+/// How long [`ClientHandle::send_labeled`] waits for a response by default.
+pub const LABEL_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Default)]
+struct Labels {
+    next: u64,
+    pending: HashMap<String, oneshot::Sender<Vec<Message>>>,
+}
+
+/// What became of a message sent with [`ClientHandle::send_confirmed`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Delivery {
+    /// The server echoed the message back, with its `msgid` and `time`.
+    Echoed(Box<Message>),
+    /// The server answered with something else, e.g. `ERR_NOSUCHNICK`.
+    Rejected(Vec<Message>),
+}
+
+/// Why a [`ClientHandle::request`] did not succeed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RequestError {
+    /// The session ended before the response arrived.
+    Connection(IRCError),
+    /// The server answered the command with `FAIL`.
+    Failed(StandardReply),
+}
+
+/// Sends messages on behalf of a session started with [`EventStream::spawn`].
+#[derive(Debug, Clone)]
+pub struct ClientHandle {
+    sender: mpsc::UnboundedSender<Message>,
+    labels: Arc<Mutex<Labels>>,
+    label_timeout: Duration,
+}
+
+impl ClientHandle {
+    /// Gives up on labeled responses after `timeout` instead of [`LABEL_TIMEOUT`].
+    pub fn label_timeout(mut self, timeout: Duration) -> Self {
+        self.label_timeout = timeout;
+        return self
+    }
+
+    /// Queues a message, returns false once the session has ended.
+    pub fn send(&self, command: Command) -> bool {
+        return self.sender.send(Message::new(None, None, command)).is_ok()
+    }
+
+    pub fn send_message(&self, message: Message) -> bool {
+        return self.sender.send(message).is_ok()
+    }
+
+    /// Sends `message` with a fresh `label` and resolves to the server's
+    /// labeled response: the single reply, nothing for an `ACK`, or the
+    /// messages of a `labeled-response` batch. The response is not turned
+    /// into events. Needs the `labeled-response` cap; without it the server
+    /// sends no label and this fails with [`IRCError::TimedOut`].
+    pub fn send_labeled(&self, message: Message) -> impl Future<Output = Result<Vec<Message>, IRCError>> {
+        let (tx, rx) = oneshot::channel();
+        let label = {
+            let mut labels = self.labels.lock().unwrap();
+            labels.next += 1;
+            let label = format!("l{}", labels.next);
+            labels.pending.insert(label.clone(), tx);
+            label
+        };
+        let sent = self.sender.send(message.with_tag("label", Some(&label))).is_ok();
+        if !sent {
+            self.labels.lock().unwrap().pending.remove(&label);
+        }
+        let labels = self.labels.clone();
+        let timeout = self.label_timeout;
+        return async move {
+            if !sent {
+                return Err(IRCError::ClientExited);
+            }
+            return match tokio::time::timeout(timeout, rx).await {
+                Ok(replies) => replies.map_err(|_| IRCError::ClientExited),
+                Err(_) => {
+                    labels.lock().unwrap().pending.remove(&label);
+                    Err(IRCError::TimedOut)
+                }
+            }
+        }
+    }
+
+    /// Fetches history with a labeled CHATHISTORY request; needs the
+    /// `draft/chathistory`, `batch` and `labeled-response` caps.
+    pub fn chathistory(&self, query: &HistoryQuery) -> impl Future<Output = Result<Vec<Message>, IRCError>> {
+        return self.send_labeled(Message::new(None, None, query.to_command()))
+    }
+
+    /// Sends `command` labeled and resolves to its response, or to the
+    /// `FAIL` the server answered it with. Needs the `labeled-response` cap.
+    pub async fn request(&self, command: Command) -> Result<Vec<Message>, RequestError> {
+        let name = command.command();
+        let replies = self.send_labeled(Message::new(None, None, command)).await.map_err(RequestError::Connection)?;
+        let failure = replies.iter()
+            .filter_map(StandardReply::from_message)
+            .find(|reply| reply.is_fail() && reply.concerns(&name));
+        return match failure {
+            Some(reply) => Err(RequestError::Failed(reply)),
+            None => Ok(replies),
+        }
+    }
+
+    /// Sends a PRIVMSG or NOTICE and waits for the server to echo it back.
+    /// Needs the `echo-message` and `labeled-response` caps.
+    pub async fn send_confirmed(&self, command: Command) -> Result<Delivery, IRCError> {
+        let name = command.command();
+        let replies = self.send_labeled(Message::new(None, None, command)).await?;
+        return match replies.iter().position(|reply| reply.command.command() == name) {
+            Some(idx) => Ok(Delivery::Echoed(Box::new(replies.into_iter().nth(idx).unwrap()))),
+            None => Ok(Delivery::Rejected(replies)),
+        }
+    }
+}
+
+/// Events of a client session; ends when the connection closes.
+pub struct EventStream {
+    receiver: mpsc::UnboundedReceiver<Event>,
+}
+
+impl EventStream {
+    /// Drives `transport` on a background task, answering PINGs and decoding
+    /// everything else into events; batched messages arrive together as
+    /// [`Event::Batch`]. Registration is left to the caller.
+    pub fn spawn<T: Transport + 'static>(mut transport: T, nick: &str) -> (ClientHandle, EventStream) {
+        let (event_tx, event_rx) = mpsc::unbounded_channel();
+        let (message_tx, mut message_rx) = mpsc::unbounded_channel::<Message>();
+        let labels = Arc::new(Mutex::new(Labels::default()));
+        let mut decoder = EventDecoder::new(nick);
+        let mut assembler = BatchAssembler::new();
+
+        let task_labels = labels.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    incoming = transport.read() => {
+                        let message = match incoming {
+                            Ok(message) => message,
+                            Err(IRCError::LengthExceeded | IRCError::InvalidUtf8) => continue,
+                            Err(_) => break,
+                        };
+                        if let Command::PING { token } = &message.command {
+                            let pong = Command::PONG { server: None, token: token.clone() };
+                            if transport.write(Message::new(None, None, pong)).await.is_err() {
+                                break;
+                            }
+                            continue;
+                        }
+                        let event = match assembler.feed(message) {
+                            Some(Assembled::Message(message)) => {
+                                let waiter = message.label().and_then(|label| task_labels.lock().unwrap().pending.remove(label));
+                                if let Some(waiter) = waiter {
+                                    decoder.feed(&message);
+                                    let replies = if message.command == Command::ACK { Vec::new() } else { vec![message] };
+                                    _ = waiter.send(replies);
+                                    continue;
+                                }
+                                decoder.feed(&message)
+                            }
+                            Some(Assembled::Batch(batch)) => {
+                                let waiter = batch.start.label().and_then(|label| task_labels.lock().unwrap().pending.remove(label));
+                                if let Some(waiter) = waiter {
+                                    decoder.track_batch(&batch);
+                                    _ = waiter.send(batch.all_messages().into_iter().cloned().collect());
+                                    continue;
+                                }
+                                decoder.feed_batch(batch)
+                            }
+                            None => continue,
+                        };
+                        if event_tx.send(event).is_err() {
+                            break;
+                        }
+                    }
+                    Some(message) = message_rx.recv() => {
+                        if transport.write(message).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+            // Fail everyone still waiting for a labeled response.
+            task_labels.lock().unwrap().pending.clear();
+        });
+
+        return (ClientHandle { sender: message_tx, labels, label_timeout: LABEL_TIMEOUT }, EventStream { receiver: event_rx })
+    }
+}
+
+impl Stream for EventStream {
+    type Item = Event;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        return self.receiver.poll_recv(cx)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+
+    use crate::connection::{IRCError, MemoryTransport};
+    use crate::types::{Command, Message};
+
+    use crate::standard_reply::StandardReply;
+    use crate::test_util::{parse, source};
+
+    use super::{Delivery, Event, EventDecoder, EventStream, RequestError};
+
+    #[test]
+    fn test_decoder() {
+        let mut decoder = EventDecoder::new("me");
+        assert_eq!(Event::Registered { nick: "me".to_string(), server: "irc.example.com".to_string() }, decoder.feed(&parse(":irc.example.com 001 me :Welcome")));
+
+        for line in [":me!u@host JOIN #a", ":me!u@host JOIN #b", ":irc.example.com 353 me = #a :@me +dan eve", ":dan!u@host JOIN #b"] {
+            decoder.feed(&parse(line));
+        }
+        assert_eq!(
+            Event::Message { from: source("dan"), target: "#a".to_string(), text: "waves".to_string(), is_action: true, is_notice: false },
+            decoder.feed(&parse(":dan!u@host PRIVMSG #a :\x01ACTION waves\x01")),
+        );
+
+        decoder.feed(&parse(":dan!u@host NICK Danny"));
+        assert_eq!(
+            Event::Quit { user: source("DANNY"), reason: Some("bye".to_string()), channels: vec!["#a".to_string(), "#b".to_string()] },
+            decoder.feed(&parse(":DANNY!u@host QUIT :bye")),
+        );
+        assert_eq!(
+            Event::Quit { user: source("eve"), reason: None, channels: vec!["#a".to_string()] },
+            decoder.feed(&parse(":eve!u@host QUIT")),
+        );
+
+        decoder.feed(&parse(":me!u@host NICK other"));
+        assert_eq!("other", decoder.nick());
+        assert!(matches!(decoder.feed(&parse(":op!u@host MODE #a +o other")), Event::ModeChange { changes, .. } if changes[0].mode == 'o'));
+        assert!(matches!(decoder.feed(&parse(":op!u@host INVITE other #c")), Event::Invite { channel, .. } if channel == "#c"));
+    }
+
+    #[tokio::test]
+    async fn test_stream() {
+        let (transport, mut peer) = MemoryTransport::pair();
+        let (handle, mut events) = EventStream::spawn(transport, "me");
+
+        handle.send(Command::NICK { nickname: "me".to_string() });
+        assert_eq!("NICK me\r\n", peer.recv().await.unwrap().to_bytes());
+        peer.send(parse("PING :123"));
+        assert_eq!("PONG 123\r\n", peer.recv().await.unwrap().to_bytes());
+
+        peer.send(parse(":irc.example.com 001 me :Welcome"));
+        assert!(matches!(events.next().await, Some(Event::Registered { .. })));
+        peer.send(parse(":dan!u@host TOPIC #a :news"));
+        assert!(matches!(events.next().await, Some(Event::TopicChange { topic, .. }) if topic == "news"));
+
+        for line in [
+            ":me!u@host JOIN #a",
+            ":dan!u@host JOIN #a",
+            ":irc.example.com BATCH +ns netsplit a.net b.net",
+            "@batch=ns :dan!u@host QUIT :a.net b.net",
+            ":irc.example.com BATCH -ns",
+            ":dan!u@host QUIT :gone again",
+        ] {
+            peer.send(parse(line));
+        }
+        events.next().await;
+        events.next().await;
+        let Some(Event::Batch(batch)) = events.next().await else { panic!() };
+        assert_eq!(("netsplit", 1), (batch.kind.as_str(), batch.messages.len()));
+        // The QUIT inside the batch already took dan out of #a.
+        assert!(matches!(events.next().await, Some(Event::Quit { channels, .. }) if channels.is_empty()));
+
+        drop(peer);
+        assert_eq!(None, events.next().await);
+    }
+
+    #[tokio::test]
+    async fn test_labeled() {
+        let (transport, mut peer) = MemoryTransport::pair();
+        let (handle, mut events) = EventStream::spawn(transport, "me");
+
+        let pending = tokio::spawn(handle.send_labeled(parse("WHOIS dan")));
+        assert_eq!("@label=l1 WHOIS dan\r\n", peer.recv().await.unwrap().to_bytes());
+        for line in [
+            ":irc.example.com NOTICE me :unrelated",
+            "@label=l1 :irc.example.com BATCH +b1 labeled-response",
+            "@batch=b1 :irc.example.com 311 me dan d host * :Dan",
+            "@batch=b1 :irc.example.com 318 me dan :End of /WHOIS list",
+            ":irc.example.com BATCH -b1",
+        ] {
+            peer.send(parse(line));
+        }
+        let replies = pending.await.unwrap().unwrap();
+        assert_eq!(vec!["311", "318"], replies.iter().map(|reply| reply.command.command()).collect::<Vec<_>>());
+        assert!(matches!(events.next().await, Some(Event::Message { text, .. }) if text == "unrelated"));
+
+        let pending = tokio::spawn(handle.send_labeled(parse("PONG x")));
+        peer.recv().await.unwrap();
+        peer.send(parse("@label=l2 :irc.example.com ACK"));
+        assert_eq!(Vec::<Message>::new(), pending.await.unwrap().unwrap());
+
+        let confirmer = handle.clone();
+        let pending = tokio::spawn(async move { confirmer.send_confirmed(Command::PRIVMSG { targets: "#a".to_string(), text: "hi".to_string() }).await });
+        assert_eq!("@label=l3 PRIVMSG #a hi\r\n", peer.recv().await.unwrap().to_bytes());
+        peer.send(parse("@label=l3;msgid=42 :me!u@host PRIVMSG #a hi"));
+        let Delivery::Echoed(echo) = pending.await.unwrap().unwrap() else { panic!() };
+        assert_eq!(Some("42"), echo.msgid());
+
+        let requester = handle.clone();
+        let pending = tokio::spawn(async move { requester.request(Command::SETNAME { realname: "".to_string() }).await });
+        assert_eq!("@label=l4 SETNAME :\r\n", peer.recv().await.unwrap().to_bytes());
+        peer.send(parse("@label=l4 :irc.example.com FAIL SETNAME INVALID_REALNAME :Realname is not valid"));
+        assert_eq!(Err(RequestError::Failed(StandardReply::fail("SETNAME", "INVALID_REALNAME"))), pending.await.unwrap());
+
+        peer.send(parse(":irc.example.com WARN * ACCOUNT_REQUIRED :You need to be logged in to do that"));
+        let Some(Event::StandardReply(warning)) = events.next().await else { panic!() };
+        assert!(!warning.is_fail() && warning.concerns("JOIN"));
+
+        // Unanswered labels time out and are forgotten.
+        let impatient = handle.clone().label_timeout(Duration::from_millis(10));
+        let pending = tokio::spawn(impatient.send_labeled(parse("PING y")));
+        peer.recv().await.unwrap();
+        assert_eq!(Err(IRCError::TimedOut), pending.await.unwrap());
+        assert!(handle.labels.lock().unwrap().pending.is_empty());
+
+        // Waiters fail once the connection is gone.
+        let pending = tokio::spawn(handle.send_labeled(parse("PING x")));
+        peer.recv().await.unwrap();
+        drop(peer);
+        assert!(pending.await.unwrap().is_err());
+    }
+}
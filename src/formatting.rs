@@ -0,0 +1,448 @@
+use std::fmt::Write;
+
+pub const BOLD: char = '\x02';
+pub const COLOR: char = '\x03';
+pub const HEX_COLOR: char = '\x04';
+pub const RESET: char = '\x0F';
+pub const MONOSPACE: char = '\x11';
+pub const REVERSE: char = '\x16';
+pub const ITALIC: char = '\x1D';
+pub const STRIKETHROUGH: char = '\x1E';
+pub const UNDERLINE: char = '\x1F';
+
+/// RGB values of colour codes 00-98, 99 means "default colour".
+const PALETTE: [u32; 99] = [
+    0xffffff, 0x000000, 0x00007f, 0x009300, 0xff0000, 0x7f0000, 0x9c009c, 0xfc7f00,
+    0xffff00, 0x00fc00, 0x009393, 0x00ffff, 0x0000fc, 0xff00ff, 0x7f7f7f, 0xd2d2d2,
+    0x470000, 0x472100, 0x474700, 0x324700, 0x004700, 0x00472c, 0x004747, 0x002747, 0x000047, 0x2e0047, 0x470047, 0x47002a,
+    0x740000, 0x743a00, 0x747400, 0x517400, 0x007400, 0x007449, 0x007474, 0x004074, 0x000074, 0x4b0074, 0x740074, 0x740045,
+    0xb50000, 0xb56300, 0xb5b500, 0x7db500, 0x00b500, 0x00b571, 0x00b5b5, 0x0063b5, 0x0000b5, 0x7500b5, 0xb500b5, 0xb5006b,
+    0xff0000, 0xff8c00, 0xffff00, 0xb2ff00, 0x00ff00, 0x00ffa0, 0x00ffff, 0x008cff, 0x0000ff, 0xa500ff, 0xff00ff, 0xff0098,
+    0xff5959, 0xffb459, 0xffff71, 0xcfff60, 0x6fff6f, 0x65ffc9, 0x6dffff, 0x59b4ff, 0x5959ff, 0xc459ff, 0xff66ff, 0xff59bc,
+    0xff9c9c, 0xffd39c, 0xffff9c, 0xe2ff9c, 0x9cff9c, 0x9cffdb, 0x9cffff, 0x9cd3ff, 0x9c9cff, 0xdc9cff, 0xff9cff, 0xff94d3,
+    0x000000, 0x131313, 0x282828, 0x363636, 0x4d4d4d, 0x656565, 0x818181, 0x9f9f9f, 0xbcbcbc, 0xe2e2e2, 0xffffff,
+];
+
+/// ANSI SGR foreground codes for the 16 basic colours.
+const ANSI_BASIC: [u8; 16] = [97, 30, 34, 32, 91, 31, 35, 33, 93, 92, 36, 96, 94, 95, 90, 37];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    /// A `\x03` colour code, 0-98.
+    Palette(u8),
+    /// A `\x04` hex colour.
+    Rgb(u8, u8, u8),
+}
+
+impl Color {
+    pub const WHITE: Color = Color::Palette(0);
+    pub const BLACK: Color = Color::Palette(1);
+    pub const BLUE: Color = Color::Palette(2);
+    pub const GREEN: Color = Color::Palette(3);
+    pub const RED: Color = Color::Palette(4);
+    pub const BROWN: Color = Color::Palette(5);
+    pub const MAGENTA: Color = Color::Palette(6);
+    pub const ORANGE: Color = Color::Palette(7);
+    pub const YELLOW: Color = Color::Palette(8);
+    pub const LIGHT_GREEN: Color = Color::Palette(9);
+    pub const CYAN: Color = Color::Palette(10);
+    pub const LIGHT_CYAN: Color = Color::Palette(11);
+    pub const LIGHT_BLUE: Color = Color::Palette(12);
+    pub const PINK: Color = Color::Palette(13);
+    pub const GREY: Color = Color::Palette(14);
+    pub const LIGHT_GREY: Color = Color::Palette(15);
+
+    pub fn rgb(&self) -> (u8, u8, u8) {
+        return match *self {
+            Color::Palette(index) => {
+                let value = PALETTE[index as usize % PALETTE.len()];
+                ((value >> 16) as u8, (value >> 8) as u8, value as u8)
+            }
+            Color::Rgb(r, g, b) => (r, g, b),
+        }
+    }
+
+    fn css(&self) -> String {
+        let (r, g, b) = self.rgb();
+        return format!("#{:02x}{:02x}{:02x}", r, g, b)
+    }
+
+    fn ansi(&self, background: bool) -> String {
+        return match *self {
+            Color::Palette(index) if (index as usize) < ANSI_BASIC.len() => (ANSI_BASIC[index as usize] + if background { 10 } else { 0 }).to_string(),
+            color => {
+                let (r, g, b) = color.rgb();
+                format!("{};2;{};{};{}", if background { 48 } else { 38 }, r, g, b)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Style {
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub strikethrough: bool,
+    pub monospace: bool,
+    pub reverse: bool,
+    pub foreground: Option<Color>,
+    pub background: Option<Color>,
+}
+
+impl Style {
+    pub fn is_plain(&self) -> bool {
+        return *self == Style::default()
+    }
+
+    /// Control codes that switch plain text to this style.
+    pub fn codes(&self) -> String {
+        let mut codes = String::new();
+        for (enabled, code) in [
+            (self.bold, BOLD), (self.italic, ITALIC), (self.underline, UNDERLINE),
+            (self.strikethrough, STRIKETHROUGH), (self.monospace, MONOSPACE), (self.reverse, REVERSE),
+        ] {
+            if enabled {
+                codes.push(code);
+            }
+        }
+        codes.push_str(&color_codes(self.foreground, self.background));
+        return codes
+    }
+}
+
+/// `\x03` for palette colours, or `\x04` with both colours in hex as soon
+/// as one of them is RGB.
+fn color_codes(foreground: Option<Color>, background: Option<Color>) -> String {
+    let is_hex = [foreground, background].iter().any(|color| matches!(color, Some(Color::Rgb(..))));
+    let format = |color: Color| match color {
+        Color::Palette(index) if !is_hex => format!("{:02}", index),
+        color => {
+            let (r, g, b) = color.rgb();
+            format!("{:02X}{:02X}{:02X}", r, g, b)
+        }
+    };
+    let code = if is_hex { HEX_COLOR } else { COLOR };
+    let default = if is_hex { "FFFFFF" } else { "99" };
+    return match (foreground, background) {
+        (None, None) => String::new(),
+        (Some(fg), None) => format!("{}{}", code, format(fg)),
+        (fg, Some(bg)) => format!("{}{},{}", code, fg.map(format).unwrap_or(default.to_string()), format(bg)),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub text: String,
+    pub style: Style,
+}
+
+/// Reads up to `max` characters matching `accept` from the front of `rest`.
+fn take_while(rest: &str, max: usize, accept: fn(&char) -> bool) -> &str {
+    let len = rest.chars().take(max).take_while(accept).map(char::len_utf8).sum();
+    return &rest[..len]
+}
+
+/// Reads the formatting code at the start of `text`, returning its length in
+/// bytes and the style it switches `style` to.
+fn read_code(text: &str, style: &Style) -> Option<(usize, Style)> {
+    let c = text.chars().next()?;
+    let mut rest = &text[c.len_utf8()..];
+    let mut next = style.clone();
+    match c {
+        BOLD => next.bold = !next.bold,
+        ITALIC => next.italic = !next.italic,
+        UNDERLINE => next.underline = !next.underline,
+        STRIKETHROUGH => next.strikethrough = !next.strikethrough,
+        MONOSPACE => next.monospace = !next.monospace,
+        REVERSE => next.reverse = !next.reverse,
+        RESET => next = Style::default(),
+        COLOR | HEX_COLOR => {
+            let (max, accept): (usize, fn(&char) -> bool) = match c {
+                COLOR => (2, char::is_ascii_digit),
+                _ => (6, char::is_ascii_hexdigit),
+            };
+            let parse_color = |digits: &str| -> Option<Color> {
+                match c {
+                    COLOR => digits.parse::<u8>().ok().filter(|index| *index < 99).map(Color::Palette),
+                    _ => u32::from_str_radix(digits, 16).ok().map(|v| Color::Rgb((v >> 16) as u8, (v >> 8) as u8, v as u8)),
+                }
+            };
+            let foreground = take_while(rest, max, accept);
+            if foreground.is_empty() || (c == HEX_COLOR && foreground.len() < 6) {
+                next.foreground = None;
+                next.background = None;
+            } else {
+                rest = &rest[foreground.len()..];
+                next.foreground = parse_color(foreground);
+                if let Some(after_comma) = rest.strip_prefix(',') {
+                    let background = take_while(after_comma, max, accept);
+                    if !background.is_empty() && (c == COLOR || background.len() == 6) {
+                        rest = &after_comma[background.len()..];
+                        next.background = parse_color(background);
+                    }
+                }
+            }
+        }
+        _ => return None,
+    }
+    return Some((text.len() - rest.len(), next))
+}
+
+/// Length in bytes of the formatting code at the start of `text`, 0 if
+/// `text` does not start with one.
+pub fn code_len(text: &str) -> usize {
+    return read_code(text, &Style::default()).map(|(len, _)| len).unwrap_or(0)
+}
+
+fn parse_from(text: &str, start: &Style) -> (Vec<Span>, Style) {
+    let mut spans = Vec::new();
+    let mut style = start.clone();
+    let mut current = String::new();
+    let mut rest = text;
+
+    while let Some(c) = rest.chars().next() {
+        let Some((len, next)) = read_code(rest, &style) else {
+            current.push(c);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        };
+        rest = &rest[len..];
+        if next != style {
+            if !current.is_empty() {
+                spans.push(Span { text: std::mem::take(&mut current), style: style.clone() });
+            }
+            style = next;
+        }
+    }
+    if !current.is_empty() {
+        spans.push(Span { text: current, style: style.clone() });
+    }
+    return (spans, style)
+}
+
+/// Splits formatted text into runs of identically styled text.
+pub fn parse(text: &str) -> Vec<Span> {
+    return parse_from(text, &Style::default()).0
+}
+
+/// The style in effect at the end of `text` when it starts in `start`.
+pub fn style_after(text: &str, start: &Style) -> Style {
+    return parse_from(text, start).1
+}
+
+/// Removes every formatting code, leaving plain text.
+pub fn strip(text: &str) -> String {
+    return parse(text).into_iter().map(|span| span.text).collect()
+}
+
+/// Renders formatted text with ANSI escape sequences for terminals.
+pub fn to_ansi(text: &str) -> String {
+    let mut out = String::new();
+    for span in parse(text) {
+        if span.style.is_plain() {
+            out.push_str(&span.text);
+            continue;
+        }
+        let style = &span.style;
+        let mut codes: Vec<String> = [(style.bold, "1"), (style.italic, "3"), (style.underline, "4"), (style.reverse, "7"), (style.strikethrough, "9")]
+            .into_iter()
+            .filter(|(enabled, _)| *enabled)
+            .map(|(_, code)| code.to_string())
+            .collect();
+        codes.extend(style.foreground.map(|color| color.ansi(false)));
+        codes.extend(style.background.map(|color| color.ansi(true)));
+        if codes.is_empty() {
+            out.push_str(&span.text);
+        } else {
+            let _ = write!(out, "\x1b[{}m{}\x1b[0m", codes.join(";"), span.text);
+        }
+    }
+    return out
+}
+
+fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    return out
+}
+
+/// Renders formatted text as HTML with inline styles; text is escaped.
+pub fn to_html(text: &str) -> String {
+    let mut out = String::new();
+    for span in parse(text) {
+        let style = &span.style;
+        let text = escape_html(&span.text);
+        let (foreground, background) = match style.reverse {
+            true => (Some(style.background.unwrap_or(Color::WHITE)), Some(style.foreground.unwrap_or(Color::BLACK))),
+            false => (style.foreground, style.background),
+        };
+
+        let mut css = Vec::new();
+        if style.bold {
+            css.push("font-weight:bold".to_string());
+        }
+        if style.italic {
+            css.push("font-style:italic".to_string());
+        }
+        match (style.underline, style.strikethrough) {
+            (true, true) => css.push("text-decoration:underline line-through".to_string()),
+            (true, false) => css.push("text-decoration:underline".to_string()),
+            (false, true) => css.push("text-decoration:line-through".to_string()),
+            (false, false) => {}
+        }
+        if style.monospace {
+            css.push("font-family:monospace".to_string());
+        }
+        css.extend(foreground.map(|color| format!("color:{}", color.css())));
+        css.extend(background.map(|color| format!("background-color:{}", color.css())));
+
+        if css.is_empty() {
+            out.push_str(&text);
+        } else {
+            let _ = write!(out, "<span style=\"{}\">{}</span>", css.join(";"), text);
+        }
+    }
+    return out
+}
+
+/// Builds formatted text piece by piece; each styled piece is closed again
+/// so pieces never leak their style into the next one.
+#[derive(Debug, Clone, Default)]
+pub struct FormatBuilder {
+    text: String,
+}
+
+impl FormatBuilder {
+    pub fn new() -> Self {
+        return FormatBuilder::default()
+    }
+
+    pub fn text(mut self, text: &str) -> Self {
+        self.text.push_str(text);
+        return self
+    }
+
+    fn toggled(mut self, code: char, text: &str) -> Self {
+        self.text.push(code);
+        self.text.push_str(text);
+        self.text.push(code);
+        return self
+    }
+
+    pub fn bold(self, text: &str) -> Self {
+        return self.toggled(BOLD, text)
+    }
+
+    pub fn italic(self, text: &str) -> Self {
+        return self.toggled(ITALIC, text)
+    }
+
+    pub fn underline(self, text: &str) -> Self {
+        return self.toggled(UNDERLINE, text)
+    }
+
+    pub fn strikethrough(self, text: &str) -> Self {
+        return self.toggled(STRIKETHROUGH, text)
+    }
+
+    pub fn monospace(self, text: &str) -> Self {
+        return self.toggled(MONOSPACE, text)
+    }
+
+    pub fn reverse(self, text: &str) -> Self {
+        return self.toggled(REVERSE, text)
+    }
+
+    pub fn color(self, foreground: Color, background: Option<Color>, text: &str) -> Self {
+        return self.styled(&Style { foreground: Some(foreground), background, ..Default::default() }, text)
+    }
+
+    /// Appends `text` in `style`, followed by a reset.
+    pub fn styled(mut self, style: &Style, text: &str) -> Self {
+        self.text.push_str(&style.codes());
+        // Colour codes are always fully padded, so only a leading comma could
+        // be mistaken for the start of a background colour.
+        if style.foreground.is_some() && style.background.is_none() && text.starts_with(',') {
+            self.text.push(BOLD);
+            self.text.push(BOLD);
+        }
+        self.text.push_str(text);
+        self.text.push(RESET);
+        return self
+    }
+
+    pub fn build(self) -> String {
+        return self.text
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, strip, to_ansi, to_html, Color, FormatBuilder, Span, Style};
+
+    #[test]
+    fn test_parse() {
+        let spans = parse("\x02bold\x02 \x0304,12red\x03 \x1Ditalic\x0F plain");
+        assert_eq!(6, spans.len());
+        assert_eq!(Span { text: "bold".to_string(), style: Style { bold: true, ..Default::default() } }, spans[0]);
+        assert_eq!(Some(Color::RED), spans[2].style.foreground);
+        assert_eq!(Some(Color::LIGHT_BLUE), spans[2].style.background);
+        assert!(spans[4].style.italic && spans[4].text == "italic");
+        assert!(spans[5].style.is_plain());
+
+        let spans = parse("\x04FF8800,000000hex\x041,5");
+        assert_eq!(Some(Color::Rgb(0xff, 0x88, 0x00)), spans[0].style.foreground);
+        assert_eq!(Some(Color::Rgb(0, 0, 0)), spans[0].style.background);
+        assert!(spans[1].style.is_plain() && spans[1].text == "1,5");
+
+        // Only two digits belong to the colour, a lone comma stays text.
+        assert_eq!("1123,", strip("\x03041123,"));
+        assert_eq!("1,x", strip("\x03031,x"));
+    }
+
+    #[test]
+    fn test_strip() {
+        assert_eq!("hello world", strip("\x02hello\x0F \x0399,99world\x03"));
+        assert_eq!("ünïcode", strip("\x1Fünï\x1Fcode"));
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!("\x1b[1;91mhi\x1b[0m there", to_ansi("\x02\x0304hi\x0F there"));
+        assert_eq!("\x1b[38;2;255;140;0mx\x1b[0m", to_ansi("\x0353x"));
+        assert_eq!(
+            "<span style=\"font-weight:bold;color:#ff0000\">&lt;b&gt;</span> &amp;",
+            to_html("\x02\x0304<b>\x0F &"),
+        );
+        assert_eq!("<span style=\"color:#ffffff;background-color:#000000\">r</span>", to_html("\x16r"));
+    }
+
+    #[test]
+    fn test_builder() {
+        let text = FormatBuilder::new()
+            .bold("Build")
+            .text(" ")
+            .color(Color::GREEN, None, ",42 passed")
+            .text(", ")
+            .styled(&Style { italic: true, foreground: Some(Color::Rgb(1, 2, 3)), ..Default::default() }, "done")
+            .build();
+        assert_eq!("\x02Build\x02 \x0303\x02\x02,42 passed\x0F, \x1D\x04010203done\x0F", text);
+        assert_eq!("Build ,42 passed, done", strip(&text));
+        assert_eq!(Some(Color::GREEN), parse(&text)[2].style.foreground);
+
+        let text = FormatBuilder::new().color(Color::RED, Some(Color::Rgb(0, 0, 0x80)), "x").build();
+        assert_eq!("\x04FF0000,000080x\x0F", text);
+        assert_eq!("x", strip(&text));
+    }
+}
@@ -1,9 +1,37 @@
 pub mod message;
 pub mod command;
+pub mod batch;
 pub mod channel;
+pub mod bot;
+pub mod channel_registry;
+pub mod chathistory;
+pub mod client_state;
 pub mod connection;
+pub mod ctcp;
+pub mod encoding;
+pub mod formatting;
+pub mod event;
+pub mod server;
+pub mod split;
+pub mod standard_reply;
+pub mod tags;
+pub mod time;
 pub mod types;
+pub mod user;
+pub mod who;
+pub mod whois;
+pub mod capability;
+pub mod names;
+pub mod mask;
+pub mod monitor;
+#[cfg(test)]
+pub(crate) mod test_util;
 
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub fn unix_time() -> u64 {
+    return SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
 
 pub fn enable_logging() {
     if std::env::var_os("RUST_LOG").is_none() {
@@ -0,0 +1,498 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+
+use crate::types::{Casemapping, Source};
+
+
+impl Casemapping {
+    pub fn from_isupport(value: &str) -> Option<Self> {
+        match value {
+            "ascii" => Some(Casemapping::Ascii),
+            "rfc1459" => Some(Casemapping::Rfc1459),
+            "strict-rfc1459" => Some(Casemapping::StrictRfc1459),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Casemapping::Ascii => "ascii",
+            Casemapping::Rfc1459 => "rfc1459",
+            Casemapping::StrictRfc1459 => "strict-rfc1459",
+        }
+    }
+
+    pub fn fold_char(&self, c: char) -> char {
+        match (self, c) {
+            (_, 'A'..='Z') => c.to_ascii_lowercase(),
+            (Casemapping::Rfc1459 | Casemapping::StrictRfc1459, '[') => '{',
+            (Casemapping::Rfc1459 | Casemapping::StrictRfc1459, ']') => '}',
+            (Casemapping::Rfc1459 | Casemapping::StrictRfc1459, '\\') => '|',
+            (Casemapping::Rfc1459, '~') => '^',
+            _ => c,
+        }
+    }
+
+    pub fn fold(&self, input: &str) -> String {
+        return input.chars().map(|c| self.fold_char(c)).collect()
+    }
+
+    pub fn equals(&self, a: &str, b: &str) -> bool {
+        return a.chars().count() == b.chars().count()
+            && a.chars().zip(b.chars()).all(|(x, y)| self.fold_char(x) == self.fold_char(y))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Literal(char),
+    AnyOne,
+    AnyMany,
+}
+
+/// A compiled IRC glob: `*` matches any run of characters, `?` exactly one,
+/// and `\` makes the following character literal.
+#[derive(Debug, Clone)]
+pub struct Glob {
+    tokens: Vec<Token>,
+    casemapping: Casemapping,
+}
+
+impl Glob {
+    pub fn new(pattern: &str, casemapping: Casemapping) -> Self {
+        let mut tokens = Vec::new();
+        let mut chars = pattern.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' => {
+                    if tokens.last() != Some(&Token::AnyMany) {
+                        tokens.push(Token::AnyMany);
+                    }
+                }
+                '?' => tokens.push(Token::AnyOne),
+                '\\' => {
+                    let escaped = chars.next().unwrap_or('\\');
+                    tokens.push(Token::Literal(casemapping.fold_char(escaped)));
+                }
+                _ => tokens.push(Token::Literal(casemapping.fold_char(c))),
+            }
+        }
+        return Glob { tokens, casemapping }
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().map(|c| self.casemapping.fold_char(c)).collect();
+
+        let (mut t, mut p) = (0, 0);
+        let mut backtrack: Option<(usize, usize)> = None;
+
+        while t < text.len() {
+            match self.tokens.get(p) {
+                Some(Token::AnyMany) => {
+                    backtrack = Some((p, t));
+                    p += 1;
+                    continue;
+                }
+                Some(Token::AnyOne) => {
+                    p += 1;
+                    t += 1;
+                    continue;
+                }
+                Some(Token::Literal(c)) if *c == text[t] => {
+                    p += 1;
+                    t += 1;
+                    continue;
+                }
+                _ => {}
+            }
+            match backtrack {
+                Some((star_p, star_t)) => {
+                    backtrack = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            }
+        }
+
+        return self.tokens[p..].iter().all(|token| *token == Token::AnyMany)
+    }
+
+    fn is_literal(&self) -> bool {
+        return self.tokens.iter().all(|token| matches!(token, Token::Literal(_)))
+    }
+
+    fn literal_suffix(&self) -> String {
+        let mut suffix: Vec<char> = self.tokens.iter()
+            .rev()
+            .map_while(|token| match token {
+                Token::Literal(c) => Some(*c),
+                _ => None,
+            })
+            .collect();
+        suffix.reverse();
+        return suffix.into_iter().collect()
+    }
+}
+
+pub fn wildcard_match(pattern: &str, text: &str, casemapping: Casemapping) -> bool {
+    return Glob::new(pattern, casemapping).is_match(text)
+}
+
+/// Expands a partial mask to the full `nick!user@host` form.
+///
+/// `nick` becomes `nick!*@*`, `user@host` becomes `*!user@host` and
+/// `nick!user` becomes `nick!user@*`; empty parts are replaced by `*`.
+pub fn normalize_mask(mask: &str) -> String {
+    fn part(s: &str) -> &str {
+        if s.is_empty() { "*" } else { s }
+    }
+
+    let (nick, user, host) = match mask.split_once('@') {
+        Some((left, host)) => match left.split_once('!') {
+            Some((nick, user)) => (nick, user, host),
+            None => ("*", left, host),
+        },
+        None => match mask.split_once('!') {
+            Some((nick, user)) => (nick, user, "*"),
+            None => (mask, "*", "*"),
+        },
+    };
+
+    return format!("{}!{}@{}", part(nick), part(user), part(host))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    pub network: IpAddr,
+    pub prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn parse(input: &str) -> Option<Cidr> {
+        let (address, prefix_len) = input.split_once('/')?;
+        let network: IpAddr = address.parse().ok()?;
+        let prefix_len: u8 = prefix_len.parse().ok()?;
+        if prefix_len > Cidr::max_prefix(&network) {
+            return None;
+        }
+        return Some(Cidr { network: Cidr::truncate(network, prefix_len), prefix_len })
+    }
+
+    pub fn contains(&self, address: IpAddr) -> bool {
+        let address = address.to_canonical();
+        if address.is_ipv4() != self.network.is_ipv4() {
+            return false;
+        }
+        return Cidr::truncate(address, self.prefix_len) == self.network
+    }
+
+    fn max_prefix(address: &IpAddr) -> u8 {
+        match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        }
+    }
+
+    fn truncate(address: IpAddr, prefix_len: u8) -> IpAddr {
+        match address {
+            IpAddr::V4(v4) => {
+                let bits = u32::from(v4);
+                let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+                IpAddr::V4((bits & mask).into())
+            }
+            IpAddr::V6(v6) => {
+                let bits = u128::from(v6);
+                let mask = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+                IpAddr::V6((bits & mask).into())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum HostPattern {
+    Glob(Glob),
+    Cidr(Cidr),
+}
+
+/// A normalised `nick!user@host` mask compiled for matching.
+#[derive(Debug, Clone)]
+pub struct Mask {
+    raw: String,
+    nick: Glob,
+    user: Glob,
+    host: HostPattern,
+}
+
+impl Mask {
+    pub fn new(mask: &str, casemapping: Casemapping) -> Self {
+        let raw = normalize_mask(mask);
+        let (left, host) = raw.split_once('@').unwrap();
+        let (nick, user) = left.split_once('!').unwrap();
+
+        let host = match Cidr::parse(host) {
+            Some(cidr) => HostPattern::Cidr(cidr),
+            None => HostPattern::Glob(Glob::new(host, casemapping)),
+        };
+
+        return Mask {
+            nick: Glob::new(nick, casemapping),
+            user: Glob::new(user, casemapping),
+            host,
+            raw,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        return &self.raw
+    }
+
+    pub fn matches(&self, source: &Source) -> bool {
+        return self.matches_parts(
+            &source.name,
+            source.user.as_deref().unwrap_or(""),
+            source.host.as_deref().unwrap_or(""),
+        )
+    }
+
+    pub fn matches_parts(&self, nick: &str, user: &str, host: &str) -> bool {
+        if !self.nick.is_match(nick) || !self.user.is_match(user) {
+            return false;
+        }
+        match &self.host {
+            HostPattern::Glob(glob) => glob.is_match(host),
+            HostPattern::Cidr(cidr) => match host.parse::<IpAddr>() {
+                Ok(address) => cidr.contains(address),
+                Err(_) => false,
+            },
+        }
+    }
+}
+
+impl Source {
+    pub fn matches(&self, mask: &Mask) -> bool {
+        return mask.matches(self)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BanEntry {
+    pub mask: Mask,
+    pub setter: String,
+    pub set_at: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Bucket {
+    ExactHost(String),
+    HostSuffix(String),
+    Cidr(IpAddr, u8),
+    Other,
+}
+
+/// A list of masks indexed by host so that lookups only fully match the
+/// entries that could apply to a given host.
+#[derive(Debug, Clone, Default)]
+pub struct BanList {
+    casemapping: Casemapping,
+    next_id: u64,
+    entries: BTreeMap<u64, BanEntry>,
+    by_mask: HashMap<String, u64>,
+    buckets: HashMap<Bucket, Vec<u64>>,
+    cidr_prefixes: BTreeMap<u8, usize>,
+}
+
+impl BanList {
+    pub fn new(casemapping: Casemapping) -> Self {
+        return BanList { casemapping, ..Default::default() }
+    }
+
+    pub fn len(&self) -> usize {
+        return self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &BanEntry> {
+        return self.entries.values()
+    }
+
+    pub fn contains(&self, mask: &str) -> bool {
+        return self.by_mask.contains_key(&self.key(mask))
+    }
+
+    /// Adds a mask, returning `false` if an equivalent mask is already listed.
+    pub fn add(&mut self, mask: &str, setter: &str, set_at: u64) -> bool {
+        let key = self.key(mask);
+        if self.by_mask.contains_key(&key) {
+            return false;
+        }
+
+        let entry = BanEntry { mask: Mask::new(mask, self.casemapping), setter: setter.to_string(), set_at };
+        let bucket = self.bucket(&entry.mask);
+        if let Bucket::Cidr(_, prefix_len) = bucket {
+            *self.cidr_prefixes.entry(prefix_len).or_insert(0) += 1;
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.buckets.entry(bucket).or_default().push(id);
+        self.by_mask.insert(key, id);
+        self.entries.insert(id, entry);
+        return true
+    }
+
+    pub fn remove(&mut self, mask: &str) -> Option<BanEntry> {
+        let id = self.by_mask.remove(&self.key(mask))?;
+        let entry = self.entries.remove(&id)?;
+        let bucket = self.bucket(&entry.mask);
+
+        if let Some(ids) = self.buckets.get_mut(&bucket) {
+            ids.retain(|other| *other != id);
+            if ids.is_empty() {
+                self.buckets.remove(&bucket);
+            }
+        }
+        if let Bucket::Cidr(_, prefix_len) = bucket {
+            if let Some(count) = self.cidr_prefixes.get_mut(&prefix_len) {
+                *count -= 1;
+                if *count == 0 {
+                    self.cidr_prefixes.remove(&prefix_len);
+                }
+            }
+        }
+        return Some(entry)
+    }
+
+    pub fn matches(&self, source: &Source) -> bool {
+        return self.find(source).is_some()
+    }
+
+    /// Returns the oldest entry matching the source.
+    pub fn find(&self, source: &Source) -> Option<&BanEntry> {
+        let host = source.host.as_deref().unwrap_or("");
+        let folded = self.casemapping.fold(host);
+
+        let mut candidates: Vec<u64> = Vec::new();
+        let mut collect = |bucket: Bucket| {
+            if let Some(ids) = self.buckets.get(&bucket) {
+                candidates.extend(ids);
+            }
+        };
+
+        collect(Bucket::ExactHost(folded.clone()));
+        collect(Bucket::Other);
+        for (idx, _) in folded.match_indices('.') {
+            collect(Bucket::HostSuffix(folded[idx..].to_string()));
+        }
+        if let Ok(address) = host.parse::<IpAddr>() {
+            let address = address.to_canonical();
+            for prefix_len in self.cidr_prefixes.keys() {
+                if let Some(cidr) = Cidr::parse(&format!("{}/{}", address, prefix_len)) {
+                    collect(Bucket::Cidr(cidr.network, cidr.prefix_len));
+                }
+            }
+        }
+
+        candidates.sort_unstable();
+        return candidates.into_iter()
+            .filter_map(|id| self.entries.get(&id))
+            .find(|entry| entry.mask.matches(source))
+    }
+
+    fn key(&self, mask: &str) -> String {
+        return self.casemapping.fold(&normalize_mask(mask))
+    }
+
+    fn bucket(&self, mask: &Mask) -> Bucket {
+        match &mask.host {
+            HostPattern::Cidr(cidr) => Bucket::Cidr(cidr.network, cidr.prefix_len),
+            HostPattern::Glob(glob) => {
+                let suffix = glob.literal_suffix();
+                if glob.is_literal() {
+                    Bucket::ExactHost(suffix)
+                } else if let Some(idx) = suffix.find('.') {
+                    Bucket::HostSuffix(suffix[idx..].to_string())
+                } else {
+                    Bucket::Other
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::types::{Casemapping, Source};
+
+    use super::{normalize_mask, wildcard_match, BanList, Mask};
+
+    fn source(nick: &str, user: &str, host: &str) -> Source {
+        return Source { name: nick.to_string(), user: Some(user.to_string()), host: Some(host.to_string()) }
+    }
+
+    #[test]
+    fn test_wildcards() {
+        let cm = Casemapping::Rfc1459;
+        assert!(wildcard_match("*", "", cm));
+        assert!(wildcard_match("a*c", "abbbc", cm));
+        assert!(wildcard_match("a?c", "abc", cm));
+        assert!(!wildcard_match("a?c", "ac", cm));
+        assert!(wildcard_match("*.example.com", "host.EXAMPLE.com", cm));
+        assert!(!wildcard_match("*.example.com", "example.com", cm));
+        assert!(wildcard_match("a\\*c", "a*c", cm));
+        assert!(!wildcard_match("a\\*c", "abc", cm));
+        assert!(wildcard_match("nick[away]", "NICK{AWAY}", cm));
+        assert!(!wildcard_match("nick[away]", "nick{away}", Casemapping::Ascii));
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!("nick!*@*", normalize_mask("nick"));
+        assert_eq!("*!*@host", normalize_mask("*@host"));
+        assert_eq!("*!user@host", normalize_mask("user@host"));
+        assert_eq!("nick!user@*", normalize_mask("nick!user"));
+        assert_eq!("*!*@*", normalize_mask(""));
+    }
+
+    #[test]
+    fn test_mask() {
+        let mask = Mask::new("*!*@*.example.com", Casemapping::Rfc1459);
+        assert!(source("dan", "d", "a.example.com").matches(&mask));
+        assert!(!source("dan", "d", "example.org").matches(&mask));
+
+        let mask = Mask::new("*@10.0.0.0/8", Casemapping::Rfc1459);
+        assert!(source("dan", "d", "10.1.2.3").matches(&mask));
+        assert!(!source("dan", "d", "11.1.2.3").matches(&mask));
+        assert!(!source("dan", "d", "ten.example.com").matches(&mask));
+
+        let mask = Mask::new("*@2001:db8::/32", Casemapping::Rfc1459);
+        assert!(source("dan", "d", "2001:db8::1").matches(&mask));
+    }
+
+    #[test]
+    fn test_ban_list() {
+        let mut bans = BanList::new(Casemapping::Rfc1459);
+        assert!(bans.add("*@*.example.com", "op", 0));
+        assert!(!bans.add("*!*@*.EXAMPLE.com", "op", 1));
+        assert!(bans.add("troll", "op", 2));
+        assert!(bans.add("*@192.168.0.0/16", "op", 3));
+        for i in 0..5000 {
+            bans.add(&format!("*!*@host{}.example.org", i), "op", 4);
+        }
+
+        assert_eq!("*!*@*.example.com", bans.find(&source("dan", "d", "a.b.example.com")).unwrap().mask.as_str());
+        assert!(bans.matches(&source("TROLL", "t", "elsewhere")));
+        assert!(bans.matches(&source("dan", "d", "192.168.4.4")));
+        assert!(bans.matches(&source("dan", "d", "host42.example.org")));
+        assert!(!bans.matches(&source("dan", "d", "host42.example.net")));
+
+        assert!(bans.remove("*@*.example.com").is_some());
+        assert!(!bans.matches(&source("dan", "d", "a.b.example.com")));
+        assert_eq!(5002, bans.len());
+    }
+}
@@ -1,4 +1,5 @@
 use core::str;
+use std::fmt;
 
 use crate::types::{Command, Message, Source, Tag, TagKey};
 
@@ -13,18 +14,14 @@ impl Message {
 
         if let Some(tags) = &self.tags {
             output.push('@');
-            for tag in tags {
-                if let Some(client_prefix) = &tag.key.client_prefix {
-                    output.push_str(client_prefix);
+            for (idx, tag) in tags.iter().enumerate() {
+                if idx > 0 {
+                    output.push(';');
                 }
-                if let Some(vendor) = &tag.key.vendor {
-                    output.push_str(vendor);
-                    output.push('/');
-                }
-                output.push_str(&tag.key.value);
+                output.push_str(&tag.key.to_string());
                 if let Some(value) = &tag.value {
                     output.push('=');
-                    output.push_str(value);
+                    output.push_str(&escape_tag_value(value));
                 }
             }
             output.push(' ');
@@ -32,39 +29,37 @@ impl Message {
 
         if let Some(source) = &self.source {
             output.push(':');
-            output.push_str(&source.name);
-            if let Some(user) = &source.user {
-                output.push('!');
-                output.push_str(&user);
-            }
-            if let Some(host) = &source.host {
-                output.push('@');
-                output.push_str(&host);
-            }
+            output.push_str(&source.to_string());
             output.push(' ');
         }
 
         output.push_str(&self.command.command());
-        for argument in self.command.params() {
+        let params = self.command.params();
+        let last = params.len().saturating_sub(1);
+        for (idx, argument) in params.iter().enumerate() {
             output.push(' ');
-            if argument.contains(' ') {
+            if idx == last && (argument.is_empty() || argument.contains(' ') || argument.starts_with(':')) {
                 output.push(':');
             }
-            output.push_str(&argument);
+            output.push_str(argument);
         }
         output.push_str("\r\n");
 
         return output
     }
 
-    pub fn from_bytes<'a>(src: &'a [u8]) -> Option<Message> {
-        // message ::= ['@' <tags> SPACE] [':' <source> SPACE] <command> <parameters>
+    pub fn from_bytes(src: &[u8]) -> Option<Message> {
+        return match str::from_utf8(src) {
+            Ok(input) => Message::parse(input),
+            Err(_) => None,
+        }
+    }
 
-        let mut input = match str::from_utf8(src) {
-            Ok(s) => s,
-            Err(_) => return None,
-        };
+    /// Parses a line that has already been decoded, see [`crate::encoding::Decoding`].
+    pub fn parse(input: &str) -> Option<Message> {
+        // message ::= ['@' <tags> SPACE] [':' <source> SPACE] <command> <parameters>
 
+        let mut input = input;
         let mut message: Message = Message { tags: None, source: None, command: Command::UNKNOWN };
 
         if input.starts_with('@') {
@@ -92,19 +87,20 @@ impl Message {
         }
 
         if let Some((command, params_input)) = input.split_once(' ') {
-            if let Some((middle, trailing)) = params_input.split_once(" :") {
-                let mut params = middle.split(' ').map(|s| s.to_string()).collect::<Vec<String>>();
-                params.push(trailing.to_string());
-                message.command = Command::new(command, params)
+            let (middle, trailing) = if let Some(trailing) = params_input.strip_prefix(':') {
+                ("", Some(trailing))
+            } else if let Some((middle, trailing)) = params_input.split_once(" :") {
+                (middle, Some(trailing))
             } else {
-                message.command = Command::new(
-                    command,
-                    params_input
-                        .split(' ')
-                        .map(|s| s.to_string())
-                        .collect::<Vec<String>>()
-                );
-            }
+                (params_input, None)
+            };
+            let params = middle
+                .split(' ')
+                .filter(|s| !s.is_empty())
+                .chain(trailing)
+                .map(|s| s.to_string())
+                .collect::<Vec<String>>();
+            message.command = Command::new(command, params);
         } else {
             message.command = Command::new(input, Vec::new());
         }
@@ -131,37 +127,20 @@ impl Message {
         // <tag>           ::= <key> ['=' <escaped value>]
 
         if let Some((key, value)) = input.split_once('=') {
-            return Ok(Tag{ key: Message::parse_key(key)?, value: Some(value.to_string()) });
+            return Ok(Tag{ key: Message::parse_key(key)?, value: Some(unescape_tag_value(value)) });
         } else {
             return Ok(Tag{ key: Message::parse_key(input)?, value: None})
         }
     }
 
     fn parse_key(input: &str) -> Result<TagKey, ()> {
-        // <key>           ::= [ <client_prefix> ] [ <vendor> '/' ] <sequence of letters, digits, hyphens (`-`)>
-        // <client_prefix> ::= '+'
-        // <escaped value> ::= <sequence of any characters except NUL, CR, LF, semicolon (`;`) and SPACE>
-        // <vendor>        ::= <host>
-
-        let mut key: TagKey = TagKey { client_prefix: None, vendor: None, value: String::new() };
-        let mut mut_input = input;
-
-        if input.starts_with('+') {
-            key.client_prefix = Some("+".to_string());
-            mut_input = &input[1..]
-        }
-
-        if let Some((vendor, value)) = mut_input.split_once('=') {
-            key.vendor = Some(vendor.to_string());
-            key.value = value.to_string();
-
-        } else {
-            key.value = mut_input.to_string();
+        if input.is_empty() {
+            return Err(());
         }
-        return Ok(key);
+        return Ok(TagKey::parse(input));
     }
 
-    fn parse_source(input: &str) -> Result<Source, ()> {
+    pub(crate) fn parse_source(input: &str) -> Result<Source, ()> {
         // source          ::=  <servername> / ( <nickname> [ "!" <user> ] [ "@" <host> ] )
         // nick            ::=  <any characters except NUL, CR, LF, chantype character, and SPACE> <possibly empty sequence of any characters except NUL, CR, LF, and SPACE>
         // user            ::=  <sequence of any characters except NUL, CR, LF, and SPACE>
@@ -182,6 +161,98 @@ impl Message {
     }
 }
 
+impl TagKey {
+    /// Parses a key as written on the wire, e.g. `+draft/reply`.
+    pub fn parse(input: &str) -> TagKey {
+        // <key>           ::= [ <client_prefix> ] [ <vendor> '/' ] <sequence of letters, digits, hyphens (`-`)>
+        // <client_prefix> ::= '+'
+        // <vendor>        ::= <host>
+
+        let mut key: TagKey = TagKey { client_prefix: None, vendor: None, value: String::new() };
+        let mut mut_input = input;
+
+        if let Some(stripped) = input.strip_prefix('+') {
+            key.client_prefix = Some("+".to_string());
+            mut_input = stripped
+        }
+
+        if let Some((vendor, value)) = mut_input.rsplit_once('/') {
+            key.vendor = Some(vendor.to_string());
+            key.value = value.to_string();
+
+        } else {
+            key.value = mut_input.to_string();
+        }
+        return key
+    }
+
+    pub fn is_client_only(&self) -> bool {
+        return self.client_prefix.is_some()
+    }
+}
+
+impl fmt::Display for TagKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(client_prefix) = &self.client_prefix {
+            write!(f, "{}", client_prefix)?;
+        }
+        if let Some(vendor) = &self.vendor {
+            write!(f, "{}/", vendor)?;
+        }
+        return write!(f, "{}", self.value)
+    }
+}
+
+// <escaped value> ::= <sequence of any characters except NUL, CR, LF, semicolon (`;`) and SPACE>
+fn escape_tag_value(value: &str) -> String {
+    let mut output = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            ';' => output.push_str("\\:"),
+            ' ' => output.push_str("\\s"),
+            '\\' => output.push_str("\\\\"),
+            '\r' => output.push_str("\\r"),
+            '\n' => output.push_str("\\n"),
+            c => output.push(c),
+        }
+    }
+    return output
+}
+
+/// Invalid escapes drop the backslash, as does a trailing one.
+fn unescape_tag_value(value: &str) -> String {
+    let mut output = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            output.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(':') => output.push(';'),
+            Some('s') => output.push(' '),
+            Some('r') => output.push('\r'),
+            Some('n') => output.push('\n'),
+            Some(c) => output.push(c),
+            None => {}
+        }
+    }
+    return output
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let Some(user) = &self.user {
+            write!(f, "!{}", user)?;
+        }
+        if let Some(host) = &self.host {
+            write!(f, "@{}", host)?;
+        }
+        return Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::types::Message;
@@ -198,4 +269,31 @@ mod tests {
         assert_eq!(":irc.example.com CAP REQ :multi-prefix extended-join sasl\r\n", message.to_bytes());
     }
 
+    #[test]
+    fn test_tags() {
+        let line = "@batch=abc;+draft/reply=123;example.com/flag :dan PRIVMSG #chan hi";
+        let message: Message = Message::from_bytes(line.as_bytes()).unwrap();
+        let tags = message.tags.as_ref().unwrap();
+        assert_eq!(Some("draft".to_string()), tags[1].key.vendor);
+        assert_eq!(Some("+".to_string()), tags[1].key.client_prefix);
+        assert_eq!("flag", tags[2].key.value);
+        assert_eq!(format!("{}\r\n", line), message.to_bytes());
+
+        let line = "@+example.com/note=a\\sb\\:c\\\\d\\xe\\ PING x";
+        let message: Message = Message::from_bytes(line.as_bytes()).unwrap();
+        assert_eq!(Some("a b;c\\dxe".to_string()), message.tags.as_ref().unwrap()[0].value);
+        assert_eq!("@+example.com/note=a\\sb\\:c\\\\dxe PING x\r\n", message.to_bytes());
+    }
+
+    #[test]
+    fn test_trailing() {
+        // Only the last parameter can be a trailing one, and it needs the
+        // colon whenever it would not parse back as a middle parameter.
+        for line in ["TOPIC #chan :", "PRIVMSG #chan ::)", "PRIVMSG #chan :a b", "PRIVMSG #chan hi"] {
+            let message = Message::from_bytes(line.as_bytes()).unwrap();
+            assert_eq!(format!("{}\r\n", line), message.clone().to_bytes());
+            assert_eq!(Some(message.clone()), Message::from_bytes(message.to_bytes().trim_end().as_bytes()));
+        }
+    }
+
 }
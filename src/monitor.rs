@@ -0,0 +1,362 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::server::ClientId;
+use crate::types::{Casemapping, Command, Message, Source};
+use crate::user::UserRegistry;
+
+/// Longest comma-separated list put in one MONITOR line or reply.
+const MAX_LIST_LEN: usize = 400;
+
+/// Joins `items` with commas, starting a new list before one gets too long.
+fn comma_lists<I: IntoIterator<Item = String>>(items: I) -> Vec<String> {
+    let mut lists: Vec<String> = Vec::new();
+    for item in items {
+        match lists.last_mut() {
+            Some(list) if list.len() + 1 + item.len() <= MAX_LIST_LEN => {
+                list.push(',');
+                list.push_str(&item);
+            }
+            _ => lists.push(item),
+        }
+    }
+    return lists
+}
+
+/// The nicks each client monitors, indexed both ways so a nick coming online
+/// or going offline finds its watchers without a scan.
+#[derive(Debug, Default)]
+pub struct MonitorRegistry {
+    casemapping: Casemapping,
+    limit: usize,
+    lists: HashMap<ClientId, Vec<String>>,
+    watchers: HashMap<String, HashSet<ClientId>>,
+}
+
+impl MonitorRegistry {
+    pub fn new(casemapping: Casemapping, limit: usize) -> Self {
+        return MonitorRegistry { casemapping, limit, lists: HashMap::new(), watchers: HashMap::new() }
+    }
+
+    /// Most nicks a single client may monitor.
+    pub fn limit(&self) -> usize {
+        return self.limit
+    }
+
+    /// The nicks `client` monitors, as it spelled them.
+    pub fn list(&self, client: ClientId) -> &[String] {
+        return self.lists.get(&client).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// Adds `nick` to the list of `client`, failing if the list is full.
+    /// Nicks already on the list are accepted again.
+    pub fn add(&mut self, client: ClientId, nick: &str) -> bool {
+        let key = self.casemapping.fold(nick);
+        if self.watchers.get(&key).is_some_and(|watchers| watchers.contains(&client)) {
+            return true;
+        }
+        let list = self.lists.entry(client).or_default();
+        if list.len() >= self.limit {
+            return false;
+        }
+        list.push(nick.to_string());
+        self.watchers.entry(key).or_default().insert(client);
+        return true
+    }
+
+    pub fn remove(&mut self, client: ClientId, nick: &str) {
+        let key = self.casemapping.fold(nick);
+        if let Some(watchers) = self.watchers.get_mut(&key) {
+            watchers.remove(&client);
+            if watchers.is_empty() {
+                self.watchers.remove(&key);
+            }
+        }
+        if let Some(list) = self.lists.get_mut(&client) {
+            list.retain(|monitored| !self.casemapping.equals(monitored, nick));
+        }
+    }
+
+    /// Empties the list of `client`, e.g. when it disconnects.
+    pub fn clear(&mut self, client: ClientId) {
+        for nick in self.lists.remove(&client).unwrap_or_default() {
+            let key = self.casemapping.fold(&nick);
+            if let Some(watchers) = self.watchers.get_mut(&key) {
+                watchers.remove(&client);
+                if watchers.is_empty() {
+                    self.watchers.remove(&key);
+                }
+            }
+        }
+    }
+
+    /// The clients monitoring `nick`.
+    pub fn watchers(&self, nick: &str) -> Vec<ClientId> {
+        return self.watchers.get(&self.casemapping.fold(nick)).map(|watchers| watchers.iter().copied().collect()).unwrap_or_default()
+    }
+}
+
+/// Handles `MONITOR <subcommand> [targets]` for `client_id`, known to others
+/// as `client`, and returns the replies.
+pub fn monitor_reply(server: &Source, client_id: ClientId, client: &str, subcommand: &str, targets: Option<&str>, monitors: &mut MonitorRegistry, users: &UserRegistry) -> Vec<Message> {
+    let client_str = client.to_string();
+    let targets: Vec<&str> = targets.unwrap_or("").split(',').filter(|target| !target.is_empty()).collect();
+    let status = |nicks: &[&str]| {
+        let (online, offline): (Vec<&str>, Vec<&str>) = nicks.iter().partition(|nick| users.contains(nick));
+        let online = comma_lists(online.into_iter().map(|nick| users.get(nick).unwrap().source().to_string()))
+            .into_iter()
+            .map(|targets| Command::RPL_MONONLINE { client: client_str.clone(), targets });
+        let offline = comma_lists(offline.into_iter().map(str::to_string))
+            .into_iter()
+            .map(|targets| Command::RPL_MONOFFLINE { client: client_str.clone(), targets });
+        return online.chain(offline).collect::<Vec<Command>>()
+    };
+
+    let replies = match subcommand {
+        "+" if targets.is_empty() => vec![Command::ERR_NEEDMOREPARAMS { client: client_str.clone(), command: "MONITOR".to_string() }],
+        "+" => {
+            let full = targets.iter().position(|nick| !monitors.add(client_id, nick)).unwrap_or(targets.len());
+            let mut replies = status(&targets[..full]);
+            if full < targets.len() {
+                replies.push(Command::ERR_MONLISTFULL {
+                    client: client_str.clone(),
+                    limit: monitors.limit().to_string(),
+                    targets: targets[full..].join(","),
+                    message: "Monitor list is full.".to_string(),
+                });
+            }
+            replies
+        }
+        "-" => {
+            for nick in targets {
+                monitors.remove(client_id, nick);
+            }
+            Vec::new()
+        }
+        "C" | "c" => {
+            monitors.clear(client_id);
+            Vec::new()
+        }
+        "L" | "l" => comma_lists(monitors.list(client_id).iter().cloned())
+            .into_iter()
+            .map(|targets| Command::RPL_MONLIST { client: client_str.clone(), targets })
+            .chain(std::iter::once(Command::RPL_ENDOFMONLIST { client: client_str.clone(), message: "End of MONITOR list".to_string() }))
+            .collect(),
+        "S" | "s" => status(&monitors.list(client_id).iter().map(String::as_str).collect::<Vec<&str>>()),
+        _ => Vec::new(),
+    };
+    return replies.into_iter()
+        .map(|command| Message::new(None, Some(server.clone()), command))
+        .collect()
+}
+
+/// A monitored nick coming online or going offline, see [`Presence::feed`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum PresenceChange {
+    Online(Source),
+    Offline(String),
+}
+
+/// Client-side view of the nicks we monitor, kept up to date by feeding it
+/// every incoming message.
+#[derive(Debug, Default)]
+pub struct Presence {
+    casemapping: Casemapping,
+    limit: Option<usize>,
+    watched: HashMap<String, String>,
+    online: HashMap<String, Source>,
+}
+
+impl Presence {
+    pub fn new() -> Self {
+        return Presence::default()
+    }
+
+    /// The `MONITOR` ISUPPORT limit, if the server advertised one.
+    pub fn limit(&self) -> Option<usize> {
+        return self.limit
+    }
+
+    pub fn watched(&self) -> impl Iterator<Item = &str> {
+        return self.watched.values().map(String::as_str)
+    }
+
+    pub fn is_watched(&self, nick: &str) -> bool {
+        return self.watched.contains_key(&self.casemapping.fold(nick))
+    }
+
+    pub fn is_online(&self, nick: &str) -> bool {
+        return self.online.contains_key(&self.casemapping.fold(nick))
+    }
+
+    /// The monitored nicks known to be online.
+    pub fn online(&self) -> impl Iterator<Item = &Source> {
+        return self.online.values()
+    }
+
+    /// Starts watching `nicks`, returning the `MONITOR +` commands to send.
+    pub fn monitor(&mut self, nicks: &[&str]) -> Vec<Command> {
+        let added: Vec<String> = nicks.iter()
+            .filter(|nick| self.watched.insert(self.casemapping.fold(nick), nick.to_string()).is_none())
+            .map(|nick| nick.to_string())
+            .collect();
+        return comma_lists(added).into_iter()
+            .map(|targets| Command::MONITOR { subcommand: "+".to_string(), targets: Some(targets) })
+            .collect()
+    }
+
+    /// Stops watching `nicks`, returning the `MONITOR -` commands to send.
+    pub fn unmonitor(&mut self, nicks: &[&str]) -> Vec<Command> {
+        let removed: Vec<String> = nicks.iter()
+            .filter_map(|nick| {
+                let key = self.casemapping.fold(nick);
+                self.online.remove(&key);
+                return self.watched.remove(&key)
+            })
+            .collect();
+        return comma_lists(removed).into_iter()
+            .map(|targets| Command::MONITOR { subcommand: "-".to_string(), targets: Some(targets) })
+            .collect()
+    }
+
+    /// Updates the online set, returning the monitored nicks whose status
+    /// changed. Nicks the server refused because the list is full stop
+    /// being watched.
+    pub fn feed(&mut self, message: &Message) -> Vec<PresenceChange> {
+        let mut changes = Vec::new();
+        match &message.command {
+            Command::RPL_ISUPPORT { tokens, .. } => {
+                for token in tokens {
+                    match token.split_once('=').unwrap_or((token, "")) {
+                        ("CASEMAPPING", value) => self.set_casemapping(Casemapping::from_isupport(value).unwrap_or(self.casemapping)),
+                        ("MONITOR", value) => self.limit = value.parse().ok(),
+                        _ => {}
+                    }
+                }
+            }
+            Command::RPL_MONONLINE { targets, .. } => {
+                for source in targets.split(',').filter_map(|target| Message::parse_source(target).ok()) {
+                    let key = self.casemapping.fold(&source.name);
+                    if !self.watched.contains_key(&key) {
+                        continue;
+                    }
+                    if self.online.insert(key, source.clone()).is_none() {
+                        changes.push(PresenceChange::Online(source));
+                    }
+                }
+            }
+            Command::RPL_MONOFFLINE { targets, .. } => {
+                for nick in targets.split(',') {
+                    if let Some(source) = self.online.remove(&self.casemapping.fold(nick)) {
+                        changes.push(PresenceChange::Offline(source.name));
+                    }
+                }
+            }
+            Command::ERR_MONLISTFULL { targets, .. } => {
+                for nick in targets.split(',') {
+                    self.watched.remove(&self.casemapping.fold(nick));
+                }
+            }
+            _ => {}
+        }
+        return changes
+    }
+
+    fn set_casemapping(&mut self, casemapping: Casemapping) {
+        self.casemapping = casemapping;
+        self.watched = self.watched.drain().map(|(_, nick)| (casemapping.fold(&nick), nick)).collect();
+        self.online = self.online.drain().map(|(_, source)| (casemapping.fold(&source.name), source)).collect();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::types::{Casemapping, Command, Message, Source};
+    use crate::user::{User, UserRegistry};
+    use crate::test_util::{parse, server};
+
+    use super::{monitor_reply, MonitorRegistry, Presence, PresenceChange};
+
+    #[test]
+    fn test_registry() {
+        let mut monitors = MonitorRegistry::new(Casemapping::Rfc1459, 2);
+        assert!(monitors.add(1, "Dan[m]"));
+        assert!(monitors.add(1, "dan{m}"));
+        assert!(monitors.add(1, "eve"));
+        assert!(!monitors.add(1, "frank"));
+        assert!(monitors.add(2, "DAN{M}"));
+        assert_eq!(&["Dan[m]".to_string(), "eve".to_string()], monitors.list(1));
+
+        let mut watchers = monitors.watchers("dan[m]");
+        watchers.sort();
+        assert_eq!(vec![1, 2], watchers);
+
+        monitors.remove(1, "DAN[M]");
+        assert_eq!(vec![2], monitors.watchers("dan{m}"));
+        monitors.clear(2);
+        assert!(monitors.watchers("dan{m}").is_empty());
+        assert_eq!(vec![1], monitors.watchers("EVE"));
+    }
+
+    #[test]
+    fn test_reply() {
+        let server = server();
+        let mut users = UserRegistry::new(Casemapping::Rfc1459);
+        users.insert(User::new("Dan".to_string(), "d".to_string(), "host".to_string(), "Dan".to_string(), server.name.clone())).unwrap();
+        let mut monitors = MonitorRegistry::new(Casemapping::Rfc1459, 2);
+        let mut reply = |subcommand: &str, targets: Option<&str>| -> Vec<String> {
+            return monitor_reply(&server, 1, "me", subcommand, targets, &mut monitors, &users)
+                .into_iter()
+                .map(Message::to_bytes)
+                .collect()
+        };
+
+        assert_eq!(vec![
+            ":irc.example.com 730 me Dan!d@host\r\n",
+            ":irc.example.com 731 me eve\r\n",
+            ":irc.example.com 734 me 2 frank,gus :Monitor list is full.\r\n",
+        ], reply("+", Some("dan,eve,frank,gus")));
+        assert_eq!(vec![
+            ":irc.example.com 732 me dan,eve\r\n",
+            ":irc.example.com 733 me :End of MONITOR list\r\n",
+        ], reply("L", None));
+        assert!(reply("-", Some("eve")).is_empty());
+        assert_eq!(vec![":irc.example.com 730 me Dan!d@host\r\n"], reply("S", None));
+        assert_eq!(vec![":irc.example.com 461 me MONITOR\r\n"], reply("+", None));
+        assert!(reply("C", None).is_empty());
+        assert_eq!(vec![":irc.example.com 733 me :End of MONITOR list\r\n"], reply("L", None));
+    }
+
+    #[test]
+    fn test_presence() {
+        let mut presence = Presence::new();
+        presence.feed(&parse(":irc.example.com 005 me CASEMAPPING=ascii MONITOR=100 :are supported"));
+        assert_eq!(Some(100), presence.limit());
+
+        let nicks: Vec<String> = (0..100).map(|i| format!("friend{:02}", i)).collect();
+        let nicks: Vec<&str> = nicks.iter().map(String::as_str).collect();
+        let commands = presence.monitor(&nicks);
+        assert_eq!(3, commands.len());
+        let Command::MONITOR { subcommand, targets: Some(targets) } = &commands[0] else { panic!() };
+        assert_eq!("+", subcommand);
+        assert!(targets.starts_with("friend00,friend01,") && targets.len() <= 400);
+        assert!(presence.monitor(&["FRIEND00"]).is_empty());
+
+        let changes = presence.feed(&parse(":irc.example.com 730 me :Friend00!f@host,stranger!s@host"));
+        assert_eq!(vec![PresenceChange::Online(Source { name: "Friend00".to_string(), user: Some("f".to_string()), host: Some("host".to_string()) })], changes);
+        assert!(presence.feed(&parse(":irc.example.com 730 me :friend00!f@host")).is_empty());
+        assert!(presence.is_online("FRIEND00"));
+        assert!(!presence.is_online("stranger"));
+        assert!(presence.feed(&parse(":irc.example.com 731 me :friend01")).is_empty());
+
+        presence.feed(&parse(":irc.example.com 730 me :friend01!f@host"));
+        assert_eq!(vec![PresenceChange::Offline("friend00".to_string())], presence.feed(&parse(":irc.example.com 731 me :friend00")));
+        assert_eq!(vec!["friend01"], presence.online().map(|source| source.name.as_str()).collect::<Vec<&str>>());
+
+        presence.feed(&parse(":irc.example.com 734 me 100 friend98,friend99 :Monitor list is full."));
+        assert!(!presence.is_watched("friend99"));
+        assert_eq!(vec![Command::MONITOR { subcommand: "-".to_string(), targets: Some("friend01".to_string()) }], presence.unmonitor(&["friend01", "friend99"]));
+        assert_eq!(0, presence.online().count());
+        assert_eq!(97, presence.watched().count());
+    }
+}
@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+
+use crate::capability::{Capability, CapabilitySet};
+use crate::channel::{Channel, PREFIX_SYMBOLS};
+use crate::types::{Casemapping, Command, Message, Source};
+
+const MAX_LINE: usize = 512;
+
+pub fn channel_symbol(channel: &Channel) -> &'static str {
+    if channel.modes.secret {
+        return "@";
+    }
+    if channel.modes.private {
+        return "*";
+    }
+    return "="
+}
+
+/// Builds the 353 lines for a channel followed by 366.
+///
+/// `lookup` resolves a member nick to its full source and is only consulted
+/// when the client negotiated `userhost-in-names`.
+pub fn names_reply<F>(server: &Source, client: &str, channel: &Channel, caps: &CapabilitySet, lookup: F) -> Vec<Message>
+where
+    F: Fn(&str) -> Option<Source>,
+{
+    let symbol = channel_symbol(channel).to_string();
+    let mut replies = Vec::new();
+
+    let overhead = Message::new(None, Some(server.clone()), Command::RPL_NAMREPLY {
+        client: client.to_string(),
+        symbol: symbol.clone(),
+        channel: channel.name.clone(),
+        members: Vec::new(),
+    }).to_bytes().len();
+
+    let mut members: Vec<String> = Vec::new();
+    let mut length = overhead;
+
+    for member in channel.members() {
+        let mut entry = String::new();
+        if caps.contains(Capability::MultiPrefix) {
+            entry.push_str(&member.prefixes);
+        } else if let Some(prefix) = member.highest_prefix() {
+            entry.push(prefix);
+        }
+        match lookup(&member.nick).filter(|_| caps.contains(Capability::UserhostInNames)) {
+            Some(source) => entry.push_str(&source.to_string()),
+            None => entry.push_str(&member.nick),
+        }
+
+        if !members.is_empty() && length + 1 + entry.len() > MAX_LINE {
+            replies.push(Command::RPL_NAMREPLY {
+                client: client.to_string(),
+                symbol: symbol.clone(),
+                channel: channel.name.clone(),
+                members: std::mem::take(&mut members),
+            });
+            length = overhead;
+        }
+        length += entry.len() + if members.is_empty() { 0 } else { 1 };
+        members.push(entry);
+    }
+
+    if !members.is_empty() {
+        replies.push(Command::RPL_NAMREPLY { client: client.to_string(), symbol, channel: channel.name.clone(), members });
+    }
+    replies.push(Command::RPL_ENDOFNAMES { client: client.to_string(), channel: channel.name.clone() });
+
+    return replies.into_iter()
+        .map(|command| Message::new(None, Some(server.clone()), command))
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NamesEntry {
+    pub nick: String,
+    pub prefixes: String,
+    pub user: Option<String>,
+    pub host: Option<String>,
+}
+
+impl NamesEntry {
+    /// Parses a single entry such as `@+dan!d@localhost`.
+    pub fn parse(input: &str, prefix_symbols: &str) -> Self {
+        let rest = input.trim_start_matches(|c| prefix_symbols.contains(c));
+        let prefixes = input[..input.len() - rest.len()].to_string();
+
+        let (rest, host) = match rest.split_once('@') {
+            Some((rest, host)) => (rest, Some(host.to_string())),
+            None => (rest, None),
+        };
+        let (nick, user) = match rest.split_once('!') {
+            Some((nick, user)) => (nick, Some(user.to_string())),
+            None => (rest, None),
+        };
+        return NamesEntry { nick: nick.to_string(), prefixes, user, host }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NamesList {
+    pub channel: String,
+    pub symbol: String,
+    pub members: Vec<NamesEntry>,
+}
+
+/// Merges a run of 353 replies into a single list once 366 arrives.
+#[derive(Debug, Default)]
+pub struct NamesCollector {
+    casemapping: Casemapping,
+    prefix_symbols: String,
+    pending: HashMap<String, NamesList>,
+}
+
+impl NamesCollector {
+    pub fn new(casemapping: Casemapping) -> Self {
+        return NamesCollector::with_prefixes(casemapping, PREFIX_SYMBOLS)
+    }
+
+    pub fn with_prefixes(casemapping: Casemapping, prefix_symbols: &str) -> Self {
+        return NamesCollector { casemapping, prefix_symbols: prefix_symbols.to_string(), pending: HashMap::new() }
+    }
+
+    pub fn feed(&mut self, message: &Message) -> Option<NamesList> {
+        match &message.command {
+            Command::RPL_NAMREPLY { symbol, channel, members, .. } => {
+                let list = self.pending.entry(self.casemapping.fold(channel)).or_insert_with(|| NamesList {
+                    channel: channel.clone(),
+                    symbol: symbol.clone(),
+                    members: Vec::new(),
+                });
+                list.members.extend(members.iter().map(|entry| NamesEntry::parse(entry, &self.prefix_symbols)));
+                None
+            }
+            Command::RPL_ENDOFNAMES { channel, .. } => {
+                let list = self.pending.remove(&self.casemapping.fold(channel));
+                Some(list.unwrap_or_else(|| NamesList { channel: channel.clone(), symbol: "=".to_string(), members: Vec::new() }))
+            }
+            _ => None,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::capability::{Capability, CapabilitySet};
+    use crate::channel::{Channel, PREFIX_SYMBOLS};
+    use crate::types::{Casemapping, Source};
+    use crate::test_util::{parse, server};
+
+    use super::{names_reply, NamesCollector, NamesEntry};
+
+    fn lookup(nick: &str) -> Option<Source> {
+        return Some(Source { name: nick.to_string(), user: Some("u".to_string()), host: Some("h".to_string()) })
+    }
+
+    #[test]
+    fn test_names_reply() {
+        let mut channel = Channel::new("#chan".to_string(), "dan".to_string());
+        channel.member_mut("dan").unwrap().add_prefix('@', PREFIX_SYMBOLS);
+        channel.member_mut("dan").unwrap().add_prefix('+', PREFIX_SYMBOLS);
+        channel.add_member("eve".to_string());
+
+        let replies = names_reply(&server(), "eve", &channel, &CapabilitySet::new(), lookup);
+        assert_eq!(":irc.example.com 353 eve = #chan :@dan eve\r\n", replies[0].clone().to_bytes());
+        assert_eq!(":irc.example.com 366 eve #chan\r\n", replies[1].clone().to_bytes());
+
+        let caps: CapabilitySet = [Capability::MultiPrefix, Capability::UserhostInNames].into_iter().collect();
+        let replies = names_reply(&server(), "eve", &channel, &caps, lookup);
+        assert_eq!(":irc.example.com 353 eve = #chan :@+dan!u@h eve!u@h\r\n", replies[0].clone().to_bytes());
+    }
+
+    #[test]
+    fn test_names_split_and_collect() {
+        let mut channel = Channel::new("#chan".to_string(), "user0".to_string());
+        for i in 1..200 {
+            channel.add_member(format!("user{}", i));
+        }
+
+        let replies = names_reply(&server(), "dan", &channel, &CapabilitySet::new(), lookup);
+        assert!(replies.len() > 3);
+
+        let mut collector = NamesCollector::new(Casemapping::Rfc1459);
+        let mut result = None;
+        for reply in replies {
+            let line = reply.to_bytes();
+            assert!(line.len() <= 512);
+            result = collector.feed(&parse(line.trim_end()));
+        }
+        assert_eq!(200, result.unwrap().members.len());
+    }
+
+    #[test]
+    fn test_names_entry() {
+        let entry = NamesEntry::parse("@+dan!d@localhost", "~&@%+");
+        assert_eq!(("dan", "@+"), (entry.nick.as_str(), entry.prefixes.as_str()));
+        assert_eq!(Some("localhost".to_string()), entry.host);
+    }
+}
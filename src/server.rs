@@ -0,0 +1,1257 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use log::{debug, info};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
+
+use crate::batch::BatchBuilder;
+use crate::capability::{Capability, CapabilitySet};
+use crate::channel::{is_channel_name, PREFIX_MODES, PREFIX_SYMBOLS};
+use crate::channel_registry::{Broadcast, ChannelRegistry};
+use crate::chathistory::{conversation_key, HistoryQuery, HistoryRequest, HistoryStore, HistoryWorker, MemoryHistory};
+use crate::connection::{Connection, IRCError};
+use crate::encoding::Decoding;
+use crate::monitor::{monitor_reply, MonitorRegistry};
+use crate::standard_reply::{Severity, StandardReply};
+use crate::tags::ClientTagDeny;
+use crate::time::Timestamp;
+use crate::types::{Casemapping, Command, Message, Source, Tag};
+use crate::unix_time;
+use crate::user::{is_valid_nick, User, UserRegistry, NICKLEN};
+use crate::whois::WhowasHistory;
+
+pub type ClientId = u64;
+
+/// Capabilities whose behaviour is up to the handler of a command, offered
+/// only once one is registered: echoes are sent by whoever routes PRIVMSG.
+const HANDLER_CAPS: &[(Capability, &str)] = &[(Capability::EchoMessage, "PRIVMSG")];
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub name: String,
+    pub network: String,
+    pub version: String,
+    pub password: Option<String>,
+    pub casemapping: Casemapping,
+    pub whowas_capacity: usize,
+    /// Advertise `UTF8ONLY` and answer lines that are not UTF-8 with
+    /// `FAIL <command> INVALID_UTF8` instead of decoding them.
+    pub utf8_only: bool,
+    /// How to decode such lines when `utf8_only` is off.
+    pub decoding: Decoding,
+    /// Messages kept per conversation by the default in-memory history.
+    pub history_capacity: usize,
+    /// Most messages returned by one CHATHISTORY request.
+    pub chathistory_limit: usize,
+    /// Most nicks one client may MONITOR.
+    pub monitor_limit: usize,
+    /// Client-only tags not relayed to other clients, see [`ServerState::relayed_tags`].
+    pub client_tag_deny: ClientTagDeny,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        return ServerConfig {
+            name: "irc.localhost".to_string(),
+            network: "Localnet".to_string(),
+            version: format!("irc_proto-{}", env!("CARGO_PKG_VERSION")),
+            password: None,
+            casemapping: Casemapping::default(),
+            whowas_capacity: 1000,
+            utf8_only: true,
+            decoding: Decoding::Lossy,
+            history_capacity: 1000,
+            chathistory_limit: 100,
+            monitor_limit: 100,
+            client_tag_deny: ClientTagDeny::default(),
+        }
+    }
+}
+
+/// A connection as seen by the server, registered or not.
+#[derive(Debug)]
+pub struct Client {
+    pub id: ClientId,
+    pub address: IpAddr,
+    pub nick: Option<String>,
+    pub username: Option<String>,
+    pub realname: Option<String>,
+    pub password: Option<String>,
+    pub registered: bool,
+    pub caps: CapabilitySet,
+    cap_negotiating: bool,
+    closing: Option<String>,
+    /// Replies held back while a labeled command is handled.
+    labeled: RefCell<Option<Vec<Message>>>,
+    sender: mpsc::UnboundedSender<Message>,
+}
+
+impl Client {
+    /// The nick to address replies to, `*` before one was chosen.
+    pub fn name(&self) -> String {
+        return self.nick.clone().unwrap_or_else(|| "*".to_string())
+    }
+
+    /// Queues `message`, adding `time` and dropping the tags this client did
+    /// not negotiate a capability for. Commands that need a capability, such
+    /// as `BATCH` or `AWAY`, are left out without it, and JOINs lose their
+    /// `extended-join` fields.
+    pub fn send(&self, mut message: Message) {
+        if let Some(replies) = self.labeled.borrow_mut().as_mut() {
+            replies.push(message);
+            return;
+        }
+        if Capability::for_command(&message.command).is_some_and(|cap| !self.caps.contains(cap)) {
+            return;
+        }
+        if let Command::JOIN { account, realname, .. } = &mut message.command {
+            if !self.caps.contains(Capability::ExtendedJoin) {
+                (*account, *realname) = (None, None);
+            }
+        }
+        if self.caps.contains(Capability::ServerTime) && !message.has_tag("time") {
+            message.set_server_time(Timestamp::now());
+        }
+        if let Some(tags) = &mut message.tags {
+            tags.retain(|tag| self.caps.contains(Capability::for_tag(&tag.key)));
+            if tags.is_empty() {
+                message.tags = None;
+            }
+        }
+        _ = self.sender.send(message);
+    }
+}
+
+pub struct ServerState {
+    pub config: ServerConfig,
+    pub users: UserRegistry,
+    pub channels: ChannelRegistry,
+    pub whowas: WhowasHistory,
+    pub history: HistoryWorker,
+    pub monitors: MonitorRegistry,
+    /// Offered in `CAP LS`.
+    capabilities: CapabilitySet,
+    clients: HashMap<ClientId, Client>,
+    nicks: HashMap<String, ClientId>,
+    next_id: ClientId,
+    msgid_prefix: String,
+    next_msgid: AtomicU64,
+    next_batch: AtomicU64,
+}
+
+impl ServerState {
+    pub fn new(config: ServerConfig) -> Self {
+        return ServerState {
+            users: UserRegistry::new(config.casemapping),
+            channels: ChannelRegistry::new(config.casemapping),
+            whowas: WhowasHistory::new(config.casemapping, config.whowas_capacity),
+            history: HistoryWorker::spawn(MemoryHistory::new(config.history_capacity)),
+            monitors: MonitorRegistry::new(config.casemapping, config.monitor_limit),
+            capabilities: Capability::ALL.iter().copied().filter(|cap| !HANDLER_CAPS.iter().any(|(handled, _)| handled == cap)).collect(),
+            clients: HashMap::new(),
+            nicks: HashMap::new(),
+            next_id: 0,
+            msgid_prefix: format!("{:x}", Timestamp::now().millis()),
+            next_msgid: AtomicU64::new(0),
+            next_batch: AtomicU64::new(0),
+            config,
+        }
+    }
+
+    pub fn server_source(&self) -> Source {
+        return Source { name: self.config.name.clone(), user: None, host: None }
+    }
+
+    pub fn client(&self, id: ClientId) -> Option<&Client> {
+        return self.clients.get(&id)
+    }
+
+    pub fn client_mut(&mut self, id: ClientId) -> Option<&mut Client> {
+        return self.clients.get_mut(&id)
+    }
+
+    pub fn client_by_nick(&self, nick: &str) -> Option<&Client> {
+        let id = self.nicks.get(&self.config.casemapping.fold(nick))?;
+        return self.clients.get(id)
+    }
+
+    pub fn clients(&self) -> impl Iterator<Item = &Client> {
+        return self.clients.values()
+    }
+
+    pub fn send_to_nick(&self, nick: &str, message: Message) -> bool {
+        match self.client_by_nick(nick) {
+            Some(client) => {
+                client.send(message);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Gives `message` a `time`, a unique `msgid` and the `account` of its
+    /// sender unless it has them; stamp once and send the result to every
+    /// recipient.
+    pub fn stamp(&self, mut message: Message) -> Message {
+        if !message.has_tag("account") {
+            let user = message.source.as_ref().and_then(|source| self.users.get(&source.name));
+            if let Some(account) = user.and_then(|user| user.account.clone()) {
+                message.set_account(&account);
+            }
+        }
+        if !message.has_tag("time") {
+            message.set_server_time(Timestamp::now());
+        }
+        if !message.has_tag("msgid") {
+            let id = self.next_msgid.fetch_add(1, Ordering::Relaxed);
+            message.set_msgid(&format!("{}-{}", self.msgid_prefix, id));
+        }
+        return message
+    }
+
+    /// A reference for a [`BatchBuilder`](crate::batch::BatchBuilder), unique
+    /// for the lifetime of the server.
+    pub fn batch_reference(&self) -> String {
+        return format!("b{}", self.next_batch.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// The client-only tags of `message`, such as `+typing`, that may be
+    /// passed on to its recipients. Those without `message-tags` never see
+    /// them, nor TAGMSG at all.
+    pub fn relayed_tags(&self, message: &Message) -> Vec<Tag> {
+        return message.client_tags()
+            .filter(|tag| self.config.client_tag_deny.allows(&tag.key.to_string()))
+            .cloned()
+            .collect()
+    }
+
+    /// Stores a stamped PRIVMSG or NOTICE that `nick` sent to `target`, in
+    /// the background. Private messages are kept for the accounts of both
+    /// sides, and not at all for those not logged in.
+    pub fn record_history(&self, nick: &str, target: &str, message: &Message) {
+        let casemapping = self.config.casemapping;
+        if is_channel_name(target) {
+            self.history.append(conversation_key(casemapping, "", target), message.clone());
+            return;
+        }
+        let account = |nick: &str| self.users.get(nick).and_then(|user| user.account.clone());
+        let mut keys: Vec<String> = [(account(nick), target), (account(target), nick)].into_iter()
+            .filter_map(|(account, peer)| Some(conversation_key(casemapping, &account?, peer)))
+            .collect();
+        keys.dedup();
+        for key in keys {
+            self.history.append(key, message.clone());
+        }
+    }
+
+    /// Tells the clients monitoring `nick` that it came online as `source`,
+    /// or went offline when `source` is `None`.
+    pub fn notify_monitors(&self, nick: &str, source: Option<&Source>) {
+        for id in self.monitors.watchers(nick) {
+            let Some(client) = self.clients.get(&id) else { continue };
+            let command = match source {
+                Some(source) => Command::RPL_MONONLINE { client: client.name(), targets: source.to_string() },
+                None => Command::RPL_MONOFFLINE { client: client.name(), targets: nick.to_string() },
+            };
+            client.send(Message::new(None, Some(self.server_source()), command));
+        }
+    }
+
+    /// Sends `command` from `source` to everyone sharing a channel with it,
+    /// and to the user itself when `echo`.
+    fn notify_neighbours(&self, source: &Source, command: Command, echo: bool) {
+        let mut recipients = self.channels.neighbours(&source.name);
+        if echo {
+            recipients.push(source.name.clone());
+        }
+        self.deliver(vec![Broadcast { recipients, message: Message::new(None, Some(source.clone()), command) }]);
+    }
+
+    /// Marks `nick` as away with `message`, or back when `None`, telling
+    /// its `away-notify` neighbours.
+    pub fn set_away(&mut self, nick: &str, message: Option<String>) {
+        let Some(user) = self.users.get_mut(nick) else { return };
+        if user.away == message {
+            return;
+        }
+        user.away = message.clone();
+        let source = user.source();
+        self.notify_neighbours(&source, Command::AWAY { message }, false);
+    }
+
+    /// Logs `nick` into `account`, or out when `None`, telling its
+    /// `account-notify` neighbours.
+    pub fn set_account(&mut self, nick: &str, account: Option<String>) {
+        let Some(user) = self.users.get_mut(nick) else { return };
+        user.account = account.clone();
+        let source = user.source();
+        self.notify_neighbours(&source, Command::ACCOUNT { account: account.unwrap_or_else(|| "*".to_string()) }, false);
+    }
+
+    /// Changes the username and host of `nick`, e.g. for a cloak, telling
+    /// the user and its `chghost` neighbours.
+    pub fn change_host(&mut self, nick: &str, username: &str, host: &str) {
+        let Some(user) = self.users.get_mut(nick) else { return };
+        let source = user.source();
+        user.user = username.to_string();
+        user.host = host.to_string();
+        self.notify_neighbours(&source, Command::CHGHOST { user: username.to_string(), host: host.to_string() }, true);
+    }
+
+    /// Changes the realname of `nick`, telling the user and its `setname`
+    /// neighbours.
+    pub fn set_realname(&mut self, nick: &str, realname: &str) {
+        let Some(user) = self.users.get_mut(nick) else { return };
+        user.realname = realname.to_string();
+        let source = user.source();
+        self.notify_neighbours(&source, Command::SETNAME { realname: realname.to_string() }, true);
+    }
+
+    /// Stamps and sends every broadcast. JOINs get the account and realname
+    /// of the joining user for `extended-join`, followed by its AWAY when
+    /// it is away.
+    pub fn deliver(&self, broadcasts: Vec<Broadcast>) {
+        for broadcast in broadcasts {
+            let mut message = self.stamp(broadcast.message);
+            let mut away = None;
+            if let (Command::JOIN { account, realname: realname @ None, .. }, Some(source)) = (&mut message.command, &message.source) {
+                if let Some(user) = self.users.get(&source.name) {
+                    (*account, *realname) = (user.account.clone(), Some(user.realname.clone()));
+                    away = user.away.clone().map(|away| (source.name.clone(), Message::new(None, Some(user.source()), Command::AWAY { message: Some(away) })));
+                }
+            }
+            for recipient in &broadcast.recipients {
+                self.send_to_nick(recipient, message.clone());
+            }
+            if let Some((joined, away)) = away {
+                let away = self.stamp(away);
+                for recipient in broadcast.recipients.iter().filter(|recipient| !self.config.casemapping.equals(recipient, &joined)) {
+                    self.send_to_nick(recipient, away.clone());
+                }
+            }
+        }
+    }
+
+    fn add_client(&mut self, address: IpAddr, sender: mpsc::UnboundedSender<Message>) -> ClientId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.clients.insert(id, Client {
+            id,
+            address,
+            nick: None,
+            username: None,
+            realname: None,
+            password: None,
+            registered: false,
+            caps: CapabilitySet::new(),
+            cap_negotiating: false,
+            closing: None,
+            labeled: RefCell::new(None),
+            sender,
+        });
+        return id
+    }
+
+    /// Forgets a client, announcing its QUIT to everyone sharing a channel.
+    fn remove_client(&mut self, id: ClientId, reason: &str) {
+        let Some(client) = self.clients.remove(&id) else { return };
+        self.monitors.clear(id);
+        let Some(nick) = client.nick else { return };
+        self.nicks.remove(&self.config.casemapping.fold(&nick));
+
+        if let Some(user) = self.users.remove(&nick) {
+            let broadcasts = self.channels.quit(&user.source(), Some(reason.to_string()));
+            self.deliver(broadcasts);
+            self.whowas.record(&user, unix_time());
+            self.notify_monitors(&nick, None);
+        }
+    }
+}
+
+/// Handed to a [`CommandHandler`] for the duration of one message.
+pub struct Context<'a> {
+    pub state: &'a mut ServerState,
+    pub client_id: ClientId,
+}
+
+impl Context<'_> {
+    pub fn client(&self) -> &Client {
+        return self.state.client(self.client_id).unwrap()
+    }
+
+    pub fn client_mut(&mut self) -> &mut Client {
+        return self.state.client_mut(self.client_id).unwrap()
+    }
+
+    pub fn nick(&self) -> String {
+        return self.client().name()
+    }
+
+    pub fn user(&self) -> Option<&User> {
+        return self.state.users.get(self.client().nick.as_deref()?)
+    }
+
+    /// The `nick!user@host` prefix for messages originating from this client.
+    pub fn source(&self) -> Source {
+        match self.user() {
+            Some(user) => user.source(),
+            None => Source { name: self.nick(), user: None, host: None },
+        }
+    }
+
+    pub fn send(&self, message: Message) {
+        self.client().send(message);
+    }
+
+    /// Sends a command to this client with the server as source.
+    pub fn reply(&self, command: Command) {
+        self.send(Message::new(None, Some(self.state.server_source()), command));
+    }
+
+    /// Sends a `FAIL`, `WARN` or `NOTE` to this client.
+    pub fn standard_reply(&self, reply: StandardReply) {
+        self.reply(reply.to_command());
+    }
+
+    /// Sends `FAIL <command> <code> [context...]` with the registered
+    /// description of `code`.
+    pub fn fail(&self, command: &str, code: &str, context: &[&str]) {
+        self.send_standard(Severity::Fail, command, code, context);
+    }
+
+    pub fn warn(&self, command: &str, code: &str, context: &[&str]) {
+        self.send_standard(Severity::Warn, command, code, context);
+    }
+
+    pub fn note(&self, command: &str, code: &str, context: &[&str]) {
+        self.send_standard(Severity::Note, command, code, context);
+    }
+
+    fn send_standard(&self, severity: Severity, command: &str, code: &str, context: &[&str]) {
+        let mut reply = StandardReply::new(severity, command, code);
+        reply.context = context.iter().map(|param| param.to_string()).collect();
+        self.standard_reply(reply);
+    }
+
+    /// Sends the batch to this client, or just its messages if the client
+    /// did not negotiate `batch`.
+    pub fn send_batch(&self, batch: BatchBuilder) {
+        for message in batch.build(&mut || self.state.batch_reference()) {
+            self.send(message);
+        }
+    }
+
+    pub fn deliver(&self, broadcasts: Vec<Broadcast>) {
+        self.state.deliver(broadcasts);
+    }
+
+    /// Closes the connection once the pending output has been flushed.
+    pub fn quit(&mut self, reason: &str) {
+        self.client_mut().closing = Some(reason.to_string());
+    }
+}
+
+pub trait CommandHandler: Send + Sync {
+    fn handle(&self, ctx: &mut Context<'_>, message: &Message);
+}
+
+impl<F> CommandHandler for F
+where
+    F: Fn(&mut Context<'_>, &Message) + Send + Sync,
+{
+    fn handle(&self, ctx: &mut Context<'_>, message: &Message) {
+        self(ctx, message)
+    }
+}
+
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    sender: Arc<watch::Sender<Option<String>>>,
+}
+
+impl ShutdownHandle {
+    /// Stops accepting connections and sends `ERROR` with `reason` to every client.
+    pub fn shutdown(&self, reason: &str) {
+        _ = self.sender.send(Some(reason.to_string()));
+    }
+}
+
+struct Handlers {
+    commands: HashMap<String, Arc<dyn CommandHandler>>,
+    registered: Vec<Arc<dyn CommandHandler>>,
+}
+
+/// Accepts connections, performs registration and dispatches every other
+/// command to the handler registered for it. AWAY, SETNAME, MONITOR and
+/// CHATHISTORY are handled out of the box.
+pub struct IrcServer {
+    state: Arc<Mutex<ServerState>>,
+    handlers: Handlers,
+    shutdown: Arc<watch::Sender<Option<String>>>,
+}
+
+impl IrcServer {
+    pub fn new(config: ServerConfig) -> Self {
+        return IrcServer {
+            state: Arc::new(Mutex::new(ServerState::new(config))),
+            handlers: Handlers { commands: HashMap::new(), registered: Vec::new() },
+            shutdown: Arc::new(watch::channel(None).0),
+        }
+    }
+
+    /// Handles `command` with `handler`, also replacing the built-in one for
+    /// AWAY, SETNAME, MONITOR or CHATHISTORY.
+    pub fn handler<H: CommandHandler + 'static>(mut self, command: &str, handler: H) -> Self {
+        let command = command.to_uppercase();
+        let mut state = self.state.lock().unwrap();
+        for (cap, _) in HANDLER_CAPS.iter().filter(|(_, handled)| *handled == command) {
+            state.capabilities.insert(*cap);
+        }
+        drop(state);
+        self.handlers.commands.insert(command, Arc::new(handler));
+        return self
+    }
+
+    /// Runs `handler` with the message that completed a client's registration,
+    /// after the welcome burst has been sent.
+    pub fn on_registered<H: CommandHandler + 'static>(mut self, handler: H) -> Self {
+        self.handlers.registered.push(Arc::new(handler));
+        return self
+    }
+
+    /// Replaces the in-memory history, e.g. with an SQLite one, which then
+    /// runs on its own thread.
+    pub fn history<S: HistoryStore + 'static>(self, store: S) -> Self {
+        self.state.lock().unwrap().history = HistoryWorker::spawn(store);
+        return self
+    }
+
+    pub fn state(&self) -> Arc<Mutex<ServerState>> {
+        return self.state.clone()
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        return ShutdownHandle { sender: self.shutdown.clone() }
+    }
+
+    pub async fn run(self, listener: TcpListener) -> io::Result<()> {
+        let handlers = Arc::new(self.handlers);
+        let mut shutdown = self.shutdown.subscribe();
+        let mut tasks = JoinSet::new();
+        info!("Server listening on {}", listener.local_addr()?);
+
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, address) = accepted?;
+                    let session = Session {
+                        state: self.state.clone(),
+                        handlers: handlers.clone(),
+                        shutdown: self.shutdown.subscribe(),
+                    };
+                    tasks.spawn(session.run(stream, address));
+                }
+                _ = shutdown.changed() => break,
+            }
+        }
+
+        while tasks.join_next().await.is_some() {}
+        return Ok(())
+    }
+}
+
+struct Session {
+    state: Arc<Mutex<ServerState>>,
+    handlers: Arc<Handlers>,
+    shutdown: watch::Receiver<Option<String>>,
+}
+
+impl Session {
+    async fn run(mut self, stream: TcpStream, address: SocketAddr) {
+        let mut connection = Connection::new(stream, address);
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let id = {
+            let mut state = self.state.lock().unwrap();
+            connection.set_decoding(if state.config.utf8_only { Decoding::Strict } else { state.config.decoding });
+            state.add_client(address.ip(), sender)
+        };
+        debug!("client {} connected from {}", id, address);
+
+        let reason = loop {
+            let shutdown = self.shutdown.borrow_and_update().clone();
+            if let Some(reason) = shutdown {
+                _ = connection.write(Message::new(None, None, Command::ERROR { reason: reason.clone() })).await;
+                break reason;
+            }
+
+            tokio::select! {
+                read = connection.read() => match read {
+                    Ok(message) => {
+                        if let Some(reason) = self.dispatch(id, &message).await {
+                            while let Ok(pending) = receiver.try_recv() {
+                                _ = connection.write(pending).await;
+                            }
+                            _ = connection.write(Message::new(None, None, Command::ERROR { reason: reason.clone() })).await;
+                            break reason;
+                        }
+                    }
+                    Err(IRCError::LengthExceeded) => {
+                        let mut state = self.state.lock().unwrap();
+                        let ctx = Context { state: &mut state, client_id: id };
+                        ctx.reply(Command::ERR_INPUTTOOLONG { client: ctx.nick() });
+                    }
+                    Err(IRCError::InvalidUtf8) => {
+                        let command = connection.take_rejected().map(|message| message.command.command());
+                        let mut state = self.state.lock().unwrap();
+                        let ctx = Context { state: &mut state, client_id: id };
+                        ctx.fail(command.as_deref().unwrap_or("*"), "INVALID_UTF8", &[]);
+                    }
+                    Err(_) => break "Connection closed".to_string(),
+                },
+                Some(outgoing) = receiver.recv() => {
+                    if connection.write(outgoing).await.is_err() {
+                        break "Write error".to_string();
+                    }
+                }
+                _ = self.shutdown.changed() => {}
+            }
+        };
+
+        connection.shutdown().await;
+        self.state.lock().unwrap().remove_client(id, &reason);
+        debug!("client {} disconnected: {}", id, reason);
+    }
+
+    /// Handles one message, returning the close reason if the client is done.
+    /// A CHATHISTORY query is answered with the state unlocked.
+    async fn dispatch(&self, id: ClientId, message: &Message) -> Option<String> {
+        let (labeled, lookup) = self.handle(id, message);
+        let answer = match lookup {
+            Some(lookup) => Some(lookup.history.run(move |store| lookup.request.answer(&lookup.server, store)).await),
+            None => None,
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let ctx = Context { state: &mut state, client_id: id };
+        let label = labeled.map(|(label, replies)| {
+            *ctx.client().labeled.borrow_mut() = Some(replies);
+            label
+        });
+        match answer {
+            Some(Some(batch)) => ctx.send_batch(batch),
+            Some(None) => ctx.fail("CHATHISTORY", "MESSAGE_ERROR", &[]),
+            None => {}
+        }
+        if let Some(label) = label {
+            send_labeled(&ctx, &label);
+        }
+        return ctx.client().closing.clone()
+    }
+
+    /// Runs the handler for `message` and takes back the replies held for
+    /// its label, so that nothing else is held while the state is unlocked.
+    fn handle(&self, id: ClientId, message: &Message) -> (Option<(String, Vec<Message>)>, Option<HistoryLookup>) {
+        let mut state = self.state.lock().unwrap();
+        let mut ctx = Context { state: &mut state, client_id: id };
+
+        let label = message.label().filter(|_| ctx.client().caps.contains(Capability::LabeledResponse)).map(str::to_string);
+        if label.is_some() {
+            *ctx.client().labeled.borrow_mut() = Some(Vec::new());
+        }
+
+        let mut lookup = None;
+        match &message.command {
+            Command::CAP { subcommand, capabilities, .. } => cap(&mut ctx, subcommand, capabilities.as_deref().unwrap_or("")),
+            Command::PASS { password } => {
+                if ctx.client().registered {
+                    ctx.reply(Command::ERR_ALREADYREGISTERED { client: ctx.nick() });
+                } else {
+                    ctx.client_mut().password = Some(password.clone());
+                }
+            }
+            Command::NICK { nickname } => nick(&mut ctx, nickname),
+            Command::USER { user, realname, .. } => {
+                if ctx.client().registered {
+                    ctx.reply(Command::ERR_ALREADYREGISTERED { client: ctx.nick() });
+                } else {
+                    ctx.client_mut().username = Some(user.clone());
+                    ctx.client_mut().realname = Some(realname.clone());
+                }
+            }
+            Command::PING { token } => {
+                let server = ctx.state.config.name.clone();
+                ctx.reply(Command::PONG { server: Some(server), token: token.clone() });
+            }
+            Command::PONG { .. } => {}
+            Command::QUIT { reason } => {
+                let reason = format!("Quit: {}", reason.as_deref().unwrap_or(""));
+                ctx.quit(&reason);
+            }
+            command if !ctx.client().registered => {
+                debug!("dropping {} from unregistered client {}", command.command(), id);
+                ctx.reply(Command::ERR_NOTREGISTERED { client: ctx.nick() });
+            }
+            command => {
+                if let Some(user) = ctx.state.users.get_mut(&ctx.state.clients[&id].name()) {
+                    user.last_active = unix_time();
+                }
+                match (self.handlers.commands.get(&command.command()), command) {
+                    (Some(handler), _) => handler.handle(&mut ctx, message),
+                    (None, Command::AWAY { message }) => away(&mut ctx, message.as_deref()),
+                    (None, Command::SETNAME { realname }) => setname(&mut ctx, realname),
+                    (None, Command::MONITOR { subcommand, targets }) => monitor(&mut ctx, subcommand, targets.as_deref()),
+                    (None, Command::CHATHISTORY { subcommand, params }) => lookup = chathistory(&mut ctx, subcommand, params),
+                    (None, command) => ctx.reply(Command::ERR_UNKNOWNCOMMAND { client: ctx.nick(), command: command.command() }),
+                }
+            }
+        }
+
+        if !ctx.client().registered && try_register(&mut ctx) {
+            for handler in &self.handlers.registered {
+                handler.handle(&mut ctx, message);
+            }
+        }
+        let labeled = label.map(|label| (label, ctx.client().labeled.borrow_mut().take().unwrap_or_default()));
+        return (labeled, lookup)
+    }
+}
+
+/// Sends the replies held back for a labeled command: `ACK` if there were
+/// none, the single reply with the label, or a `labeled-response` batch.
+fn send_labeled(ctx: &Context<'_>, label: &str) {
+    let mut replies = ctx.client().labeled.borrow_mut().take().unwrap_or_default();
+    match replies.len() {
+        0 => ctx.send(Message::new(None, Some(ctx.state.server_source()), Command::ACK).with_tag("label", Some(label))),
+        1 => ctx.send(replies.remove(0).with_tag("label", Some(label))),
+        // The batch would be dropped, so at least tie the first reply to the label.
+        _ if !ctx.client().caps.contains(Capability::Batch) => {
+            let rest = replies.split_off(1);
+            ctx.send(replies.remove(0).with_tag("label", Some(label)));
+            for reply in rest {
+                ctx.send(reply);
+            }
+        }
+        _ => ctx.send_batch(BatchBuilder::new("labeled-response", Vec::new())
+            .source(ctx.state.server_source())
+            .tag("label", Some(label))
+            .messages(replies)),
+    }
+}
+
+fn cap(ctx: &mut Context<'_>, subcommand: &str, capabilities: &str) {
+    let target = Some(ctx.nick());
+    let reply = |subcommand: &str, capabilities: String| Command::CAP {
+        target: target.clone(),
+        subcommand: subcommand.to_string(),
+        capabilities: Some(capabilities),
+    };
+
+    match subcommand {
+        "LS" => {
+            if !ctx.client().registered {
+                ctx.client_mut().cap_negotiating = true;
+            }
+            let offered: Vec<&str> = Capability::ALL.iter()
+                .filter(|cap| ctx.state.capabilities.contains(**cap))
+                .map(|cap| cap.name())
+                .collect();
+            ctx.reply(reply("LS", offered.join(" ")));
+        }
+        "LIST" => {
+            let enabled: Vec<&str> = ctx.client().caps.iter().map(|cap| cap.name()).collect();
+            ctx.reply(reply("LIST", enabled.join(" ")));
+        }
+        "REQ" => {
+            if !ctx.client().registered {
+                ctx.client_mut().cap_negotiating = true;
+            }
+            let offered = |name: &str| Capability::from_name(name).filter(|cap| ctx.state.capabilities.contains(*cap));
+            let requested: Vec<(bool, Option<Capability>)> = capabilities.split_whitespace()
+                .map(|name| match name.strip_prefix('-') {
+                    Some(name) => (false, offered(name)),
+                    None => (true, offered(name)),
+                })
+                .collect();
+
+            if requested.iter().any(|(_, cap)| cap.is_none()) {
+                ctx.reply(reply("NAK", capabilities.to_string()));
+                return;
+            }
+            for (enable, cap) in requested {
+                if enable {
+                    ctx.client_mut().caps.insert(cap.unwrap());
+                } else {
+                    ctx.client_mut().caps.remove(cap.unwrap());
+                }
+            }
+            ctx.reply(reply("ACK", capabilities.to_string()));
+        }
+        "END" => ctx.client_mut().cap_negotiating = false,
+        _ => {}
+    }
+}
+
+fn nick(ctx: &mut Context<'_>, nickname: &str) {
+    let client = ctx.nick();
+    if !is_valid_nick(nickname) {
+        ctx.reply(Command::ERR_ERRONEUSNICKNAME { client, nick: nickname.to_string() });
+        return;
+    }
+
+    let casemapping = ctx.state.config.casemapping;
+    let folded = casemapping.fold(nickname);
+    if ctx.state.nicks.get(&folded).is_some_and(|owner| *owner != ctx.client_id) {
+        ctx.reply(Command::ERR_NICKNAMEINUSE { client, nick: nickname.to_string() });
+        return;
+    }
+
+    let old = ctx.client().nick.clone();
+    if let Some(old) = &old {
+        ctx.state.nicks.remove(&casemapping.fold(old));
+    }
+    ctx.state.nicks.insert(folded, ctx.client_id);
+    ctx.client_mut().nick = Some(nickname.to_string());
+
+    if let (Some(old), true) = (old, ctx.client().registered) {
+        let user = ctx.state.users.get(&old).unwrap();
+        let source = user.source();
+        ctx.state.whowas.record(user, unix_time());
+        ctx.state.users.rename(&old, nickname);
+
+        let broadcasts = ctx.state.channels.nick_change(&source, nickname);
+        ctx.deliver(broadcasts);
+        ctx.state.notify_monitors(&old, None);
+        ctx.state.notify_monitors(nickname, Some(&ctx.source()));
+    }
+}
+
+fn away(ctx: &mut Context<'_>, message: Option<&str>) {
+    let away = message.filter(|away| !away.is_empty()).map(str::to_string);
+    let client = ctx.nick();
+    let reply = match away {
+        Some(_) => Command::RPL_NOWAWAY { client: client.clone(), message: "You have been marked as being away".to_string() },
+        None => Command::RPL_UNAWAY { client: client.clone(), message: "You are no longer marked as being away".to_string() },
+    };
+    ctx.state.set_away(&client, away);
+    ctx.reply(reply);
+}
+
+fn setname(ctx: &mut Context<'_>, realname: &str) {
+    if realname.is_empty() || !ctx.client().caps.contains(Capability::SetName) {
+        ctx.fail("SETNAME", "INVALID_REALNAME", &[]);
+        return;
+    }
+    let nick = ctx.nick();
+    ctx.state.set_realname(&nick, realname);
+}
+
+fn monitor(ctx: &mut Context<'_>, subcommand: &str, targets: Option<&str>) {
+    let (server, nick, id) = (ctx.state.server_source(), ctx.nick(), ctx.client_id);
+    let state = &mut ctx.state;
+    for reply in monitor_reply(&server, id, &nick, subcommand, targets, &mut state.monitors, &state.users) {
+        ctx.send(reply);
+    }
+}
+
+/// A CHATHISTORY query to answer once the server state is unlocked.
+struct HistoryLookup {
+    history: HistoryWorker,
+    server: Source,
+    request: HistoryRequest,
+}
+
+fn chathistory(ctx: &mut Context<'_>, subcommand: &str, params: &[String]) -> Option<HistoryLookup> {
+    let mut query = match HistoryQuery::parse(subcommand, params) {
+        Ok(query) => query,
+        Err(error) => {
+            ctx.reply(error.to_command());
+            return None;
+        }
+    };
+    query.clamp_limit(ctx.state.config.chathistory_limit);
+    let account = ctx.user().and_then(|user| user.account.clone());
+    return match HistoryRequest::new(&ctx.nick(), account.as_deref(), query, &ctx.state.channels) {
+        Ok(request) => Some(HistoryLookup { history: ctx.state.history.clone(), server: ctx.state.server_source(), request }),
+        Err(error) => {
+            ctx.reply(error.to_command());
+            None
+        }
+    }
+}
+
+fn try_register(ctx: &mut Context<'_>) -> bool {
+    let client = ctx.client();
+    let (Some(nick), Some(username), Some(realname)) = (client.nick.clone(), client.username.clone(), client.realname.clone()) else {
+        return false;
+    };
+    if client.cap_negotiating {
+        return false;
+    }
+
+    if ctx.state.config.password.is_some() && client.password != ctx.state.config.password {
+        ctx.reply(Command::ERR_PASSWDMISMATCH { client: nick });
+        ctx.quit("Bad password");
+        return false;
+    }
+
+    let config = ctx.state.config.clone();
+    let now = unix_time();
+    let mut user = User::new(nick.clone(), username, client.address.to_string(), realname, config.name.clone());
+    user.ip = Some(client.address);
+    user.signon = now;
+    user.last_active = now;
+    if ctx.state.users.insert(user).is_err() {
+        ctx.reply(Command::ERR_NICKNAMEINUSE { client: nick.clone(), nick });
+        return false;
+    }
+    ctx.client_mut().registered = true;
+
+    let source = ctx.source();
+    ctx.state.notify_monitors(&nick, Some(&source));
+    ctx.reply(Command::RPL_WELCOME { client: nick.clone(), message: format!("Welcome to the {} Network, {}", config.network, source) });
+    ctx.reply(Command::RPL_YOURHOST { client: nick.clone(), message: format!("Your host is {}, running version {}", config.name, config.version) });
+    ctx.reply(Command::RPL_CREATED { client: nick.clone(), message: "This server was created at startup".to_string() });
+    ctx.reply(Command::RPL_MYINFO {
+        client: nick.clone(),
+        servername: config.name.clone(),
+        version: config.version.clone(),
+        user_modes: "io".to_string(),
+        channel_modes: "beIiklmnpst".to_string(),
+    });
+    ctx.reply(Command::RPL_ISUPPORT {
+        client: nick,
+        tokens: vec![
+            format!("CASEMAPPING={}", config.casemapping.name()),
+            "CHANTYPES=#&".to_string(),
+            "CHANMODES=beI,k,l,imnpst".to_string(),
+            format!("PREFIX=({}){}", PREFIX_MODES, PREFIX_SYMBOLS),
+            format!("NETWORK={}", config.network),
+            format!("NICKLEN={}", NICKLEN),
+            "WHOX".to_string(),
+            format!("CHATHISTORY={}", config.chathistory_limit),
+            "MSGREFTYPES=msgid,timestamp".to_string(),
+            format!("MONITOR={}", config.monitor_limit),
+        ].into_iter()
+            .chain(config.utf8_only.then(|| "UTF8ONLY".to_string()))
+            .chain((!config.client_tag_deny.is_empty()).then(|| format!("CLIENTTAGDENY={}", config.client_tag_deny)))
+            .collect(),
+    });
+    return true
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpStream;
+
+    use crate::connection::Connection;
+    use crate::types::{Command, Message};
+
+    use crate::batch::BatchBuilder;
+    use crate::capability::Capability;
+    use crate::channel_registry::Broadcast;
+    use crate::tags::ClientTagDeny;
+    use crate::test_util::{connect, connect_with_caps, spawn_server};
+
+    use super::{Context, IrcServer, ServerConfig, ServerState};
+
+    #[tokio::test]
+    async fn test_register_dispatch_shutdown() {
+        let server = IrcServer::new(ServerConfig::default())
+            .handler("PRIVMSG", |ctx: &mut Context<'_>, message: &Message| {
+                if let Command::PRIVMSG { targets, text } = &message.command {
+                    let echo = Message::new(None, Some(ctx.source()), Command::PRIVMSG { targets: targets.clone(), text: text.clone() });
+                    ctx.state.send_to_nick(targets, echo);
+                }
+            });
+        assert!(server.state().lock().unwrap().capabilities.contains(Capability::EchoMessage));
+        let shutdown = server.shutdown_handle();
+        let (addr, handle) = spawn_server(server).await;
+
+        let mut dan = connect(addr, "dan").await;
+        let mut eve = connect(addr, "eve").await;
+
+        dan.write(Message::from_bytes(b"NICK EVE").unwrap()).await.unwrap();
+        assert!(matches!(dan.read().await.unwrap().command, Command::ERR_NICKNAMEINUSE { .. }));
+
+        dan.write(Message::from_bytes(b"PRIVMSG eve :hello there").unwrap()).await.unwrap();
+        let message = eve.read().await.unwrap();
+        assert_eq!(":dan!dan@127.0.0.1 PRIVMSG eve :hello there\r\n", message.to_bytes());
+
+        dan.write(Message::from_bytes(b"WHOWAS bob").unwrap()).await.unwrap();
+        assert!(matches!(dan.read().await.unwrap().command, Command::ERR_UNKNOWNCOMMAND { .. }));
+
+        shutdown.shutdown("Server going down");
+        let message = tokio::time::timeout(Duration::from_secs(5), eve.read()).await.unwrap().unwrap();
+        assert_eq!(Command::ERROR { reason: "Server going down".to_string() }, message.command);
+        tokio::time::timeout(Duration::from_secs(5), handle).await.unwrap().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unregistered() {
+        let server = IrcServer::new(ServerConfig { password: Some("secret".to_string()), ..Default::default() });
+        let (addr, _) = spawn_server(server).await;
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut client = Connection::new(stream, addr);
+        client.write(Message::from_bytes(b"JOIN #chan").unwrap()).await.unwrap();
+        assert!(matches!(client.read().await.unwrap().command, Command::ERR_NOTREGISTERED { .. }));
+
+        // echo-message is up to a PRIVMSG handler, and there is none.
+        client.write(Message::from_bytes(b"CAP LS 302").unwrap()).await.unwrap();
+        let Command::CAP { capabilities: Some(offered), .. } = client.read().await.unwrap().command else { panic!() };
+        assert!(offered.split(' ').any(|cap| cap == "draft/chathistory"));
+        assert!(!offered.split(' ').any(|cap| cap == "echo-message"));
+        client.write(Message::from_bytes(b"CAP REQ echo-message").unwrap()).await.unwrap();
+        assert_eq!(":irc.localhost CAP * NAK echo-message\r\n", client.read().await.unwrap().to_bytes());
+        for line in ["NICK dan", "USER dan 0 * :Dan", "CAP END"] {
+            client.write(Message::from_bytes(line.as_bytes()).unwrap()).await.unwrap();
+        }
+        assert!(matches!(client.read().await.unwrap().command, Command::ERR_PASSWDMISMATCH { .. }));
+        assert!(matches!(client.read().await.unwrap().command, Command::ERROR { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_utf8_only() {
+        let (addr, _) = spawn_server(IrcServer::new(ServerConfig::default())).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"NICK dan\r\nUSER dan 0 * :Dan\r\nPRIVMSG eve :caf\xe9\r\n").await.unwrap();
+        let mut client = Connection::new(stream, addr);
+        let isupport = loop {
+            if let Command::RPL_ISUPPORT { tokens, .. } = client.read().await.unwrap().command {
+                break tokens;
+            }
+        };
+        assert!(isupport.contains(&"UTF8ONLY".to_string()));
+        let message = client.read().await.unwrap();
+        assert_eq!(":irc.localhost FAIL PRIVMSG INVALID_UTF8 :Message rejected, your IRC software MUST use UTF-8 encoding on this network\r\n", message.to_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_stamp() {
+        let server = IrcServer::new(ServerConfig::default())
+            .handler("PRIVMSG", |ctx: &mut Context<'_>, message: &Message| {
+                let outgoing = Message::new(message.tags.clone(), Some(ctx.source()), message.command.clone());
+                ctx.deliver(vec![Broadcast { recipients: vec!["dan".to_string(), "eve".to_string()], message: outgoing }]);
+            });
+        let (addr, _) = spawn_server(server).await;
+
+        let mut dan = connect_with_caps(addr, "dan", &["server-time", "message-tags"]).await;
+        let mut eve = connect(addr, "eve").await;
+        let mut bob = connect_with_caps(addr, "bob", &["server-time"]).await;
+
+        bob.write(Message::from_bytes(b"@+draft/reply=1 PRIVMSG dan,eve :hi").unwrap()).await.unwrap();
+        let message = dan.read().await.unwrap();
+        assert!(message.server_time().is_some());
+        assert!(message.msgid().is_some());
+        assert_eq!(Some("1"), message.tag_value("+draft/reply"));
+        assert_eq!(None, eve.read().await.unwrap().tags);
+
+        // Replies get a time for clients with server-time, but no msgid.
+        bob.write(Message::from_bytes(b"PING x").unwrap()).await.unwrap();
+        let pong = bob.read().await.unwrap();
+        assert!(pong.server_time().is_some());
+        assert_eq!(1, pong.tags.unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_send_batch() {
+        let server = IrcServer::new(ServerConfig::default())
+            .handler("MOTD", |ctx: &mut Context<'_>, _: &Message| {
+                let notice = Message::new(None, Some(ctx.state.server_source()), Command::NOTICE { targets: ctx.nick(), text: "inside".to_string() });
+                ctx.send_batch(BatchBuilder::new("example.com/test", Vec::new()).source(ctx.state.server_source()).message(notice));
+            });
+        let (addr, _) = spawn_server(server).await;
+
+        let mut dan = connect_with_caps(addr, "dan", &["batch"]).await;
+        dan.write(Message::from_bytes(b"MOTD").unwrap()).await.unwrap();
+        assert_eq!(":irc.localhost BATCH +b0 example.com/test\r\n", dan.read().await.unwrap().to_bytes());
+        assert_eq!("@batch=b0 :irc.localhost NOTICE dan inside\r\n", dan.read().await.unwrap().to_bytes());
+        assert_eq!(":irc.localhost BATCH -b0\r\n", dan.read().await.unwrap().to_bytes());
+
+        let mut eve = connect(addr, "eve").await;
+        eve.write(Message::from_bytes(b"MOTD").unwrap()).await.unwrap();
+        assert_eq!(":irc.localhost NOTICE eve inside\r\n", eve.read().await.unwrap().to_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_labeled_response() {
+        let server = IrcServer::new(ServerConfig::default())
+            .handler("MOTD", |ctx: &mut Context<'_>, _: &Message| {
+                for text in ["one", "two"] {
+                    ctx.reply(Command::NOTICE { targets: ctx.nick(), text: text.to_string() });
+                }
+            });
+        let (addr, _) = spawn_server(server).await;
+
+        let mut dan = connect_with_caps(addr, "dan", &["labeled-response", "batch"]).await;
+        dan.write(Message::from_bytes(b"@label=a PING x").unwrap()).await.unwrap();
+        assert_eq!("@label=a :irc.localhost PONG x irc.localhost\r\n", dan.read().await.unwrap().to_bytes());
+        dan.write(Message::from_bytes(b"@label=b PONG x").unwrap()).await.unwrap();
+        assert_eq!("@label=b :irc.localhost ACK\r\n", dan.read().await.unwrap().to_bytes());
+
+        dan.write(Message::from_bytes(b"@label=c MOTD").unwrap()).await.unwrap();
+        let mut received = Vec::new();
+        for _ in 0..4 {
+            received.push(dan.read().await.unwrap().to_bytes());
+        }
+        assert_eq!(vec![
+            "@label=c :irc.localhost BATCH +b0 labeled-response\r\n",
+            "@batch=b0 :irc.localhost NOTICE dan one\r\n",
+            "@batch=b0 :irc.localhost NOTICE dan two\r\n",
+            ":irc.localhost BATCH -b0\r\n",
+        ], received);
+
+        // Without batch only the first reply carries the label.
+        let mut ann = connect_with_caps(addr, "ann", &["labeled-response"]).await;
+        ann.write(Message::from_bytes(b"@label=d MOTD").unwrap()).await.unwrap();
+        assert_eq!("@label=d :irc.localhost NOTICE ann one\r\n", ann.read().await.unwrap().to_bytes());
+        assert_eq!(":irc.localhost NOTICE ann two\r\n", ann.read().await.unwrap().to_bytes());
+
+        // Without the cap the label is ignored.
+        let mut eve = connect(addr, "eve").await;
+        eve.write(Message::from_bytes(b"@label=a PING x").unwrap()).await.unwrap();
+        assert_eq!(":irc.localhost PONG x irc.localhost\r\n", eve.read().await.unwrap().to_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_monitor() {
+        let server = IrcServer::new(ServerConfig::default());
+        let (addr, _) = spawn_server(server).await;
+
+        let mut dan = connect(addr, "dan").await;
+        dan.write(Message::from_bytes(b"MONITOR + EVE").unwrap()).await.unwrap();
+        assert_eq!(":irc.localhost 731 dan EVE\r\n", dan.read().await.unwrap().to_bytes());
+
+        let mut eve = connect(addr, "eve").await;
+        assert_eq!(":irc.localhost 730 dan eve!eve@127.0.0.1\r\n", dan.read().await.unwrap().to_bytes());
+        eve.write(Message::from_bytes(b"NICK eve2").unwrap()).await.unwrap();
+        assert_eq!(":irc.localhost 731 dan eve\r\n", dan.read().await.unwrap().to_bytes());
+        eve.write(Message::from_bytes(b"NICK eve").unwrap()).await.unwrap();
+        assert_eq!(":irc.localhost 730 dan eve!eve@127.0.0.1\r\n", dan.read().await.unwrap().to_bytes());
+        eve.write(Message::from_bytes(b"QUIT").unwrap()).await.unwrap();
+        assert_eq!(":irc.localhost 731 dan eve\r\n", dan.read().await.unwrap().to_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_notify_caps() {
+        let server = IrcServer::new(ServerConfig::default());
+        let state = server.state();
+        let (addr, _) = spawn_server(server).await;
+
+        let caps = ["away-notify", "account-notify", "chghost", "setname", "extended-join"];
+        let mut dan = connect_with_caps(addr, "dan", &caps).await;
+        let mut eve = connect(addr, "eve").await;
+        let join = |nick: &str, channel: &str| {
+            let mut state = state.lock().unwrap();
+            let source = state.users.get(nick).unwrap().source();
+            let broadcasts = state.channels.join(&source, channel, None).unwrap();
+            state.deliver(broadcasts);
+        };
+        for channel in ["#a", "#b"] {
+            join("dan", channel);
+            assert_eq!(format!(":dan!dan@127.0.0.1 JOIN {} * :Real Name\r\n", channel), dan.read().await.unwrap().to_bytes());
+        }
+        join("eve", "#a");
+        assert_eq!(":eve!eve@127.0.0.1 JOIN #a\r\n", eve.read().await.unwrap().to_bytes());
+        assert_eq!(":eve!eve@127.0.0.1 JOIN #a * :Real Name\r\n", dan.read().await.unwrap().to_bytes());
+
+        eve.write(Message::from_bytes(b"AWAY :lunch").unwrap()).await.unwrap();
+        assert_eq!(":irc.localhost 306 eve :You have been marked as being away\r\n", eve.read().await.unwrap().to_bytes());
+        assert_eq!(":eve!eve@127.0.0.1 AWAY lunch\r\n", dan.read().await.unwrap().to_bytes());
+        join("eve", "#b");
+        assert_eq!(":eve!eve@127.0.0.1 JOIN #b * :Real Name\r\n", dan.read().await.unwrap().to_bytes());
+        assert_eq!(":eve!eve@127.0.0.1 AWAY lunch\r\n", dan.read().await.unwrap().to_bytes());
+
+        {
+            let mut state = state.lock().unwrap();
+            state.set_account("eve", Some("eve".to_string()));
+            state.change_host("eve", "e", "cloak.example");
+            state.set_realname("dan", "Dan");
+        }
+        assert_eq!(":eve!eve@127.0.0.1 ACCOUNT eve\r\n", dan.read().await.unwrap().to_bytes());
+        assert_eq!(":eve!eve@127.0.0.1 CHGHOST e cloak.example\r\n", dan.read().await.unwrap().to_bytes());
+        assert_eq!(":dan!dan@127.0.0.1 SETNAME Dan\r\n", dan.read().await.unwrap().to_bytes());
+
+        // eve negotiated none of the caps and only sees the plain JOIN.
+        dan.write(Message::from_bytes(b"AWAY :busy").unwrap()).await.unwrap();
+        assert_eq!(":irc.localhost 306 dan :You have been marked as being away\r\n", dan.read().await.unwrap().to_bytes());
+        eve.write(Message::from_bytes(b"SETNAME :Eve").unwrap()).await.unwrap();
+        assert_eq!(":eve!eve@127.0.0.1 JOIN #b\r\n", eve.read().await.unwrap().to_bytes());
+        assert_eq!(":irc.localhost FAIL SETNAME INVALID_REALNAME :Realname is not valid\r\n", eve.read().await.unwrap().to_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_chathistory() {
+        let server = IrcServer::new(ServerConfig::default());
+        let state = server.state();
+        let (addr, _) = spawn_server(server).await;
+
+        let mut dan = connect_with_caps(addr, "dan", &["batch", "labeled-response"]).await;
+        {
+            let mut state = state.lock().unwrap();
+            let source = state.users.get("dan").unwrap().source();
+            state.channels.join(&source, "#c", None).unwrap();
+            let message = Message::from_bytes(b"@time=2024-01-01T00:00:00.000Z;msgid=m1 :eve!e@host PRIVMSG #c hi").unwrap();
+            state.record_history("eve", "#c", &message);
+        }
+        dan.write(Message::from_bytes(b"@label=l CHATHISTORY LATEST #c * 10").unwrap()).await.unwrap();
+        let mut received = Vec::new();
+        for _ in 0..5 {
+            received.push(dan.read().await.unwrap().to_bytes());
+        }
+        assert_eq!(vec![
+            "@label=l :irc.localhost BATCH +b1 labeled-response\r\n",
+            "@batch=b1 :irc.localhost BATCH +b0 chathistory #c\r\n",
+            "@batch=b0 :eve!e@host PRIVMSG #c hi\r\n",
+            "@batch=b1 :irc.localhost BATCH -b0\r\n",
+            ":irc.localhost BATCH -b1\r\n",
+        ], received);
+
+        dan.write(Message::from_bytes(b"CHATHISTORY BEFORE #c timestamp=99999999999-01-01T00:00:00Z 10").unwrap()).await.unwrap();
+        assert_eq!(":irc.localhost FAIL CHATHISTORY INVALID_PARAMS timestamp=99999999999-01-01T00:00:00Z :Invalid parameters\r\n", dan.read().await.unwrap().to_bytes());
+        dan.write(Message::from_bytes(b"PING x").unwrap()).await.unwrap();
+        assert_eq!(":irc.localhost PONG x irc.localhost\r\n", dan.read().await.unwrap().to_bytes());
+
+        // Private history stays with the account when someone else takes the nick.
+        {
+            let mut state = state.lock().unwrap();
+            state.set_account("dan", Some("dan".to_string()));
+            let message = Message::from_bytes(b"@time=2024-01-01T00:00:01.000Z;msgid=m2 :dan!dan@127.0.0.1 PRIVMSG eve secret").unwrap();
+            state.record_history("dan", "eve", &message);
+        }
+        dan.write(Message::from_bytes(b"CHATHISTORY LATEST eve * 10").unwrap()).await.unwrap();
+        dan.read().await.unwrap();
+        assert!(dan.read().await.unwrap().to_bytes().ends_with("PRIVMSG eve secret\r\n"));
+        dan.read().await.unwrap();
+        dan.write(Message::from_bytes(b"QUIT").unwrap()).await.unwrap();
+        while !matches!(dan.read().await.unwrap().command, Command::ERROR { .. }) {}
+
+        let mut mallory = connect_with_caps(addr, "dan", &["batch"]).await;
+        mallory.write(Message::from_bytes(b"CHATHISTORY LATEST eve * 10").unwrap()).await.unwrap();
+        assert_eq!(":irc.localhost FAIL CHATHISTORY INVALID_TARGET eve :Messages could not be retrieved\r\n", mallory.read().await.unwrap().to_bytes());
+        state.lock().unwrap().set_account("dan", Some("mallory".to_string()));
+        mallory.write(Message::from_bytes(b"CHATHISTORY LATEST eve * 10").unwrap()).await.unwrap();
+        assert!(mallory.read().await.unwrap().to_bytes().contains("BATCH +"));
+        assert!(mallory.read().await.unwrap().to_bytes().contains("BATCH -"));
+    }
+
+    #[test]
+    fn test_relayed_tags() {
+        let config = ServerConfig { client_tag_deny: ClientTagDeny::parse("*,-draft/react"), ..Default::default() };
+        let state = ServerState::new(config);
+        let message = Message::from_bytes(b"@+draft/react=x;+typing=active;msgid=forged TAGMSG #chan").unwrap();
+        let relayed: Vec<String> = state.relayed_tags(&message).iter().map(|tag| tag.key.to_string()).collect();
+        assert_eq!(vec!["+draft/react"], relayed);
+    }
+}
@@ -0,0 +1,268 @@
+use crate::batch::BatchBuilder;
+use crate::connection::MAX_LINE_LEN;
+use crate::formatting::{code_len, style_after, Style};
+use crate::types::{Command, Message, Source};
+
+/// Limits advertised in the `draft/multiline` capability value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MultilineLimits {
+    pub max_bytes: usize,
+    pub max_lines: Option<usize>,
+}
+
+impl MultilineLimits {
+    /// Parses a capability value such as `max-bytes=4096,max-lines=24`.
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut max_bytes = None;
+        let mut max_lines = None;
+        for item in value.split(',') {
+            match item.split_once('=') {
+                Some(("max-bytes", bytes)) => max_bytes = bytes.parse().ok(),
+                Some(("max-lines", lines)) => max_lines = lines.parse().ok(),
+                _ => {}
+            }
+        }
+        return Some(MultilineLimits { max_bytes: max_bytes?, max_lines })
+    }
+}
+
+/// Splits one line of text into chunks of at most `max_len` bytes, cutting on
+/// character boundaries, outside formatting codes and preferably at a space.
+///
+/// With `standalone` every chunk re-opens the formatting active where the
+/// previous one stopped and the space at a cut is dropped; otherwise the
+/// chunks concatenate back to exactly `line`.
+pub fn split_line(line: &str, max_len: usize, standalone: bool) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut style = Style::default();
+    let mut rest = line;
+
+    loop {
+        let prefix = if standalone { style.codes() } else { String::new() };
+        let budget = max_len.saturating_sub(prefix.len());
+        if rest.len() <= budget {
+            chunks.push(prefix + rest);
+            return chunks;
+        }
+
+        let mut cut = 0;
+        let mut last_space = None;
+        while cut < rest.len() {
+            let code = code_len(&rest[cut..]);
+            let step = if code > 0 { code } else { rest[cut..].chars().next().map(char::len_utf8).unwrap_or(1) };
+            if cut + step > budget {
+                // Always make progress, even if a single code or character does not fit.
+                if cut == 0 {
+                    cut = step;
+                }
+                break;
+            }
+            if code == 0 && rest.as_bytes()[cut] == b' ' && cut > 0 {
+                last_space = Some(cut);
+            }
+            cut += step;
+        }
+
+        let (end, next) = match last_space {
+            Some(space) if standalone => (space, space + 1),
+            Some(space) => (space + 1, space + 1),
+            None => (cut, cut),
+        };
+        chunks.push(prefix + &rest[..end]);
+        if standalone {
+            style = style_after(&rest[..next], &style);
+        }
+        rest = &rest[next..];
+    }
+}
+
+/// Splits PRIVMSG and NOTICE text so every line stays within
+/// [`MAX_LINE_LEN`] once the server relays it with our source prefix.
+#[derive(Debug, Clone)]
+pub struct Splitter {
+    source_len: usize,
+    multiline: Option<MultilineLimits>,
+    next_batch: u64,
+}
+
+impl Splitter {
+    pub fn new(source: &Source) -> Self {
+        return Splitter { source_len: source.to_string().len(), multiline: None, next_batch: 0 }
+    }
+
+    /// Sends long or multi-line text as `draft/multiline` batches; only use
+    /// once the cap (and `batch`) has been negotiated.
+    pub fn multiline(mut self, limits: MultilineLimits) -> Self {
+        self.multiline = Some(limits);
+        return self
+    }
+
+    /// Bytes of text that fit into one relayed line.
+    pub fn max_text_len(&self, command: &str, target: &str) -> usize {
+        // ":<source> <command> <target> :<text>\r\n"
+        let overhead = 1 + self.source_len + 1 + command.len() + 1 + target.len() + 2 + 2;
+        return MAX_LINE_LEN.saturating_sub(overhead)
+    }
+
+    /// Splits a PRIVMSG or NOTICE; other commands are returned as they are.
+    /// Text lines separated by `\n` always end up in separate messages.
+    pub fn split(&mut self, command: Command) -> Vec<Message> {
+        let (targets, text, is_notice) = match command {
+            Command::PRIVMSG { targets, text } => (targets, text, false),
+            Command::NOTICE { targets, text } => (targets, text, true),
+            command => return vec![Message::new(None, None, command)],
+        };
+        let name = if is_notice { "NOTICE" } else { "PRIVMSG" };
+        let build = |text: String| match is_notice {
+            true => Command::NOTICE { targets: targets.clone(), text },
+            false => Command::PRIVMSG { targets: targets.clone(), text },
+        };
+        let max_len = self.max_text_len(name, &targets).max(1);
+        let lines = text.split('\n').map(|line| line.strip_suffix('\r').unwrap_or(line));
+
+        let Some(limits) = self.multiline else {
+            return lines
+                .filter(|line| !line.is_empty())
+                .flat_map(|line| split_line(line, max_len, true))
+                .map(|chunk| Message::new(None, None, build(chunk)))
+                .collect();
+        };
+
+        // The chunks of every line, which concatenate back to it.
+        let lines: Vec<Vec<String>> = lines.map(|line| split_line(line, max_len, false)).collect();
+        if lines.len() == 1 && lines[0].len() == 1 {
+            return vec![Message::new(None, None, build(lines.into_iter().flatten().next().unwrap()))];
+        }
+
+        let mut messages = Vec::new();
+        // (text, continues the previous line)
+        let mut batch: Vec<(String, bool)> = Vec::new();
+        let mut batch_bytes = 0;
+        let fits = |count: usize, bytes: usize, chunks: &[String]| {
+            bytes + chunks.iter().map(|chunk| chunk.len() + 1).sum::<usize>() <= limits.max_bytes
+                && limits.max_lines.is_none_or(|max| count + chunks.len() <= max)
+        };
+        for chunks in lines {
+            // Start a new batch rather than cut a line in two, unless it is
+            // too long for any batch.
+            if !batch.is_empty() && !fits(batch.len(), batch_bytes, &chunks) {
+                self.flush_batch(&mut messages, &targets, std::mem::take(&mut batch), &build);
+                batch_bytes = 0;
+            }
+            for (idx, text) in chunks.into_iter().enumerate() {
+                if !batch.is_empty() && !fits(batch.len(), batch_bytes, std::slice::from_ref(&text)) {
+                    self.flush_batch(&mut messages, &targets, std::mem::take(&mut batch), &build);
+                    batch_bytes = 0;
+                }
+                batch_bytes += text.len() + 1;
+                batch.push((text, idx > 0));
+            }
+        }
+        self.flush_batch(&mut messages, &targets, batch, &build);
+        return messages
+    }
+
+    fn flush_batch<F: Fn(String) -> Command>(&mut self, messages: &mut Vec<Message>, target: &str, batch: Vec<(String, bool)>, build: &F) {
+        let lines = batch.into_iter().enumerate().map(|(idx, (text, concat))| {
+            let message = Message::new(None, None, build(text));
+            // A batch never starts with a continuation, which only happens
+            // to a line too long for one batch.
+            match concat && idx > 0 {
+                true => message.with_tag("draft/multiline-concat", None),
+                false => message,
+            }
+        });
+        let next_batch = &mut self.next_batch;
+        messages.extend(BatchBuilder::new("draft/multiline", vec![target.to_string()]).messages(lines).build(&mut || {
+            *next_batch += 1;
+            format!("ml{}", next_batch)
+        }));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::connection::MAX_LINE_LEN;
+    use crate::formatting::strip;
+    use crate::types::{Command, Message, Source};
+
+    use super::{split_line, MultilineLimits, Splitter};
+
+    // A long host leaves less room for the text.
+    fn source() -> Source {
+        return Source { name: "dan".to_string(), user: Some("d".to_string()), host: Some("some.long.hostname.example.com".to_string()) }
+    }
+
+    fn relayed(message: Message) -> String {
+        return Message { source: Some(source()), ..message }.to_bytes()
+    }
+
+    #[test]
+    fn test_split_line() {
+        assert_eq!(vec!["hello", "world"], split_line("hello world", 8, true));
+        assert_eq!(vec!["hello ", "world"], split_line("hello world", 8, false));
+        assert_eq!(vec!["abcd", "efgh", "ij"], split_line("abcdefghij", 4, true));
+        // Multi-byte characters are never cut in half.
+        assert_eq!(vec!["żó", "łć"], split_line("żółć", 5, true));
+        // Formatting is carried over and codes are never cut.
+        assert_eq!(vec!["\x02\x0304abc", "\x02\x0304d"], split_line("\x02\x0304abcd", 7, true));
+        assert_eq!(vec!["\x0304,12", "x"], split_line("\x0304,12x", 6, false));
+    }
+
+    #[test]
+    fn test_split() {
+        let mut splitter = Splitter::new(&source());
+        let text = "lorem ipsum dolor sit amet ".repeat(60).trim_end().to_string();
+        let messages = splitter.split(Command::PRIVMSG { targets: "#chan".to_string(), text: text.clone() });
+        assert!(messages.len() > 3);
+        let mut joined = Vec::new();
+        for message in messages {
+            assert!(relayed(message.clone()).len() <= MAX_LINE_LEN);
+            if let Command::PRIVMSG { text, .. } = message.command {
+                joined.push(text);
+            }
+        }
+        assert_eq!(text, joined.join(" "));
+
+        let messages = splitter.split(Command::NOTICE { targets: "dan".to_string(), text: "one\r\n\ntwo".to_string() });
+        assert_eq!(vec!["NOTICE dan one\r\n", "NOTICE dan two\r\n"], messages.into_iter().map(Message::to_bytes).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_multiline() {
+        let limits = MultilineLimits::parse("max-bytes=1000,max-lines=3").unwrap();
+        let mut splitter = Splitter::new(&source()).multiline(limits);
+        assert_eq!(1, splitter.split(Command::PRIVMSG { targets: "#chan".to_string(), text: "short".to_string() }).len());
+
+        let text = format!("{}\nsecond line", "x".repeat(600));
+        let lines: Vec<String> = splitter.split(Command::PRIVMSG { targets: "#chan".to_string(), text })
+            .into_iter()
+            .map(|message| message.to_bytes())
+            .collect();
+        assert_eq!("BATCH +ml1 draft/multiline #chan\r\n", lines[0]);
+        assert!(lines[1].starts_with("@batch=ml1 PRIVMSG #chan xxx"));
+        assert!(lines[2].starts_with("@draft/multiline-concat;batch=ml1 PRIVMSG #chan xxx"));
+        assert_eq!("@batch=ml1 PRIVMSG #chan :second line\r\n", lines[3]);
+        assert_eq!("BATCH -ml1\r\n", lines[4]);
+
+        // The two halves of a line stay in one batch.
+        let text = format!("one\ntwo\n{}", "x".repeat(600));
+        let lines: Vec<String> = splitter.split(Command::PRIVMSG { targets: "#chan".to_string(), text })
+            .into_iter()
+            .map(|message| message.to_bytes())
+            .collect();
+        assert_eq!(8, lines.len());
+        assert_eq!("BATCH -ml2\r\n", lines[3]);
+        assert!(lines[5].starts_with("@batch=ml3 PRIVMSG #chan xxx"));
+        assert!(lines[6].starts_with("@draft/multiline-concat;batch=ml3 PRIVMSG #chan xxx"));
+
+        let text = "a\nb\nc\nd\ne".to_string();
+        let messages = splitter.split(Command::PRIVMSG { targets: "#chan".to_string(), text });
+        assert_eq!(9, messages.len());
+        assert_eq!("abcde", messages.iter().filter_map(|message| match &message.command {
+            Command::PRIVMSG { text, .. } => Some(strip(text)),
+            _ => None,
+        }).collect::<String>());
+    }
+}
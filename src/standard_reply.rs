@@ -0,0 +1,166 @@
+use std::fmt;
+
+use crate::types::{Command, Message};
+
+/// Codes from the IRCv3 registry this crate sends or understands, with the
+/// description used when the sender gives none.
+pub const CODES: &[(&str, &str)] = &[
+    ("ACCOUNT_REQUIRED", "You need to be logged in to do that"),
+    ("CANNOT_CHANGE_REALNAME", "Your realname cannot be changed"),
+    ("INVALID_MSGREFTYPE", "Unsupported message reference type"),
+    ("INVALID_PARAMS", "Invalid parameters"),
+    ("INVALID_REALNAME", "Realname is not valid"),
+    ("INVALID_TARGET", "Invalid target"),
+    ("INVALID_UTF8", "Message rejected, your IRC software MUST use UTF-8 encoding on this network"),
+    ("MESSAGE_ERROR", "Message could not be processed"),
+    ("NEED_MORE_PARAMS", "Missing parameters"),
+    ("UNKNOWN_COMMAND", "Unknown command"),
+    ("UNKNOWN_ERROR", "An unknown error occurred"),
+];
+
+/// The registered description of `code`.
+pub fn description(code: &str) -> Option<&'static str> {
+    return CODES.iter().find(|(known, _)| *known == code).map(|(_, description)| *description)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Fail,
+    Warn,
+    Note,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Fail => "FAIL",
+            Severity::Warn => "WARN",
+            Severity::Note => "NOTE",
+        }
+    }
+}
+
+/// A `FAIL`, `WARN` or `NOTE` message in structured form.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StandardReply {
+    pub severity: Severity,
+    /// The command this is about, `*` for none in particular.
+    pub command: String,
+    pub code: String,
+    pub context: Vec<String>,
+    pub description: String,
+}
+
+impl StandardReply {
+    /// A reply with the registered description of `code`, or the code itself.
+    pub fn new(severity: Severity, command: &str, code: &str) -> Self {
+        return StandardReply {
+            severity,
+            command: command.to_string(),
+            code: code.to_string(),
+            context: Vec::new(),
+            description: description(code).unwrap_or(code).to_string(),
+        }
+    }
+
+    pub fn fail(command: &str, code: &str) -> Self {
+        return StandardReply::new(Severity::Fail, command, code)
+    }
+
+    pub fn warn(command: &str, code: &str) -> Self {
+        return StandardReply::new(Severity::Warn, command, code)
+    }
+
+    pub fn note(command: &str, code: &str) -> Self {
+        return StandardReply::new(Severity::Note, command, code)
+    }
+
+    pub fn context(mut self, param: &str) -> Self {
+        self.context.push(param.to_string());
+        return self
+    }
+
+    pub fn description(mut self, description: &str) -> Self {
+        self.description = description.to_string();
+        return self
+    }
+
+    pub fn from_command(command: &Command) -> Option<StandardReply> {
+        let (severity, command, code, context, description) = match command {
+            Command::FAIL { command, code, context, description } => (Severity::Fail, command, code, context, description),
+            Command::WARN { command, code, context, description } => (Severity::Warn, command, code, context, description),
+            Command::NOTE { command, code, context, description } => (Severity::Note, command, code, context, description),
+            _ => return None,
+        };
+        return Some(StandardReply {
+            severity,
+            command: command.clone(),
+            code: code.clone(),
+            context: context.clone(),
+            description: description.clone(),
+        })
+    }
+
+    pub fn from_message(message: &Message) -> Option<StandardReply> {
+        return StandardReply::from_command(&message.command)
+    }
+
+    pub fn to_command(&self) -> Command {
+        let (command, code, context, description) = (self.command.clone(), self.code.clone(), self.context.clone(), self.description.clone());
+        match self.severity {
+            Severity::Fail => Command::FAIL { command, code, context, description },
+            Severity::Warn => Command::WARN { command, code, context, description },
+            Severity::Note => Command::NOTE { command, code, context, description },
+        }
+    }
+
+    pub fn is_fail(&self) -> bool {
+        return self.severity == Severity::Fail
+    }
+
+    /// Whether this is about `command`, or about no command in particular.
+    pub fn concerns(&self, command: &str) -> bool {
+        return self.command == "*" || self.command.eq_ignore_ascii_case(command)
+    }
+}
+
+impl fmt::Display for StandardReply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.severity.as_str(), self.command, self.code)?;
+        for param in &self.context {
+            write!(f, " {}", param)?;
+        }
+        return write!(f, ": {}", self.description)
+    }
+}
+
+impl std::error::Error for StandardReply {}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::types::{Command, Message};
+    use crate::test_util::parse;
+
+    use super::{description, Severity, StandardReply};
+
+    #[test]
+    fn test_standard_reply() {
+        let reply = StandardReply::fail("SETNAME", "INVALID_REALNAME");
+        assert_eq!("Realname is not valid", reply.description);
+        assert_eq!("FAIL SETNAME INVALID_REALNAME :Realname is not valid\r\n", Message::new(None, None, reply.to_command()).to_bytes());
+
+        let reply = StandardReply::warn("REHASH", "CERTS_EXPIRED").context("*").description("Certificate [xyz] has expired");
+        assert_eq!("WARN REHASH CERTS_EXPIRED *: Certificate [xyz] has expired", reply.to_string());
+        assert_eq!(None, description("CERTS_EXPIRED"));
+        assert_eq!("CERTS_EXPIRED", StandardReply::note("*", "CERTS_EXPIRED").description);
+
+        let message = parse(":irc.example.com NOTE * OPER_MESSAGE :The message");
+        let reply = StandardReply::from_message(&message).unwrap();
+        assert_eq!((Severity::Note, "OPER_MESSAGE"), (reply.severity, reply.code.as_str()));
+        assert!(!reply.is_fail() && reply.concerns("PRIVMSG"));
+        assert_eq!(message.command, reply.to_command());
+        assert!(!StandardReply::fail("SETNAME", "INVALID_REALNAME").concerns("NICK"));
+        assert_eq!(None, StandardReply::from_command(&Command::ACK));
+    }
+}
@@ -0,0 +1,309 @@
+use std::fmt;
+
+use crate::time::Timestamp;
+use crate::types::{Command, Message, Tag, TagKey};
+
+/// The value of the `+typing` client tag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Typing {
+    Active,
+    Paused,
+    Done,
+}
+
+impl Typing {
+    pub fn parse(value: &str) -> Option<Typing> {
+        match value {
+            "active" => Some(Typing::Active),
+            "paused" => Some(Typing::Paused),
+            "done" => Some(Typing::Done),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Typing::Active => "active",
+            Typing::Paused => "paused",
+            Typing::Done => "done",
+        }
+    }
+}
+
+/// The client-only tags a server refuses to relay, from the `CLIENTTAGDENY`
+/// ISUPPORT token, e.g. `*,-draft/react` to allow reactions only.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientTagDeny {
+    all: bool,
+    /// Denied names, or the exempted ones when `all`; without the `+`.
+    names: Vec<String>,
+}
+
+impl ClientTagDeny {
+    pub fn parse(value: &str) -> ClientTagDeny {
+        let entries: Vec<&str> = value.split(',').filter(|entry| !entry.is_empty()).collect();
+        let all = entries.contains(&"*");
+        let names = entries.iter()
+            .filter_map(|entry| match entry.strip_prefix('-') {
+                Some(exempt) if all => Some(exempt),
+                None if !all => Some(*entry),
+                _ => None,
+            })
+            .map(str::to_string)
+            .collect();
+        return ClientTagDeny { all, names }
+    }
+
+    /// Whether nothing is denied, in which case the token is not advertised.
+    pub fn is_empty(&self) -> bool {
+        return !self.all && self.names.is_empty()
+    }
+
+    /// Whether client tag `key`, with or without its `+`, may be relayed.
+    pub fn allows(&self, key: &str) -> bool {
+        let key = key.strip_prefix('+').unwrap_or(key);
+        return self.all == self.names.iter().any(|name| name == key)
+    }
+}
+
+impl fmt::Display for ClientTagDeny {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = self.names.iter().map(|name| match self.all {
+            true => format!("-{}", name),
+            false => name.clone(),
+        });
+        let all = self.all.then(|| "*".to_string());
+        return write!(f, "{}", all.into_iter().chain(names).collect::<Vec<String>>().join(","))
+    }
+}
+
+/// Accessors for message tags. Keys are given as written on the wire, e.g.
+/// `time` or `+draft/reply`; values are unescaped.
+impl Message {
+    pub fn tag(&self, key: &str) -> Option<&Tag> {
+        let key = TagKey::parse(key);
+        return self.tags.as_ref()?.iter().find(|tag| tag.key == key)
+    }
+
+    /// The value of `key`; a missing and an empty value are the same thing.
+    pub fn tag_value(&self, key: &str) -> Option<&str> {
+        return self.tag(key)?.value.as_deref().filter(|value| !value.is_empty())
+    }
+
+    pub fn has_tag(&self, key: &str) -> bool {
+        return self.tag(key).is_some()
+    }
+
+    /// Adds `key` or replaces its value.
+    pub fn set_tag(&mut self, key: &str, value: Option<&str>) {
+        let tag = Tag { key: TagKey::parse(key), value: value.map(str::to_string) };
+        let tags = self.tags.get_or_insert_with(Vec::new);
+        match tags.iter_mut().find(|existing| existing.key == tag.key) {
+            Some(existing) => *existing = tag,
+            None => tags.push(tag),
+        }
+    }
+
+    pub fn with_tag(mut self, key: &str, value: Option<&str>) -> Self {
+        self.set_tag(key, value);
+        return self
+    }
+
+    pub fn remove_tag(&mut self, key: &str) -> Option<Tag> {
+        let key = TagKey::parse(key);
+        let tags = self.tags.as_mut()?;
+        let idx = tags.iter().position(|tag| tag.key == key)?;
+        let tag = tags.remove(idx);
+        if tags.is_empty() {
+            self.tags = None;
+        }
+        return Some(tag)
+    }
+
+    /// `server-time`: when the server saw the message.
+    pub fn server_time(&self) -> Option<Timestamp> {
+        return Timestamp::parse(self.tag_value("time")?)
+    }
+
+    pub fn set_server_time(&mut self, time: Timestamp) {
+        self.set_tag("time", Some(&time.to_string()));
+    }
+
+    pub fn msgid(&self) -> Option<&str> {
+        return self.tag_value("msgid")
+    }
+
+    pub fn set_msgid(&mut self, msgid: &str) {
+        self.set_tag("msgid", Some(msgid));
+    }
+
+    /// `account-tag`: the account the sender is logged in as.
+    pub fn account(&self) -> Option<&str> {
+        return self.tag_value("account")
+    }
+
+    pub fn set_account(&mut self, account: &str) {
+        self.set_tag("account", Some(account));
+    }
+
+    /// `labeled-response`: ties a reply to the request that caused it.
+    pub fn label(&self) -> Option<&str> {
+        return self.tag_value("label")
+    }
+
+    pub fn set_label(&mut self, label: &str) {
+        self.set_tag("label", Some(label));
+    }
+
+    /// The reference of the batch this message belongs to.
+    pub fn batch(&self) -> Option<&str> {
+        return self.tag_value("batch")
+    }
+
+    pub fn set_batch(&mut self, reference: &str) {
+        self.set_tag("batch", Some(reference));
+    }
+
+    /// Whether the sender marked itself as a bot.
+    pub fn is_bot(&self) -> bool {
+        return self.has_tag("bot")
+    }
+
+    pub fn set_bot(&mut self, bot: bool) {
+        match bot {
+            true => self.set_tag("bot", None),
+            false => _ = self.remove_tag("bot"),
+        }
+    }
+
+    /// `+typing`: whether the sender is composing a message to us.
+    pub fn typing(&self) -> Option<Typing> {
+        return Typing::parse(self.tag_value("+typing")?)
+    }
+
+    pub fn set_typing(&mut self, typing: Typing) {
+        self.set_tag("+typing", Some(typing.as_str()));
+    }
+
+    /// `+draft/react`: the reaction, usually an emoji, to the message
+    /// named by [`Message::reply_to`].
+    pub fn react(&self) -> Option<&str> {
+        return self.tag_value("+draft/react")
+    }
+
+    pub fn set_react(&mut self, reaction: &str) {
+        self.set_tag("+draft/react", Some(reaction));
+    }
+
+    /// `+draft/reply`: the msgid of the message this one answers.
+    pub fn reply_to(&self) -> Option<&str> {
+        return self.tag_value("+draft/reply")
+    }
+
+    pub fn set_reply_to(&mut self, msgid: &str) {
+        self.set_tag("+draft/reply", Some(msgid));
+    }
+
+    /// A TAGMSG telling `target` we are typing, or stopped.
+    pub fn typing_notification(target: &str, typing: Typing) -> Message {
+        return Message::new(None, None, Command::TAGMSG { targets: target.to_string() })
+            .with_tag("+typing", Some(typing.as_str()))
+    }
+
+    /// A TAGMSG reacting to the message `msgid` sent to `target`.
+    pub fn reaction(target: &str, msgid: &str, reaction: &str) -> Message {
+        return Message::new(None, None, Command::TAGMSG { targets: target.to_string() })
+            .with_tag("+draft/reply", Some(msgid))
+            .with_tag("+draft/react", Some(reaction))
+    }
+
+    /// A PRIVMSG to `target` answering the message `msgid`.
+    pub fn reply(target: &str, msgid: &str, text: &str) -> Message {
+        return Message::new(None, None, Command::PRIVMSG { targets: target.to_string(), text: text.to_string() })
+            .with_tag("+draft/reply", Some(msgid))
+    }
+
+    /// The client-only tags, e.g. to relay them with a message.
+    pub fn client_tags(&self) -> impl Iterator<Item = &Tag> {
+        return self.tags.iter().flatten().filter(|tag| tag.key.is_client_only())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::time::Timestamp;
+    use crate::types::Message;
+    use crate::test_util::parse;
+
+    use super::{ClientTagDeny, Typing};
+
+    #[test]
+    fn test_accessors() {
+        let message = parse("@time=2023-11-14T22:13:20.042Z;msgid=abc;account=dan;bot;label=;+draft/reply=xyz :dan PRIVMSG #chan hi");
+        assert_eq!(Some(Timestamp::from_millis(1700000000042)), message.server_time());
+        assert_eq!(Some("abc"), message.msgid());
+        assert_eq!(Some("dan"), message.account());
+        assert!(message.is_bot());
+        assert_eq!(None, message.label());
+        assert!(message.has_tag("label"));
+        assert_eq!(None, message.batch());
+        assert_eq!(Some("xyz"), message.tag_value("+draft/reply"));
+        assert_eq!(None, message.tag_value("draft/reply"));
+
+        let broken = parse("@time=yesterday PING x");
+        assert!(broken.has_tag("time"));
+        assert_eq!(None, broken.server_time());
+    }
+
+    #[test]
+    fn test_setters() {
+        let mut message = parse("PING x");
+        message.set_server_time(Timestamp::from_millis(1700000000042));
+        message.set_msgid("a b");
+        message.set_bot(true);
+        message.set_msgid("a;b");
+        assert_eq!(Some("a;b"), message.msgid());
+        assert_eq!("@time=2023-11-14T22:13:20.042Z;msgid=a\\:b;bot PING x\r\n", message.clone().to_bytes());
+
+        message.set_bot(false);
+        message.remove_tag("time");
+        assert_eq!(Some("abc"), message.clone().with_tag("msgid", Some("abc")).msgid());
+        message.remove_tag("msgid");
+        assert_eq!(None, message.tags);
+        assert_eq!("PING x\r\n", message.to_bytes());
+    }
+
+    #[test]
+    fn test_client_tags() {
+        let typing = Message::typing_notification("#chan", Typing::Paused);
+        assert_eq!("@+typing=paused TAGMSG #chan\r\n", typing.clone().to_bytes());
+        assert_eq!(Some(Typing::Paused), parse("@+typing=paused :dan TAGMSG #chan").typing());
+        assert_eq!(None, parse("@+typing=bored :dan TAGMSG #chan").typing());
+
+        let reaction = Message::reaction("#chan", "abc", "👍");
+        assert_eq!("@+draft/reply=abc;+draft/react=👍 TAGMSG #chan\r\n", reaction.clone().to_bytes());
+        assert_eq!((Some("abc"), Some("👍")), (reaction.reply_to(), reaction.react()));
+
+        let mut reply = Message::reply("dan", "abc", "yes");
+        reply.set_msgid("def");
+        assert_eq!(vec!["+draft/reply"], reply.client_tags().map(|tag| tag.key.to_string()).collect::<Vec<String>>());
+        assert_eq!(0, typing.client_tags().filter(|tag| tag.key.value == "react").count());
+    }
+
+    #[test]
+    fn test_client_tag_deny() {
+        let deny = ClientTagDeny::parse("*,-draft/react,-draft/reply");
+        assert!(deny.allows("+draft/react") && deny.allows("draft/reply"));
+        assert!(!deny.allows("+typing"));
+        assert_eq!("*,-draft/react,-draft/reply", deny.to_string());
+
+        let deny = ClientTagDeny::parse("typing");
+        assert!(!deny.allows("+typing"));
+        assert!(deny.allows("+draft/react"));
+        assert_eq!("typing", deny.to_string());
+
+        assert!(ClientTagDeny::parse("").is_empty());
+        assert!(ClientTagDeny::default().allows("+typing"));
+    }
+}
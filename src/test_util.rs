@@ -0,0 +1,51 @@
+use std::io;
+use std::net::SocketAddr;
+
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+use crate::connection::Connection;
+use crate::server::IrcServer;
+use crate::types::{Command, Message, Source};
+
+pub(crate) fn parse(line: &str) -> Message {
+    return Message::from_bytes(line.as_bytes()).unwrap()
+}
+
+pub(crate) fn server() -> Source {
+    return Source { name: "irc.example.com".to_string(), user: None, host: None }
+}
+
+pub(crate) fn source(nick: &str) -> Source {
+    return Source { name: nick.to_string(), user: Some("u".to_string()), host: Some("host".to_string()) }
+}
+
+/// Runs `server` on a free local port.
+pub(crate) async fn spawn_server(server: IrcServer) -> (SocketAddr, JoinHandle<io::Result<()>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    return (addr, tokio::spawn(server.run(listener)))
+}
+
+pub(crate) async fn connect(addr: SocketAddr, nick: &str) -> Connection {
+    return connect_with_caps(addr, nick, &[]).await
+}
+
+/// Registers `nick` with `caps`, returning once the welcome burst is read.
+pub(crate) async fn connect_with_caps(addr: SocketAddr, nick: &str, caps: &[&str]) -> Connection {
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let mut client = Connection::new(stream, addr);
+    let mut lines = vec![format!("NICK {}", nick), format!("USER {} 0 * :Real Name", nick)];
+    if !caps.is_empty() {
+        lines.insert(0, format!("CAP REQ :{}", caps.join(" ")));
+        lines.push("CAP END".to_string());
+    }
+    for line in lines {
+        client.write(parse(&line)).await.unwrap();
+    }
+    loop {
+        if let Command::RPL_ISUPPORT { .. } = client.read().await.unwrap().command {
+            return client;
+        }
+    }
+}
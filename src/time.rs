@@ -0,0 +1,129 @@
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A UTC point in time with millisecond precision, as carried by the
+/// IRCv3 `time` tag (`2023-11-14T22:13:20.000Z`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp {
+    millis: u64,
+}
+
+impl Timestamp {
+    pub fn from_millis(millis: u64) -> Self {
+        return Timestamp { millis }
+    }
+
+    /// Saturates at the largest representable time.
+    pub fn from_secs(secs: u64) -> Self {
+        return Timestamp { millis: secs.saturating_mul(1000) }
+    }
+
+    pub fn now() -> Self {
+        let millis = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+        return Timestamp { millis }
+    }
+
+    /// Milliseconds since the epoch.
+    pub fn millis(&self) -> u64 {
+        return self.millis
+    }
+
+    /// Whole seconds since the epoch.
+    pub fn secs(&self) -> u64 {
+        return self.millis / 1000
+    }
+
+    /// Parses `YYYY-MM-DDThh:mm:ss[.fraction]Z`; digits beyond milliseconds
+    /// are ignored, years past 9999 are rejected.
+    pub fn parse(input: &str) -> Option<Timestamp> {
+        let input = input.strip_suffix('Z')?;
+        let (date, time) = input.split_once('T')?;
+        let mut date = date.splitn(3, '-').map(|part| part.parse::<u64>().ok());
+        let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+        let (time, fraction) = time.split_once('.').unwrap_or((time, ""));
+        let mut time = time.splitn(3, ':').map(|part| part.parse::<u64>().ok());
+        let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+        if year > 9999 || !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+            return None;
+        }
+        if !fraction.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let millis = format!("{:0<3}", &fraction[..fraction.len().min(3)]).parse::<u64>().ok()?;
+
+        let days = u64::try_from(days_from_civil(year as i64, month as u32, day as u32)).ok()?;
+        let secs = days.checked_mul(86400)?.checked_add(hour * 3600 + minute * 60 + second)?;
+        return Some(Timestamp { millis: secs.checked_mul(1000)?.checked_add(millis)? })
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.secs();
+        let (year, month, day) = civil_from_days((secs / 86400) as i64);
+        let time = secs % 86400;
+        write!(
+            f, "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year, month, day, time / 3600, time / 60 % 60, time % 60, self.millis % 1000,
+        )
+    }
+}
+
+/// Proleptic Gregorian date of a day count since 1970-01-01.
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    return (year, month, day)
+}
+
+/// Day count since 1970-01-01 of a proleptic Gregorian date.
+pub(crate) fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let day_of_year = (153 * mp + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    return era * 146097 + day_of_era - 719468
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{civil_from_days, days_from_civil, Timestamp};
+
+    #[test]
+    fn test_timestamp() {
+        let time = Timestamp::parse("2023-11-14T22:13:20.042Z").unwrap();
+        assert_eq!(1700000000042, time.millis());
+        assert_eq!("2023-11-14T22:13:20.042Z", time.to_string());
+        assert_eq!(Some(Timestamp::from_secs(1700000000)), Timestamp::parse("2023-11-14T22:13:20Z"));
+        assert_eq!(Some(Timestamp::from_millis(1709208000500)), Timestamp::parse("2024-02-29T12:00:00.5001Z"));
+        assert_eq!("1970-01-01T00:00:00.000Z", Timestamp::from_millis(0).to_string());
+
+        assert_eq!(None, Timestamp::parse("2023-11-14T22:13:20"));
+        assert_eq!(None, Timestamp::parse("2023-13-14T22:13:20Z"));
+        assert_eq!(None, Timestamp::parse("1969-12-31T23:59:59Z"));
+        assert_eq!(None, Timestamp::parse("2023-11-14T22:13:20.+1Z"));
+        assert_eq!(None, Timestamp::parse("9000000000000000000-01-01T00:00:00Z"));
+        assert_eq!(None, Timestamp::parse("99999999999-01-01T00:00:00Z"));
+        assert_eq!(None, Timestamp::parse("10000-01-01T00:00:00Z"));
+        assert_eq!(Some(253402300799999), Timestamp::parse("9999-12-31T23:59:59.999Z").map(|time| time.millis()));
+        assert_eq!(u64::MAX, Timestamp::from_secs(u64::MAX).millis());
+    }
+
+    #[test]
+    fn test_civil() {
+        for days in [-719468, -1, 0, 59, 19675, 2932896] {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days, days_from_civil(year, month, day));
+        }
+    }
+}
@@ -1,25 +1,25 @@
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Message {
     pub tags: Option<Vec<Tag>>,
     pub source: Option<Source>,
     pub command: Command,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Tag {
     pub key: TagKey,
     pub value: Option<String>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct TagKey {
     pub client_prefix: Option<String>,
     pub vendor: Option<String>,
     pub value: String,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Source {
     pub name: String,
     pub user: Option<String>,
@@ -38,6 +38,14 @@ pub struct ClientSource {
     pub host: Option<String>
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Casemapping {
+    Ascii,
+    #[default]
+    Rfc1459,
+    StrictRfc1459,
+}
+
 #[derive(Debug, Clone)]
 pub struct Nickname {
     pub value: String,
@@ -45,10 +53,10 @@ pub struct Nickname {
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Command {
     // Connection Messages
-    CAP{subcommand: String, capabilities: Option<String>},
+    CAP{target: Option<String>, subcommand: String, capabilities: Option<String>},
     PASS{password: String},
     NICK{nickname: String},
     USER{user: String, mode: String, unused: String, realname: String},
@@ -59,31 +67,157 @@ pub enum Command {
     ERROR{reason: String},
 
     // Channel Operations
-    JOIN{channels: String, keys: Option<String>},
+    /// With `extended-join` the server fills in `account` (`None` when the
+    /// user is not logged in) and `realname` instead of `keys`.
+    JOIN{channels: String, keys: Option<String>, account: Option<String>, realname: Option<String>},
+    PART{channels: String, reason: Option<String>},
+    KICK{channel: String, user: String, comment: Option<String>},
+    INVITE{nickname: String, channel: String},
+    TOPIC{channel: String, topic: Option<String>},
+    NAMES{channels: Option<String>},
+
+    // Server Queries and Commands
+    MOTD{target: Option<String>},
+    MODE{target: String, modestring: Option<String>, arguments: Vec<String>},
 
     // Sending Messages
     PRIVMSG{targets: String, text: String},
+    NOTICE{targets: String, text: String},
 
     // User-Based Queries
-    WHO{mask: String},
+    WHO{mask: String, fields: Option<String>},
+    WHOIS{target: Option<String>, nick: String},
+    WHOWAS{nick: String, count: Option<String>},
+
+    // Optional Messages
+    AWAY{message: Option<String>},
+
+    // IRCv3
+    /// `reference` keeps its leading `+` (start) or `-` (end).
+    BATCH{reference: String, kind: Option<String>, params: Vec<String>},
+    CHATHISTORY{subcommand: String, params: Vec<String>},
+    /// `subcommand` is one of `+`, `-`, `C`, `L` and `S`; `targets` is a
+    /// comma-separated list of nicks.
+    MONITOR{subcommand: String, targets: Option<String>},
+    /// `account` is `*` when the user logged out.
+    ACCOUNT{account: String},
+    CHGHOST{user: String, host: String},
+    SETNAME{realname: String},
+    /// A message carrying nothing but tags, e.g. `+typing`.
+    TAGMSG{targets: String},
+    /// Labeled response to a command that has no other reply.
+    ACK,
+    /// Standard replies, see [`StandardReply`](crate::standard_reply::StandardReply);
+    /// `command` is `*` when the reply is not tied to one.
+    FAIL{command: String, code: String, context: Vec<String>, description: String},
+    WARN{command: String, code: String, context: Vec<String>, description: String},
+    NOTE{command: String, code: String, context: Vec<String>, description: String},
 
+    /// Reply 001
+    RPL_WELCOME{client: String, message: String},
+    /// Reply 002
+    RPL_YOURHOST{client: String, message: String},
+    /// Reply 003
+    RPL_CREATED{client: String, message: String},
+    /// Reply 004
+    RPL_MYINFO{client: String, servername: String, version: String, user_modes: String, channel_modes: String},
+    /// Reply 005
+    RPL_ISUPPORT{client: String, tokens: Vec<String>},
+    /// Reply 221
+    RPL_UMODEIS{client: String, modes: String},
+    /// Reply 301
+    RPL_AWAY{client: String, nick: String, message: String},
+    /// Reply 305
+    RPL_UNAWAY{client: String, message: String},
+    /// Reply 306
+    RPL_NOWAWAY{client: String, message: String},
+    /// Reply 311
+    RPL_WHOISUSER{client: String, nick: String, username: String, host: String, realname: String},
+    /// Reply 312
+    RPL_WHOISSERVER{client: String, nick: String, server: String, info: String},
+    /// Reply 313
+    RPL_WHOISOPERATOR{client: String, nick: String},
+    /// Reply 314
+    RPL_WHOWASUSER{client: String, nick: String, username: String, host: String, realname: String},
     /// Reply 315
     RPL_ENDOFWHO{client: String, mask: String},
+    /// Reply 317
+    RPL_WHOISIDLE{client: String, nick: String, secs: String, signon: String},
+    /// Reply 318
+    RPL_ENDOFWHOIS{client: String, nick: String},
+    /// Reply 319
+    RPL_WHOISCHANNELS{client: String, nick: String, channels: Vec<String>},
+    /// Reply 324
+    RPL_CHANNELMODEIS{client: String, channel: String, modes: Vec<String>},
+    /// Reply 329
+    RPL_CREATIONTIME{client: String, channel: String, creationtime: String},
+    /// Reply 330
+    RPL_WHOISACCOUNT{client: String, nick: String, account: String},
+    /// Reply 331
+    RPL_NOTOPIC{client: String, channel: String},
+    /// Reply 332
+    RPL_TOPIC{client: String, channel: String, topic: String},
+    /// Reply 333
+    RPL_TOPICWHOTIME{client: String, channel: String, nick: String, setat: String},
+    /// Reply 346
+    RPL_INVITELIST{client: String, channel: String, mask: String, who: String, set_ts: String},
+    /// Reply 347
+    RPL_ENDOFINVITELIST{client: String, channel: String},
+    /// Reply 348
+    RPL_EXCEPTLIST{client: String, channel: String, mask: String, who: String, set_ts: String},
+    /// Reply 349
+    RPL_ENDOFEXCEPTLIST{client: String, channel: String},
     /// Reply 352
     RPL_WHOREPLY{client: String, channel: String, username: String, host: String, server: String, nick: String, flags: String, hopcount: String, realname: String},
     /// Reply 353
     RPL_NAMREPLY{client: String, symbol: String, channel: String, members: Vec<String>},
     /// Reply 366
     RPL_ENDOFNAMES{client: String, channel: String},
+    /// Reply 354
+    RPL_WHOSPCRPL{client: String, fields: Vec<String>},
+    /// Reply 367
+    RPL_BANLIST{client: String, channel: String, mask: String, who: String, set_ts: String},
+    /// Reply 368
+    RPL_ENDOFBANLIST{client: String, channel: String},
+    /// Reply 369
+    RPL_ENDOFWHOWAS{client: String, nick: String},
     /// Reply 372
     RPL_MOTD{client: String, line: String},
     /// Reply 375
     RPL_MOTDSTART{client: String, line: String},
     /// Reply 376
     RPL_ENDOFMOTD{client: String},
+    /// Reply 381
+    RPL_YOUREOPER{client: String},
+    /// Reply 671
+    RPL_WHOISSECURE{client: String, nick: String},
+    /// Reply 730
+    RPL_MONONLINE{client: String, targets: String},
+    /// Reply 731
+    RPL_MONOFFLINE{client: String, targets: String},
+    /// Reply 732
+    RPL_MONLIST{client: String, targets: String},
+    /// Reply 733
+    RPL_ENDOFMONLIST{client: String, message: String},
 
+    /// Error 401
+    ERR_NOSUCHNICK{client: String, nick: String},
+    /// Error 403
+    ERR_NOSUCHCHANNEL{client: String, channel: String},
+    /// Error 404
+    ERR_CANNOTSENDTOCHAN{client: String, channel: String},
+    /// Error 406
+    ERR_WASNOSUCHNICK{client: String, nick: String},
+    /// Error 411
+    ERR_NORECIPIENT{client: String},
     /// Error 412
     ERR_NOTEXTTOSEND{client: String},
+    /// Error 417
+    ERR_INPUTTOOLONG{client: String},
+    /// Error 421
+    ERR_UNKNOWNCOMMAND{client: String, command: String},
+    /// Error 422
+    ERR_NOMOTD{client: String},
     /// Error 431
     ERR_NONICKNAMEGIVEN{client: String},
     /// Error 432
@@ -92,12 +226,38 @@ pub enum Command {
     ERR_NICKNAMEINUSE{client: String, nick: String},
     /// Error 436
     ERR_NICKCOLLISION{client: String, nick: String, user: String, host: String},
+    /// Error 441
+    ERR_USERNOTINCHANNEL{client: String, nick: String, channel: String},
+    /// Error 442
+    ERR_NOTONCHANNEL{client: String, channel: String},
+    /// Error 451
+    ERR_NOTREGISTERED{client: String},
     /// Error 461
     ERR_NEEDMOREPARAMS{client: String, command: String},
     /// Error 462
     ERR_ALREADYREGISTERED{client: String},
     /// Error 464
     ERR_PASSWDMISMATCH{client: String}, // 464
+    /// Error 471
+    ERR_CHANNELISFULL{client: String, channel: String},
+    /// Error 472
+    ERR_UNKNOWNMODE{client: String, modechar: String},
+    /// Error 473
+    ERR_INVITEONLYCHAN{client: String, channel: String},
+    /// Error 474
+    ERR_BANNEDFROMCHAN{client: String, channel: String},
+    /// Error 475
+    ERR_BADCHANNELKEY{client: String, channel: String},
+    /// Error 482
+    ERR_CHANOPRIVSNEEDED{client: String, channel: String},
+    /// Error 491
+    ERR_NOOPERHOST{client: String},
+    /// Error 501
+    ERR_UMODEUNKNOWNFLAG{client: String},
+    /// Error 502
+    ERR_USERSDONTMATCH{client: String},
+    /// Error 734
+    ERR_MONLISTFULL{client: String, limit: String, targets: String, message: String},
 
     // UNKNOWN
     UNKNOWN,
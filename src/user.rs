@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use crate::types::{Casemapping, Source};
+
+pub const NICKLEN: usize = 30;
+
+pub fn is_valid_nick(nick: &str) -> bool {
+    // nick ::= ( letter / special ) *( letter / digit / special / "-" )
+    let special = |c: char| "[]\\`_^{|}".contains(c);
+    let mut chars = nick.chars();
+    return match chars.next() {
+        Some(first) if first.is_ascii_alphabetic() || special(first) => {
+            nick.len() <= NICKLEN && chars.all(|c| c.is_ascii_alphanumeric() || special(c) || c == '-')
+        }
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct User {
+    pub nick: String,
+    pub user: String,
+    pub host: String,
+    pub realname: String,
+    pub server: String,
+    pub ip: Option<IpAddr>,
+    pub account: Option<String>,
+    pub away: Option<String>,
+    pub oper: bool,
+    pub invisible: bool,
+    pub secure: bool,
+    pub signon: u64,
+    pub last_active: u64,
+}
+
+impl User {
+    pub fn new(nick: String, user: String, host: String, realname: String, server: String) -> Self {
+        return User {
+            nick,
+            user,
+            host,
+            realname,
+            server,
+            ip: None,
+            account: None,
+            away: None,
+            oper: false,
+            invisible: false,
+            secure: false,
+            signon: 0,
+            last_active: 0,
+        }
+    }
+
+    pub fn source(&self) -> Source {
+        return Source { name: self.nick.clone(), user: Some(self.user.clone()), host: Some(self.host.clone()) }
+    }
+}
+
+/// Registered users keyed by their casefolded nick.
+#[derive(Debug, Default)]
+pub struct UserRegistry {
+    casemapping: Casemapping,
+    users: HashMap<String, User>,
+}
+
+impl UserRegistry {
+    pub fn new(casemapping: Casemapping) -> Self {
+        return UserRegistry { casemapping, users: HashMap::new() }
+    }
+
+    pub fn casemapping(&self) -> Casemapping {
+        return self.casemapping
+    }
+
+    pub fn len(&self) -> usize {
+        return self.users.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.users.is_empty()
+    }
+
+    pub fn contains(&self, nick: &str) -> bool {
+        return self.users.contains_key(&self.casemapping.fold(nick))
+    }
+
+    pub fn get(&self, nick: &str) -> Option<&User> {
+        return self.users.get(&self.casemapping.fold(nick))
+    }
+
+    pub fn get_mut(&mut self, nick: &str) -> Option<&mut User> {
+        return self.users.get_mut(&self.casemapping.fold(nick))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &User> {
+        return self.users.values()
+    }
+
+    /// Adds a user, handing it back if the nick is already taken.
+    pub fn insert(&mut self, user: User) -> Result<(), Box<User>> {
+        let key = self.casemapping.fold(&user.nick);
+        if self.users.contains_key(&key) {
+            return Err(Box::new(user));
+        }
+        self.users.insert(key, user);
+        return Ok(())
+    }
+
+    pub fn remove(&mut self, nick: &str) -> Option<User> {
+        return self.users.remove(&self.casemapping.fold(nick))
+    }
+
+    /// Changes a user's nick, failing if the new nick belongs to someone else.
+    pub fn rename(&mut self, old: &str, new: &str) -> bool {
+        let (old_key, new_key) = (self.casemapping.fold(old), self.casemapping.fold(new));
+        if old_key != new_key && self.users.contains_key(&new_key) {
+            return false;
+        }
+        match self.users.remove(&old_key) {
+            Some(mut user) => {
+                user.nick = new.to_string();
+                self.users.insert(new_key, user);
+                true
+            }
+            None => false,
+        }
+    }
+}
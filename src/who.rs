@@ -0,0 +1,432 @@
+use crate::capability::{Capability, CapabilitySet};
+use crate::channel::{is_channel_name, Member};
+use crate::channel_registry::ChannelRegistry;
+use crate::mask::Glob;
+use crate::types::{Casemapping, Command, Message, Source};
+use crate::unix_time;
+use crate::user::{User, UserRegistry};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum WhoxField {
+    Token,
+    Channel,
+    Username,
+    Ip,
+    Host,
+    Server,
+    Nick,
+    Flags,
+    Hopcount,
+    Idle,
+    Account,
+    OpLevel,
+    Realname,
+}
+
+impl WhoxField {
+    /// All fields in the order replies carry them.
+    pub const ALL: &'static [WhoxField] = &[
+        WhoxField::Token,
+        WhoxField::Channel,
+        WhoxField::Username,
+        WhoxField::Ip,
+        WhoxField::Host,
+        WhoxField::Server,
+        WhoxField::Nick,
+        WhoxField::Flags,
+        WhoxField::Hopcount,
+        WhoxField::Idle,
+        WhoxField::Account,
+        WhoxField::OpLevel,
+        WhoxField::Realname,
+    ];
+
+    pub fn letter(&self) -> char {
+        match self {
+            WhoxField::Token => 't',
+            WhoxField::Channel => 'c',
+            WhoxField::Username => 'u',
+            WhoxField::Ip => 'i',
+            WhoxField::Host => 'h',
+            WhoxField::Server => 's',
+            WhoxField::Nick => 'n',
+            WhoxField::Flags => 'f',
+            WhoxField::Hopcount => 'd',
+            WhoxField::Idle => 'l',
+            WhoxField::Account => 'a',
+            WhoxField::OpLevel => 'o',
+            WhoxField::Realname => 'r',
+        }
+    }
+
+    pub fn from_letter(letter: char) -> Option<WhoxField> {
+        return WhoxField::ALL.iter().find(|field| field.letter() == letter).copied()
+    }
+}
+
+/// The `%<fields>[,<token>]` part of a WHOX query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WhoxQuery {
+    pub fields: Vec<WhoxField>,
+    pub token: Option<String>,
+}
+
+impl WhoxQuery {
+    pub fn new(fields: &[WhoxField], token: Option<String>) -> Self {
+        let mut fields = fields.to_vec();
+        fields.sort();
+        fields.dedup();
+        return WhoxQuery { fields, token }
+    }
+
+    pub fn parse(input: &str) -> Option<WhoxQuery> {
+        let (fields, token) = match input.strip_prefix('%')?.split_once(',') {
+            Some((fields, token)) => (fields, Some(token.to_string())),
+            None => (input.strip_prefix('%')?, None),
+        };
+        let fields: Vec<WhoxField> = fields.chars().filter_map(WhoxField::from_letter).collect();
+        return Some(WhoxQuery::new(&fields, token))
+    }
+
+    pub fn has(&self, field: WhoxField) -> bool {
+        return self.fields.contains(&field)
+    }
+}
+
+impl std::fmt::Display for WhoxQuery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "%")?;
+        for field in &self.fields {
+            write!(f, "{}", field.letter())?;
+        }
+        if let Some(token) = &self.token {
+            write!(f, ",{}", token)?;
+        }
+        return Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WhoQuery {
+    pub mask: String,
+    pub opers_only: bool,
+    pub whox: Option<WhoxQuery>,
+}
+
+impl WhoQuery {
+    pub fn new(mask: &str) -> Self {
+        return WhoQuery { mask: mask.to_string(), opers_only: false, whox: None }
+    }
+
+    pub fn from_command(command: &Command) -> Option<WhoQuery> {
+        match command {
+            Command::WHO { mask, fields } => {
+                let options = fields.as_deref().unwrap_or("");
+                let (flags, whox) = match options.find('%') {
+                    Some(idx) => (&options[..idx], WhoxQuery::parse(&options[idx..])),
+                    None => (options, None),
+                };
+                Some(WhoQuery { mask: mask.clone(), opers_only: flags.contains('o'), whox })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn to_command(&self) -> Command {
+        let mut fields = String::new();
+        if self.opers_only {
+            fields.push('o');
+        }
+        if let Some(whox) = &self.whox {
+            fields.push_str(&whox.to_string());
+        }
+        return Command::WHO { mask: self.mask.clone(), fields: (!fields.is_empty()).then_some(fields) }
+    }
+}
+
+/// Evaluates a WHO query on behalf of `client` and returns the reply lines
+/// followed by `RPL_ENDOFWHO`.
+pub fn who_reply(server: &Source, client: &str, query: &WhoQuery, users: &UserRegistry, channels: &ChannelRegistry, caps: &CapabilitySet) -> Vec<Message> {
+    let casemapping = users.casemapping();
+    let requester_is_oper = users.get(client).is_some_and(|user| user.oper);
+    let mut rows: Vec<(Option<&str>, &User, Option<&Member>)> = Vec::new();
+
+    if is_channel_name(&query.mask) {
+        if let Some(channel) = channels.get(&query.mask) {
+            let see_all = channel.is_member(client) || requester_is_oper;
+            if !channel.modes.secret || see_all {
+                for member in channel.members() {
+                    match users.get(&member.nick) {
+                        Some(user) if !user.invisible || see_all => rows.push((Some(&channel.name), user, Some(member))),
+                        _ => {}
+                    }
+                }
+            }
+        }
+    } else {
+        let glob = match query.mask.as_str() {
+            "0" | "" => Glob::new("*", casemapping),
+            mask => Glob::new(mask, casemapping),
+        };
+        let neighbours = channels.neighbours(client);
+        for user in users.iter() {
+            let visible = !user.invisible
+                || requester_is_oper
+                || casemapping.equals(&user.nick, client)
+                || neighbours.iter().any(|nick| casemapping.equals(nick, &user.nick));
+            let matched = [&user.nick, &user.user, &user.host, &user.server, &user.realname]
+                .iter()
+                .any(|field| glob.is_match(field));
+            if visible && matched {
+                rows.push((None, user, None));
+            }
+        }
+        rows.sort_by(|a, b| a.1.nick.cmp(&b.1.nick));
+    }
+
+    let now = unix_time();
+    let mut replies: Vec<Command> = rows.into_iter()
+        .filter(|(_, user, _)| !query.opers_only || user.oper)
+        .map(|(channel, user, member)| {
+            let mut flags = String::from(if user.away.is_some() { "G" } else { "H" });
+            if user.oper {
+                flags.push('*');
+            }
+            if let Some(member) = member {
+                if caps.contains(Capability::MultiPrefix) {
+                    flags.push_str(&member.prefixes);
+                } else if let Some(prefix) = member.highest_prefix() {
+                    flags.push(prefix);
+                }
+            }
+            let channel = channel.unwrap_or("*").to_string();
+
+            match &query.whox {
+                None => Command::RPL_WHOREPLY {
+                    client: client.to_string(),
+                    channel,
+                    username: user.user.clone(),
+                    host: user.host.clone(),
+                    server: user.server.clone(),
+                    nick: user.nick.clone(),
+                    flags,
+                    hopcount: "0".to_string(),
+                    realname: user.realname.clone(),
+                },
+                Some(whox) => {
+                    let fields = whox.fields.iter().map(|field| match field {
+                        WhoxField::Token => whox.token.clone().unwrap_or_else(|| "0".to_string()),
+                        WhoxField::Channel => channel.clone(),
+                        WhoxField::Username => user.user.clone(),
+                        WhoxField::Ip => user.ip.map(|ip| ip.to_string()).unwrap_or_else(|| "255.255.255.255".to_string()),
+                        WhoxField::Host => user.host.clone(),
+                        WhoxField::Server => user.server.clone(),
+                        WhoxField::Nick => user.nick.clone(),
+                        WhoxField::Flags => flags.clone(),
+                        WhoxField::Hopcount => "0".to_string(),
+                        WhoxField::Idle => now.saturating_sub(user.last_active).to_string(),
+                        WhoxField::Account => user.account.clone().unwrap_or_else(|| "0".to_string()),
+                        WhoxField::OpLevel => "n/a".to_string(),
+                        WhoxField::Realname => user.realname.clone(),
+                    }).collect();
+                    Command::RPL_WHOSPCRPL { client: client.to_string(), fields }
+                }
+            }
+        })
+        .collect();
+
+    replies.push(Command::RPL_ENDOFWHO { client: client.to_string(), mask: query.mask.clone() });
+    return replies.into_iter()
+        .map(|command| Message::new(None, Some(server.clone()), command))
+        .collect()
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WhoRecord {
+    pub token: Option<String>,
+    pub channel: Option<String>,
+    pub username: Option<String>,
+    pub ip: Option<String>,
+    pub host: Option<String>,
+    pub server: Option<String>,
+    pub nick: Option<String>,
+    pub flags: Option<String>,
+    pub hopcount: Option<u32>,
+    pub idle: Option<u64>,
+    pub account: Option<String>,
+    pub oplevel: Option<String>,
+    pub realname: Option<String>,
+}
+
+impl WhoRecord {
+    pub fn is_away(&self) -> bool {
+        return self.flags.as_deref().is_some_and(|flags| flags.starts_with('G'))
+    }
+
+    pub fn is_oper(&self) -> bool {
+        return self.flags.as_deref().is_some_and(|flags| flags.contains('*'))
+    }
+
+    fn from_whoreply(command: &Command) -> Option<WhoRecord> {
+        match command {
+            Command::RPL_WHOREPLY { channel, username, host, server, nick, flags, hopcount, realname, .. } => Some(WhoRecord {
+                channel: (channel != "*").then(|| channel.clone()),
+                username: Some(username.clone()),
+                host: Some(host.clone()),
+                server: Some(server.clone()),
+                nick: Some(nick.clone()),
+                flags: Some(flags.clone()),
+                hopcount: hopcount.parse().ok(),
+                realname: Some(realname.clone()),
+                ..Default::default()
+            }),
+            _ => None,
+        }
+    }
+
+    fn from_whox(whox: &WhoxQuery, values: &[String]) -> WhoRecord {
+        let mut record = WhoRecord::default();
+        for (field, value) in whox.fields.iter().zip(values) {
+            let value = value.clone();
+            match field {
+                WhoxField::Token => record.token = Some(value),
+                WhoxField::Channel => record.channel = (value != "*").then_some(value),
+                WhoxField::Username => record.username = Some(value),
+                WhoxField::Ip => record.ip = Some(value),
+                WhoxField::Host => record.host = Some(value),
+                WhoxField::Server => record.server = Some(value),
+                WhoxField::Nick => record.nick = Some(value),
+                WhoxField::Flags => record.flags = Some(value),
+                WhoxField::Hopcount => record.hopcount = value.parse().ok(),
+                WhoxField::Idle => record.idle = value.parse().ok(),
+                WhoxField::Account => record.account = (value != "0").then_some(value),
+                WhoxField::OpLevel => record.oplevel = Some(value),
+                WhoxField::Realname => record.realname = Some(value),
+            }
+        }
+        return record
+    }
+}
+
+/// Collects the replies to one WHO query until `RPL_ENDOFWHO`.
+#[derive(Debug)]
+pub struct WhoCollector {
+    query: WhoQuery,
+    casemapping: Casemapping,
+    records: Vec<WhoRecord>,
+}
+
+impl WhoCollector {
+    pub fn new(query: WhoQuery, casemapping: Casemapping) -> Self {
+        return WhoCollector { query, casemapping, records: Vec::new() }
+    }
+
+    pub fn feed(&mut self, message: &Message) -> Option<Vec<WhoRecord>> {
+        match &message.command {
+            Command::RPL_WHOREPLY { .. } if self.query.whox.is_none() => {
+                self.records.extend(WhoRecord::from_whoreply(&message.command));
+            }
+            Command::RPL_WHOSPCRPL { fields, .. } => {
+                if let Some(whox) = &self.query.whox {
+                    let record = WhoRecord::from_whox(whox, fields);
+                    if !whox.has(WhoxField::Token) || record.token == whox.token {
+                        self.records.push(record);
+                    }
+                }
+            }
+            Command::RPL_ENDOFWHO { mask, .. } if self.casemapping.equals(mask, &self.query.mask) => {
+                return Some(std::mem::take(&mut self.records));
+            }
+            _ => {}
+        }
+        return None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::capability::CapabilitySet;
+    use crate::channel_registry::ChannelRegistry;
+    use crate::types::{Casemapping, Command, Message};
+    use crate::user::{User, UserRegistry};
+    use crate::test_util::{parse, server};
+
+    use super::{who_reply, WhoCollector, WhoQuery, WhoxField, WhoxQuery};
+
+    fn setup() -> (UserRegistry, ChannelRegistry) {
+        let mut users = UserRegistry::new(Casemapping::Rfc1459);
+        let mut channels = ChannelRegistry::new(Casemapping::Rfc1459);
+        for nick in ["dan", "eve", "bob"] {
+            let mut user = User::new(nick.to_string(), "u".to_string(), format!("{}.example.com", nick), format!("{} real", nick), "irc.example.com".to_string());
+            user.invisible = nick == "bob";
+            user.account = (nick == "eve").then(|| "eve_acct".to_string());
+            channels.join(&user.source(), "#chan", None).unwrap();
+            users.insert(user).unwrap();
+        }
+        users.get_mut("eve").unwrap().away = Some("lunch".to_string());
+        users.get_mut("dan").unwrap().oper = true;
+        return (users, channels)
+    }
+
+    #[test]
+    fn test_whox_query() {
+        let query = WhoQuery::from_command(&Command::WHO { mask: "#chan".to_string(), fields: Some("o%nuat,42".to_string()) }).unwrap();
+        assert!(query.opers_only);
+        assert_eq!(Some(WhoxQuery::new(&[WhoxField::Nick, WhoxField::Username, WhoxField::Account, WhoxField::Token], Some("42".to_string()))), query.whox);
+        assert_eq!("WHO #chan o%tuna,42\r\n", Message::new(None, None, query.to_command()).to_bytes());
+
+        let whox = WhoxQuery::parse("%na,42").unwrap();
+        assert_eq!(vec![WhoxField::Nick, WhoxField::Account], whox.fields);
+        assert_eq!("%na,42", whox.to_string());
+    }
+
+    #[test]
+    fn test_who_channel() {
+        let (users, channels) = setup();
+        let replies = who_reply(&server(), "dan", &WhoQuery::new("#chan"), &users, &channels, &CapabilitySet::new());
+        assert_eq!(4, replies.len());
+        assert_eq!(":irc.example.com 352 dan #chan u dan.example.com irc.example.com dan H*@ :0 dan real\r\n", replies[1].clone().to_bytes());
+
+        let mut collector = WhoCollector::new(WhoQuery::new("#chan"), Casemapping::Rfc1459);
+        let records: Vec<_> = replies.iter().filter_map(|reply| {
+            collector.feed(&parse(reply.clone().to_bytes().trim_end()))
+        }).collect();
+        let records = &records[0];
+        assert_eq!(3, records.len());
+        assert!(records.iter().any(|record| record.nick.as_deref() == Some("eve") && record.is_away()));
+        assert_eq!(Some("dan real".to_string()), records[1].realname);
+    }
+
+    #[test]
+    fn test_who_channel_invisible() {
+        let (mut users, channels) = setup();
+        users.insert(User::new("zed".to_string(), "u".to_string(), "zed.example.com".to_string(), "zed real".to_string(), "irc.example.com".to_string())).unwrap();
+        let replies = who_reply(&server(), "zed", &WhoQuery::new("#chan"), &users, &channels, &CapabilitySet::new());
+        let nicks: Vec<_> = replies.iter().filter_map(|reply| match &reply.command {
+            Command::RPL_WHOREPLY { nick, .. } => Some(nick.as_str()),
+            _ => None,
+        }).collect();
+        assert_eq!(vec!["dan", "eve"], nicks);
+
+        users.get_mut("zed").unwrap().oper = true;
+        let replies = who_reply(&server(), "zed", &WhoQuery::new("#chan"), &users, &channels, &CapabilitySet::new());
+        assert_eq!(4, replies.len());
+    }
+
+    #[test]
+    fn test_who_mask_whox() {
+        let (users, mut channels) = setup();
+        channels.quit(&users.get("bob").unwrap().source(), None);
+
+        let query = WhoQuery { mask: "*.example.com".to_string(), opers_only: false, whox: WhoxQuery::parse("%tnar,7") };
+        let replies = who_reply(&server(), "eve", &query, &users, &channels, &CapabilitySet::new());
+        assert_eq!(3, replies.len());
+        assert_eq!(":irc.example.com 354 eve 7 eve eve_acct :eve real\r\n", replies[1].clone().to_bytes());
+
+        let mut collector = WhoCollector::new(query, Casemapping::Rfc1459);
+        let records = replies.iter().find_map(|reply| collector.feed(reply)).unwrap();
+        assert_eq!(Some("eve_acct".to_string()), records[1].account);
+        assert_eq!(None, records[0].account);
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::VecDeque;
+
+use crate::channel_registry::ChannelRegistry;
+use crate::types::{Casemapping, Command, Message, Source};
+use crate::unix_time;
+use crate::user::{User, UserRegistry};
+
+/// Composes the full WHOIS burst for `nick`, ending with `RPL_ENDOFWHOIS`.
+///
+/// Secret channels are only listed when `client` shares them.
+pub fn whois_reply(server: &Source, server_info: &str, client: &str, nick: &str, users: &UserRegistry, channels: &ChannelRegistry) -> Vec<Message> {
+    let mut replies = Vec::new();
+    let client_str = client.to_string();
+
+    match users.get(nick) {
+        None => replies.push(Command::ERR_NOSUCHNICK { client: client_str.clone(), nick: nick.to_string() }),
+        Some(user) => {
+            let nick = user.nick.clone();
+            replies.push(Command::RPL_WHOISUSER {
+                client: client_str.clone(),
+                nick: nick.clone(),
+                username: user.user.clone(),
+                host: user.host.clone(),
+                realname: user.realname.clone(),
+            });
+
+            let mut memberships: Vec<String> = channels.channels_of(&user.nick)
+                .filter(|channel| !channel.modes.secret || channel.is_member(client))
+                .map(|channel| {
+                    let member = channel.member(&user.nick).unwrap();
+                    format!("{}{}", member.highest_prefix().map(String::from).unwrap_or_default(), channel.name)
+                })
+                .collect();
+            memberships.sort();
+            if !memberships.is_empty() {
+                replies.push(Command::RPL_WHOISCHANNELS { client: client_str.clone(), nick: nick.clone(), channels: memberships });
+            }
+
+            replies.push(Command::RPL_WHOISSERVER {
+                client: client_str.clone(),
+                nick: nick.clone(),
+                server: user.server.clone(),
+                info: server_info.to_string(),
+            });
+            if let Some(message) = &user.away {
+                replies.push(Command::RPL_AWAY { client: client_str.clone(), nick: nick.clone(), message: message.clone() });
+            }
+            if user.oper {
+                replies.push(Command::RPL_WHOISOPERATOR { client: client_str.clone(), nick: nick.clone() });
+            }
+            if user.secure {
+                replies.push(Command::RPL_WHOISSECURE { client: client_str.clone(), nick: nick.clone() });
+            }
+            if let Some(account) = &user.account {
+                replies.push(Command::RPL_WHOISACCOUNT { client: client_str.clone(), nick: nick.clone(), account: account.clone() });
+            }
+            replies.push(Command::RPL_WHOISIDLE {
+                client: client_str.clone(),
+                nick: nick.clone(),
+                secs: unix_time().saturating_sub(user.last_active).to_string(),
+                signon: user.signon.to_string(),
+            });
+        }
+    }
+
+    replies.push(Command::RPL_ENDOFWHOIS { client: client_str, nick: nick.to_string() });
+    return replies.into_iter()
+        .map(|command| Message::new(None, Some(server.clone()), command))
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WhowasEntry {
+    pub nick: String,
+    pub user: String,
+    pub host: String,
+    pub realname: String,
+    pub server: String,
+    pub logoff: u64,
+}
+
+/// Bounded history of users that quit or changed nick, newest first.
+#[derive(Debug)]
+pub struct WhowasHistory {
+    casemapping: Casemapping,
+    capacity: usize,
+    entries: VecDeque<WhowasEntry>,
+}
+
+impl WhowasHistory {
+    pub fn new(casemapping: Casemapping, capacity: usize) -> Self {
+        return WhowasHistory { casemapping, capacity, entries: VecDeque::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        return self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.entries.is_empty()
+    }
+
+    pub fn record(&mut self, user: &User, logoff: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_back();
+        }
+        self.entries.push_front(WhowasEntry {
+            nick: user.nick.clone(),
+            user: user.user.clone(),
+            host: user.host.clone(),
+            realname: user.realname.clone(),
+            server: user.server.clone(),
+            logoff,
+        });
+    }
+
+    /// Entries for `nick`, newest first, limited to `count` when given.
+    pub fn lookup(&self, nick: &str, count: Option<usize>) -> Vec<&WhowasEntry> {
+        return self.entries.iter()
+            .filter(|entry| self.casemapping.equals(&entry.nick, nick))
+            .take(count.filter(|count| *count > 0).unwrap_or(usize::MAX))
+            .collect()
+    }
+}
+
+pub fn whowas_reply(server: &Source, client: &str, nick: &str, count: Option<usize>, history: &WhowasHistory) -> Vec<Message> {
+    let mut replies = Vec::new();
+    let entries = history.lookup(nick, count);
+
+    if entries.is_empty() {
+        replies.push(Command::ERR_WASNOSUCHNICK { client: client.to_string(), nick: nick.to_string() });
+    }
+    for entry in entries {
+        replies.push(Command::RPL_WHOWASUSER {
+            client: client.to_string(),
+            nick: entry.nick.clone(),
+            username: entry.user.clone(),
+            host: entry.host.clone(),
+            realname: entry.realname.clone(),
+        });
+        replies.push(Command::RPL_WHOISSERVER {
+            client: client.to_string(),
+            nick: entry.nick.clone(),
+            server: entry.server.clone(),
+            info: entry.logoff.to_string(),
+        });
+    }
+
+    replies.push(Command::RPL_ENDOFWHOWAS { client: client.to_string(), nick: nick.to_string() });
+    return replies.into_iter()
+        .map(|command| Message::new(None, Some(server.clone()), command))
+        .collect()
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WhoisInfo {
+    pub nick: String,
+    pub username: String,
+    pub host: String,
+    pub realname: String,
+    pub server: Option<String>,
+    pub server_info: Option<String>,
+    pub channels: Vec<String>,
+    pub away: Option<String>,
+    pub operator: bool,
+    pub secure: bool,
+    pub account: Option<String>,
+    pub idle: Option<u64>,
+    pub signon: Option<u64>,
+}
+
+/// Turns one WHOIS reply burst into a [`WhoisInfo`], or the error numeric
+/// the server answered with.
+#[derive(Debug)]
+pub struct WhoisCollector {
+    nick: String,
+    casemapping: Casemapping,
+    info: Option<WhoisInfo>,
+    error: Option<Command>,
+}
+
+impl WhoisCollector {
+    pub fn new(nick: &str, casemapping: Casemapping) -> Self {
+        return WhoisCollector { nick: nick.to_string(), casemapping, info: None, error: None }
+    }
+
+    pub fn feed(&mut self, message: &Message) -> Option<Result<WhoisInfo, Command>> {
+        use Command::*;
+
+        let info = &mut self.info;
+        match &message.command {
+            RPL_WHOISUSER { nick, username, host, realname, .. } if self.casemapping.equals(nick, &self.nick) => {
+                *info = Some(WhoisInfo {
+                    nick: nick.clone(),
+                    username: username.clone(),
+                    host: host.clone(),
+                    realname: realname.clone(),
+                    ..Default::default()
+                });
+            }
+            ERR_NOSUCHNICK { nick, .. } if self.casemapping.equals(nick, &self.nick) => {
+                self.error = Some(message.command.clone());
+            }
+            RPL_WHOISCHANNELS { nick, channels, .. } if self.casemapping.equals(nick, &self.nick) => {
+                if let Some(info) = info {
+                    info.channels.extend(channels.iter().cloned());
+                }
+            }
+            RPL_WHOISSERVER { nick, server, info: server_info, .. } if self.casemapping.equals(nick, &self.nick) => {
+                if let Some(info) = info {
+                    info.server = Some(server.clone());
+                    info.server_info = Some(server_info.clone());
+                }
+            }
+            RPL_AWAY { nick, message, .. } if self.casemapping.equals(nick, &self.nick) => {
+                if let Some(info) = info {
+                    info.away = Some(message.clone());
+                }
+            }
+            RPL_WHOISOPERATOR { nick, .. } if self.casemapping.equals(nick, &self.nick) => {
+                if let Some(info) = info {
+                    info.operator = true;
+                }
+            }
+            RPL_WHOISSECURE { nick, .. } if self.casemapping.equals(nick, &self.nick) => {
+                if let Some(info) = info {
+                    info.secure = true;
+                }
+            }
+            RPL_WHOISACCOUNT { nick, account, .. } if self.casemapping.equals(nick, &self.nick) => {
+                if let Some(info) = info {
+                    info.account = Some(account.clone());
+                }
+            }
+            RPL_WHOISIDLE { nick, secs, signon, .. } if self.casemapping.equals(nick, &self.nick) => {
+                if let Some(info) = info {
+                    info.idle = secs.parse().ok();
+                    info.signon = signon.parse().ok();
+                }
+            }
+            RPL_ENDOFWHOIS { nick, .. } if self.casemapping.equals(nick, &self.nick) => {
+                return match (self.info.take(), self.error.take()) {
+                    (Some(info), _) => Some(Ok(info)),
+                    (None, Some(error)) => Some(Err(error)),
+                    (None, None) => Some(Err(ERR_NOSUCHNICK { client: String::new(), nick: self.nick.clone() })),
+                };
+            }
+            _ => {}
+        }
+        return None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::channel_registry::ChannelRegistry;
+    use crate::types::{Casemapping, Command, Message};
+    use crate::user::{User, UserRegistry};
+    use crate::test_util::{parse, server};
+
+    use super::{whois_reply, whowas_reply, WhoisCollector, WhowasHistory};
+
+    fn reparse(message: &Message) -> Message {
+        return parse(message.clone().to_bytes().trim_end())
+    }
+
+    #[test]
+    fn test_whois() {
+        let mut users = UserRegistry::new(Casemapping::Rfc1459);
+        let mut channels = ChannelRegistry::new(Casemapping::Rfc1459);
+        let mut user = User::new("dan".to_string(), "d".to_string(), "localhost".to_string(), "Dan Real".to_string(), "irc.example.com".to_string());
+        user.account = Some("dan".to_string());
+        user.secure = true;
+        user.away = Some("gone fishing".to_string());
+        user.signon = 1700000000;
+        channels.join(&user.source(), "#chan", None).unwrap();
+        channels.join(&user.source(), "#other", None).unwrap();
+        channels.get_mut("#other").unwrap().modes.secret = true;
+        users.insert(user).unwrap();
+
+        let replies = whois_reply(&server(), "Example server", "eve", "DAN", &users, &channels);
+        assert_eq!(":irc.example.com 311 eve dan d localhost * :Dan Real\r\n", replies[0].clone().to_bytes());
+
+        let mut collector = WhoisCollector::new("DAN", Casemapping::Rfc1459);
+        let info = replies.iter().find_map(|reply| collector.feed(&reparse(reply))).unwrap().unwrap();
+        assert_eq!(vec!["@#chan"], info.channels);
+        assert_eq!(Some("gone fishing".to_string()), info.away);
+        assert_eq!(Some("dan".to_string()), info.account);
+        assert_eq!(Some(1700000000), info.signon);
+        assert!(info.secure && !info.operator);
+
+        let replies = whois_reply(&server(), "Example server", "eve", "nobody", &users, &channels);
+        let mut collector = WhoisCollector::new("nobody", Casemapping::Rfc1459);
+        let result = replies.iter().find_map(|reply| collector.feed(&reparse(reply))).unwrap();
+        assert!(matches!(result, Err(Command::ERR_NOSUCHNICK { .. })));
+    }
+
+    #[test]
+    fn test_whowas() {
+        let mut history = WhowasHistory::new(Casemapping::Rfc1459, 2);
+        for host in ["a", "b", "c"] {
+            let user = User::new("dan".to_string(), "d".to_string(), host.to_string(), "Dan".to_string(), "irc.example.com".to_string());
+            history.record(&user, 0);
+        }
+        assert_eq!(2, history.len());
+        assert_eq!("c", history.lookup("Dan", Some(1))[0].host);
+
+        let replies = whowas_reply(&server(), "eve", "dan", None, &history);
+        assert_eq!(5, replies.len());
+        let replies = whowas_reply(&server(), "eve", "bob", None, &history);
+        assert!(matches!(replies[0].command, Command::ERR_WASNOSUCHNICK { .. }));
+    }
+}